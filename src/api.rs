@@ -0,0 +1,211 @@
+//! HTTP API serving the latest samples of the daemon as JSON
+//! (`api_addr`), to curl what just happened during an incident
+//!
+//! `/api/v1/current` has the last sample, `/api/v1/history` all the
+//! samples of the last [crate::Settings::api_history], or of the last
+//! `?seconds=N`. Both are a JSON array of the objects of
+//! [crate::json], one per graph and sample, oldest first.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{fanout::Target, json, CpuStat, Settings};
+use anyhow::Result;
+use log::{info, warn};
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
+
+/// How long a client may take for its request, or to read the answer
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The samples we serve
+#[derive(Debug, Default)]
+struct History {
+    /// The samples, oldest first
+    samples: VecDeque<Vec<CpuStat>>,
+    /// How many we keep
+    capacity: usize,
+}
+
+impl History {
+    /// Add the sample `graphs`, dropping the oldest one if full
+    fn push(&mut self, graphs: &[CpuStat]) {
+        if self.samples.len() >= self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(graphs.to_vec());
+    }
+
+    /// The samples of the last `seconds` before the latest one, all
+    /// for None, as JSON
+    fn render(&self, seconds: Option<u64>) -> Vec<u8> {
+        let latest = self
+            .samples
+            .back()
+            .and_then(|graphs| graphs.first())
+            .map_or(0, |stat| stat.epoch);
+        let since = seconds.map_or(0, |seconds| latest.saturating_sub(seconds));
+        let stats: Vec<CpuStat> = self
+            .samples
+            .iter()
+            .flatten()
+            .filter(|stat| stat.epoch > since || seconds.is_none())
+            .copied()
+            .collect();
+        json::encode_array(&stats)
+    }
+
+    /// The last sample, as JSON
+    fn current(&self) -> Vec<u8> {
+        json::encode_array(self.samples.back().map_or(&[], Vec::as_slice))
+    }
+}
+
+/// Serves the API, from a thread of its own
+#[derive(Debug)]
+pub(crate) struct Api {
+    /// Where we listen
+    pub(crate) addr: SocketAddr,
+    /// Shared with the thread serving it
+    history: Arc<Mutex<History>>,
+}
+
+impl Api {
+    /// Listen on `addr` (host:port), keeping `capacity` samples. Has
+    /// to happen after daemonizing, threads do not survive the fork.
+    pub(crate) fn spawn(addr: &str, capacity: usize) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let history = Arc::new(Mutex::new(History {
+            capacity,
+            ..Default::default()
+        }));
+        let shared = Arc::clone(&history);
+        thread::Builder::new()
+            .name(String::from("api"))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if let Err(e) = stream.and_then(|stream| serve(stream, &shared)) {
+                        warn!("Could not serve an API request: {e}");
+                    }
+                }
+            })?;
+        Ok(Self { addr, history })
+    }
+
+    /// Add the sample `graphs`
+    pub(crate) fn update(&self, graphs: &[CpuStat]) {
+        self.history
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(graphs);
+    }
+}
+
+impl Target for Api {
+    fn deliver(&mut self, graphs: &[CpuStat]) {
+        self.update(graphs);
+    }
+}
+
+/// The [Target] for [Settings::api_addr], if set and we can listen
+/// there
+pub(crate) fn target(settings: &Settings) -> Option<Box<dyn Target>> {
+    let addr = settings.api_addr.as_ref()?;
+    let capacity = settings.api_history.as_millis() / settings.interval.as_millis().max(1);
+    match Api::spawn(addr, capacity as usize) {
+        Ok(api) => {
+            info!("Serving the API on http://{}/api/v1/", api.addr);
+            Some(Box::new(api))
+        }
+        Err(e) => {
+            warn!("Not serving the API on {addr}: {e}");
+            None
+        }
+    }
+}
+
+/// Answer one HTTP request
+fn serve(stream: TcpStream, history: &Mutex<History>) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Read the headers we have no use for, closing with unread data
+    // makes the kernel reset the connection
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let target = request.split_whitespace().nth(1).unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let seconds = query
+        .split('&')
+        .find_map(|param| param.strip_prefix("seconds="))
+        .map(str::parse::<u64>);
+    let history = || history.lock().unwrap_or_else(PoisonError::into_inner);
+    let (status, content_type, body) = match (path, seconds) {
+        ("/api/v1/current", _) => ("200 OK", "application/json", history().current()),
+        ("/api/v1/history", Some(Err(_))) => (
+            "400 Bad Request",
+            "text/plain",
+            b"seconds has to be a number\n".to_vec(),
+        ),
+        ("/api/v1/history", seconds) => (
+            "200 OK",
+            "application/json",
+            history().render(seconds.and_then(Result::ok)),
+        ),
+        _ => (
+            "404 Not Found",
+            "text/plain",
+            b"Try /api/v1/current or /api/v1/history?seconds=300\n".to_vec(),
+        ),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+#[test]
+fn test_api() {
+    use std::io::Read;
+
+    let api = Api::spawn("127.0.0.1:0", 3).unwrap();
+    for epoch in 1..=4 {
+        api.update(&[CpuStat {
+            epoch,
+            ..Default::default()
+        }]);
+    }
+    let get = |path: &str| {
+        let mut stream = TcpStream::connect(api.addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let epochs = |response: String| response.matches("\"epoch\":").count();
+    let current = get("/api/v1/current");
+    assert!(current.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(current.contains("\r\n\r\n[{\"cpu\":\"total\",\"epoch\":4,"));
+    assert_eq!(1, epochs(current));
+    // Only room for three
+    assert_eq!(3, epochs(get("/api/v1/history")));
+    assert_eq!(2, epochs(get("/api/v1/history?seconds=2")));
+    assert!(get("/api/v1/history?seconds=many").starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
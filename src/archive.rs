@@ -0,0 +1,252 @@
+//! Parquet files of the samples (`parquet_dir`), for long term
+//! analysis with pandas, polars, DuckDB and friends
+//!
+//! One row per CPU and sample, with the columns of the CSV output,
+//! compressed with snappy. Samples are written in row groups of
+//! [BATCH], and every [crate::Settings::parquet_rotate] a new file is
+//! started. Files are named `cpu1sec-<YYYYMMDD-HHMMSS>.parquet` after
+//! their first sample, UTC, and only get that name once they are
+//! complete. Until then they end in `.tmp`, a Parquet file is of no
+//! use without the footer written last.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{csv, fanout::Target, CpuStat, Field, Settings};
+use anyhow::{anyhow, Result};
+use log::warn;
+use parquet::{
+    basic::Compression,
+    data_type::{ByteArray, ByteArrayType, DataType, DoubleType, Int64Type},
+    file::{
+        properties::WriterProperties,
+        writer::{SerializedFileWriter, SerializedRowGroupWriter},
+    },
+    schema::parser::parse_message_type,
+};
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Samples in one row group
+const BATCH: usize = 60;
+
+/// The file currently written
+#[derive(Debug)]
+struct Current {
+    /// The writer
+    writer: SerializedFileWriter<File>,
+    /// Where it writes to
+    tmp: PathBuf,
+    /// Where the file goes once complete
+    path: PathBuf,
+}
+
+/// Writes the samples to Parquet files in
+/// [crate::Settings::parquet_dir]
+#[derive(Debug)]
+pub(crate) struct ParquetArchive {
+    /// The directory
+    dir: PathBuf,
+    /// Seconds of samples in one file
+    rotate: u64,
+    /// Epoch of the first sample of the current file
+    start: Option<u64>,
+    /// The current file, once the first row group is written
+    current: Option<Current>,
+    /// Samples not yet written
+    pending: Vec<Vec<CpuStat>>,
+}
+
+impl ParquetArchive {
+    /// Write to `dir`, a new file every `rotate`
+    pub(crate) fn new(dir: &Path, rotate: Duration) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            rotate: rotate.as_secs(),
+            start: None,
+            current: None,
+            pending: Vec::with_capacity(BATCH),
+        }
+    }
+
+    /// Add the sample `graphs`. If that fails, the current file is
+    /// given up, a new one started with the next sample.
+    pub(crate) fn write(&mut self, graphs: &[CpuStat]) {
+        if let Err(e) = self.append(graphs) {
+            warn!("Could not archive sample: {e}");
+            self.start = None;
+            self.current = None;
+            self.pending.clear();
+        }
+    }
+
+    /// Add the sample `graphs`, writing a row group when [BATCH]
+    /// are together, and starting a new file when it is time
+    fn append(&mut self, graphs: &[CpuStat]) -> Result<()> {
+        let Some(epoch) = graphs.first().map(|stat| stat.epoch) else {
+            return Ok(());
+        };
+        match self.start {
+            // The wall clock may jump back too
+            Some(start) if epoch >= start + self.rotate || epoch < start => self.finish()?,
+            Some(_) => (),
+            None => (),
+        }
+        self.start.get_or_insert(epoch);
+        self.pending.push(graphs.to_vec());
+        if self.pending.len() >= BATCH {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write the pending samples as one row group
+    fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let current = match &mut self.current {
+            Some(current) => current,
+            None => {
+                let name = format!(
+                    "cpu1sec-{}.parquet",
+                    csv::timestamp(self.start.unwrap_or_default())
+                );
+                let path = self.dir.join(name);
+                let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+                let writer = SerializedFileWriter::new(
+                    File::create(&tmp)?,
+                    Arc::new(parse_message_type(&schema())?),
+                    Arc::new(
+                        WriterProperties::builder()
+                            .set_compression(Compression::SNAPPY)
+                            .build(),
+                    ),
+                )?;
+                self.current.insert(Current { writer, tmp, path })
+            }
+        };
+        let rows: Vec<&CpuStat> = self.pending.iter().flatten().collect();
+        let mut group = current.writer.next_row_group()?;
+        column::<Int64Type>(&mut group, rows.iter().map(|stat| stat.epoch as i64))?;
+        column::<ByteArrayType>(
+            &mut group,
+            rows.iter()
+                .map(|stat| ByteArray::from(stat.cpu.to_string().as_str())),
+        )?;
+        for field in Field::FINE {
+            column::<Int64Type>(&mut group, rows.iter().map(|stat| field.value(stat) as i64))?;
+        }
+        column::<DoubleType>(&mut group, rows.iter().map(|stat| stat.busy()))?;
+        group.close()?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Write what is pending and complete the current file
+    fn finish(&mut self) -> Result<()> {
+        self.flush()?;
+        self.start = None;
+        if let Some(current) = self.current.take() {
+            current.writer.close()?;
+            fs::rename(&current.tmp, &current.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Target for ParquetArchive {
+    fn deliver(&mut self, graphs: &[CpuStat]) {
+        self.write(graphs);
+    }
+}
+
+/// The [Target] for [Settings::parquet_dir], if set
+pub(crate) fn target(settings: &Settings) -> Option<Box<dyn Target>> {
+    let dir = settings.parquet_dir.as_ref()?;
+    Some(Box::new(ParquetArchive::new(dir, settings.parquet_rotate)))
+}
+
+impl Drop for ParquetArchive {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            warn!("Could not complete the Parquet file: {e}");
+        }
+    }
+}
+
+/// The schema of our files, the columns of the CSV output
+fn schema() -> String {
+    let values: String = Field::FINE
+        .iter()
+        .map(|field| format!("REQUIRED INT64 {};\n", field.name()))
+        .collect();
+    format!(
+        "message cpu1sec {{
+            REQUIRED INT64 epoch;
+            REQUIRED BYTE_ARRAY cpu (UTF8);
+            {values}
+            REQUIRED DOUBLE busy;
+        }}"
+    )
+}
+
+/// Write the next column of `group`, with `values`
+fn column<T: DataType>(
+    group: &mut SerializedRowGroupWriter<'_, File>,
+    values: impl Iterator<Item = T::T>,
+) -> Result<()> {
+    let mut column = group
+        .next_column()?
+        .ok_or_else(|| anyhow!("Schema has fewer columns than we write"))?;
+    column
+        .typed::<T>()
+        .write_batch(&values.collect::<Vec<_>>(), None, None)?;
+    column.close()?;
+    Ok(())
+}
+
+#[test]
+fn test_archive() {
+    use crate::CpuId;
+    use parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        record::RowAccessor,
+    };
+
+    let dir = std::env::temp_dir().join(format!("cpu1sec-archive-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut archive = ParquetArchive::new(&dir, Duration::from_secs(3600));
+    let stat = |cpu, epoch| CpuStat {
+        cpu,
+        epoch,
+        user: 1,
+        idle: 3,
+        ..Default::default()
+    };
+    // A file and a half
+    let start = 1_650_000_000;
+    for epoch in start..start + 5400 {
+        archive.write(&[stat(CpuId::Core(0), epoch), stat(CpuId::Total, epoch)]);
+    }
+    let first = dir.join("cpu1sec-20220415-052000.parquet");
+    let second = dir.join("cpu1sec-20220415-062000.parquet");
+    assert!(first.exists());
+    assert!(!second.exists());
+    drop(archive);
+    assert!(second.exists());
+
+    let reader = SerializedFileReader::new(File::open(&first).unwrap()).unwrap();
+    assert_eq!(7200, reader.metadata().file_metadata().num_rows());
+    assert_eq!(60, reader.metadata().num_row_groups());
+    let row = reader.get_row_iter(None).unwrap().nth(1).unwrap().unwrap();
+    assert_eq!(start as i64, row.get_long(0).unwrap());
+    assert_eq!("total", row.get_string(1).unwrap());
+    assert_eq!(1, row.get_long(2).unwrap());
+    assert_eq!(25.0, row.get_double(12).unwrap());
+    let reader = SerializedFileReader::new(File::open(&second).unwrap()).unwrap();
+    assert_eq!(3600, reader.metadata().file_metadata().num_rows());
+    fs::remove_dir_all(&dir).unwrap();
+}
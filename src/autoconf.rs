@@ -0,0 +1,70 @@
+//! Answers to munin-node-configure, which asks plugins if they can
+//! run on this host
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::Settings;
+use anyhow::{anyhow, Result};
+use std::{fs, io::Write};
+
+/// Magic markers munin-node-configure looks for in plugins, see
+/// <https://guide.munin-monitoring.org/en/latest/architecture/syntax.html#magic-markers>
+pub const MAGIC_MARKERS: &str = "#%# family=auto\n#%# capabilities=autoconf suggest\n";
+
+/// Can we read the CPU usage from the stat file of `settings`?
+fn check(settings: &Settings) -> Result<()> {
+    let path = settings.proc_stat();
+    let content =
+        fs::read_to_string(&path).map_err(|e| anyhow!("{} not readable: {e}", path.display()))?;
+    if !content.lines().any(|line| line.starts_with("cpu ")) {
+        return Err(anyhow!("{} has no cpu line", path.display()));
+    }
+    Ok(())
+}
+
+/// Implements `cpu1sec autoconf`: Write `yes` if we can run here, or
+/// `no` with the reason why not
+pub fn autoconf<W: Write>(out: &mut W, settings: &Settings) -> Result<()> {
+    match check(settings) {
+        Ok(()) => writeln!(out, "yes")?,
+        Err(e) => writeln!(out, "no ({e})")?,
+    }
+    Ok(())
+}
+
+/// Implements `cpu1sec suggest`: Write the plugins munin-node-configure
+/// should set up, one per line. We are no wildcard plugin, one
+/// cpu1sec covers the whole host, so that is just us, if we can run
+/// here at all.
+pub fn suggest<W: Write>(out: &mut W, settings: &Settings) -> Result<()> {
+    if check(settings).is_ok() {
+        writeln!(out, "cpu1sec")?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_autoconf() {
+    let root = std::env::temp_dir().join(format!("cpu1sec-autoconf-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let settings = Settings {
+        proc_root: root.clone(),
+        ..Default::default()
+    };
+    let answer = |f: fn(&mut Vec<u8>, &Settings) -> Result<()>| {
+        let mut out = vec![];
+        f(&mut out, &settings).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    assert!(answer(autoconf).starts_with("no ("));
+    assert!(answer(autoconf).contains("not readable"));
+    assert_eq!("", answer(suggest));
+
+    std::fs::write(root.join("stat"), "intr 1\n").unwrap();
+    assert!(answer(autoconf).contains("has no cpu line"));
+
+    std::fs::write(root.join("stat"), "cpu  1 0 1 10 0 0 0 0 0 0\n").unwrap();
+    assert_eq!("yes\n", answer(autoconf));
+    assert_eq!("cpu1sec\n", answer(suggest));
+    std::fs::remove_dir_all(&root).unwrap();
+}
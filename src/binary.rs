@@ -0,0 +1,174 @@
+//! Compact binary framing of the CPU values, for consumers of the
+//! TCP output that do not want to parse text every second
+//! (`format=binary`).
+//!
+//! Every sample is one frame, all numbers little endian:
+//!
+//! | Bytes      | Type | Content                                        |
+//! |------------|------|------------------------------------------------|
+//! | 4          | u32  | Length of the rest of the frame                |
+//! | 8          | u64  | Epoch of the sample                            |
+//! | 4          | u32  | Number of CPUs that follow                     |
+//! | 84 per CPU | ...  | The CPU number (u32, see below) and its 10     |
+//! |            |      | values as u64, in the order user, nice,        |
+//! |            |      | system, idle, iowait, irq, softirq, steal,     |
+//! |            |      | guest, guest_nice                              |
+//!
+//! The values are the same deltas as the munin output, other
+//! collectors are not part of the frame.
+//!
+//! The CPU number is the core number for single cores, [u32::MAX]
+//! for the total, `u32::MAX - 1` for others and `u32::MAX - 2` for
+//! the aggregate. Cores with those numbers would clash with them, so
+//! they are not sent at all.
+//!
+//! [u32::MAX]: u32::MAX
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{CpuId, CpuStat};
+use anyhow::{bail, Result};
+use std::io::Read;
+
+/// Size of one CPU in a frame
+const CPU_SIZE: usize = 4 + 10 * 8;
+
+/// CPU number used in a frame for the CpuIds that are not a core
+const WIRE_IDS: [(CpuId, u32); 3] = [
+    (CpuId::Total, u32::MAX),
+    (CpuId::Others, u32::MAX - 1),
+    (CpuId::Aggregate, u32::MAX - 2),
+];
+
+/// The CPU number of `cpu` in a frame, None if it can not be sent
+fn to_wire(cpu: CpuId) -> Option<u32> {
+    match cpu {
+        CpuId::Core(num) if WIRE_IDS.iter().any(|(_, wire)| *wire == num) => None,
+        CpuId::Core(num) => Some(num),
+        // Only ever seen with cpudetail, which makes no sense here
+        CpuId::Policy(_) | CpuId::Node(_) => None,
+        _ => WIRE_IDS
+            .iter()
+            .find(|(id, _)| *id == cpu)
+            .map(|(_, wire)| *wire),
+    }
+}
+
+/// The CpuId of a CPU number in a frame
+fn from_wire(num: u32) -> CpuId {
+    WIRE_IDS
+        .iter()
+        .find(|(_, wire)| *wire == num)
+        .map_or(CpuId::Core(num), |(id, _)| *id)
+}
+
+/// Encode the CpuStats of one sample, taken at `epoch`, as a frame
+pub fn encode(epoch: u64, stats: &[CpuStat]) -> Vec<u8> {
+    let stats: Vec<(u32, &CpuStat)> = stats
+        .iter()
+        .filter_map(|stat| Some((to_wire(stat.cpu)?, stat)))
+        .collect();
+    let len = 8 + 4 + stats.len() * CPU_SIZE;
+    let mut frame = Vec::with_capacity(4 + len);
+    frame.extend((len as u32).to_le_bytes());
+    frame.extend(epoch.to_le_bytes());
+    frame.extend((stats.len() as u32).to_le_bytes());
+    for (cpu, stat) in stats {
+        frame.extend(cpu.to_le_bytes());
+        for (_, value) in stat.fields() {
+            frame.extend(value.to_le_bytes());
+        }
+    }
+    frame
+}
+
+/// Read one frame from `reader` and decode it. The CpuStats get the
+/// epoch of the frame.
+pub fn decode<R: Read>(reader: &mut R) -> Result<Vec<CpuStat>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut frame = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut frame)?;
+    if frame.len() < 12 {
+        bail!("Frame of {} bytes is too short", frame.len());
+    }
+    let (head, cpus) = frame.split_at(12);
+    let epoch = u64::from_le_bytes(head[0..8].try_into()?);
+    let count = u32::from_le_bytes(head[8..12].try_into()?) as usize;
+    if cpus.len() != count * CPU_SIZE {
+        bail!("Frame has {} bytes for {count} CPUs", cpus.len());
+    }
+    cpus.chunks_exact(CPU_SIZE)
+        .map(|cpu| {
+            let value = |field: usize| -> Result<u64> {
+                let start = 4 + field * 8;
+                Ok(u64::from_le_bytes(cpu[start..start + 8].try_into()?))
+            };
+            Ok(CpuStat {
+                cpu: from_wire(u32::from_le_bytes(cpu[0..4].try_into()?)),
+                epoch,
+                user: value(0)?,
+                nice: value(1)?,
+                system: value(2)?,
+                idle: value(3)?,
+                iowait: value(4)?,
+                irq: value(5)?,
+                softirq: value(6)?,
+                steal: value(7)?,
+                guest: value(8)?,
+                guest_nice: value(9)?,
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn test_binary_roundtrip() {
+    let stats = [
+        CpuStat {
+            cpu: CpuId::Core(0),
+            epoch: 1_650_000_001,
+            user: 30,
+            nice: 1,
+            system: 10,
+            idle: 59,
+            steal: u64::MAX,
+            ..Default::default()
+        },
+        CpuStat {
+            cpu: CpuId::Aggregate,
+            epoch: 1_650_000_001,
+            user: 30,
+            system: 10,
+            idle: 59,
+            ..Default::default()
+        },
+        CpuStat {
+            epoch: 1_650_000_001,
+            user: 60,
+            system: 20,
+            idle: 118,
+            guest_nice: 7,
+            ..Default::default()
+        },
+    ];
+    let frame = encode(1_650_000_001, &stats);
+    assert_eq!(4 + 12 + 3 * CPU_SIZE, frame.len());
+    // Two frames in a stream
+    let stream = [frame.clone(), frame].concat();
+    let mut reader = stream.as_slice();
+    assert_eq!(stats.to_vec(), decode(&mut reader).unwrap());
+    assert_eq!(stats.to_vec(), decode(&mut reader).unwrap());
+    assert!(reader.is_empty());
+
+    // Truncated
+    assert!(decode(&mut &stream[..20]).is_err());
+
+    // A core that would clash with the total is left out
+    let clash = CpuStat {
+        cpu: CpuId::Core(u32::MAX),
+        ..stats[0]
+    };
+    let frame = encode(1_650_000_001, &[clash, stats[2]]);
+    assert_eq!(vec![stats[2]], decode(&mut frame.as_slice()).unwrap());
+}
@@ -0,0 +1,101 @@
+//! What this build of the plugin can do
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{Collector, Format, Output};
+use anyhow::Result;
+use std::{env::consts::OS, io::Write};
+
+/// Optional cargo features, and whether they are compiled in
+const FEATURES: [(&str, bool); 11] = [
+    ("load", cfg!(feature = "load")),
+    ("temp", cfg!(feature = "temp")),
+    ("freq", cfg!(feature = "freq")),
+    ("psi", cfg!(feature = "psi")),
+    ("irq", cfg!(feature = "irq")),
+    ("softirq", cfg!(feature = "softirq")),
+    ("cgroup", cfg!(feature = "cgroup")),
+    ("top", cfg!(feature = "top")),
+    ("prometheus", cfg!(feature = "prometheus")),
+    ("sqlite", cfg!(feature = "sqlite")),
+    ("parquet", cfg!(feature = "parquet")),
+];
+
+/// Implements `cpu1sec capabilities`: Write what this build supports
+/// to `out`, one `name: values` line per kind of thing.
+pub fn capabilities<W: Write>(out: &mut W) -> Result<()> {
+    let features: Vec<&str> = FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect();
+    let list = |names: Vec<&str>| {
+        if names.is_empty() {
+            String::from("none")
+        } else {
+            names.join(" ")
+        }
+    };
+    writeln!(out, "backend: {OS} (procfs)")?;
+    writeln!(out, "features: {}", list(features))?;
+    writeln!(
+        out,
+        "collectors: {}",
+        list(Collector::ALL.iter().map(Collector::name).collect())
+    )?;
+    writeln!(
+        out,
+        "outputs: {}",
+        list(Output::ALL.iter().map(Output::name).collect())
+    )?;
+    writeln!(
+        out,
+        "formats: {}",
+        list(Format::ALL.iter().map(Format::name).collect())
+    )?;
+    Ok(())
+}
+
+#[test]
+fn test_capabilities() {
+    let mut out = vec![];
+    capabilities(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("backend: linux (procfs)\n"));
+    assert!(out
+        .lines()
+        .any(|l| l.starts_with("outputs: ") && l.split(' ').any(|o| o == "munin")));
+    assert!(out.contains("collectors: cpu"));
+}
+
+#[cfg(not(any(
+    feature = "load",
+    feature = "temp",
+    feature = "freq",
+    feature = "psi",
+    feature = "irq",
+    feature = "softirq",
+    feature = "cgroup",
+    feature = "top",
+    feature = "prometheus",
+    feature = "sqlite",
+    feature = "parquet"
+)))]
+#[test]
+fn test_minimal_build() {
+    let mut out = vec![];
+    capabilities(&mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("features: none\n"));
+    assert!(out.contains("collectors: cpu ctxt intr forks procs\n"));
+    assert_eq!(
+        &[
+            Collector::Cpu,
+            Collector::Ctxt,
+            Collector::Intr,
+            Collector::Forks,
+            Collector::Procs
+        ],
+        Collector::ALL
+    );
+    assert!("temp".parse::<Collector>().is_err());
+}
@@ -0,0 +1,44 @@
+//! CPU usage from cgroup v2
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use std::fs;
+
+/// Where the cgroup v2 root keeps its CPU usage in microseconds
+pub(crate) const CGROUP_CPU_STAT: &str = "/sys/fs/cgroup/cpu.stat";
+
+/// CPU time of the root cgroup, from cgroup v2 `cpu.stat`.
+///
+/// The kernel fills this from the same counters as /proc/stat, but
+/// in microseconds instead of ticks. user includes nice, system
+/// includes irq and softirq.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct CgroupCpuTime {
+    /// Microseconds spent in user mode (including nice)
+    pub(crate) user_usec: u64,
+    /// Microseconds spent in system mode (including irq, softirq)
+    pub(crate) system_usec: u64,
+}
+
+impl CgroupCpuTime {
+    /// Parse the content of a `cpu.stat` file
+    pub(crate) fn parse(content: &str) -> Option<Self> {
+        let mut user_usec = None;
+        let mut system_usec = None;
+        for line in content.lines() {
+            match line.split_once(' ') {
+                Some(("user_usec", val)) => user_usec = val.trim().parse().ok(),
+                Some(("system_usec", val)) => system_usec = val.trim().parse().ok(),
+                _ => {}
+            }
+        }
+        Some(Self {
+            user_usec: user_usec?,
+            system_usec: system_usec?,
+        })
+    }
+
+    /// Read the root cgroup's `cpu.stat`, if there is one
+    pub(crate) fn read() -> Option<Self> {
+        Self::parse(&fs::read_to_string(CGROUP_CPU_STAT).ok()?)
+    }
+}
@@ -0,0 +1,373 @@
+//! CPU usage and throttling of cgroups, from their cgroup v2
+//! `cpu.stat`, the `cgroup1sec` graphs of [crate::Collector::Cgroup]
+//!
+//! The overview graph has the usage of every cgroup, each cgroup also
+//! gets a graph of its own below it with its usage, the time it was
+//! throttled by its `cpu.max` and how often that happened. The
+//! cgroups are the ones of [crate::Settings::cgroup_paths], or else
+//! the children of the root cgroup, e.g. system.slice and user.slice.
+//! 100% is one CPU busy all the time, like `top` shows it.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::Settings;
+use anyhow::Result;
+use log::warn;
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Name of our graph
+const GRAPH: &str = "cgroup1sec";
+
+/// Where the cgroup v2 hierarchy is mounted
+pub(crate) const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The counters of a cgroup's `cpu.stat` we graph
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+struct CpuStat {
+    /// Microseconds of CPU time used
+    usage_usec: Option<u64>,
+    /// Microseconds the cgroup was throttled, only with the cpu
+    /// controller enabled
+    throttled_usec: Option<u64>,
+    /// Periods the cgroup was throttled in, only with the cpu
+    /// controller enabled
+    nr_throttled: Option<u64>,
+}
+
+impl CpuStat {
+    /// Parse the content of a `cpu.stat` file
+    fn parse(content: &str) -> Self {
+        let mut stat = Self::default();
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once(' ') else {
+                continue;
+            };
+            let value = value.trim().parse().ok();
+            match key {
+                "usage_usec" => stat.usage_usec = value,
+                "throttled_usec" => stat.throttled_usec = value,
+                "nr_throttled" => stat.nr_throttled = value,
+                _ => {}
+            }
+        }
+        stat
+    }
+
+    /// Read the `cpu.stat` of the cgroup in `dir`, all unknown if we
+    /// can not
+    fn read(dir: &Path) -> Self {
+        fs::read_to_string(dir.join("cpu.stat"))
+            .map(|content| Self::parse(&content))
+            .unwrap_or_default()
+    }
+}
+
+#[test]
+fn test_parse() {
+    let stat = CpuStat::parse(
+        "usage_usec 2831456\n\
+         user_usec 1928312\n\
+         system_usec 903144\n\
+         nr_periods 40\n\
+         nr_throttled 12\n\
+         throttled_usec 81000\n",
+    );
+    assert_eq!(
+        CpuStat {
+            usage_usec: Some(2831456),
+            throttled_usec: Some(81000),
+            nr_throttled: Some(12),
+        },
+        stat
+    );
+    assert_eq!(None, CpuStat::parse("usage_usec 5\n").nr_throttled);
+    assert_eq!(CpuStat::default(), CpuStat::parse(""));
+}
+
+/// munin field name of the cgroup `path`: anything but letters and
+/// digits an underscore, not starting with a digit
+fn field(path: &str) -> String {
+    let field: String = path
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if field.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{field}")
+    } else {
+        field
+    }
+}
+
+/// One cgroup we graph
+#[derive(Debug)]
+struct Cgroup {
+    /// Path below the cgroup root, e.g. `system.slice`
+    path: String,
+    /// munin field name, and name of its own graph
+    field: String,
+    /// Its directory
+    dir: PathBuf,
+}
+
+/// Reads the `cpu.stat` of the cgroups, and keeps what it read last to
+/// take the difference
+#[derive(Debug, Default)]
+pub(crate) struct Cgroups {
+    /// The cgroups
+    cgroups: Vec<Cgroup>,
+    /// Epoch of the last read, and what we read then, in the order of
+    /// cgroups
+    last: Option<(u64, Vec<CpuStat>)>,
+}
+
+impl Cgroups {
+    /// Take the cgroups below the cgroup v2 root `root`, usually
+    /// `/sys/fs/cgroup`, see the module documentation for which
+    pub(crate) fn new(root: &Path, settings: &Settings) -> Self {
+        let paths = match &settings.cgroup_paths {
+            Some(paths) => {
+                // Containers come and go, keep the missing ones
+                for path in paths.iter().filter(|path| !root.join(path).is_dir()) {
+                    warn!("No cgroup {path} in {}, graphing it anyway", root.display());
+                }
+                paths.clone()
+            }
+            None => {
+                let mut paths: Vec<String> = fs::read_dir(root)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|entry| {
+                        let entry = entry.ok()?;
+                        entry
+                            .path()
+                            .join("cpu.stat")
+                            .exists()
+                            .then(|| entry.file_name().into_string().ok())?
+                    })
+                    .collect();
+                paths.sort_unstable();
+                if paths.is_empty() {
+                    warn!("No cgroups in {}, nothing to graph", root.display());
+                }
+                paths
+            }
+        };
+        let cgroups = paths
+            .into_iter()
+            .map(|path| Cgroup {
+                field: field(&path),
+                dir: root.join(&path),
+                path,
+            })
+            .collect();
+        Self {
+            cgroups,
+            last: None,
+        }
+    }
+
+    /// Write out the config of our graphs
+    pub(crate) fn config<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        settings: &Settings,
+    ) -> Result<()> {
+        writeln!(handle, "multigraph {GRAPH}")?;
+        self.write_head(handle, settings, "CPU usage of cgroups")?;
+        writeln!(
+            handle,
+            "graph_info CPU time the cgroups used, from their cpu.stat. 100% is one CPU."
+        )?;
+        for cgroup in &self.cgroups {
+            let field = &cgroup.field;
+            writeln!(handle, "{field}.label {}", cgroup.path)?;
+            writeln!(handle, "{field}.min 0")?;
+            writeln!(handle, "{field}.type GAUGE")?;
+        }
+        for cgroup in &self.cgroups {
+            let path = &cgroup.path;
+            writeln!(handle, "multigraph {GRAPH}.{}", cgroup.field)?;
+            self.write_head(handle, settings, &format!("CPU of cgroup {path}"))?;
+            writeln!(
+                handle,
+                "graph_info CPU time cgroup {path} used and was throttled for by its cpu.max, from its cpu.stat. 100% is one CPU."
+            )?;
+            for (field, label, info) in [
+                ("usage", "usage", "CPU time used"),
+                ("throttled", "throttled", "Time the cgroup was throttled"),
+                (
+                    "nr_throttled",
+                    "throttled periods",
+                    "Periods per second the cgroup got throttled in",
+                ),
+            ] {
+                writeln!(handle, "{field}.label {label}")?;
+                writeln!(handle, "{field}.min 0")?;
+                writeln!(handle, "{field}.type GAUGE")?;
+                writeln!(handle, "{field}.info {info}")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The start of the config of one of our graphs, titled `title`
+    fn write_head<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        settings: &Settings,
+        title: &str,
+    ) -> Result<()> {
+        writeln!(handle, "graph_title {title} (1sec)")?;
+        writeln!(handle, "graph_category {}", settings.graph_category)?;
+        writeln!(handle, "update_rate {}", settings.update_rate())?;
+        writeln!(
+            handle,
+            "graph_data_size {}",
+            settings.retention.graph_data_size()
+        )?;
+        writeln!(handle, "graph_args --base 1000 --lower-limit 0")?;
+        writeln!(handle, "graph_vlabel %")?;
+        writeln!(handle, "graph_scale no")?;
+        Ok(())
+    }
+
+    /// Read the `cpu.stat` of the cgroups and write out what they did
+    /// per second since the last read, for `epoch`. Unknown if we can
+    /// not tell, after more than `max_gap`, with counters going
+    /// backwards because the cgroup got recreated, or for throttling
+    /// without the cpu controller. With more than one sample per
+    /// second only the first one in a second counts.
+    pub(crate) fn write<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        epoch: u64,
+        max_gap: Duration,
+    ) -> Result<()> {
+        if self.last.as_ref().is_some_and(|(last, _)| *last == epoch) {
+            return Ok(());
+        }
+        let new: Vec<CpuStat> = self
+            .cgroups
+            .iter()
+            .map(|cgroup| CpuStat::read(&cgroup.dir))
+            .collect();
+        let old = self
+            .last
+            .as_ref()
+            .filter(|(last, _)| epoch > *last && Duration::from_secs(epoch - last) <= max_gap)
+            .map(|(last, old)| (epoch - last, old));
+        // Per second of the counter `get` of the cgroup at `index`
+        let rate = |index: usize, get: fn(&CpuStat) -> Option<u64>| {
+            let (seconds, old) = old?;
+            let diff = get(&new[index])?.checked_sub(get(&old[index])?)?;
+            Some(diff as f64 / seconds as f64)
+        };
+        let value = |value: Option<f64>| match value {
+            Some(value) => format!("{epoch}:{value:.2}"),
+            None => format!("{epoch}:U"),
+        };
+        // Microseconds per second to percent of a CPU
+        let percent = |usec: Option<f64>| usec.map(|usec| usec / 10_000.0);
+
+        writeln!(handle, "multigraph {GRAPH}")?;
+        for (index, cgroup) in self.cgroups.iter().enumerate() {
+            let usage = percent(rate(index, |stat| stat.usage_usec));
+            writeln!(handle, "{}.value {}", cgroup.field, value(usage))?;
+        }
+        for (index, cgroup) in self.cgroups.iter().enumerate() {
+            writeln!(handle, "multigraph {GRAPH}.{}", cgroup.field)?;
+            let usage = percent(rate(index, |stat| stat.usage_usec));
+            writeln!(handle, "usage.value {}", value(usage))?;
+            let throttled = percent(rate(index, |stat| stat.throttled_usec));
+            writeln!(handle, "throttled.value {}", value(throttled))?;
+            let periods = rate(index, |stat| stat.nr_throttled);
+            writeln!(handle, "nr_throttled.value {}", value(periods))?;
+        }
+        self.last = Some((epoch, new));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_cgroups() {
+    let root = std::env::temp_dir().join(format!("cpu1sec-cgroups-{}", std::process::id()));
+    let write = |path: &str, usage, throttled| {
+        let dir = root.join(path);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("cpu.stat"),
+            format!(
+                "usage_usec {usage}\nnr_throttled {throttled}\nthrottled_usec {}\n",
+                throttled * 1000
+            ),
+        )
+        .unwrap();
+    };
+    write("user.slice", 1_000_000, 0);
+    write("system.slice", 5_000_000, 0);
+    write("system.slice/docker-1a2b.scope", 2_000_000, 10);
+    // No cpu.stat, no cgroup
+    fs::create_dir_all(root.join("misc")).unwrap();
+
+    let settings = Settings::default();
+    let cgroups = Cgroups::new(&root, &settings);
+    assert_eq!(
+        vec!["system.slice", "user.slice"],
+        cgroups
+            .cgroups
+            .iter()
+            .map(|cgroup| cgroup.path.as_str())
+            .collect::<Vec<_>>()
+    );
+
+    let settings = Settings {
+        cgroup_paths: Some(vec![
+            String::from("system.slice/docker-1a2b.scope"),
+            String::from("gone.scope"),
+        ]),
+        ..Default::default()
+    };
+    let mut cgroups = Cgroups::new(&root, &settings);
+    let mut config = BufWriter::new(Vec::new());
+    cgroups.config(&mut config, &settings).unwrap();
+    let config = String::from_utf8(config.into_inner().unwrap()).unwrap();
+    assert!(config.starts_with("multigraph cgroup1sec\ngraph_title CPU usage of cgroups (1sec)\n"));
+    assert!(
+        config.contains("system_slice_docker_1a2b_scope.label system.slice/docker-1a2b.scope\n")
+    );
+    assert!(config.contains(
+        "multigraph cgroup1sec.gone_scope\ngraph_title CPU of cgroup gone.scope (1sec)\n"
+    ));
+
+    let mut values = |epoch| {
+        let mut handle = BufWriter::new(Vec::new());
+        cgroups
+            .write(&mut handle, epoch, Duration::from_secs(5))
+            .unwrap();
+        String::from_utf8(handle.into_inner().unwrap()).unwrap()
+    };
+    assert!(
+        values(1).starts_with("multigraph cgroup1sec\nsystem_slice_docker_1a2b_scope.value 1:U\n")
+    );
+    write("system.slice/docker-1a2b.scope", 3_500_000, 14);
+    assert_eq!(
+        "multigraph cgroup1sec\n\
+         system_slice_docker_1a2b_scope.value 3:75.00\n\
+         gone_scope.value 3:U\n\
+         multigraph cgroup1sec.system_slice_docker_1a2b_scope\n\
+         usage.value 3:75.00\n\
+         throttled.value 3:0.20\n\
+         nr_throttled.value 3:2.00\n\
+         multigraph cgroup1sec.gone_scope\n\
+         usage.value 3:U\n\
+         throttled.value 3:U\n\
+         nr_throttled.value 3:U\n",
+        values(3)
+    );
+    assert_eq!("", values(3));
+    fs::remove_dir_all(&root).unwrap();
+}
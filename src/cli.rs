@@ -0,0 +1,225 @@
+//! Command line of the plugin
+//!
+//! Munin calls us with no argument to fetch data, or with `config`,
+//! `autoconf` or (the daemon, started by ourselves) `acquire`. All of
+//! that keeps working, the flags here are for running us by hand.
+//! Every flag stands for one of the environment variables of
+//! [Settings] and wins over it, which in turn wins over the
+//! [ConfigFile].
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{config_file::DEFAULT_PATH, ConfigFile, Settings};
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use log::{warn, LevelFilter};
+use std::{env, path::PathBuf};
+
+/// munin graph plugin for CPU statistics, 1 second resolution
+#[derive(Debug, Clone, PartialEq, Eq, Parser)]
+#[command(name = "cpu1sec", version)]
+pub struct Cli {
+    /// Graph every CPU on its own, like cpudetail=1
+    #[arg(long, global = true)]
+    pub detail: bool,
+
+    /// Cores to look at, e.g. 0-3,8, like cores=
+    #[arg(long, global = true, value_name = "LIST")]
+    pub cores: Option<String>,
+
+    /// Milliseconds between two samples, like interval=
+    #[arg(long, global = true, value_name = "MS")]
+    pub interval: Option<u64>,
+
+    /// Where procfs is mounted, like proc_root=
+    #[arg(long, global = true, value_name = "PATH")]
+    pub proc_root: Option<PathBuf>,
+
+    /// Keep the daemon in the foreground, like foreground=1
+    #[arg(long, global = true)]
+    pub no_daemon: bool,
+
+    /// Take one sample, an interval after starting, write it to
+    /// stdout and exit
+    #[arg(long, global = true)]
+    pub once: bool,
+
+    /// Most verbose log level to show, e.g. warn or debug
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+
+    /// Configuration file to read, like config_file=, default
+    /// /etc/cpu1sec.toml
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config_file: Option<PathBuf>,
+
+    /// What to do, fetch if not given
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// The configuration file, once loaded, see [Cli::load_file]
+    #[arg(skip)]
+    pub file: ConfigFile,
+}
+
+/// What we can be asked to do
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Write the graph configuration for munin
+    Config,
+    /// Write the samples collected since the last fetch, starting
+    /// the daemon if it is not running
+    Fetch,
+    /// Run the daemon collecting samples
+    Run,
+    /// Same as run, the name munin-plugin starts the daemon with
+    #[command(hide = true)]
+    Acquire,
+    /// Tell munin-node-configure if we can run here
+    Autoconf,
+    /// Tell munin-node-configure which plugins to set up
+    Suggest,
+    /// Check the configuration and list all problems found
+    Checkconfig,
+    /// List what this build supports
+    Capabilities,
+    /// Write the samples kept in the history (sqlite_path) as CSV
+    Query {
+        /// First epoch to write, default --last seconds before --to
+        #[arg(long, value_name = "EPOCH", conflicts_with = "last")]
+        from: Option<u64>,
+        /// Last epoch to write, default now
+        #[arg(long, value_name = "EPOCH")]
+        to: Option<u64>,
+        /// How many seconds up to --to to write
+        #[arg(long, value_name = "SECONDS", default_value_t = 3600)]
+        last: u64,
+    },
+}
+
+impl Cli {
+    /// The flags given, as the variables of [Settings] they stand
+    /// for. Also handed to the daemon when we start it.
+    pub fn vars(&self) -> Vec<(&'static str, String)> {
+        let mut vars = vec![];
+        if self.detail {
+            vars.push(("cpudetail", String::from("1")));
+        }
+        if let Some(cores) = &self.cores {
+            vars.push(("cores", cores.clone()));
+        }
+        if let Some(interval) = self.interval {
+            vars.push(("interval", interval.to_string()));
+        }
+        if let Some(proc_root) = &self.proc_root {
+            vars.push(("proc_root", proc_root.display().to_string()));
+        }
+        if self.no_daemon {
+            vars.push(("foreground", String::from("1")));
+        }
+        if let Some(config_file) = &self.config_file {
+            vars.push(("config_file", config_file.display().to_string()));
+        }
+        vars
+    }
+
+    /// Load the configuration file, the one given on the command
+    /// line or in the environment, or the default one, if that
+    /// exists
+    pub fn load_file(&mut self) -> Result<()> {
+        let path = self
+            .var("config_file")
+            .map_or_else(|| PathBuf::from(DEFAULT_PATH), PathBuf::from);
+        self.file = ConfigFile::load(&path)?;
+        Ok(())
+    }
+
+    /// Look up the variable `name`. The command line wins over the
+    /// environment, which wins over the configuration file.
+    pub fn var(&self, name: &str) -> Option<String> {
+        self.vars()
+            .into_iter()
+            .find(|(var, _)| *var == name)
+            .map(|(_, val)| val)
+            .or_else(|| env::var(name).ok())
+            .or_else(|| self.file.var(name))
+    }
+
+    /// Our settings, from the command line, the environment and the
+    /// configuration file. Invalid values get warned about and their
+    /// default is used.
+    pub fn settings(&self) -> Settings {
+        let (settings, errors) = Settings::from_vars(|name| self.var(name));
+        for e in errors {
+            warn!("{e}, using default");
+        }
+        settings
+    }
+}
+
+#[test]
+fn test_cli() {
+    // The way munin calls us
+    let cli = Cli::try_parse_from(["cpu1sec"]).unwrap();
+    assert_eq!(None, cli.command);
+    assert!(cli.vars().is_empty());
+    let cli = Cli::try_parse_from(["cpu1sec", "acquire"]).unwrap();
+    assert_eq!(Some(Command::Acquire), cli.command);
+
+    // And by hand, flags go before or after the subcommand
+    let cli = Cli::try_parse_from([
+        "cpu1sec",
+        "--detail",
+        "run",
+        "--interval",
+        "500",
+        "--proc-root",
+        "/host/proc",
+        "--no-daemon",
+        "--log-level",
+        "debug",
+    ])
+    .unwrap();
+    assert_eq!(Some(Command::Run), cli.command);
+    assert_eq!(Some(LevelFilter::Debug), cli.log_level);
+    assert_eq!(Some(String::from("500")), cli.var("interval"));
+    let (settings, errors) = Settings::from_vars(|name| {
+        cli.vars()
+            .into_iter()
+            .find(|(var, _)| *var == name)
+            .map(|(_, val)| val)
+    });
+    assert!(errors.is_empty());
+    assert!(settings.cpudetail);
+    assert!(settings.foreground);
+    assert_eq!(std::time::Duration::from_millis(500), settings.interval);
+    assert_eq!(PathBuf::from("/host/proc"), settings.proc_root);
+
+    assert!(Cli::try_parse_from(["cpu1sec", "--interval", "soon"]).is_err());
+}
+
+#[test]
+fn test_precedence() {
+    let dir = std::env::temp_dir().join(format!("cpu1sec-precedence-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("cpu1sec.toml");
+    std::fs::write(&path, "interval = 2000\nsteal_graph = true\n").unwrap();
+
+    let mut cli = Cli::try_parse_from([
+        "cpu1sec",
+        "--interval",
+        "500",
+        "--config-file",
+        path.to_str().unwrap(),
+    ])
+    .unwrap();
+    cli.load_file().unwrap();
+    assert_eq!(Some(path.clone()), cli.file.path);
+    // The command line wins, the rest comes from the file
+    assert_eq!(Some(String::from("500")), cli.var("interval"));
+    assert_eq!(Some(String::from("1")), cli.var("steal_graph"));
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // Given explicitly, the file has to be there
+    let mut cli = Cli::try_parse_from(["cpu1sec", "--config-file", "/nonexistent.toml"]).unwrap();
+    assert!(cli.load_file().is_err());
+}
@@ -0,0 +1,78 @@
+//! Where the daemon takes the time of its samples from
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use anyhow::Result;
+use std::{
+    str::FromStr,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Which clock gives the epochs of our samples
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Clock {
+    /// Ask the wall clock every time. Follows NTP steps and manual
+    /// changes of the clock, so epochs may repeat or go backwards.
+    #[default]
+    Wall,
+    /// Ask the wall clock once, when the daemon starts, and go from
+    /// there with the monotonic clock. Epochs always move forward,
+    /// but drift from the wall clock if that gets corrected.
+    Monotonic,
+}
+
+impl FromStr for Clock {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "wall" => Ok(Clock::Wall),
+            "monotonic" => Ok(Clock::Monotonic),
+            _ => Err(anyhow::anyhow!("Unknown clock {s}")),
+        }
+    }
+}
+
+/// Tells the time since [UNIX_EPOCH], the way its [Clock] does
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct EpochClock {
+    /// Which clock to go by
+    clock: Clock,
+    /// Wall clock time when we started
+    anchor: Duration,
+    /// Monotonic time when we started
+    started: Instant,
+}
+
+impl EpochClock {
+    /// Start a clock, anchored at the current wall clock time
+    pub(crate) fn new(clock: Clock) -> Result<Self> {
+        Ok(Self {
+            clock,
+            anchor: SystemTime::now().duration_since(UNIX_EPOCH)?,
+            started: Instant::now(),
+        })
+    }
+
+    /// The time since [UNIX_EPOCH]
+    pub(crate) fn now(&self) -> Duration {
+        match self.clock {
+            Clock::Wall => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Couldn't get epoch"),
+            Clock::Monotonic => self.anchor + self.started.elapsed(),
+        }
+    }
+}
+
+#[test]
+fn test_monotonic_clock() {
+    let mut clock = EpochClock::new(Clock::Monotonic).unwrap();
+    // As if the wall clock got stepped back an hour after we started
+    clock.anchor += Duration::from_secs(3600);
+    let first = clock.now();
+    assert!(first >= clock.anchor);
+    assert!(first > EpochClock::new(Clock::Wall).unwrap().now());
+    std::thread::sleep(Duration::from_millis(10));
+    assert!(clock.now() >= first + Duration::from_millis(10));
+    assert_eq!(Ok(Clock::Monotonic), "monotonic".parse().map_err(|_| ()));
+}
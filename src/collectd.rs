@@ -0,0 +1,119 @@
+//! PUTVAL lines for collectd's Exec plugin (`format=collectd`), so
+//! collectd can run the daemon and take its samples
+//!
+//! With output=stdout, e.g. in collectd.conf:
+//!
+//! ```text
+//! <Plugin exec>
+//!   Exec "nobody" "/usr/sbin/cpu1sec" "run"
+//! </Plugin>
+//! ```
+//!
+//! Every field becomes a `percent` value of the plugin instance of
+//! its CPU, e.g. `web01/cpu1sec-cpu3/percent-user`, the way collectd's
+//! own cpu plugin reports percentages.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{
+    output,
+    sink::{OutputSink, Sample},
+    CpuStat, Settings,
+};
+use std::{
+    fmt::Write,
+    io::{self, StdoutLock},
+    time::Duration,
+};
+
+/// The sample `graphs` of `host`, taken every `interval`, as PUTVAL
+/// lines with the same fields munin gets
+pub(crate) fn encode(graphs: &[CpuStat], host: &str, interval: Duration) -> Vec<u8> {
+    let mut out = String::new();
+    let interval = interval.as_secs_f64();
+    for stat in graphs {
+        let fields = stat.rollup.fields().iter();
+        for field in fields.filter(|field| stat.guest_fields || !field.is_guest()) {
+            let _ = writeln!(
+                out,
+                "PUTVAL \"{host}/cpu1sec-{}/percent-{}\" interval={interval} {}:{:.2}",
+                stat.cpu,
+                field.name(),
+                stat.epoch,
+                stat.percent(field.value(stat))
+            );
+        }
+    }
+    out.into_bytes()
+}
+
+#[test]
+fn test_encode() {
+    use crate::{CpuId, Rollup};
+
+    let graphs = [
+        CpuStat {
+            cpu: CpuId::Core(3),
+            epoch: 1_650_000_000,
+            user: 1,
+            idle: 3,
+            guest_fields: false,
+            ..Default::default()
+        },
+        CpuStat {
+            epoch: 1_650_000_000,
+            idle: 1,
+            rollup: Rollup::Coarse,
+            ..Default::default()
+        },
+    ];
+    let out = String::from_utf8(encode(&graphs, "web01", Duration::from_millis(500))).unwrap();
+    assert!(out.starts_with(
+        "PUTVAL \"web01/cpu1sec-cpu3/percent-user\" interval=0.5 1650000000:25.00\n\
+         PUTVAL \"web01/cpu1sec-cpu3/percent-nice\" interval=0.5 1650000000:0.00\n"
+    ));
+    assert!(out
+        .ends_with("PUTVAL \"web01/cpu1sec-total/percent-idle\" interval=0.5 1650000000:100.00\n"));
+    assert_eq!(12, out.lines().count());
+    let out = String::from_utf8(encode(&graphs, "web01", Duration::from_secs(1))).unwrap();
+    assert!(out.contains(" interval=1 "));
+}
+
+/// Writes the samples for collectd to stdout
+#[derive(Debug)]
+pub(crate) struct Collectd {
+    /// Where collectd reads them
+    out: StdoutLock<'static>,
+    /// Host the samples belong to, see
+    /// [Settings::collectd_hostname]
+    host: String,
+    /// Time between two samples
+    interval: Duration,
+}
+
+impl Collectd {
+    /// Write the samples of the daemon running with `settings`
+    pub(crate) fn new(settings: &Settings) -> Self {
+        let host = settings
+            .collectd_hostname
+            .clone()
+            .or_else(output::hostname)
+            .unwrap_or_else(|| String::from("localhost"));
+        Self {
+            out: io::stdout().lock(),
+            host,
+            interval: settings.interval,
+        }
+    }
+}
+
+impl OutputSink for Collectd {
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        match sample.graphs {
+            Some(graphs) => {
+                let block = encode(graphs, &self.host, self.interval);
+                output::write_block(&mut self.out, &block)
+            }
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! The data collectors of the plugin
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use anyhow::Result;
+use std::str::FromStr;
+
+/// The data collectors this plugin knows about.
+///
+/// The order of declaration is the order their graphs appear in the
+/// config output.
+///
+/// [Collector::Cpu] is the core of this plugin and always enabled,
+/// everything else is optional and off unless switched on, either by
+/// its own environment variable (see [Collector::env_flag]) or by
+/// listing it in the `collectors` variable, e.g.
+/// `collectors=cpu,ctxt,intr,forks,procs,load,temp,freq,psi,irq,softirq,cgroup,top`.
+/// If `collectors` is set, it overrides the individual flags.
+///
+/// [Collector::Ctxt], [Collector::Intr], [Collector::Forks] and
+/// [Collector::Procs] come from the /proc/stat read for the CPU usage
+/// anyway and are always compiled in. The other optional ones only
+/// are with their cargo feature (`load`,
+/// `temp`, `freq`, `psi`, `irq`, `softirq`, `cgroup`, `top`, or all
+/// of them with `collectors`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Collector {
+    /// CPU usage from /proc/stat, always on
+    Cpu,
+    /// Context switches per second from /proc/stat
+    Ctxt,
+    /// Interrupts per second from /proc/stat, all IRQs together
+    Intr,
+    /// Processes and threads created per second from /proc/stat
+    Forks,
+    /// Runnable and blocked tasks from /proc/stat
+    Procs,
+    /// Load average from /proc/loadavg
+    #[cfg(feature = "load")]
+    Load,
+    /// Temperatures from hwmon or the thermal zones in sysfs
+    #[cfg(feature = "temp")]
+    Temp,
+    /// CPU frequencies from cpufreq in sysfs
+    #[cfg(feature = "freq")]
+    Freq,
+    /// Pressure stall information from /proc/pressure
+    #[cfg(feature = "psi")]
+    Psi,
+    /// Interrupts per IRQ from /proc/interrupts
+    #[cfg(feature = "irq")]
+    Irq,
+    /// Softirqs by kind from /proc/softirqs
+    #[cfg(feature = "softirq")]
+    Softirq,
+    /// CPU usage and throttling of cgroups from their cgroup v2 cpu.stat
+    #[cfg(feature = "cgroup")]
+    Cgroup,
+    /// CPU usage of the busiest processes from /proc/<pid>/stat
+    #[cfg(feature = "top")]
+    Top,
+}
+
+impl Collector {
+    /// All collectors compiled in
+    pub const ALL: &'static [Collector] = &[
+        Collector::Cpu,
+        Collector::Ctxt,
+        Collector::Intr,
+        Collector::Forks,
+        Collector::Procs,
+        #[cfg(feature = "load")]
+        Collector::Load,
+        #[cfg(feature = "temp")]
+        Collector::Temp,
+        #[cfg(feature = "freq")]
+        Collector::Freq,
+        #[cfg(feature = "psi")]
+        Collector::Psi,
+        #[cfg(feature = "irq")]
+        Collector::Irq,
+        #[cfg(feature = "softirq")]
+        Collector::Softirq,
+        #[cfg(feature = "cgroup")]
+        Collector::Cgroup,
+        #[cfg(feature = "top")]
+        Collector::Top,
+    ];
+
+    /// Name used for this collector in the `collectors` variable
+    pub fn name(&self) -> &'static str {
+        match self {
+            Collector::Cpu => "cpu",
+            Collector::Ctxt => "ctxt",
+            Collector::Intr => "intr",
+            Collector::Forks => "forks",
+            Collector::Procs => "procs",
+            #[cfg(feature = "load")]
+            Collector::Load => "load",
+            #[cfg(feature = "temp")]
+            Collector::Temp => "temp",
+            #[cfg(feature = "freq")]
+            Collector::Freq => "freq",
+            #[cfg(feature = "psi")]
+            Collector::Psi => "psi",
+            #[cfg(feature = "irq")]
+            Collector::Irq => "irq",
+            #[cfg(feature = "softirq")]
+            Collector::Softirq => "softirq",
+            #[cfg(feature = "cgroup")]
+            Collector::Cgroup => "cgroup",
+            #[cfg(feature = "top")]
+            Collector::Top => "top",
+        }
+    }
+
+    /// Name of the environment variable that enables this collector
+    /// on its own, if there is one
+    pub fn env_flag(&self) -> Option<&'static str> {
+        match self {
+            Collector::Cpu => None,
+            Collector::Ctxt => Some("ctxt"),
+            Collector::Intr => Some("intr"),
+            Collector::Forks => Some("forks"),
+            Collector::Procs => Some("procs_graph"),
+            #[cfg(feature = "load")]
+            Collector::Load => Some("loadavg"),
+            #[cfg(feature = "temp")]
+            Collector::Temp => Some("cputemp"),
+            #[cfg(feature = "freq")]
+            Collector::Freq => Some("cpufreq"),
+            #[cfg(feature = "psi")]
+            Collector::Psi => Some("psi"),
+            #[cfg(feature = "irq")]
+            Collector::Irq => Some("interrupts"),
+            #[cfg(feature = "softirq")]
+            Collector::Softirq => Some("softirqs"),
+            #[cfg(feature = "cgroup")]
+            Collector::Cgroup => Some("cgroups"),
+            #[cfg(feature = "top")]
+            Collector::Top => Some("topprocs"),
+        }
+    }
+
+    /// Bytes the config of this collector may take, with `cores`
+    /// CPUs in the machine, for the buffer we write it to
+    pub fn config_size(&self, cores: usize) -> usize {
+        match self {
+            Collector::Cpu => cores * 3000,
+            Collector::Ctxt => 1000,
+            Collector::Intr => 1000,
+            Collector::Forks => 1000,
+            Collector::Procs => 1000,
+            #[cfg(feature = "load")]
+            Collector::Load => 1000,
+            #[cfg(feature = "temp")]
+            Collector::Temp => cores * 150 + 2000,
+            #[cfg(feature = "freq")]
+            Collector::Freq => cores * 100 + 1000,
+            #[cfg(feature = "psi")]
+            Collector::Psi => 3000,
+            #[cfg(feature = "irq")]
+            Collector::Irq => 3000,
+            #[cfg(feature = "softirq")]
+            Collector::Softirq => (cores + 1) * 1500,
+            // Depends on the number of cgroups, not of cores
+            #[cfg(feature = "cgroup")]
+            Collector::Cgroup => 20000,
+            #[cfg(feature = "top")]
+            Collector::Top => 5000,
+        }
+    }
+}
+
+impl FromStr for Collector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(collector) = Collector::ALL.iter().copied().find(|c| c.name() == s) {
+            return Ok(collector);
+        }
+        match OPTIONAL.iter().find(|(name, _)| *name == s) {
+            Some((_, feature)) => Err(anyhow::anyhow!(
+                "collector {s} is not compiled into this build (cargo feature {feature})"
+            )),
+            None => Err(anyhow::anyhow!("Unknown collector {s}")),
+        }
+    }
+}
+
+/// Names of all collectors behind a cargo feature, compiled in or
+/// not, with their feature, so we can tell a missing one from a typo
+const OPTIONAL: [(&str, &str); 8] = [
+    ("load", "load"),
+    ("temp", "temp"),
+    ("freq", "freq"),
+    ("psi", "psi"),
+    ("irq", "irq"),
+    ("softirq", "softirq"),
+    ("cgroup", "cgroup"),
+    ("top", "top"),
+];
+
+#[test]
+fn test_from_str() {
+    assert_eq!(Collector::Ctxt, "ctxt".parse().unwrap());
+    let e = "tmep".parse::<Collector>().unwrap_err();
+    assert_eq!("Unknown collector tmep", e.to_string());
+    for (name, feature) in OPTIONAL {
+        match name.parse::<Collector>() {
+            Ok(collector) => assert_eq!(name, collector.name()),
+            Err(e) => assert_eq!(
+                format!(
+                    "collector {name} is not compiled into this build (cargo feature {feature})"
+                ),
+                e.to_string()
+            ),
+        }
+    }
+    #[cfg(not(feature = "temp"))]
+    assert_eq!(
+        "collector temp is not compiled into this build (cargo feature temp)",
+        "temp".parse::<Collector>().unwrap_err().to_string()
+    );
+}
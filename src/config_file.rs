@@ -0,0 +1,121 @@
+//! Configuration file, for settings that should hold for every run
+//!
+//! Administrators can put the variables of [Settings] into
+//! [DEFAULT_PATH] instead of munin's plugin configuration, e.g.
+//!
+//! ```toml
+//! cpudetail = true
+//! interval = 500
+//! aggregate = [0, 1, "8-11"]
+//! collectors = ["cpu", "temp"]
+//! retention = "long"
+//! ```
+//!
+//! The environment and the command line win over the file.
+//!
+//! [Settings]: crate::Settings
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+/// Where we look for the file, unless told otherwise
+pub const DEFAULT_PATH: &str = "/etc/cpu1sec.toml";
+
+/// A value in the file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum Value {
+    /// `true` or `false`, for the flags
+    Flag(bool),
+    /// Anything numeric
+    Number(u64),
+    /// Everything else
+    Text(String),
+    /// A list, e.g. of cores or collectors
+    List(Vec<Value>),
+}
+
+impl Value {
+    /// The value the way it would be set in the environment
+    fn to_var(&self) -> String {
+        match self {
+            Value::Flag(flag) => String::from(if *flag { "1" } else { "0" }),
+            Value::Number(number) => number.to_string(),
+            Value::Text(text) => text.clone(),
+            Value::List(list) => list.iter().map(Value::to_var).collect::<Vec<_>>().join(","),
+        }
+    }
+}
+
+/// The variables set in a configuration file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigFile {
+    /// Where we read it from, None if there was no file
+    pub path: Option<PathBuf>,
+    /// Variable names and their values
+    vars: BTreeMap<String, String>,
+}
+
+impl ConfigFile {
+    /// Read the file at `path`. A missing file is fine for the
+    /// [DEFAULT_PATH], then nothing is set.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound && path == Path::new(DEFAULT_PATH) => {
+                return Ok(Self::default())
+            }
+            content => content.with_context(|| format!("Could not read {}", path.display()))?,
+        };
+        let mut file =
+            Self::parse(&content).with_context(|| format!("Could not parse {}", path.display()))?;
+        file.path = Some(path.to_path_buf());
+        Ok(file)
+    }
+
+    /// Parse the content of a file
+    fn parse(content: &str) -> Result<Self> {
+        let vars: BTreeMap<String, Value> = toml::from_str(content)?;
+        Ok(Self {
+            path: None,
+            vars: vars
+                .into_iter()
+                .map(|(name, value)| (name, value.to_var()))
+                .collect(),
+        })
+    }
+
+    /// The value of the variable `name`, if set
+    pub fn var(&self, name: &str) -> Option<String> {
+        self.vars.get(name).cloned()
+    }
+}
+
+#[test]
+fn test_config_file() {
+    let file = ConfigFile::parse(
+        r#"
+cpudetail = true
+steal_graph = false
+interval = 500
+aggregate = [0, 1, "8-11"]
+retention = "custom full 1d"
+"#,
+    )
+    .unwrap();
+    assert_eq!(Some(String::from("1")), file.var("cpudetail"));
+    assert_eq!(Some(String::from("0")), file.var("steal_graph"));
+    assert_eq!(Some(String::from("500")), file.var("interval"));
+    assert_eq!(Some(String::from("0,1,8-11")), file.var("aggregate"));
+    assert_eq!(Some(String::from("custom full 1d")), file.var("retention"));
+    assert_eq!(None, file.var("output"));
+
+    assert!(ConfigFile::parse("cpudetail = ").is_err());
+    assert!(ConfigFile::load(Path::new("/nonexistent/cpu1sec.toml")).is_err());
+}
@@ -0,0 +1,80 @@
+//! cpufreq policies, the groups of cores that scale their frequency
+//! together
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::CpuSet;
+use anyhow::Result;
+use std::{collections::BTreeMap, fs, path::Path, str::FromStr};
+
+/// Where the kernel lists the cpufreq policies, relative to `/`
+const CPUFREQ: &str = "sys/devices/system/cpu/cpufreq";
+
+/// How the per-core graphs of [crate::Settings::cpudetail] are
+/// grouped
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum GroupBy {
+    /// One graph per core
+    #[default]
+    Core,
+    /// One graph per cpufreq policy, summing up the cores that scale
+    /// together, e.g. the clusters of big.LITTLE SoCs
+    Policy,
+    /// One graph per NUMA node, summing up the cores that share a
+    /// memory controller, to see imbalance between the nodes of big
+    /// boxes
+    Node,
+}
+
+impl FromStr for GroupBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "core" => Ok(GroupBy::Core),
+            "policy" => Ok(GroupBy::Policy),
+            "node" => Ok(GroupBy::Node),
+            _ => Err(anyhow::anyhow!("Unknown grouping {s}")),
+        }
+    }
+}
+
+/// The cpufreq policies below `root`, usually `/`, by their number,
+/// with the cores in them (`policyN/affected_cpus`). Empty without
+/// cpufreq, policies we can not read are left out.
+pub(crate) fn policies(root: &Path) -> BTreeMap<u32, CpuSet> {
+    let Ok(dir) = fs::read_dir(root.join(CPUFREQ)) else {
+        return BTreeMap::new();
+    };
+    dir.filter_map(|entry| {
+        let entry = entry.ok()?;
+        let policy = entry
+            .file_name()
+            .to_str()?
+            .strip_prefix("policy")?
+            .parse()
+            .ok()?;
+        let cpus = fs::read_to_string(entry.path().join("affected_cpus")).ok()?;
+        let cpus = cpus.split_whitespace().collect::<Vec<_>>().join(",");
+        Some((policy, cpus.parse().ok()?))
+    })
+    .collect()
+}
+
+#[test]
+fn test_policies() {
+    let root = std::env::temp_dir().join(format!("cpu1sec-cpufreq-{}", std::process::id()));
+    assert!(policies(&root).is_empty());
+    for (policy, cpus) in [("policy0", "0 1\n"), ("policy2", "2 3\n")] {
+        fs::create_dir_all(root.join(CPUFREQ).join(policy)).unwrap();
+        fs::write(root.join(CPUFREQ).join(policy).join("affected_cpus"), cpus).unwrap();
+    }
+    fs::create_dir_all(root.join(CPUFREQ).join("ondemand")).unwrap();
+    assert_eq!(
+        BTreeMap::from([
+            (0, "0-1".parse::<CpuSet>().unwrap()),
+            (2, "2-3".parse::<CpuSet>().unwrap())
+        ]),
+        policies(&root)
+    );
+    fs::remove_dir_all(&root).unwrap();
+}
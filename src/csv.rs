@@ -0,0 +1,309 @@
+//! CSV files, keeping the raw samples around for spreadsheets and
+//! offline analysis, without munin's downsampling
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{fanout::Target, output, CpuStat, Field, Settings};
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::{
+    fmt::{Display, Write as _},
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::UNIX_EPOCH,
+};
+
+/// When the CSV file gets moved aside and a new one started
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Rotate {
+    /// Keep appending to the same file, e.g. for logrotate to handle
+    #[default]
+    Never,
+    /// With the first sample of a new day (UTC)
+    Daily,
+    /// Before the file grows beyond that many bytes
+    Size(u64),
+}
+
+impl FromStr for Rotate {
+    type Err = anyhow::Error;
+
+    /// `never`, `daily` or a size in bytes, optionally with a K, M or
+    /// G suffix
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "never" => return Ok(Rotate::Never),
+            "daily" => return Ok(Rotate::Daily),
+            _ => (),
+        }
+        let (number, factor) = match s.char_indices().last() {
+            Some((i, 'K')) => (&s[..i], 1 << 10),
+            Some((i, 'M')) => (&s[..i], 1 << 20),
+            Some((i, 'G')) => (&s[..i], 1 << 30),
+            _ => (s, 1),
+        };
+        match number.parse::<u64>() {
+            Ok(0) | Err(_) => Err(anyhow!("Unknown rotation {s}")),
+            Ok(size) => Ok(Rotate::Size(size.saturating_mul(factor))),
+        }
+    }
+}
+
+/// Version of the fields in the CSV and JSON lines files, in the
+/// header of both. Bump it whenever they change.
+pub(crate) const SCHEMA: u32 = 1;
+
+/// The columns, separated by `sep`
+pub(crate) fn header(sep: char) -> String {
+    let mut out = format!("epoch{sep}cpu");
+    for field in Field::FINE {
+        let _ = write!(out, "{sep}{}", field.name());
+    }
+    out.push_str(&format!("{sep}busy\n"));
+    out
+}
+
+/// The first lines of every file, and of `cpu1sec query`, the
+/// [SCHEMA] version as a comment and the [header]
+pub(crate) fn file_header(sep: char) -> String {
+    format!("# schema {SCHEMA}\n{}", header(sep))
+}
+
+/// The sample `graphs`, one line per graph, with the epoch, CPU, all
+/// values in ticks and how busy the CPU was in percent. Fields are
+/// separated by `sep`, `decimal` separates the fraction of busy.
+pub(crate) fn encode(graphs: &[CpuStat], sep: char, decimal: char) -> Vec<u8> {
+    let mut out = String::new();
+    for stat in graphs {
+        let values = stat.fields().into_iter().map(|(_, value)| value);
+        line(
+            &mut out,
+            stat.epoch,
+            stat.cpu,
+            values,
+            stat.busy(),
+            sep,
+            decimal,
+        );
+    }
+    out.into_bytes()
+}
+
+/// Append the line of one CPU to `out`, see [encode]
+pub(crate) fn line(
+    out: &mut String,
+    epoch: u64,
+    cpu: impl Display,
+    values: impl IntoIterator<Item = u64>,
+    busy: f64,
+    sep: char,
+    decimal: char,
+) {
+    let _ = write!(out, "{epoch}{sep}{cpu}");
+    for value in values {
+        let _ = write!(out, "{sep}{value}");
+    }
+    let busy = format!("{busy:.2}").replace('.', &decimal.to_string());
+    let _ = writeln!(out, "{sep}{busy}");
+}
+
+#[test]
+fn test_encode() {
+    use crate::CpuId;
+
+    let graphs = [
+        CpuStat {
+            cpu: CpuId::Core(1),
+            epoch: 1_650_000_000,
+            user: 1,
+            idle: 2,
+            ..Default::default()
+        },
+        CpuStat {
+            epoch: 1_650_000_000,
+            system: 10,
+            idle: 10,
+            ..Default::default()
+        },
+    ];
+    assert_eq!(
+        "epoch,cpu,user,nice,system,idle,iowait,irq,softirq,steal,guest,guest_nice,busy\n",
+        header(',')
+    );
+    assert_eq!(
+        "1650000000,cpu1,1,0,0,2,0,0,0,0,0,0,33.33\n\
+         1650000000,total,0,0,10,10,0,0,0,0,0,0,50.00\n",
+        String::from_utf8(encode(&graphs, ',', '.')).unwrap()
+    );
+    assert_eq!(
+        "1650000000;cpu1;1;0;0;2;0;0;0;0;0;0;33,33\n\
+         1650000000;total;0;0;10;10;0;0;0;0;0;0;50,00\n",
+        String::from_utf8(encode(&graphs, ';', ',')).unwrap()
+    );
+}
+
+/// The UTC time of `epoch` as `YYYYMMDD-HHMMSS`, for the names of
+/// rotated files
+pub(crate) fn timestamp(epoch: u64) -> String {
+    let (days, secs) = (epoch / 86400, epoch % 86400);
+    // Days to civil date, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[test]
+fn test_timestamp() {
+    assert_eq!("19700101-000000", timestamp(0));
+    assert_eq!("20220415-052000", timestamp(1_650_000_000));
+    assert_eq!("20240229-235959", timestamp(1_709_251_199));
+}
+
+/// Appends every sample to a CSV file, see [crate::Settings::csv_path]
+#[derive(Debug)]
+pub(crate) struct CsvFile {
+    /// The file we append to
+    path: PathBuf,
+    /// When to start a new one
+    rotate: Rotate,
+    /// Between the fields
+    sep: char,
+    /// Between the integer and fractional part of numbers
+    decimal: char,
+}
+
+impl CsvFile {
+    /// Append to the file at `path`, rotating it as told
+    pub(crate) fn new(path: &Path, rotate: Rotate, sep: char, decimal: char) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            rotate,
+            sep,
+            decimal,
+        }
+    }
+
+    /// Does the file, `len` bytes long so far, need to go before
+    /// `block` of the sample at `epoch` gets added?
+    fn due(&self, len: u64, block: usize, epoch: u64) -> io::Result<bool> {
+        if len == 0 {
+            return Ok(false);
+        }
+        Ok(match self.rotate {
+            Rotate::Never => false,
+            Rotate::Size(size) => len + block as u64 > size,
+            Rotate::Daily => {
+                let modified = fs::metadata(&self.path)?.modified()?;
+                let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+                modified.as_secs() / 86400 != epoch / 86400
+            }
+        })
+    }
+
+    /// Write the sample `graphs`. The file gets opened for every
+    /// sample, so it can also be moved away underneath us. Every new
+    /// file starts with the [SCHEMA] version and the [header].
+    pub(crate) fn write(&self, graphs: &[CpuStat]) {
+        if let Err(e) = self.try_write(graphs) {
+            warn!("Could not write CSV sample to {}: {e}", self.path.display());
+        }
+    }
+
+    /// See [CsvFile::write]
+    fn try_write(&self, graphs: &[CpuStat]) -> io::Result<()> {
+        let Some(epoch) = graphs.first().map(|stat| stat.epoch) else {
+            return Ok(());
+        };
+        let block = encode(graphs, self.sep, self.decimal);
+        let mut len = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        if self.due(len, block.len(), epoch)? {
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(format!(".{}", timestamp(epoch)));
+            let rotated = PathBuf::from(rotated);
+            fs::rename(&self.path, &rotated)?;
+            info!("Rotated {} to {}", self.path.display(), rotated.display());
+            len = 0;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        if len == 0 {
+            output::write_block(&mut file, file_header(self.sep).as_bytes())?;
+        }
+        output::write_block(&mut file, &block)
+    }
+}
+
+impl Target for CsvFile {
+    fn deliver(&mut self, graphs: &[CpuStat]) {
+        self.write(graphs);
+    }
+}
+
+/// The [Target] for [Settings::csv_path], if set
+pub(crate) fn target(settings: &Settings) -> Option<Box<dyn Target>> {
+    let path = settings.csv_path.as_ref()?;
+    Some(Box::new(CsvFile::new(
+        path,
+        settings.csv_rotate,
+        settings.csv_sep,
+        settings.csv_decimal,
+    )))
+}
+
+#[test]
+fn test_csv_file() {
+    let dir = std::env::temp_dir().join(format!("cpu1sec-csv-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("cpu1sec.csv");
+    let stat = |epoch| CpuStat {
+        epoch,
+        idle: 1,
+        ..Default::default()
+    };
+    let line = encode(&[stat(1)], ',', '.').len() as u64;
+    let csv = CsvFile::new(
+        &path,
+        Rotate::Size(file_header(',').len() as u64 + 2 * line),
+        ',',
+        '.',
+    );
+    for epoch in 1..=3 {
+        csv.write(&[stat(epoch)]);
+    }
+    let content = fs::read_to_string(&path).unwrap();
+    assert!(content.starts_with("# schema 1\nepoch,cpu,user,nice,"));
+    assert!(content.ends_with("3,total,0,0,0,1,0,0,0,0,0,0,0.00\n"));
+    let rotated = fs::read_to_string(dir.join("cpu1sec.csv.19700101-000003")).unwrap();
+    assert_eq!(4, rotated.lines().count());
+    // Once per file
+    assert!(rotated.starts_with("# schema 1\nepoch,cpu,"));
+    assert_eq!(1, rotated.matches("# schema").count());
+    assert_eq!(1, rotated.matches("epoch,cpu,").count());
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(Rotate::Size(10 << 20), "10M".parse().unwrap());
+    assert_eq!(Rotate::Size(4096), "4096".parse().unwrap());
+    assert_eq!(Rotate::Daily, "daily".parse().unwrap());
+    assert!("0".parse::<Rotate>().is_err());
+    assert!("weekly".parse::<Rotate>().is_err());
+}
@@ -0,0 +1,234 @@
+//! Context switches, interrupts and forks per second, from the ctxt,
+//! intr and processes lines of the /proc/stat we read for the CPU
+//! usage anyway. Each is a graph of its own, see [Counter], and can
+//! be switched on alone, see [crate::Collector::Ctxt],
+//! [crate::Collector::Intr] and [crate::Collector::Forks].
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::Settings;
+use anyhow::Result;
+use std::{
+    io::{BufWriter, Write},
+    time::Duration,
+};
+
+/// The counters of one /proc/stat
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub(crate) struct Activity {
+    /// When the /proc/stat was read
+    pub(crate) epoch: u64,
+    /// Context switches since boot, None if /proc/stat has no ctxt
+    /// line, as in some minimal containers
+    pub(crate) ctxt: Option<u64>,
+    /// Interrupts since boot, the first number of the intr line
+    pub(crate) intr: Option<u64>,
+    /// Processes and threads created since boot, the processes line
+    pub(crate) forks: Option<u64>,
+}
+
+impl Activity {
+    /// The counters of the /proc/stat `content` read at `epoch`
+    pub(crate) fn parse(content: &str, epoch: u64) -> Self {
+        let counter = |key: &str| {
+            content.lines().find_map(|line| {
+                let mut words = line.split_whitespace();
+                if words.next()? != key {
+                    return None;
+                }
+                words.next()?.parse().ok()
+            })
+        };
+        Self {
+            epoch,
+            ctxt: counter("ctxt"),
+            intr: counter("intr"),
+            forks: counter("processes"),
+        }
+    }
+
+    /// Per second of `counter` since `old`. None if it is missing in
+    /// one of them, the counter went backwards, as after a reboot, or
+    /// more than `max_gap` passed.
+    fn rate(&self, old: &Activity, max_gap: Duration, counter: Counter) -> Option<f64> {
+        let seconds = self.epoch.checked_sub(old.epoch)?;
+        if seconds == 0 || Duration::from_secs(seconds) > max_gap {
+            return None;
+        }
+        let diff = counter.value(self)?.checked_sub(counter.value(old)?)?;
+        Some(diff as f64 / seconds as f64)
+    }
+}
+
+#[test]
+fn test_parse() {
+    let stat = "cpu  10 0 10 100 0 0 0 0 0 0\n\
+                intr 4711 12 0 3\n\
+                ctxt 815\n\
+                btime 1000\n\
+                processes 42\n";
+    assert_eq!(
+        Activity {
+            epoch: 7,
+            ctxt: Some(815),
+            intr: Some(4711),
+            forks: Some(42),
+        },
+        Activity::parse(stat, 7)
+    );
+    assert_eq!(
+        Activity {
+            epoch: 7,
+            ..Default::default()
+        },
+        Activity::parse("cpu  10 0 10 100 0 0 0 0\n", 7)
+    );
+}
+
+/// One of the counters of [Activity], each with a graph of its own
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Counter {
+    /// Context switches, the `ctxt1sec` graph
+    Ctxt,
+    /// Interrupts, the `intr1sec` graph
+    Intr,
+    /// New processes and threads, the `forks1sec` graph
+    Forks,
+}
+
+impl Counter {
+    /// Name of the graph, and of its only field
+    fn name(&self) -> &'static str {
+        match self {
+            Counter::Ctxt => "ctxt",
+            Counter::Intr => "intr",
+            Counter::Forks => "forks",
+        }
+    }
+
+    /// The value of this counter in `activity`
+    fn value(&self, activity: &Activity) -> Option<u64> {
+        match self {
+            Counter::Ctxt => activity.ctxt,
+            Counter::Intr => activity.intr,
+            Counter::Forks => activity.forks,
+        }
+    }
+
+    /// Write out the config of the graph of this counter
+    pub(crate) fn config<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        settings: &Settings,
+    ) -> Result<()> {
+        let (title, label, info) = match self {
+            Counter::Ctxt => (
+                "Context switches",
+                "context switches",
+                "How often the kernel switched between tasks.",
+            ),
+            Counter::Intr => (
+                "Interrupts",
+                "interrupts",
+                "How many interrupts the kernel handled, all IRQs together.",
+            ),
+            Counter::Forks => (
+                "Forks",
+                "forks",
+                "How many processes and threads were created.",
+            ),
+        };
+        let field = self.name();
+        writeln!(handle, "multigraph {field}1sec")?;
+        writeln!(handle, "graph_title {title} (1sec)")?;
+        writeln!(handle, "graph_category {}", settings.graph_category)?;
+        writeln!(handle, "update_rate {}", settings.update_rate())?;
+        writeln!(
+            handle,
+            "graph_data_size {}",
+            settings.retention.graph_data_size()
+        )?;
+        writeln!(handle, "graph_args --base 1000 --lower-limit 0")?;
+        writeln!(handle, "graph_vlabel per second")?;
+        writeln!(handle, "graph_info {info}")?;
+        writeln!(handle, "{field}.label {label}")?;
+        writeln!(handle, "{field}.min 0")?;
+        writeln!(handle, "{field}.type GAUGE")?;
+        writeln!(handle, "{field}.info {title} per second")?;
+        Ok(())
+    }
+
+    /// Write out the value of the graph of this counter, the rate
+    /// between `old` and `new`, unknown if we can not tell, see
+    /// [Activity::rate]
+    pub(crate) fn write<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        new: &Activity,
+        old: Option<&Activity>,
+        max_gap: Duration,
+    ) -> Result<()> {
+        let field = self.name();
+        let epoch = new.epoch;
+        writeln!(handle, "multigraph {field}1sec")?;
+        match old.and_then(|old| new.rate(old, max_gap, *self)) {
+            Some(value) => writeln!(handle, "{field}.value {epoch}:{value:.0}")?,
+            None => writeln!(handle, "{field}.value {epoch}:U")?,
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_write() {
+    let values = |counter: Counter, new: &Activity, old: Option<&Activity>| {
+        let mut handle = BufWriter::new(Vec::new());
+        counter
+            .write(&mut handle, new, old, Duration::from_secs(5))
+            .unwrap();
+        String::from_utf8(handle.into_inner().unwrap()).unwrap()
+    };
+    let at = |epoch, ctxt, intr| Activity {
+        epoch,
+        ctxt: Some(ctxt),
+        intr,
+        forks: Some(ctxt / 10),
+    };
+    assert_eq!(
+        "multigraph ctxt1sec\nctxt.value 1:U\n",
+        values(Counter::Ctxt, &at(1, 100, Some(10)), None)
+    );
+    let (new, old) = (at(3, 1000, Some(50)), at(1, 100, Some(10)));
+    assert_eq!(
+        "multigraph ctxt1sec\nctxt.value 3:450\n",
+        values(Counter::Ctxt, &new, Some(&old))
+    );
+    assert_eq!(
+        "multigraph intr1sec\nintr.value 3:20\n",
+        values(Counter::Intr, &new, Some(&old))
+    );
+    assert_eq!(
+        "multigraph forks1sec\nforks.value 3:45\n",
+        values(Counter::Forks, &new, Some(&old))
+    );
+    // Rebooted, no intr line
+    assert_eq!(
+        "multigraph intr1sec\nintr.value 2:U\n",
+        values(Counter::Intr, &at(2, 5, None), Some(&old))
+    );
+    assert_eq!(
+        "multigraph ctxt1sec\nctxt.value 2:U\n",
+        values(Counter::Ctxt, &at(2, 5, None), Some(&old))
+    );
+    // Suspended
+    assert!(
+        values(Counter::Ctxt, &at(60, 1000, Some(50)), Some(&old)).contains("ctxt.value 60:U\n")
+    );
+
+    let mut config = BufWriter::new(Vec::new());
+    Counter::Forks
+        .config(&mut config, &Settings::default())
+        .unwrap();
+    let config = String::from_utf8(config.into_inner().unwrap()).unwrap();
+    assert!(config.starts_with("multigraph forks1sec\ngraph_title Forks (1sec)\n"));
+    assert!(config.contains("\nforks.type GAUGE\n"));
+}
@@ -0,0 +1,177 @@
+//! Delivers every sample to the sinks besides munin (InfluxDB,
+//! Graphite, Prometheus, files, ...), from a thread of their own
+//!
+//! The munin path never waits for them: a sink that hangs on the
+//! network only holds up the other sinks, and once they fall behind
+//! by [QUEUE] samples, new ones get dropped for them until they
+//! catch up.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{
+    api, csv, graphite, influx, json, mqtt, otlp, shm, socket, statsd, websocket, CpuStat, Settings,
+};
+use log::{info, warn};
+use std::{
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread::{self, JoinHandle},
+};
+
+/// Samples we queue for the sinks before dropping new ones
+const QUEUE: usize = 10;
+
+/// One sink a sample gets delivered to. A new sink is an impl of
+/// this plus its opener in [OPENERS], nothing here changes.
+pub(crate) trait Target: Send {
+    /// Deliver the sample `graphs`. Sinks log their own trouble,
+    /// nothing gets back to us.
+    fn deliver(&mut self, graphs: &[CpuStat]);
+}
+
+/// Opens one kind of [Target], if the settings ask for it
+type Opener = fn(&Settings) -> Option<Box<dyn Target>>;
+
+/// Every kind of [Target], in the order they get the samples
+const OPENERS: &[Opener] = &[
+    influx::target,
+    graphite::target,
+    statsd::target,
+    mqtt::target,
+    otlp::target,
+    json::target,
+    csv::target,
+    socket::target,
+    shm::target,
+    websocket::target,
+    api::target,
+    #[cfg(feature = "prometheus")]
+    crate::prometheus::target,
+    #[cfg(feature = "sqlite")]
+    crate::history::target,
+    #[cfg(feature = "parquet")]
+    crate::archive::target,
+];
+
+/// All sinks `settings` ask for. Has to happen after daemonizing,
+/// the Prometheus exporter and the socket server have threads of
+/// their own.
+pub(crate) fn targets(settings: &Settings) -> Vec<Box<dyn Target>> {
+    OPENERS.iter().filter_map(|open| open(settings)).collect()
+}
+
+/// Hands the samples over to the thread delivering them to the
+/// [Target]s
+#[derive(Debug)]
+pub(crate) struct FanOut {
+    /// The queue to the thread
+    tx: SyncSender<Vec<CpuStat>>,
+    /// The thread, to wait for it when we stop
+    thread: JoinHandle<()>,
+    /// Samples dropped since the thread last kept up
+    dropped: u64,
+}
+
+impl FanOut {
+    /// Start delivering to `targets`. None if there are none, no
+    /// thread needed then.
+    pub(crate) fn spawn(mut targets: Vec<Box<dyn Target>>) -> Option<Self> {
+        if targets.is_empty() {
+            return None;
+        }
+        let (tx, rx) = mpsc::sync_channel::<Vec<CpuStat>>(QUEUE);
+        let thread = thread::Builder::new()
+            .name(String::from("fanout"))
+            .spawn(move || {
+                for graphs in rx {
+                    for target in &mut targets {
+                        target.deliver(&graphs);
+                    }
+                }
+            });
+        match thread {
+            Ok(thread) => Some(Self {
+                tx,
+                thread,
+                dropped: 0,
+            }),
+            Err(e) => {
+                warn!("Not sending samples anywhere but munin, could not start a thread: {e}");
+                None
+            }
+        }
+    }
+
+    /// Queue the sample `graphs` for the sinks, or drop it if they
+    /// are too far behind
+    pub(crate) fn send(&mut self, graphs: &[CpuStat]) {
+        match self.tx.try_send(graphs.to_vec()) {
+            Ok(()) if self.dropped > 0 => {
+                info!(
+                    "Sinks besides munin caught up, dropped {} samples for them",
+                    self.dropped
+                );
+                self.dropped = 0;
+            }
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    warn!("Sinks besides munin fall behind, dropping samples for them");
+                }
+                self.dropped += 1;
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    /// Deliver what is still queued and stop the thread
+    pub(crate) fn close(self) {
+        drop(self.tx);
+        if self.thread.join().is_err() {
+            warn!("Delivering samples to the sinks besides munin panicked");
+        }
+    }
+}
+
+#[test]
+fn test_fan_out() {
+    use std::path::PathBuf;
+
+    assert!(FanOut::spawn(vec![]).is_none());
+
+    let path = std::env::temp_dir().join(format!("cpu1sec-fanout-{}", std::process::id()));
+    let csv = PathBuf::from(format!("{}.csv", path.display()));
+    let settings = Settings {
+        json_path: Some(path.clone()),
+        csv_path: Some(csv.clone()),
+        ..Default::default()
+    };
+    let targets = targets(&settings);
+    assert_eq!(2, targets.len());
+    let mut fanout = FanOut::spawn(targets).unwrap();
+    for epoch in 1..=3 {
+        fanout.send(&[CpuStat {
+            epoch,
+            ..Default::default()
+        }]);
+    }
+    fanout.close();
+    // Both got every sample, in order, past their headers
+    let json: Vec<u64> = std::fs::read_to_string(&path)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let object: serde_json::Value = serde_json::from_str(line).unwrap();
+            object["epoch"].as_u64().unwrap()
+        })
+        .collect();
+    let csv_epochs: Vec<u64> = std::fs::read_to_string(&csv)
+        .unwrap()
+        .lines()
+        .skip(2)
+        .map(|line| line.split(',').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(vec![1, 2, 3], json);
+    assert_eq!(json, csv_epochs);
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&csv).unwrap();
+}
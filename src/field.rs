@@ -0,0 +1,127 @@
+//! The values we write out for every CPU, and what each output names
+//! them
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{Compat, CpuId, CpuStat};
+
+/// One value of a [CpuStat]. All outputs name their values after
+/// these, so they can not drift apart.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Field {
+    /// Ticks spent in user mode
+    User,
+    /// Ticks spent in user mode with low priority (nice)
+    Nice,
+    /// Ticks spent in system mode
+    System,
+    /// Ticks spent in the idle state
+    Idle,
+    /// Ticks waiting for I/O to complete
+    Iowait,
+    /// Ticks servicing interrupts
+    Irq,
+    /// Ticks servicing softirqs
+    Softirq,
+    /// Ticks of stolen time
+    Steal,
+    /// Ticks spent running a virtual CPU for guests
+    Guest,
+    /// Ticks spent running a niced guest
+    GuestNice,
+    /// user + nice, see [crate::Rollup::Coarse]
+    Userspace,
+    /// system + irq + softirq, see [crate::Rollup::Coarse]
+    Kernel,
+    /// iowait + steal, see [crate::Rollup::Coarse]
+    Wait,
+}
+
+impl Field {
+    /// The values the kernel has, in the order we write them
+    pub const FINE: [Field; 10] = [
+        Field::User,
+        Field::Nice,
+        Field::System,
+        Field::Idle,
+        Field::Iowait,
+        Field::Irq,
+        Field::Softirq,
+        Field::Steal,
+        Field::Guest,
+        Field::GuestNice,
+    ];
+
+    /// The bands of [crate::Rollup::Coarse], in the order we write
+    /// them
+    pub const COARSE: [Field; 4] = [Field::Userspace, Field::Kernel, Field::Wait, Field::Idle];
+
+    /// The canonical name, also the key in structured outputs
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::User => "user",
+            Field::Nice => "nice",
+            Field::System => "system",
+            Field::Idle => "idle",
+            Field::Iowait => "iowait",
+            Field::Irq => "irq",
+            Field::Softirq => "softirq",
+            Field::Steal => "steal",
+            Field::Guest => "guest",
+            Field::GuestNice => "guest_nice",
+            Field::Userspace => "userspace",
+            Field::Kernel => "kernel",
+            Field::Wait => "wait",
+        }
+    }
+
+    /// Name of the munin datasource for `cpu`, e.g. `total_user`
+    pub fn munin(&self, compat: Compat, cpu: CpuId) -> String {
+        format!("{}{}", compat.prefix(&cpu.to_string()), self.name())
+    }
+
+    /// Prometheus label for this value, e.g. `mode="user"`
+    pub fn prometheus(&self) -> String {
+        format!("mode=\"{}\"", self.name())
+    }
+
+    /// Does this only ever count with a hypervisor around? See
+    /// [crate::Settings::guest_fields]
+    pub fn is_guest(&self) -> bool {
+        matches!(self, Field::Guest | Field::GuestNice)
+    }
+
+    /// This value of `stat`
+    pub fn value(&self, stat: &CpuStat) -> u64 {
+        match self {
+            Field::User => stat.user,
+            Field::Nice => stat.nice,
+            Field::System => stat.system,
+            Field::Idle => stat.idle,
+            Field::Iowait => stat.iowait,
+            Field::Irq => stat.irq,
+            Field::Softirq => stat.softirq,
+            Field::Steal => stat.steal,
+            Field::Guest => stat.guest,
+            Field::GuestNice => stat.guest_nice,
+            Field::Userspace => stat.user + stat.nice,
+            Field::Kernel => stat.system + stat.irq + stat.softirq,
+            Field::Wait => stat.iowait + stat.steal,
+        }
+    }
+}
+
+#[test]
+fn test_field_names() {
+    let field = Field::GuestNice;
+    assert_eq!(
+        "total_guest_nice",
+        field.munin(Compat::Native, CpuId::Total)
+    );
+    assert_eq!("guest_nice", field.munin(Compat::MuninCpu, CpuId::Total));
+    assert_eq!(
+        "cpu3_guest_nice",
+        field.munin(Compat::MuninCpu, CpuId::Core(3))
+    );
+    assert_eq!("mode=\"guest_nice\"", field.prometheus());
+    assert_eq!("guest_nice", field.name());
+}
@@ -0,0 +1,227 @@
+//! Current frequency of every core, from cpufreq in sysfs, the
+//! `freq1sec` graph of [crate::Collector::Freq]
+//!
+//! One graph with a line per core, in MHz, next to the usage graphs
+//! it explains the "slow but idle" cases: cores clocked down, or
+//! held back by thermal or power limits. The cores are the ones with
+//! cpufreq when we start, in [crate::Settings::cores], at most
+//! [crate::Settings::max_core_graphs] of them.
+//!
+//! With [crate::Collector::Cpu] we also know how busy every core
+//! was. A core more than [BUSY_AT_MIN] percent busy while at its
+//! scaling_min_freq is held back, by a thermal or power cap or a
+//! misconfigured governor, and gets a warning, at most every
+//! [WARN_EVERY] seconds.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::Settings;
+use anyhow::Result;
+use log::warn;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Name of our graph
+const GRAPH: &str = "freq1sec";
+
+/// Where the kernel lists the CPUs, relative to `/`
+const CPUS: &str = "sys/devices/system/cpu";
+
+/// Percent busy above which a core at its lowest frequency gets a
+/// warning
+const BUSY_AT_MIN: f64 = 80.0;
+
+/// Seconds between two warnings about cores held at their lowest
+/// frequency
+const WARN_EVERY: u64 = 300;
+
+/// A number in kHz from the cpufreq file `name` of `cpu` below `root`
+fn read_khz(root: &Path, cpu: u32, name: &str) -> Option<u64> {
+    let path = root.join(CPUS).join(format!("cpu{cpu}/cpufreq/{name}"));
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Reads the frequencies of the cores
+#[derive(Debug, Default)]
+pub(crate) struct Frequencies {
+    /// Where sysfs is, usually `/`
+    root: PathBuf,
+    /// The cores, with their highest frequency in kHz if known
+    cpus: Vec<(u32, Option<u64>)>,
+    /// Epoch of the last warning about busy cores at their lowest
+    /// frequency
+    warned: Option<u64>,
+}
+
+impl Frequencies {
+    /// Look for the cores with cpufreq below `root`, usually `/`, see
+    /// the module documentation for which
+    pub(crate) fn new(root: &Path, settings: &Settings) -> Self {
+        let mut cpus: Vec<u32> = fs::read_dir(root.join(CPUS))
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let cpu = entry
+                    .file_name()
+                    .to_str()?
+                    .strip_prefix("cpu")?
+                    .parse()
+                    .ok()?;
+                entry.path().join("cpufreq").exists().then_some(cpu)
+            })
+            .filter(|cpu| settings.selected(*cpu))
+            .collect();
+        cpus.sort_unstable();
+        cpus.truncate(settings.max_core_graphs);
+        Self {
+            root: root.to_path_buf(),
+            cpus: cpus
+                .into_iter()
+                .map(|cpu| (cpu, read_khz(root, cpu, "cpuinfo_max_freq")))
+                .collect(),
+            warned: None,
+        }
+    }
+
+    /// Write out the config of the `freq1sec` graph
+    pub(crate) fn config<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        settings: &Settings,
+    ) -> Result<()> {
+        writeln!(handle, "multigraph {GRAPH}")?;
+        writeln!(handle, "graph_title CPU frequency (1sec)")?;
+        writeln!(handle, "graph_category {}", settings.graph_category)?;
+        writeln!(handle, "update_rate {}", settings.update_rate())?;
+        writeln!(
+            handle,
+            "graph_data_size {}",
+            settings.retention.graph_data_size()
+        )?;
+        writeln!(handle, "graph_args --base 1000 --lower-limit 0")?;
+        writeln!(handle, "graph_vlabel MHz")?;
+        writeln!(handle, "graph_scale no")?;
+        writeln!(
+            handle,
+            "graph_info The frequency every core runs at, as cpufreq sees it (scaling_cur_freq)."
+        )?;
+        for (cpu, max) in &self.cpus {
+            writeln!(handle, "cpu{cpu}.label cpu{cpu}")?;
+            writeln!(handle, "cpu{cpu}.min 0")?;
+            writeln!(handle, "cpu{cpu}.type GAUGE")?;
+            // Not as max, some cores run above what cpufreq says is
+            // their highest frequency, and munin would drop that
+            if let Some(max) = max {
+                writeln!(handle, "cpu{cpu}.info Highest frequency {} MHz", max / 1000)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the frequencies and write out the values of the
+    /// `freq1sec` graph for `epoch`. Unknown for cores we can not
+    /// read, e.g. when they went offline. `busy` is how busy the
+    /// cores were in percent, if we know, to warn about those held
+    /// at their lowest frequency.
+    pub(crate) fn write<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        epoch: u64,
+        busy: &BTreeMap<u32, f64>,
+    ) -> Result<()> {
+        writeln!(handle, "multigraph {GRAPH}")?;
+        let mut held = vec![];
+        for (cpu, _) in &self.cpus {
+            match read_khz(&self.root, *cpu, "scaling_cur_freq") {
+                Some(khz) => {
+                    writeln!(handle, "cpu{cpu}.value {epoch}:{}", khz / 1000)?;
+                    if let Some(busy) = busy.get(cpu).filter(|busy| **busy > BUSY_AT_MIN) {
+                        if read_khz(&self.root, *cpu, "scaling_min_freq")
+                            .is_some_and(|min| khz <= min)
+                        {
+                            held.push(format!("cpu{cpu} ({busy:.0}% at {} MHz)", khz / 1000));
+                        }
+                    }
+                }
+                None => writeln!(handle, "cpu{cpu}.value {epoch}:U")?,
+            }
+        }
+        if !held.is_empty() && self.warned.is_none_or(|last| epoch >= last + WARN_EVERY) {
+            warn!(
+                "Busy but at their lowest frequency, thermal or power capped? {}",
+                held.join(", ")
+            );
+            self.warned = Some(epoch);
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_frequencies() {
+    let root = std::env::temp_dir().join(format!("cpu1sec-freq-{}", std::process::id()));
+    for (cpu, cur) in [(0, Some("2400000\n")), (1, None), (10, Some("800000\n"))] {
+        let dir = root.join(CPUS).join(format!("cpu{cpu}/cpufreq"));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cpuinfo_max_freq"), "3600000\n").unwrap();
+        if let Some(cur) = cur {
+            fs::write(dir.join("scaling_cur_freq"), cur).unwrap();
+        }
+    }
+    // No cpufreq for this one
+    fs::create_dir_all(root.join(CPUS).join("cpu2")).unwrap();
+    fs::create_dir_all(root.join(CPUS).join("cpufreq")).unwrap();
+    let settings = Settings::default();
+    let mut freq = Frequencies::new(&root, &settings);
+    assert_eq!(
+        vec![0, 1, 10],
+        freq.cpus.iter().map(|(cpu, _)| *cpu).collect::<Vec<_>>()
+    );
+
+    let mut config = BufWriter::new(Vec::new());
+    freq.config(&mut config, &settings).unwrap();
+    let config = String::from_utf8(config.into_inner().unwrap()).unwrap();
+    assert!(config.starts_with("multigraph freq1sec\n"));
+    assert!(config.contains("cpu10.type GAUGE\ncpu10.info Highest frequency 3600 MHz\n"));
+
+    let mut values = BufWriter::new(Vec::new());
+    freq.write(&mut values, 5, &BTreeMap::new()).unwrap();
+    assert_eq!(
+        "multigraph freq1sec\n\
+         cpu0.value 5:2400\n\
+         cpu1.value 5:U\n\
+         cpu10.value 5:800\n",
+        String::from_utf8(values.into_inner().unwrap()).unwrap()
+    );
+
+    // cpu10 busy at its lowest frequency, cpu0 busy but clocked up
+    for cpu in [0, 10] {
+        let dir = root.join(CPUS).join(format!("cpu{cpu}/cpufreq"));
+        fs::write(dir.join("scaling_min_freq"), "800000\n").unwrap();
+    }
+    let mut sink = BufWriter::new(Vec::new());
+    let mut write = |freq: &mut Frequencies, epoch, busy: &[(u32, f64)]| {
+        freq.write(&mut sink, epoch, &busy.iter().copied().collect())
+            .unwrap();
+        freq.warned
+    };
+    assert_eq!(None, write(&mut freq, 5, &[(0, 99.0), (10, 50.0)]));
+    assert_eq!(Some(6), write(&mut freq, 6, &[(0, 99.0), (10, 95.0)]));
+    // Not again right away
+    assert_eq!(Some(6), write(&mut freq, 7, &[(10, 95.0)]));
+    assert_eq!(Some(306), write(&mut freq, 306, &[(10, 95.0)]));
+
+    let freq = Frequencies::new(
+        &root,
+        &Settings {
+            cores: Some("1-4".parse().unwrap()),
+            ..Default::default()
+        },
+    );
+    assert_eq!(1, freq.cpus.len());
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,118 @@
+//! Graphite plaintext protocol, for pushing the samples to Carbon
+//! next to munin
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{
+    fanout::Target,
+    output::{self, Sender},
+    CpuStat, Endpoint, Settings,
+};
+use std::fmt::Write;
+
+/// Default first part of our metric names, followed by the host
+/// name
+const PREFIX: &str = "cpu1sec";
+
+/// Our metric names start with `cpu1sec.<host>`, the dots of the host
+/// name turned into underscores, so it stays one part of the path
+pub(crate) fn default_prefix() -> String {
+    match output::hostname() {
+        Some(host) => format!("{PREFIX}.{}", host.replace('.', "_")),
+        None => format!("{PREFIX}.localhost"),
+    }
+}
+
+/// The sample `graphs` as `<prefix>.<cpu>.<field> <value> <epoch>`
+/// lines, with the same fields munin gets
+pub(crate) fn encode(graphs: &[CpuStat], prefix: &str) -> Vec<u8> {
+    let mut out = String::new();
+    for stat in graphs {
+        let fields = stat.rollup.fields().iter();
+        for field in fields.filter(|field| stat.guest_fields || !field.is_guest()) {
+            let _ = writeln!(
+                out,
+                "{prefix}.{}.{} {} {}",
+                stat.cpu,
+                field.name(),
+                field.value(stat),
+                stat.epoch
+            );
+        }
+    }
+    out.into_bytes()
+}
+
+#[test]
+fn test_encode() {
+    use crate::{CpuId, Rollup};
+
+    let graphs = [
+        CpuStat {
+            cpu: CpuId::Core(1),
+            epoch: 1_650_000_000,
+            user: 42,
+            idle: 58,
+            rollup: Rollup::Coarse,
+            ..Default::default()
+        },
+        CpuStat {
+            epoch: 1_650_000_000,
+            user: 10,
+            guest_fields: false,
+            ..Default::default()
+        },
+    ];
+    let out = String::from_utf8(encode(&graphs, "cpu1sec.web01")).unwrap();
+    assert!(out.starts_with(
+        "cpu1sec.web01.cpu1.userspace 42 1650000000\n\
+         cpu1sec.web01.cpu1.kernel 0 1650000000\n"
+    ));
+    assert!(out.contains("cpu1sec.web01.total.user 10 1650000000\n"));
+    assert!(!out.contains("guest"));
+    assert_eq!(12, out.lines().count());
+    assert!(default_prefix().starts_with("cpu1sec."));
+}
+
+/// Pushes samples to Carbon, see [crate::Settings::graphite_url]
+#[derive(Debug)]
+pub(crate) struct Graphite {
+    /// Where they go
+    sender: Sender,
+    /// Start of our metric names
+    prefix: String,
+}
+
+impl Graphite {
+    /// Push to `endpoint`, naming our metrics `<prefix>.<cpu>.<field>`
+    pub(crate) fn new(endpoint: &Endpoint, prefix: String, buffer: usize) -> Self {
+        Self {
+            sender: endpoint.sender(buffer),
+            prefix,
+        }
+    }
+
+    /// Push the sample `graphs`
+    pub(crate) fn send(&mut self, graphs: &[CpuStat]) {
+        self.sender.send(encode(graphs, &self.prefix));
+    }
+}
+
+impl Target for Graphite {
+    fn deliver(&mut self, graphs: &[CpuStat]) {
+        self.send(graphs);
+    }
+}
+
+/// The [Target] for [Settings::graphite_url], if set
+pub(crate) fn target(settings: &Settings) -> Option<Box<dyn Target>> {
+    let endpoint = settings.graphite_url.as_ref()?;
+    let prefix = settings
+        .graphite_prefix
+        .clone()
+        .unwrap_or_else(default_prefix);
+    Some(Box::new(Graphite::new(
+        endpoint,
+        prefix,
+        settings.tcp_buffer,
+    )))
+}
@@ -0,0 +1,243 @@
+//! History of the samples in an SQLite database (`sqlite_path`), for
+//! looking at an incident at 1 second resolution long after munin
+//! averaged it away. `cpu1sec query` gets them out again.
+//!
+//! One row per CPU and sample, in the table `samples`, with the
+//! epoch, the CPU, its values in ticks and how busy it was. Rows
+//! older than [crate::Settings::sqlite_keep] get removed as new ones
+//! come in.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{csv, fanout::Target, CpuStat, Field, Settings};
+use anyhow::{anyhow, Result};
+use log::warn;
+use rusqlite::{params_from_iter, types::Value, Connection, OpenFlags};
+use std::{
+    io::Write,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Seconds between two runs removing old rows
+const EXPIRE_EVERY: u64 = 60;
+
+/// How long a query waits for the daemon to finish a write
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The columns with the values, one per [Field::FINE]
+fn columns() -> Vec<&'static str> {
+    Field::FINE.iter().map(Field::name).collect()
+}
+
+/// Writes every sample to the database at
+/// [crate::Settings::sqlite_path]
+#[derive(Debug)]
+pub(crate) struct History {
+    /// The database
+    db: Connection,
+    /// Seconds we keep rows
+    keep: u64,
+    /// Epoch of the last run removing old rows
+    expired: u64,
+}
+
+impl History {
+    /// Open the database at `path`, creating it and its table if
+    /// need be. Rows are kept for `keep`.
+    pub(crate) fn open(path: &Path, keep: Duration) -> Result<Self> {
+        let db = Connection::open(path)?;
+        // Queries may read while we write
+        db.pragma_update(None, "journal_mode", "WAL")?;
+        let values: Vec<String> = columns()
+            .iter()
+            .map(|column| format!("{column} INTEGER NOT NULL"))
+            .collect();
+        db.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS samples (
+                 epoch INTEGER NOT NULL,
+                 cpu TEXT NOT NULL,
+                 {},
+                 busy REAL NOT NULL
+             );
+             CREATE INDEX IF NOT EXISTS samples_epoch ON samples (epoch);",
+            values.join(", ")
+        ))?;
+        Ok(Self {
+            db,
+            keep: keep.as_secs(),
+            expired: 0,
+        })
+    }
+
+    /// Add the sample `graphs`, warning if that fails
+    pub(crate) fn write(&mut self, graphs: &[CpuStat]) {
+        if let Err(e) = self.insert(graphs) {
+            warn!("Could not add sample to the history: {e}");
+        }
+    }
+
+    /// Add the sample `graphs`, and remove what is too old now
+    fn insert(&mut self, graphs: &[CpuStat]) -> Result<()> {
+        let columns = columns();
+        let sql = format!(
+            "INSERT INTO samples (epoch, cpu, {}, busy) VALUES ({})",
+            columns.join(", "),
+            vec!["?"; columns.len() + 3].join(", ")
+        );
+        let tx = self.db.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(&sql)?;
+            for stat in graphs {
+                let mut row = vec![
+                    Value::Integer(stat.epoch as i64),
+                    Value::Text(stat.cpu.to_string()),
+                ];
+                row.extend(
+                    stat.fields()
+                        .into_iter()
+                        .map(|(_, value)| Value::Integer(value as i64)),
+                );
+                row.push(Value::Real(stat.busy()));
+                insert.execute(params_from_iter(row))?;
+            }
+        }
+        tx.commit()?;
+        let epoch = graphs.first().map_or(0, |stat| stat.epoch);
+        if epoch >= self.expired + EXPIRE_EVERY {
+            self.db.execute(
+                "DELETE FROM samples WHERE epoch < ?1",
+                [epoch.saturating_sub(self.keep) as i64],
+            )?;
+            self.expired = epoch;
+        }
+        Ok(())
+    }
+}
+
+impl Target for History {
+    fn deliver(&mut self, graphs: &[CpuStat]) {
+        self.write(graphs);
+    }
+}
+
+/// The [Target] for [Settings::sqlite_path], if set and we can open
+/// it
+pub(crate) fn target(settings: &Settings) -> Option<Box<dyn Target>> {
+    let path = settings.sqlite_path.as_ref()?;
+    match History::open(path, settings.sqlite_keep) {
+        Ok(history) => Some(Box::new(history)),
+        Err(e) => {
+            warn!("Not keeping a history in {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// Implements `cpu1sec query`: Write the samples in the history of
+/// `settings` taken between `from` and `to` (both epochs, both
+/// included) to `out`, as CSV in the format of
+/// [crate::Settings::csv_path]. `to` defaults to now, `from` to `last`
+/// seconds before `to`.
+pub fn query<W: Write>(
+    out: &mut W,
+    settings: &Settings,
+    from: Option<u64>,
+    to: Option<u64>,
+    last: u64,
+) -> Result<()> {
+    let path = settings
+        .sqlite_path
+        .as_ref()
+        .ok_or_else(|| anyhow!("No history to query, sqlite_path is not set"))?;
+    let db = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )
+    .map_err(|e| anyhow!("Could not open history {}: {e}", path.display()))?;
+    db.busy_timeout(BUSY_TIMEOUT)?;
+    let to = match to {
+        Some(to) => to,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    let from = from.unwrap_or_else(|| to.saturating_sub(last));
+    let columns = columns();
+    let mut select = db.prepare(&format!(
+        "SELECT epoch, cpu, {}, busy FROM samples
+         WHERE epoch BETWEEN ?1 AND ?2 ORDER BY epoch, rowid",
+        columns.join(", ")
+    ))?;
+    let mut rows = select.query([from as i64, to as i64])?;
+    out.write_all(csv::file_header(settings.csv_sep).as_bytes())?;
+    while let Some(row) = rows.next()? {
+        let values = (0..columns.len())
+            .map(|i| row.get::<_, i64>(i + 2).map(|value| value as u64))
+            .collect::<rusqlite::Result<Vec<u64>>>()?;
+        let mut line = String::new();
+        csv::line(
+            &mut line,
+            row.get::<_, i64>(0)? as u64,
+            row.get::<_, String>(1)?,
+            values,
+            row.get(columns.len() + 2)?,
+            settings.csv_sep,
+            settings.csv_decimal,
+        );
+        out.write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_history() {
+    use crate::CpuId;
+
+    let dir = std::env::temp_dir().join(format!("cpu1sec-history-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("history.db");
+    let settings = Settings {
+        sqlite_path: Some(path.clone()),
+        ..Default::default()
+    };
+    let mut history = History::open(&path, Duration::from_secs(3600)).unwrap();
+    let stat = |cpu, epoch| CpuStat {
+        cpu,
+        epoch,
+        user: 1,
+        idle: 3,
+        ..Default::default()
+    };
+    for epoch in [1000, 2000, 5000] {
+        history.write(&[stat(CpuId::Core(0), epoch), stat(CpuId::Total, epoch)]);
+    }
+    let rows = |settings: &Settings, from, to, last| {
+        let mut out = vec![];
+        query(&mut out, settings, from, to, last).map(|()| String::from_utf8(out).unwrap())
+    };
+    // 1000 is more than an hour before 5000, gone
+    assert_eq!(
+        format!(
+            "{}\
+             2000,cpu0,1,0,0,3,0,0,0,0,0,0,25.00\n\
+             2000,total,1,0,0,3,0,0,0,0,0,0,25.00\n",
+            csv::file_header(',')
+        ),
+        rows(&settings, Some(0), Some(4999), 0).unwrap()
+    );
+    assert_eq!(
+        4,
+        rows(&settings, None, Some(5000), 10)
+            .unwrap()
+            .lines()
+            .count()
+    );
+    assert_eq!(
+        2,
+        rows(&settings, Some(6000), None, 0)
+            .unwrap()
+            .lines()
+            .count()
+    );
+    // Nothing to query
+    assert!(rows(&Settings::default(), None, None, 60).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
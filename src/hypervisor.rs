@@ -0,0 +1,75 @@
+//! Is there virtualization around us, and so a point in the guest
+//! fields?
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use log::info;
+use std::{fs, path::Path};
+
+/// Is there a hypervisor involved, either below us (we run in a
+/// virtual machine) or in our kernel (KVM, so we run virtual machines
+/// ourself)? On other machines guest and guest_nice can only ever be
+/// 0. `root` is the directory to look at, usually `/`, `proc` where
+/// procfs is, usually `/proc`.
+pub(crate) fn detect(root: &Path, proc: &Path) -> bool {
+    let found = if cpu_flag(proc) {
+        Some("hypervisor cpu flag")
+    } else if root.join("sys/hypervisor/type").exists() {
+        Some("/sys/hypervisor")
+    } else if proc.join("device-tree/hypervisor").exists() {
+        Some("hypervisor in the device tree")
+    } else if root.join("dev/kvm").exists() {
+        Some("/dev/kvm")
+    } else {
+        None
+    };
+    match found {
+        Some(how) => info!("Virtualization found ({how}), emitting guest fields"),
+        None => info!("No virtualization found, not emitting guest fields"),
+    }
+    found.is_some()
+}
+
+/// Does /proc/cpuinfo list the hypervisor flag (x86)?
+fn cpu_flag(proc: &Path) -> bool {
+    fs::read_to_string(proc.join("cpuinfo")).is_ok_and(|cpuinfo| {
+        cpuinfo
+            .lines()
+            .filter(|line| line.starts_with("flags"))
+            .any(|line| line.split_whitespace().any(|flag| flag == "hypervisor"))
+    })
+}
+
+#[test]
+fn test_detect_hypervisor() {
+    let root = std::env::temp_dir().join(format!("cpu1sec-hypervisor-{}", std::process::id()));
+    let proc = root.join("proc");
+    fs::create_dir_all(&proc).unwrap();
+
+    // Bare metal
+    fs::write(
+        root.join("proc/cpuinfo"),
+        "processor\t: 0\nflags\t\t: fpu vme de pse tsc msr\n",
+    )
+    .unwrap();
+    assert!(!detect(&root, &proc));
+
+    // Virtual machine
+    fs::write(
+        root.join("proc/cpuinfo"),
+        "processor\t: 0\nflags\t\t: fpu vme de pse tsc msr hypervisor\n",
+    )
+    .unwrap();
+    assert!(detect(&root, &proc));
+
+    // Bare metal running virtual machines
+    fs::write(
+        root.join("proc/cpuinfo"),
+        "processor\t: 0\nflags\t\t: fpu\n",
+    )
+    .unwrap();
+    fs::create_dir_all(root.join("dev")).unwrap();
+    fs::write(root.join("dev/kvm"), "").unwrap();
+    assert!(detect(&root, &proc));
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,74 @@
+//! InfluxDB line protocol, for sending the samples to InfluxDB (or
+//! Telegraf, or anything else speaking it) next to munin
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{fanout::Target, output::Sender, CpuStat, Settings};
+use std::fmt::Write;
+
+/// The sample `graphs` in line protocol, one line per graph in the
+/// measurement `cpu1sec`, tagged with the `cpu`, with the same fields
+/// munin gets. Timestamps are in nanoseconds, the default precision.
+pub(crate) fn encode(graphs: &[CpuStat]) -> Vec<u8> {
+    let mut out = String::new();
+    for stat in graphs {
+        let fields: Vec<String> = stat
+            .rollup
+            .fields()
+            .iter()
+            .filter(|field| stat.guest_fields || !field.is_guest())
+            .map(|field| format!("{}={}i", field.name(), field.value(stat)))
+            .collect();
+        let _ = writeln!(
+            out,
+            "cpu1sec,cpu={} {} {}",
+            stat.cpu,
+            fields.join(","),
+            u128::from(stat.epoch) * 1_000_000_000
+        );
+    }
+    out.into_bytes()
+}
+
+#[test]
+fn test_encode() {
+    use crate::{CpuId, Rollup};
+
+    let graphs = [
+        CpuStat {
+            cpu: CpuId::Core(3),
+            epoch: 1_650_000_000,
+            user: 42,
+            idle: 58,
+            guest_fields: false,
+            ..Default::default()
+        },
+        CpuStat {
+            epoch: 1_650_000_000,
+            user: 10,
+            system: 5,
+            idle: 85,
+            rollup: Rollup::Coarse,
+            ..Default::default()
+        },
+    ];
+    assert_eq!(
+        "cpu1sec,cpu=cpu3 user=42i,nice=0i,system=0i,idle=58i,iowait=0i,irq=0i,softirq=0i,steal=0i 1650000000000000000\n\
+         cpu1sec,cpu=total userspace=10i,kernel=5i,wait=0i,idle=85i 1650000000000000000\n",
+        String::from_utf8(encode(&graphs)).unwrap()
+    );
+}
+
+/// Sends samples to InfluxDB, see [crate::Settings::influx_url]
+struct Influx(Sender);
+
+impl Target for Influx {
+    fn deliver(&mut self, graphs: &[CpuStat]) {
+        self.0.send(encode(graphs));
+    }
+}
+
+/// The [Target] for [Settings::influx_url], if set
+pub(crate) fn target(settings: &Settings) -> Option<Box<dyn Target>> {
+    let endpoint = settings.influx_url.as_ref()?;
+    Some(Box::new(Influx(endpoint.sender(settings.tcp_buffer))))
+}
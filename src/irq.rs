@@ -0,0 +1,312 @@
+//! Interrupts per second, per IRQ, from /proc/interrupts, the
+//! `irq1sec` graph of [crate::Collector::Irq]
+//!
+//! Munin needs to know the fields of a graph up front, so the IRQs
+//! are picked when we start: the ones in [crate::Settings::irqs], or
+//! else the [crate::Settings::irq_top] ones with the most interrupts
+//! since boot. Those rarely change, so the config munin gets from
+//! its own run of the plugin matches what the daemon picked.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::Settings;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{BufWriter, Write},
+    path::Path,
+    time::Duration,
+};
+
+/// Name of our graph
+const GRAPH: &str = "irq1sec";
+
+/// One line of /proc/interrupts
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Irq {
+    /// The number, or a name like NMI or LOC
+    id: String,
+    /// Interrupts since boot, summed up over all CPUs
+    count: u64,
+    /// What the kernel says it is, e.g. `IO-APIC 4-edge ttyS0`
+    description: String,
+}
+
+/// Parse the content of /proc/interrupts
+fn parse(content: &str) -> Result<Vec<Irq>> {
+    let mut lines = content.lines();
+    let cpus = lines
+        .next()
+        .map(|header| header.split_whitespace().count())
+        .filter(|cpus| *cpus > 0)
+        .ok_or_else(|| anyhow!("No CPUs in the header of interrupts"))?;
+    Ok(lines
+        .filter_map(|line| {
+            let (id, rest) = line.split_once(':')?;
+            let mut words = rest.split_whitespace().peekable();
+            let mut count = 0;
+            for _ in 0..cpus {
+                match words.peek().and_then(|word| word.parse::<u64>().ok()) {
+                    Some(value) => count += value,
+                    None => break,
+                }
+                words.next();
+            }
+            Some(Irq {
+                id: id.trim().to_string(),
+                count,
+                description: words.collect::<Vec<_>>().join(" "),
+            })
+        })
+        .collect())
+}
+
+#[test]
+fn test_parse() {
+    let irqs = parse(
+        "           CPU0       CPU1\n  \
+           0:         44          6   IO-APIC   2-edge      timer\n \
+          26:          2          0   IO-APIC   4-edge      ttyS0\n\
+         NMI:          1          2   Non-maskable interrupts\n\
+         ERR:          0\n",
+    )
+    .unwrap();
+    assert_eq!(
+        Irq {
+            id: String::from("0"),
+            count: 50,
+            description: String::from("IO-APIC 2-edge timer")
+        },
+        irqs[0]
+    );
+    assert_eq!(3, irqs[2].count);
+    assert_eq!("Non-maskable interrupts", irqs[2].description);
+    assert_eq!(
+        ("ERR", 0, ""),
+        (
+            irqs[3].id.as_str(),
+            irqs[3].count,
+            irqs[3].description.as_str()
+        )
+    );
+    assert!(parse("").is_err());
+}
+
+/// munin field name of the IRQ `id`, which may neither start with a
+/// digit nor have anything but letters, digits and underscores
+fn field(id: &str) -> String {
+    let id: String = id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("irq_{id}")
+}
+
+/// The IRQs we look at, and their counts when we looked last
+#[derive(Debug, Default)]
+pub(crate) struct Interrupts {
+    /// The IRQs, with their label and description
+    selected: Vec<(String, String, String)>,
+    /// Epoch of the last read, and the counts of the IRQs then
+    last: Option<(u64, BTreeMap<String, u64>)>,
+}
+
+impl Interrupts {
+    /// Pick the IRQs of the interrupts file of
+    /// [Settings::proc_root] to look at, see the module documentation
+    pub(crate) fn new(settings: &Settings) -> Self {
+        let path = settings.proc_root.join("interrupts");
+        let irqs = match fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| parse(&content))
+        {
+            Ok(irqs) => irqs,
+            Err(e) => {
+                warn!("Could not read {}: {e}, no IRQs to graph", path.display());
+                return Self::default();
+            }
+        };
+        let picked: Vec<Irq> = match &settings.irqs {
+            Some(ids) => {
+                for id in ids
+                    .iter()
+                    .filter(|id| !irqs.iter().any(|irq| irq.id == **id))
+                {
+                    warn!("No IRQ {id} in {}, ignoring it", path.display());
+                }
+                irqs.into_iter()
+                    .filter(|irq| ids.contains(&irq.id))
+                    .collect()
+            }
+            None => {
+                let mut irqs = irqs;
+                // Stable order for IRQs with the same count
+                irqs.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.id.cmp(&b.id)));
+                irqs.truncate(settings.irq_top);
+                irqs
+            }
+        };
+        let selected = picked
+            .into_iter()
+            .map(|irq| {
+                // Numbered ones get the device, the last word of
+                // their description, the named ones are well known
+                let label = match irq.id.parse::<u32>() {
+                    Ok(_) => match irq.description.split_whitespace().last() {
+                        Some(device) => format!("{} {device}", irq.id),
+                        None => irq.id.clone(),
+                    },
+                    Err(_) => irq.id.clone(),
+                };
+                (irq.id, label, irq.description)
+            })
+            .collect();
+        Self {
+            selected,
+            last: None,
+        }
+    }
+
+    /// Write out the config of the `irq1sec` graph
+    pub(crate) fn config<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        settings: &Settings,
+    ) -> Result<()> {
+        writeln!(handle, "multigraph {GRAPH}")?;
+        writeln!(handle, "graph_title Interrupts per IRQ (1sec)")?;
+        writeln!(handle, "graph_category {}", settings.graph_category)?;
+        writeln!(handle, "update_rate {}", settings.update_rate())?;
+        writeln!(
+            handle,
+            "graph_data_size {}",
+            settings.retention.graph_data_size()
+        )?;
+        writeln!(handle, "graph_args --base 1000 --lower-limit 0")?;
+        writeln!(handle, "graph_vlabel interrupts per second")?;
+        writeln!(
+            handle,
+            "graph_info Interrupts handled per second, summed up over all CPUs, from /proc/interrupts."
+        )?;
+        for (id, label, description) in &self.selected {
+            let field = field(id);
+            writeln!(handle, "{field}.label {label}")?;
+            writeln!(handle, "{field}.min 0")?;
+            writeln!(handle, "{field}.type GAUGE")?;
+            if !description.is_empty() {
+                writeln!(handle, "{field}.info {description}")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read /proc/interrupts below `proc_root` and write out the
+    /// interrupts per second of our IRQs since the last read, for
+    /// `epoch`. Unknown if we can not tell, after more than `max_gap`
+    /// or with counts going backwards. With more than one sample per
+    /// second only the first one in a second counts.
+    pub(crate) fn write<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        proc_root: &Path,
+        epoch: u64,
+        max_gap: Duration,
+    ) -> Result<()> {
+        if self.last.as_ref().is_some_and(|(last, _)| *last == epoch) {
+            return Ok(());
+        }
+        let path = proc_root.join("interrupts");
+        let counts: BTreeMap<String, u64> = match fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| parse(&content))
+        {
+            Ok(irqs) => irqs.into_iter().map(|irq| (irq.id, irq.count)).collect(),
+            Err(e) => {
+                info!("Could not read {}: {e}", path.display());
+                BTreeMap::new()
+            }
+        };
+        let seconds = self
+            .last
+            .as_ref()
+            .and_then(|(last, _)| epoch.checked_sub(*last))
+            .filter(|seconds| *seconds > 0 && Duration::from_secs(*seconds) <= max_gap);
+        writeln!(handle, "multigraph {GRAPH}")?;
+        for (id, _, _) in &self.selected {
+            let field = field(id);
+            let rate = seconds.and_then(|seconds| {
+                let (_, old) = self.last.as_ref()?;
+                let diff = counts.get(id)?.checked_sub(*old.get(id)?)?;
+                Some(diff as f64 / seconds as f64)
+            });
+            match rate {
+                Some(rate) => writeln!(handle, "{field}.value {epoch}:{rate:.0}")?,
+                None => writeln!(handle, "{field}.value {epoch}:U")?,
+            }
+        }
+        self.last = Some((epoch, counts));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_interrupts() {
+    let root = std::env::temp_dir().join(format!("cpu1sec-irq-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let interrupts = |timer, nvme, loc| {
+        format!(
+            "           CPU0       CPU1\n  \
+               0:  {timer}    0   IO-APIC   2-edge      timer\n \
+              26:  {nvme}     0   PCI-MSI 1-edge      nvme0q0\n\
+             LOC:  {loc}      5   Local timer interrupts\n\
+             ERR:          0\n"
+        )
+    };
+    fs::write(root.join("interrupts"), interrupts(10, 500, 1000)).unwrap();
+    let settings = Settings {
+        proc_root: root.clone(),
+        irq_top: 2,
+        ..Default::default()
+    };
+    let mut irqs = Interrupts::new(&settings);
+    let mut config = BufWriter::new(Vec::new());
+    irqs.config(&mut config, &settings).unwrap();
+    let config = String::from_utf8(config.into_inner().unwrap()).unwrap();
+    assert!(config.contains("irq_loc.label LOC\nirq_loc.min 0\nirq_loc.type GAUGE\nirq_loc.info Local timer interrupts\n"));
+    assert!(config.contains("irq_26.label 26 nvme0q0\n"));
+    assert!(!config.contains("irq_0."));
+
+    let mut values = |epoch| {
+        let mut handle = BufWriter::new(Vec::new());
+        irqs.write(&mut handle, &root, epoch, Duration::from_secs(5))
+            .unwrap();
+        String::from_utf8(handle.into_inner().unwrap()).unwrap()
+    };
+    assert_eq!(
+        "multigraph irq1sec\nirq_loc.value 1:U\nirq_26.value 1:U\n",
+        values(1)
+    );
+    fs::write(root.join("interrupts"), interrupts(20, 700, 3000)).unwrap();
+    assert_eq!(
+        "multigraph irq1sec\nirq_loc.value 3:1000\nirq_26.value 3:100\n",
+        values(3)
+    );
+    assert_eq!("", values(3));
+
+    let settings = Settings {
+        proc_root: root.clone(),
+        irqs: Some(vec![String::from("0"), String::from("NMI")]),
+        ..Default::default()
+    };
+    let irqs = Interrupts::new(&settings);
+    assert_eq!(1, irqs.selected.len());
+    assert_eq!("0 timer", irqs.selected[0].1);
+    fs::remove_dir_all(&root).unwrap();
+}
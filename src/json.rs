@@ -0,0 +1,200 @@
+//! JSON lines, one object per graph and sample, for anything that
+//! would rather not parse munin's format
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{csv::SCHEMA, fanout::Target, output, CpuStat, Field, Settings};
+use log::warn;
+use serde::Serialize;
+use std::{
+    fs::OpenOptions,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// One line of output
+#[derive(Debug, Serialize)]
+struct Line<'a> {
+    /// CPU, epoch and all values
+    #[serde(flatten)]
+    stat: &'a CpuStat,
+    /// See [CpuStat::busy]
+    busy: f64,
+}
+
+impl<'a> Line<'a> {
+    /// The line of `stat`
+    fn new(stat: &'a CpuStat) -> Self {
+        Self {
+            stat,
+            busy: stat.busy(),
+        }
+    }
+}
+
+/// The sample `graphs`, one JSON object per line and graph, with the
+/// CPU, epoch, all values in ticks and how busy the CPU was in
+/// percent. Unlike munin, this always gets every field, whatever the
+/// rollup.
+pub(crate) fn encode(graphs: &[CpuStat]) -> Vec<u8> {
+    let mut out = vec![];
+    for stat in graphs {
+        out.extend(object(stat));
+        out.push(b'\n');
+    }
+    out
+}
+
+/// The object of a single graph, `stat`, as in [encode]
+pub(crate) fn object(stat: &CpuStat) -> Vec<u8> {
+    // Serializing plain numbers and strings into memory can not fail
+    serde_json::to_vec(&Line::new(stat)).unwrap_or_default()
+}
+
+/// The sample `graphs` as one JSON array, of the objects [encode]
+/// writes one per line
+pub(crate) fn encode_array(graphs: &[CpuStat]) -> Vec<u8> {
+    let lines: Vec<Line> = graphs.iter().map(Line::new).collect();
+    serde_json::to_vec(&lines).unwrap_or_default()
+}
+
+#[test]
+fn test_encode() {
+    use crate::CpuId;
+
+    let graphs = [
+        CpuStat {
+            cpu: CpuId::Core(2),
+            epoch: 1_650_000_000,
+            user: 30,
+            idle: 60,
+            iowait: 10,
+            ..Default::default()
+        },
+        CpuStat {
+            epoch: 1_650_000_000,
+            ..Default::default()
+        },
+    ];
+    assert_eq!(
+        "{\"cpu\":\"cpu2\",\"epoch\":1650000000,\"user\":30,\"nice\":0,\"system\":0,\"idle\":60,\"iowait\":10,\"irq\":0,\"softirq\":0,\"steal\":0,\"guest\":0,\"guest_nice\":0,\"busy\":30.0}\n\
+         {\"cpu\":\"total\",\"epoch\":1650000000,\"user\":0,\"nice\":0,\"system\":0,\"idle\":0,\"iowait\":0,\"irq\":0,\"softirq\":0,\"steal\":0,\"guest\":0,\"guest_nice\":0,\"busy\":0.0}\n",
+        String::from_utf8(encode(&graphs)).unwrap()
+    );
+    let array = String::from_utf8(encode_array(&graphs)).unwrap();
+    assert!(array.starts_with("[{\"cpu\":\"cpu2\","));
+    assert!(array.ends_with("\"busy\":0.0}]"));
+}
+
+/// The first line of a JSON lines output
+#[derive(Debug, Serialize)]
+struct Header {
+    /// See [SCHEMA]
+    schema: u32,
+    /// The keys of the objects after it, in their order
+    fields: Vec<&'static str>,
+}
+
+/// The first line of every file, and of stdout: the [SCHEMA] version
+/// and the fields of the objects after it, in their order
+pub(crate) fn header() -> Vec<u8> {
+    let mut fields = vec!["cpu", "epoch"];
+    fields.extend(Field::FINE.iter().map(Field::name));
+    fields.push("busy");
+    let header = Header {
+        schema: SCHEMA,
+        fields,
+    };
+    let mut out = serde_json::to_vec(&header).unwrap_or_default();
+    out.push(b'\n');
+    out
+}
+
+#[test]
+fn test_header() {
+    assert_eq!(
+        "{\"schema\":1,\"fields\":[\"cpu\",\"epoch\",\"user\",\"nice\",\"system\",\"idle\",\"iowait\",\"irq\",\"softirq\",\"steal\",\"guest\",\"guest_nice\",\"busy\"]}\n",
+        String::from_utf8(header()).unwrap()
+    );
+}
+
+/// Writes every sample as JSON lines, see [crate::Settings::json_path]
+#[derive(Debug)]
+pub(crate) struct JsonLines {
+    /// File we append to, None for stdout
+    path: Option<PathBuf>,
+    /// Did stdout get the [header] yet?
+    header: bool,
+}
+
+impl JsonLines {
+    /// Write to the file at `path`, or to stdout for `-`
+    pub(crate) fn new(path: &Path) -> Self {
+        Self {
+            path: (path != Path::new("-")).then(|| path.to_path_buf()),
+            header: false,
+        }
+    }
+
+    /// Write the sample `graphs`. The file gets opened for every
+    /// sample, so it can be rotated away underneath us. A new file
+    /// starts with the [header], stdout gets it once.
+    pub(crate) fn write(&mut self, graphs: &[CpuStat]) {
+        let mut block = encode(graphs);
+        let result = match &self.path {
+            Some(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| {
+                    if file.metadata()?.len() == 0 {
+                        block.splice(0..0, header());
+                    }
+                    output::write_block(&mut file, &block)
+                }),
+            None => {
+                if !self.header {
+                    block.splice(0..0, header());
+                    self.header = true;
+                }
+                output::write_block(&mut io::stdout().lock(), &block)
+            }
+        };
+        if let Err(e) = result {
+            warn!("Could not write JSON sample: {e}");
+        }
+    }
+}
+
+impl Target for JsonLines {
+    fn deliver(&mut self, graphs: &[CpuStat]) {
+        self.write(graphs);
+    }
+}
+
+/// The [Target] for [Settings::json_path], if set
+pub(crate) fn target(settings: &Settings) -> Option<Box<dyn Target>> {
+    let path = settings.json_path.as_ref()?;
+    Some(Box::new(JsonLines::new(path)))
+}
+
+#[test]
+fn test_json_lines() {
+    let path = std::env::temp_dir().join(format!("cpu1sec-json-{}", std::process::id()));
+    let mut json = JsonLines::new(&path);
+    let stat = CpuStat {
+        epoch: 1,
+        user: 1,
+        idle: 3,
+        ..Default::default()
+    };
+    json.write(&[stat]);
+    json.write(&[stat]);
+    let content = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(3, content.lines().count());
+    // Once, at the start
+    assert!(content.starts_with("{\"schema\":1,\"fields\":[\"cpu\",\"epoch\","));
+    assert_eq!(1, content.matches("\"schema\":1").count());
+    assert!(content.ends_with("\"busy\":25.0}\n"));
+    std::fs::remove_file(&path).unwrap();
+    assert!(JsonLines::new(Path::new("-")).path.is_none());
+}
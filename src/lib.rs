@@ -0,0 +1,91 @@
+//! munin-cpu1sec - Collect CPU usage data for munin every second
+//!
+//! The plugin itself is [CpuPlugin], configured by [Settings]. The
+//! values it collects end up in [CpuStat]s, which can also be used on
+//! their own, e.g. to get the CPU time spent between two snapshots
+//! with [CpuStat::diff].
+// SPDX-License-Identifier:  GPL-3.0-only
+
+#![warn(missing_docs)]
+
+mod api;
+#[cfg(feature = "parquet")]
+mod archive;
+mod autoconf;
+pub mod binary;
+mod capabilities;
+mod cgroup;
+#[cfg(feature = "cgroup")]
+mod cgroups;
+mod cli;
+mod clock;
+mod collectd;
+mod collector;
+mod config_file;
+mod cpufreq;
+mod csv;
+mod ctxt;
+mod fanout;
+mod field;
+#[cfg(feature = "freq")]
+mod freq;
+mod graphite;
+#[cfg(feature = "sqlite")]
+mod history;
+mod hypervisor;
+mod influx;
+#[cfg(feature = "irq")]
+mod irq;
+mod json;
+#[cfg(feature = "load")]
+mod loadavg;
+mod mqtt;
+mod node;
+mod numa;
+mod otlp;
+mod output;
+mod plugin;
+mod procs;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+#[cfg(feature = "psi")]
+mod psi;
+mod replay;
+mod settings;
+pub mod shm;
+mod sink;
+mod sleep;
+mod socket;
+#[cfg(feature = "softirq")]
+mod softirq;
+mod source;
+mod spool;
+mod stat;
+mod statsd;
+mod stock;
+mod summary;
+#[cfg(feature = "temp")]
+mod temp;
+#[cfg(feature = "top")]
+mod top;
+mod watchdog;
+mod websocket;
+
+pub use autoconf::{autoconf, suggest, MAGIC_MARKERS};
+pub use capabilities::capabilities;
+pub use cli::{Cli, Command};
+pub use clock::Clock;
+pub use collector::Collector;
+pub use config_file::ConfigFile;
+pub use cpufreq::GroupBy;
+pub use csv::Rotate;
+pub use field::Field;
+#[cfg(feature = "sqlite")]
+pub use history::query;
+pub use output::{Endpoint, Format, LineEnding, Output};
+pub use plugin::{CpuPlugin, Retention};
+pub use replay::ReplaySource;
+pub use settings::{checkconfig, CpuSet, Settings, TopBy, MIN_INTERVAL};
+pub use sleep::SleepMode;
+pub use source::Source;
+pub use stat::{AggregateFn, Compat, CpuId, CpuStat, Resolution, Rollup, Rounding};
@@ -0,0 +1,161 @@
+//! Load average from /proc/loadavg, the `load1sec` graph of
+//! [crate::Collector::Load]
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::Settings;
+use anyhow::{anyhow, Result};
+use log::info;
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+/// Name of our graph
+const GRAPH: &str = "load1sec";
+
+/// The load averages of /proc/loadavg, and what is running right now
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub(crate) struct LoadAvg {
+    /// Over the last minute
+    pub(crate) load1: f64,
+    /// Over the last five minutes
+    pub(crate) load5: f64,
+    /// Over the last fifteen minutes
+    pub(crate) load15: f64,
+    /// Runnable tasks (processes and threads) right now
+    pub(crate) running: u64,
+}
+
+impl LoadAvg {
+    /// Parse the content of /proc/loadavg, e.g.
+    /// `0.20 0.18 0.12 1/80 11206`
+    pub(crate) fn parse(content: &str) -> Result<Self> {
+        let mut words = content.split_whitespace();
+        let mut next = |what| {
+            words
+                .next()
+                .ok_or_else(|| anyhow!("No {what} in loadavg {content:?}"))
+        };
+        let load1 = next("load1")?.parse()?;
+        let load5 = next("load5")?.parse()?;
+        let load15 = next("load15")?.parse()?;
+        let (running, _) = next("tasks")?
+            .split_once('/')
+            .ok_or_else(|| anyhow!("No running/total tasks in loadavg {content:?}"))?;
+        Ok(Self {
+            load1,
+            load5,
+            load15,
+            running: running.parse()?,
+        })
+    }
+
+    /// Read loadavg of `proc_root`
+    pub(crate) fn read(proc_root: &Path) -> Result<Self> {
+        Self::parse(&fs::read_to_string(proc_root.join("loadavg"))?)
+    }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(
+        LoadAvg {
+            load1: 0.2,
+            load5: 0.18,
+            load15: 12.5,
+            running: 3,
+        },
+        LoadAvg::parse("0.20 0.18 12.50 3/80 11206\n").unwrap()
+    );
+    assert!(LoadAvg::parse("0.20 0.18\n").is_err());
+    assert!(LoadAvg::parse("0.20 0.18 0.12 80 11206\n").is_err());
+    assert!(LoadAvg::parse("").is_err());
+}
+
+/// Write out the config of the `load1sec` graph
+pub(crate) fn config<W: Write>(handle: &mut BufWriter<W>, settings: &Settings) -> Result<()> {
+    writeln!(handle, "multigraph {GRAPH}")?;
+    writeln!(handle, "graph_title Load average (1sec)")?;
+    writeln!(handle, "graph_category {}", settings.graph_category)?;
+    writeln!(handle, "update_rate {}", settings.update_rate())?;
+    writeln!(
+        handle,
+        "graph_data_size {}",
+        settings.retention.graph_data_size()
+    )?;
+    writeln!(handle, "graph_args --base 1000 --lower-limit 0")?;
+    writeln!(handle, "graph_vlabel load")?;
+    writeln!(handle, "graph_scale no")?;
+    writeln!(
+        handle,
+        "graph_info The load averages of the kernel, and the tasks runnable at the time of the sample. The kernel updates its averages every 5 seconds."
+    )?;
+    for (field, label, info) in [
+        ("load1", "1 minute", "Load average over the last minute"),
+        ("load5", "5 minutes", "Load average over the last 5 minutes"),
+        (
+            "load15",
+            "15 minutes",
+            "Load average over the last 15 minutes",
+        ),
+        (
+            "running",
+            "running",
+            "Processes and threads runnable at the time of the sample",
+        ),
+    ] {
+        writeln!(handle, "{field}.label {label}")?;
+        writeln!(handle, "{field}.min 0")?;
+        writeln!(handle, "{field}.type GAUGE")?;
+        writeln!(handle, "{field}.info {info}")?;
+    }
+    Ok(())
+}
+
+/// Read loadavg of `proc_root` and write out the values of the
+/// `load1sec` graph for `epoch`. Unknown if it can not be read.
+pub(crate) fn write<W: Write>(
+    handle: &mut BufWriter<W>,
+    proc_root: &Path,
+    epoch: u64,
+) -> Result<()> {
+    writeln!(handle, "multigraph {GRAPH}")?;
+    match LoadAvg::read(proc_root) {
+        Ok(load) => {
+            writeln!(handle, "load1.value {epoch}:{:.2}", load.load1)?;
+            writeln!(handle, "load5.value {epoch}:{:.2}", load.load5)?;
+            writeln!(handle, "load15.value {epoch}:{:.2}", load.load15)?;
+            writeln!(handle, "running.value {epoch}:{}", load.running)?;
+        }
+        Err(e) => {
+            info!("Could not read the load average: {e}");
+            for field in ["load1", "load5", "load15", "running"] {
+                writeln!(handle, "{field}.value {epoch}:U")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_write() {
+    let root = std::env::temp_dir().join(format!("cpu1sec-loadavg-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let values = |root: &Path| {
+        let mut handle = BufWriter::new(Vec::new());
+        write(&mut handle, root, 7).unwrap();
+        String::from_utf8(handle.into_inner().unwrap()).unwrap()
+    };
+    assert!(values(&root).ends_with("load15.value 7:U\nrunning.value 7:U\n"));
+    fs::write(root.join("loadavg"), "1.50 0.75 0.25 4/312 4242\n").unwrap();
+    assert_eq!(
+        "multigraph load1sec\n\
+         load1.value 7:1.50\n\
+         load5.value 7:0.75\n\
+         load15.value 7:0.25\n\
+         running.value 7:4\n",
+        values(&root)
+    );
+    fs::remove_dir_all(&root).unwrap();
+}
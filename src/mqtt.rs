@@ -0,0 +1,327 @@
+//! MQTT publisher (`mqtt_url`), for fleets that collect everything
+//! through a broker
+//!
+//! Every graph of a sample is published as one JSON object, as in
+//! [crate::json], to `<mqtt_topic>/<cpu>`. Just enough of MQTT 3.1.1
+//! for that: connect, publish with QoS 0 or 1, disconnect. With QoS 1
+//! a sample the broker did not acknowledge is published again, marked
+//! as duplicate, once we are connected again. Samples taken while the
+//! broker is away are dropped.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{fanout::Target, json, output, CpuStat, Endpoint, Settings};
+use anyhow::{bail, Result};
+use log::{info, warn};
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+/// Default start of our topics, followed by the host name and
+/// [PLUGIN]
+const TOPIC: &str = "monitoring";
+
+/// Last part of the default topic
+const PLUGIN: &str = "cpu1sec";
+
+/// How long the broker may take to answer, or to take a message
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keep alive we ask the broker for, in seconds. We send far more
+/// often than that.
+const KEEP_ALIVE: u16 = 60;
+
+/// Longest wait between two connection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Our topics start with `monitoring/<host>/cpu1sec`
+pub(crate) fn default_topic() -> String {
+    let host = output::hostname().unwrap_or_else(|| String::from("localhost"));
+    format!("{TOPIC}/{host}/{PLUGIN}")
+}
+
+/// Append the MQTT string `s` to `out`
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    out.extend((s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// A packet of `kind` (the first byte, type and flags) with `body`
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    // Remaining length, 7 bits per byte, least significant first
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+/// CONNECT for `client_id`, with a clean session
+fn connect_packet(client_id: &str) -> Vec<u8> {
+    let mut body = vec![];
+    put_str(&mut body, "MQTT");
+    // Protocol level 4 is 3.1.1, flag 0x02 a clean session
+    body.extend([4, 0x02]);
+    body.extend(KEEP_ALIVE.to_be_bytes());
+    put_str(&mut body, client_id);
+    packet(0x10, &body)
+}
+
+/// PUBLISH of `payload` to `topic`, with QoS 1 if given an `id`
+fn publish_packet(topic: &str, id: Option<u16>, payload: &[u8]) -> Vec<u8> {
+    let mut body = vec![];
+    put_str(&mut body, topic);
+    if let Some(id) = id {
+        body.extend(id.to_be_bytes());
+    }
+    body.extend_from_slice(payload);
+    packet(if id.is_some() { 0x32 } else { 0x30 }, &body)
+}
+
+#[test]
+fn test_packets() {
+    assert_eq!(
+        b"\x10\x11\x00\x04MQTT\x04\x02\x00\x3c\x00\x05cpu1s".to_vec(),
+        connect_packet("cpu1s")
+    );
+    assert_eq!(
+        b"\x30\x06\x00\x01ax{}".to_vec(),
+        publish_packet("a", None, b"x{}")
+    );
+    assert_eq!(
+        b"\x32\x08\x00\x01a\x00\x07x{}".to_vec(),
+        publish_packet("a", Some(7), b"x{}")
+    );
+    // Two bytes of remaining length
+    let long = packet(0x30, &[0; 200]);
+    assert_eq!(&[0x30, 0xc8, 0x01], &long[..3]);
+    assert!(default_topic().starts_with("monitoring/"));
+    assert!(default_topic().ends_with("/cpu1sec"));
+}
+
+/// Publishes samples to an MQTT broker, see
+/// [crate::Settings::mqtt_url]
+#[derive(Debug)]
+pub(crate) struct Mqtt {
+    /// host:port of the broker
+    addr: String,
+    /// Start of our topics
+    topic: String,
+    /// Quality of service, 0 or 1
+    qos: u8,
+    /// Who we are to the broker
+    client_id: String,
+    /// The connection, if we have one
+    stream: Option<TcpStream>,
+    /// Id of the next QoS 1 message
+    next_id: u16,
+    /// QoS 1 messages of the last sample the broker did not
+    /// acknowledge yet
+    unacked: Vec<Vec<u8>>,
+    /// How long to wait after the next failed connection attempt
+    backoff: Duration,
+    /// When we may try to connect again
+    next_try: Instant,
+}
+
+impl Mqtt {
+    /// Publish to the broker at `endpoint` under `topic`, with `qos`
+    pub(crate) fn new(endpoint: &Endpoint, topic: String, qos: u8) -> Self {
+        let addr = match endpoint {
+            Endpoint::Udp(addr) | Endpoint::Tcp(addr) => addr.clone(),
+            Endpoint::Http { authority, .. } => authority.clone(),
+        };
+        let host = output::hostname().unwrap_or_else(|| String::from("localhost"));
+        Self {
+            addr,
+            topic,
+            qos,
+            client_id: format!("{PLUGIN}-{host}-{}", std::process::id()),
+            stream: None,
+            next_id: 1,
+            unacked: vec![],
+            backoff: Duration::from_secs(1),
+            next_try: Instant::now(),
+        }
+    }
+
+    /// Publish the sample `graphs`. Trouble is logged, the connection
+    /// dropped and tried again later.
+    pub(crate) fn send(&mut self, graphs: &[CpuStat]) {
+        if self.stream.is_none() && Instant::now() >= self.next_try {
+            match self.connect() {
+                Ok(stream) => {
+                    info!("Connected to MQTT broker {}", self.addr);
+                    self.stream = Some(stream);
+                    self.backoff = Duration::from_secs(1);
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not connect to MQTT broker {}: {e}, retrying in {:?}",
+                        self.addr, self.backoff
+                    );
+                    self.next_try = Instant::now() + self.backoff;
+                    self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+        if self.stream.is_none() {
+            return;
+        }
+        // Not acknowledged last time, so again, as duplicates
+        let mut messages: Vec<Vec<u8>> = self
+            .unacked
+            .drain(..)
+            .map(|mut message| {
+                message[0] |= 0x08;
+                message
+            })
+            .collect();
+        for stat in graphs {
+            let topic = format!("{}/{}", self.topic, stat.cpu);
+            let id = (self.qos == 1).then(|| {
+                let id = self.next_id;
+                // 0 is no valid id
+                self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+                id
+            });
+            messages.push(publish_packet(&topic, id, &json::object(stat)));
+        }
+        let Some(stream) = self.stream.as_mut() else {
+            return;
+        };
+        if let Err(e) = publish(stream, &messages, self.qos) {
+            warn!("Lost connection to MQTT broker {}: {e}", self.addr);
+            self.stream = None;
+            self.next_try = Instant::now() + self.backoff;
+            if self.qos == 1 {
+                self.unacked = messages;
+            }
+        }
+    }
+
+    /// Connect to the broker and say hello
+    fn connect(&self) -> Result<TcpStream> {
+        let Some(addr) = self.addr.to_socket_addrs()?.next() else {
+            bail!("no address found");
+        };
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        stream.write_all(&connect_packet(&self.client_id))?;
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack)?;
+        match connack {
+            [0x20, 2, _, 0] => Ok(stream),
+            [0x20, 2, _, code] => bail!("broker refused us with code {code}"),
+            _ => bail!("broker did not answer with CONNACK"),
+        }
+    }
+}
+
+impl Target for Mqtt {
+    fn deliver(&mut self, graphs: &[CpuStat]) {
+        self.send(graphs);
+    }
+}
+
+/// The [Target] for [Settings::mqtt_url], if set
+pub(crate) fn target(settings: &Settings) -> Option<Box<dyn Target>> {
+    let endpoint = settings.mqtt_url.as_ref()?;
+    let topic = settings.mqtt_topic.clone().unwrap_or_else(default_topic);
+    Some(Box::new(Mqtt::new(endpoint, topic, settings.mqtt_qos)))
+}
+
+/// Write `messages`, and with `qos` 1 wait for all their PUBACKs
+fn publish(stream: &mut TcpStream, messages: &[Vec<u8>], qos: u8) -> Result<()> {
+    for message in messages {
+        stream.write_all(message)?;
+    }
+    if qos == 1 {
+        for _ in messages {
+            let mut puback = [0; 4];
+            stream.read_exact(&mut puback)?;
+            if puback[..2] != [0x40, 2] {
+                bail!("broker did not answer with PUBACK");
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Drop for Mqtt {
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.as_mut() {
+            let _ = stream.write_all(&packet(0xe0, &[]));
+        }
+    }
+}
+
+#[test]
+fn test_mqtt() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = Endpoint::Tcp(listener.local_addr().unwrap().to_string());
+    // Type and body of the next packet from `client`
+    fn read_packet(client: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut byte = [0; 1];
+        client.read_exact(&mut byte).unwrap();
+        let kind = byte[0];
+        let (mut len, mut shift) = (0, 0);
+        loop {
+            client.read_exact(&mut byte).unwrap();
+            len |= usize::from(byte[0] & 0x7f) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; len];
+        client.read_exact(&mut body).unwrap();
+        (kind, body)
+    }
+    // A broker that wants QoS 1 messages acknowledged
+    let broker = std::thread::spawn(move || {
+        let (mut client, _) = listener.accept().unwrap();
+        let (kind, connect) = read_packet(&mut client);
+        assert_eq!(0x10, kind);
+        assert_eq!(b"\x00\x04MQTT\x04", &connect[..7]);
+        client.write_all(&[0x20, 2, 0, 0]).unwrap();
+        let mut published = vec![];
+        for _ in 0..2 {
+            let (kind, body) = read_packet(&mut client);
+            assert_eq!(0x32, kind);
+            let topic_len = usize::from(u16::from_be_bytes([body[0], body[1]]));
+            let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+            let id = [body[2 + topic_len], body[3 + topic_len]];
+            let payload = String::from_utf8(body[4 + topic_len..].to_vec()).unwrap();
+            client.write_all(&[0x40, 2, id[0], id[1]]).unwrap();
+            published.push((topic, payload));
+        }
+        published
+    });
+    let mut mqtt = Mqtt::new(&endpoint, String::from("monitoring/web01/cpu1sec"), 1);
+    let stat = |cpu| CpuStat {
+        cpu,
+        epoch: 3,
+        ..Default::default()
+    };
+    mqtt.send(&[stat(crate::CpuId::Core(0)), stat(crate::CpuId::Total)]);
+    assert!(mqtt.unacked.is_empty());
+    let published = broker.join().unwrap();
+    assert_eq!("monitoring/web01/cpu1sec/cpu0", published[0].0);
+    assert_eq!("monitoring/web01/cpu1sec/total", published[1].0);
+    assert!(published[1]
+        .1
+        .starts_with("{\"cpu\":\"total\",\"epoch\":3,"));
+}
@@ -0,0 +1,183 @@
+//! Just enough of munin-node (`output=node`), so a munin master can
+//! query the daemon directly on hosts without a munin-node
+//!
+//! Knows `list`, `nodes`, `config`, `fetch`, `cap`, `version` and
+//! `quit`. `fetch cpu1sec` hands out all samples since the last
+//! fetch, the way the spooled fetch does with a real munin-node.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{
+    output,
+    sink::{OutputSink, Sample},
+};
+use anyhow::Result;
+use log::warn;
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    thread,
+    time::Duration,
+};
+
+/// Name of the one plugin we have
+const PLUGIN: &str = "cpu1sec";
+
+/// How long a master may stay silent before we hang up on it
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What the master gets
+#[derive(Debug, Default)]
+struct Shared {
+    /// Our config, as `cpu1sec config` has it
+    config: Vec<u8>,
+    /// Samples since the last fetch, oldest first
+    pending: VecDeque<Vec<u8>>,
+    /// How many samples we keep until the master fetches them
+    capacity: usize,
+}
+
+/// Serves the samples to a munin master, from a thread of its own
+#[derive(Debug)]
+pub(crate) struct Node {
+    /// Where we listen
+    pub(crate) addr: SocketAddr,
+    /// Shared with the thread serving the master
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Node {
+    /// Listen on `addr` (host:port), keeping up to `capacity` samples
+    /// between two fetches. Has to happen after daemonizing, threads
+    /// do not survive the fork.
+    pub(crate) fn spawn(addr: &str, capacity: usize) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let shared = Arc::new(Mutex::new(Shared {
+            capacity,
+            ..Default::default()
+        }));
+        let master = Arc::clone(&shared);
+        let host = output::hostname().unwrap_or_else(|| String::from("localhost"));
+        thread::Builder::new()
+            .name(String::from("node"))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if let Err(e) = stream.and_then(|stream| serve(stream, &master, &host)) {
+                        warn!("Could not serve a munin master: {e}");
+                    }
+                }
+            })?;
+        Ok(Self { addr, shared })
+    }
+
+    /// Our shared state, whoever panicked with it
+    fn shared(&self) -> MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl OutputSink for Node {
+    fn write_config(&mut self, config: &[u8]) -> io::Result<()> {
+        self.shared().config = config.to_vec();
+        Ok(())
+    }
+
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        let mut shared = self.shared();
+        if shared.pending.len() >= shared.capacity {
+            shared.pending.pop_front();
+        }
+        shared.pending.push_back(sample.munin.to_vec());
+        Ok(())
+    }
+}
+
+/// Talk to one master, until it quits
+fn serve(stream: TcpStream, shared: &Mutex<Shared>, host: &str) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut out = &stream;
+    writeln!(out, "# munin node at {host}")?;
+    let mut lines = BufReader::new(&stream).lines();
+    while let Some(line) = lines.next().transpose()? {
+        let mut words = line.split_whitespace();
+        let (command, arg) = (words.next().unwrap_or_default(), words.next());
+        match (command, arg) {
+            ("list", _) => writeln!(out, "{PLUGIN}")?,
+            ("nodes", _) => write!(out, "{host}\n.\n")?,
+            ("cap", _) => writeln!(out, "cap multigraph")?,
+            ("version", _) => writeln!(
+                out,
+                "munins node on {host} version: {PLUGIN} {}",
+                env!("CARGO_PKG_VERSION")
+            )?,
+            ("config", Some(PLUGIN)) => {
+                let config = shared
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .config
+                    .clone();
+                out.write_all(&config)?;
+                writeln!(out, ".")?;
+            }
+            ("fetch", Some(PLUGIN)) => {
+                let pending: Vec<Vec<u8>> = shared
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .pending
+                    .drain(..)
+                    .collect();
+                for sample in pending {
+                    out.write_all(&sample)?;
+                }
+                writeln!(out, ".")?;
+            }
+            ("config" | "fetch", _) => write!(out, "# Unknown service\n.\n")?,
+            ("quit" | ".", _) => break,
+            _ => writeln!(
+                out,
+                "# Unknown command. Try cap, list, nodes, config, fetch, version or quit"
+            )?,
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_node() {
+    let mut node = Node::spawn("127.0.0.1:0", 2).unwrap();
+    node.write_config(b"graph_title CPU\n").unwrap();
+    for epoch in 1..=3 {
+        let munin = format!("total_user.value {epoch}:1\n");
+        let sample = Sample {
+            epoch,
+            munin: munin.as_bytes(),
+            graphs: None,
+        };
+        node.write_sample(&sample).unwrap();
+    }
+
+    let stream = TcpStream::connect(node.addr).unwrap();
+    let mut out = &stream;
+    let mut lines = BufReader::new(&stream).lines().map(Result::unwrap);
+    assert!(lines.next().unwrap().starts_with("# munin node at "));
+    let mut ask = |command: &str, answer: &[&str]| {
+        writeln!(out, "{command}").unwrap();
+        for expected in answer {
+            assert_eq!(*expected, lines.next().unwrap());
+        }
+    };
+    ask("cap multigraph", &["cap multigraph"]);
+    ask("list", &["cpu1sec"]);
+    ask("config cpu1sec", &["graph_title CPU", "."]);
+    // Only room for the last two
+    ask(
+        "fetch cpu1sec",
+        &["total_user.value 2:1", "total_user.value 3:1", "."],
+    );
+    ask("fetch cpu1sec", &["."]);
+    ask("fetch load", &["# Unknown service", "."]);
+    writeln!(out, "quit").unwrap();
+}
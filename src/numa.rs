@@ -0,0 +1,53 @@
+//! NUMA nodes, the groups of cores that share a memory controller
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::CpuSet;
+use std::{collections::BTreeMap, fs, path::Path};
+
+/// Where the kernel lists the NUMA nodes, relative to `/`
+const NODES: &str = "sys/devices/system/node";
+
+/// The NUMA nodes below `root`, usually `/`, by their number, with
+/// the cores in them (`nodeN/cpulist`). Empty without NUMA support,
+/// nodes we can not read and those without cores, e.g. memory-only
+/// ones, are left out.
+pub(crate) fn nodes(root: &Path) -> BTreeMap<u32, CpuSet> {
+    let Ok(dir) = fs::read_dir(root.join(NODES)) else {
+        return BTreeMap::new();
+    };
+    dir.filter_map(|entry| {
+        let entry = entry.ok()?;
+        let node = entry
+            .file_name()
+            .to_str()?
+            .strip_prefix("node")?
+            .parse()
+            .ok()?;
+        let cpus = fs::read_to_string(entry.path().join("cpulist")).ok()?;
+        Some((node, cpus.trim().parse().ok()?))
+    })
+    .collect()
+}
+
+#[test]
+fn test_nodes() {
+    let root = std::env::temp_dir().join(format!("cpu1sec-numa-{}", std::process::id()));
+    assert!(nodes(&root).is_empty());
+    for (node, cpus) in [
+        ("node0", "0-3,8-11\n"),
+        ("node1", "4-7,12-15\n"),
+        ("node2", "\n"),
+    ] {
+        fs::create_dir_all(root.join(NODES).join(node)).unwrap();
+        fs::write(root.join(NODES).join(node).join("cpulist"), cpus).unwrap();
+    }
+    fs::create_dir_all(root.join(NODES).join("power")).unwrap();
+    assert_eq!(
+        BTreeMap::from([
+            (0, "0-3,8-11".parse::<CpuSet>().unwrap()),
+            (1, "4-7,12-15".parse::<CpuSet>().unwrap())
+        ]),
+        nodes(&root)
+    );
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,168 @@
+//! OpenTelemetry metrics, pushed to an OTel collector over OTLP/HTTP
+//! with JSON encoding, next to munin
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{
+    fanout::Target,
+    output::{self, Sender},
+    CpuStat, Endpoint, Settings,
+};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+/// An attribute, the way OTLP writes them
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+/// The sample `graphs` as an OTLP ExportMetricsServiceRequest, with
+/// the same fields munin gets:
+///
+/// * `cpu1sec.time`, a delta sum of the time spent, in the unit of
+///   the munin graphs, over the last `interval`
+/// * `cpu1sec.utilization`, a gauge of the share of that time, from
+///   0 to 1
+///
+/// Both with `cpu` and `state` attributes, the resource is named by
+/// `host`.
+pub(crate) fn encode(graphs: &[CpuStat], interval: Duration, host: &str) -> Vec<u8> {
+    let mut time = vec![];
+    let mut utilization = vec![];
+    for stat in graphs {
+        let end = u128::from(stat.epoch) * 1_000_000_000;
+        let start = end.saturating_sub(interval.as_nanos());
+        let fields = stat.rollup.fields().iter();
+        for field in fields.filter(|field| stat.guest_fields || !field.is_guest()) {
+            let value = field.value(stat);
+            let attributes = [
+                attribute("cpu", &stat.cpu.to_string()),
+                attribute("state", field.name()),
+            ];
+            // 64 bit integers are strings in OTLP's JSON
+            time.push(json!({
+                "attributes": attributes,
+                "startTimeUnixNano": start.to_string(),
+                "timeUnixNano": end.to_string(),
+                "asInt": value.to_string(),
+            }));
+            utilization.push(json!({
+                "attributes": attributes,
+                "startTimeUnixNano": start.to_string(),
+                "timeUnixNano": end.to_string(),
+                "asDouble": stat.percent(value) / 100.0,
+            }));
+        }
+    }
+    let request = json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [
+                    attribute("service.name", "cpu1sec"),
+                    attribute("host.name", host),
+                ],
+            },
+            "scopeMetrics": [{
+                "scope": {
+                    "name": "cpu1sec",
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "metrics": [
+                    {
+                        "name": "cpu1sec.time",
+                        "description": "CPU time spent, in the unit of the munin graphs",
+                        "sum": {
+                            "dataPoints": time,
+                            // AGGREGATION_TEMPORALITY_DELTA
+                            "aggregationTemporality": 1,
+                            "isMonotonic": true,
+                        },
+                    },
+                    {
+                        "name": "cpu1sec.utilization",
+                        "description": "Share of the CPU time spent",
+                        "unit": "1",
+                        "gauge": { "dataPoints": utilization },
+                    },
+                ],
+            }],
+        }],
+    });
+    request.to_string().into_bytes()
+}
+
+#[test]
+fn test_encode() {
+    use crate::CpuId;
+
+    let graphs = [CpuStat {
+        cpu: CpuId::Core(0),
+        epoch: 1_650_000_000,
+        user: 25,
+        idle: 75,
+        guest_fields: false,
+        ..Default::default()
+    }];
+    let out = encode(&graphs, Duration::from_secs(1), "web01");
+    let request: Value = serde_json::from_slice(&out).unwrap();
+    let resource = &request["resourceMetrics"][0];
+    assert_eq!(
+        "web01",
+        resource["resource"]["attributes"][1]["value"]["stringValue"]
+    );
+    let metrics = &resource["scopeMetrics"][0]["metrics"];
+    let time = &metrics[0]["sum"]["dataPoints"];
+    assert_eq!(8, time.as_array().unwrap().len());
+    assert_eq!("cpu0", time[0]["attributes"][0]["value"]["stringValue"]);
+    assert_eq!("user", time[0]["attributes"][1]["value"]["stringValue"]);
+    assert_eq!("25", time[0]["asInt"]);
+    assert_eq!("1649999999000000000", time[0]["startTimeUnixNano"]);
+    assert_eq!("1650000000000000000", time[0]["timeUnixNano"]);
+    let utilization = &metrics[1]["gauge"]["dataPoints"];
+    assert_eq!(0.25, utilization[0]["asDouble"]);
+    assert_eq!(
+        "idle",
+        utilization[3]["attributes"][1]["value"]["stringValue"]
+    );
+    assert_eq!(0.75, utilization[3]["asDouble"]);
+}
+
+/// Pushes samples to an OTel collector, see
+/// [crate::Settings::otlp_url]
+#[derive(Debug)]
+pub(crate) struct Otlp {
+    /// Where they go
+    sender: Sender,
+    /// Time between two samples
+    interval: Duration,
+    /// Name of our host, for the resource
+    host: String,
+}
+
+impl Otlp {
+    /// Push to `endpoint`, one request per sample, taken every
+    /// `interval`
+    pub(crate) fn new(endpoint: &Endpoint, interval: Duration) -> Self {
+        Self {
+            sender: endpoint.sender(0).content_type("application/json"),
+            interval,
+            host: output::hostname().unwrap_or_else(|| String::from("localhost")),
+        }
+    }
+
+    /// Push the sample `graphs`
+    pub(crate) fn send(&mut self, graphs: &[CpuStat]) {
+        self.sender.send(encode(graphs, self.interval, &self.host));
+    }
+}
+
+impl Target for Otlp {
+    fn deliver(&mut self, graphs: &[CpuStat]) {
+        self.send(graphs);
+    }
+}
+
+/// The [Target] for [Settings::otlp_url], if set
+pub(crate) fn target(settings: &Settings) -> Option<Box<dyn Target>> {
+    let endpoint = settings.otlp_url.as_ref()?;
+    Some(Box::new(Otlp::new(endpoint, settings.interval)))
+}
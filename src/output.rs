@@ -0,0 +1,766 @@
+//! Where the daemon sends its samples
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// Where the daemon writes its samples to
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Output {
+    /// The file munin's fetch picks them up from
+    #[default]
+    Munin,
+    /// Stream them over a TCP connection, see
+    /// [crate::Settings::tcp_addr]
+    Tcp,
+    /// Write them to stdout, flushed every second, e.g. for `docker
+    /// logs` when running as a sidecar. The config comes first, there
+    /// is no munin to ask for it. The daemon then stays in the
+    /// foreground.
+    Stdout,
+    /// munin-async's spool directory, see
+    /// [crate::Settings::spool_dir]
+    Spool,
+    /// Our own little munin-node, see [crate::Settings::node_addr]
+    Node,
+}
+
+impl Output {
+    /// All known outputs
+    pub const ALL: [Output; 5] = [
+        Output::Munin,
+        Output::Tcp,
+        Output::Stdout,
+        Output::Spool,
+        Output::Node,
+    ];
+
+    /// Name used for this output in the `output` variable
+    pub fn name(&self) -> &'static str {
+        match self {
+            Output::Munin => "munin",
+            Output::Tcp => "tcp",
+            Output::Stdout => "stdout",
+            Output::Spool => "spool",
+            Output::Node => "node",
+        }
+    }
+}
+
+impl FromStr for Output {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Output::ALL
+            .into_iter()
+            .find(|o| o.name() == s)
+            .ok_or_else(|| anyhow::anyhow!("Unknown output {s}"))
+    }
+}
+
+/// Format of the samples sent with [Output::Tcp]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Format {
+    /// The same text munin gets
+    #[default]
+    Munin,
+    /// Only the CPU values, framed as described in [crate::binary]
+    Binary,
+    /// PUTVAL lines for collectd's Exec plugin, in percent
+    Collectd,
+    /// JSON lines, one object per CPU graph, as json_path writes
+    /// them. Every connection starts with their schema header.
+    Json,
+}
+
+impl Format {
+    /// All known formats
+    pub const ALL: [Format; 4] = [
+        Format::Munin,
+        Format::Binary,
+        Format::Collectd,
+        Format::Json,
+    ];
+
+    /// Name used for this format in the `format` variable
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Munin => "munin",
+            Format::Binary => "binary",
+            Format::Collectd => "collectd",
+            Format::Json => "json",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Format::ALL
+            .into_iter()
+            .find(|f| f.name() == s)
+            .ok_or_else(|| anyhow::anyhow!("Unknown format {s}"))
+    }
+}
+
+/// Line terminator of the daemon's output
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum LineEnding {
+    /// `\n`, what munin wants
+    #[default]
+    Lf,
+    /// `\r\n`, for consumers of the TCP output on Windows
+    Crlf,
+}
+
+impl FromStr for LineEnding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lf" => Ok(LineEnding::Lf),
+            "crlf" => Ok(LineEnding::Crlf),
+            _ => Err(anyhow::anyhow!("Unknown line ending {s}")),
+        }
+    }
+}
+
+/// Writer that ends lines with the given [LineEnding]. Everything
+/// is written with `\n`, which gets replaced on the way through.
+#[derive(Debug)]
+pub(crate) struct LineEndingWriter<W> {
+    /// Where it all ends up
+    inner: W,
+    /// What to end lines with
+    ending: LineEnding,
+}
+
+impl<W: Write> LineEndingWriter<W> {
+    /// Write to `inner`, with lines ending in `ending`
+    pub(crate) fn new(inner: W, ending: LineEnding) -> Self {
+        Self { inner, ending }
+    }
+
+    /// Get the inner writer back
+    pub(crate) fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for LineEndingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.ending {
+            LineEnding::Lf => self.inner.write(buf),
+            LineEnding::Crlf => {
+                for line in buf.split_inclusive(|b| *b == b'\n') {
+                    match line.strip_suffix(b"\n") {
+                        Some(line) => {
+                            self.inner.write_all(line)?;
+                            self.inner.write_all(b"\r\n")?;
+                        }
+                        None => self.inner.write_all(line)?,
+                    }
+                }
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn test_line_ending() {
+    use std::io::BufWriter;
+
+    let mut handle = BufWriter::new(LineEndingWriter::new(Vec::new(), LineEnding::Crlf));
+    writeln!(handle, "total_user.value 1:42").unwrap();
+    write!(handle, "total_nice.value 1:").unwrap();
+    writeln!(handle, "0\nmultigraph cpu1sec_steal").unwrap();
+    let out = handle.into_inner().unwrap().into_inner();
+    assert_eq!(
+        b"total_user.value 1:42\r\ntotal_nice.value 1:0\r\nmultigraph cpu1sec_steal\r\n".as_slice(),
+        out
+    );
+
+    let mut handle = BufWriter::new(LineEndingWriter::new(Vec::new(), LineEnding::Lf));
+    writeln!(handle, "total_user.value 1:42").unwrap();
+    let out = handle.into_inner().unwrap().into_inner();
+    assert_eq!(b"total_user.value 1:42\n".as_slice(), out);
+}
+
+/// Somewhere samples get written to, in one piece. If writing a
+/// sample fails halfway, what made it out is taken back, see
+/// [write_block].
+pub(crate) trait Sink: Write {
+    /// Where we are, before writing a sample
+    fn mark(&mut self) -> io::Result<u64>;
+    /// Drop everything written after `mark`
+    fn rollback(&mut self, mark: u64) -> io::Result<()>;
+}
+
+/// The file munin's fetch reads, opened for appending
+impl Sink for File {
+    fn mark(&mut self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn rollback(&mut self, mark: u64) -> io::Result<()> {
+        self.set_len(mark)
+    }
+}
+
+impl Sink for Vec<u8> {
+    fn mark(&mut self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+
+    fn rollback(&mut self, mark: u64) -> io::Result<()> {
+        self.truncate(mark as usize);
+        Ok(())
+    }
+}
+
+/// Whatever reads our stdout already got what made it out, there is
+/// nothing to take back
+impl Sink for io::StdoutLock<'_> {
+    fn mark(&mut self) -> io::Result<u64> {
+        Ok(0)
+    }
+
+    fn rollback(&mut self, _mark: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: Sink> Sink for &mut S {
+    fn mark(&mut self) -> io::Result<u64> {
+        (**self).mark()
+    }
+
+    fn rollback(&mut self, mark: u64) -> io::Result<()> {
+        (**self).rollback(mark)
+    }
+}
+
+/// Write the complete sample `block` to `out`. Should that fail
+/// (disk full, ...), the part that got written is rolled back, so
+/// the reader never sees half a sample.
+pub(crate) fn write_block<S: Sink>(out: &mut S, block: &[u8]) -> io::Result<()> {
+    let mark = out.mark()?;
+    let result = out.write_all(block).and_then(|()| out.flush());
+    if result.is_err() {
+        if let Err(e) = out.rollback(mark) {
+            warn!("Could not roll back a partly written sample: {e}");
+        }
+    }
+    result
+}
+
+#[test]
+fn test_write_block() {
+    /// Takes 10 bytes per write, and fails the 5th write
+    struct Flaky {
+        written: Vec<u8>,
+        writes: usize,
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            if self.writes == 5 {
+                return Err(io::Error::other("disk full"));
+            }
+            let len = buf.len().min(10);
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Sink for Flaky {
+        fn mark(&mut self) -> io::Result<u64> {
+            self.written.mark()
+        }
+
+        fn rollback(&mut self, mark: u64) -> io::Result<()> {
+            self.written.rollback(mark)
+        }
+    }
+
+    let mut out = Flaky {
+        written: vec![],
+        writes: 0,
+    };
+    write_block(&mut out, b"total_user.value 1:42\n").unwrap();
+    assert_eq!(b"total_user.value 1:42\n".as_slice(), out.written);
+    // Fails on the 5th write, after 10 more bytes made it out
+    let block = b"total_user.value 2:23\ntotal_nice.value 2:0\n";
+    assert!(write_block(&mut out, block).is_err());
+    assert_eq!(b"total_user.value 1:42\n".as_slice(), out.written);
+    write_block(&mut out, block).unwrap();
+    assert_eq!(
+        b"total_user.value 1:42\ntotal_user.value 2:23\ntotal_nice.value 2:0\n".as_slice(),
+        out.written
+    );
+}
+
+/// Longest wait between two connection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Streams samples over a persistent TCP connection. Samples are
+/// buffered while the connection is down, up to a limit, and sent
+/// once we are connected again.
+pub(crate) struct TcpOutput {
+    /// host:port to connect to
+    pub(crate) addr: String,
+    /// The connection, if we have one
+    stream: Option<TcpStream>,
+    /// Samples not yet sent, oldest first
+    buffer: VecDeque<Vec<u8>>,
+    /// How many samples we keep in buffer
+    capacity: usize,
+    /// Samples dropped because the buffer was full, since we last
+    /// said so
+    dropped: u64,
+    /// How long to wait after the next failed connection attempt
+    backoff: Duration,
+    /// When we may try to connect again
+    next_try: Instant,
+    /// Sent first on every new connection, like a schema header.
+    /// Empty for none.
+    pub(crate) greeting: Vec<u8>,
+}
+
+impl TcpOutput {
+    /// Stream to `addr`, keeping up to `capacity` samples while not
+    /// connected
+    pub(crate) fn new(addr: String, capacity: usize) -> Self {
+        Self {
+            addr,
+            stream: None,
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+            dropped: 0,
+            backoff: Duration::from_secs(1),
+            next_try: Instant::now(),
+            greeting: vec![],
+        }
+    }
+
+    /// Queue a sample and send out everything we have. Never fails,
+    /// network trouble only means the sample waits in the buffer.
+    pub(crate) fn send(&mut self, sample: Vec<u8>) {
+        if self.buffer.len() >= self.capacity {
+            self.buffer.pop_front();
+            self.dropped += 1;
+        }
+        self.buffer.push_back(sample);
+        if self.stream.is_none() && Instant::now() >= self.next_try {
+            self.connect();
+        }
+        while let (Some(stream), Some(sample)) = (self.stream.as_mut(), self.buffer.front()) {
+            match stream.write_all(sample) {
+                Ok(()) => {
+                    self.buffer.pop_front();
+                }
+                Err(e) => {
+                    warn!("Lost connection to {}: {e}", self.addr);
+                    self.stream = None;
+                    self.next_try = Instant::now() + self.backoff;
+                }
+            }
+        }
+    }
+
+    /// Try to (re)connect, backing off further on failure
+    fn connect(&mut self) {
+        let stream = self.addr.to_socket_addrs().and_then(|mut addrs| {
+            let addr = addrs.next().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, "no address found")
+            })?;
+            let stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+            // A collector that stops reading must not block us forever
+            stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+            (&stream).write_all(&self.greeting)?;
+            Ok(stream)
+        });
+        match stream {
+            Ok(stream) => {
+                info!("Connected to {}", self.addr);
+                if self.dropped > 0 {
+                    warn!(
+                        "Dropped {} samples while not connected to {}",
+                        self.dropped, self.addr
+                    );
+                    self.dropped = 0;
+                }
+                self.stream = Some(stream);
+                self.backoff = Duration::from_secs(1);
+            }
+            Err(e) => {
+                warn!(
+                    "Could not connect to {}: {e}, retrying in {:?}",
+                    self.addr, self.backoff
+                );
+                self.next_try = Instant::now() + self.backoff;
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_tcp_output() {
+    use crate::{
+        json,
+        sink::{self, Sample},
+        CpuStat, Settings,
+    };
+    use std::{
+        io::{BufRead, BufReader, Read},
+        net::TcpListener,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut tcp = TcpOutput::new(addr.to_string(), 3);
+    tcp.send(b"1\n".to_vec());
+    tcp.send(b"2\n".to_vec());
+    let (mut conn, _) = listener.accept().unwrap();
+    let mut received = [0; 4];
+    conn.read_exact(&mut received).unwrap();
+    assert_eq!(b"1\n2\n", &received);
+
+    // Collector goes away, we buffer and drop the oldest
+    drop(conn);
+    drop(listener);
+    // Writing to a closed connection only fails a little later, so
+    // pretend we noticed already
+    tcp.stream = None;
+    for sample in ["3\n", "4\n", "5\n", "6\n"] {
+        tcp.send(sample.as_bytes().to_vec());
+    }
+    assert_eq!(3, tcp.buffer.len());
+    assert_eq!(1, tcp.dropped);
+
+    // And comes back, we reconnect and send what we kept
+    let listener = TcpListener::bind(addr).unwrap();
+    tcp.next_try = Instant::now();
+    tcp.send(b"7\n".to_vec());
+    let (mut conn, _) = listener.accept().unwrap();
+    let mut received = [0; 6];
+    conn.read_exact(&mut received).unwrap();
+    assert_eq!(b"5\n6\n7\n", &received);
+    assert_eq!(0, tcp.dropped);
+    assert!(tcp.buffer.is_empty());
+
+    // JSON lines, the schema header first
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let settings = Settings {
+        output: Output::Tcp,
+        tcp_addr: Some(listener.local_addr().unwrap().to_string()),
+        format: Format::Json,
+        ..Default::default()
+    };
+    let mut sink = sink::open(&settings, std::path::PathBuf::new()).unwrap();
+    let graphs = [CpuStat {
+        epoch: 8,
+        user: 42,
+        ..Default::default()
+    }];
+    let sample = Sample {
+        epoch: 8,
+        munin: b"total_user.value 8:42\n",
+        graphs: Some(&graphs),
+    };
+    sink.write_sample(&sample).unwrap();
+    let (conn, _) = listener.accept().unwrap();
+    let mut lines = BufReader::new(conn).lines();
+    let header = lines.next().unwrap().unwrap();
+    assert_eq!(json::header(), format!("{header}\n").into_bytes());
+    assert_eq!(
+        json::encode(&graphs),
+        format!("{}\n", lines.next().unwrap().unwrap()).into_bytes()
+    );
+}
+
+/// Somewhere on the network samples get pushed to, written as an
+/// URL: `udp://host:port`, `tcp://host:port` or
+/// `http://host:port/path`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Endpoint {
+    /// One datagram per sample, whatever happens to it
+    Udp(String),
+    /// A persistent connection, samples get buffered while it is
+    /// down, see [crate::Settings::tcp_buffer]
+    Tcp(String),
+    /// One POST request per sample
+    Http {
+        /// host:port to connect to
+        authority: String,
+        /// Path and query of the request
+        path: String,
+    },
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (scheme, rest) = s
+            .split_once("://")
+            .ok_or_else(|| anyhow!("{s} is no URL, expected e.g. udp://host:port"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        if authority.rsplit_once(':').is_none() {
+            return Err(anyhow!("{s} has no port"));
+        }
+        let authority = authority.to_string();
+        match scheme {
+            "udp" => Ok(Endpoint::Udp(authority)),
+            "tcp" => Ok(Endpoint::Tcp(authority)),
+            "http" => Ok(Endpoint::Http {
+                authority,
+                path: path.to_string(),
+            }),
+            _ => Err(anyhow!("Unknown scheme {scheme} in {s}")),
+        }
+    }
+}
+
+impl Endpoint {
+    /// Get ready to send to the endpoint, keeping up to `buffer`
+    /// samples while a TCP connection is down. Nothing gets
+    /// connected yet.
+    pub(crate) fn sender(&self, buffer: usize) -> Sender {
+        match self {
+            Endpoint::Udp(addr) => Sender::Udp {
+                addr: addr.clone(),
+                socket: None,
+            },
+            Endpoint::Tcp(addr) => Sender::Tcp(TcpOutput::new(addr.clone(), buffer)),
+            Endpoint::Http { authority, path } => Sender::Http {
+                authority: authority.clone(),
+                path: path.clone(),
+                content_type: "text/plain",
+                failing: false,
+            },
+        }
+    }
+}
+
+#[test]
+fn test_endpoint() {
+    assert_eq!(
+        Ok(Endpoint::Udp(String::from("localhost:8089"))),
+        "udp://localhost:8089".parse().map_err(|_| ())
+    );
+    assert_eq!(
+        Ok(Endpoint::Tcp(String::from("[::1]:2003"))),
+        "tcp://[::1]:2003".parse().map_err(|_| ())
+    );
+    assert_eq!(
+        Ok(Endpoint::Http {
+            authority: String::from("influx:8086"),
+            path: String::from("/write?db=cpu1sec&precision=s"),
+        }),
+        "http://influx:8086/write?db=cpu1sec&precision=s"
+            .parse()
+            .map_err(|_| ())
+    );
+    assert!("influx:8086".parse::<Endpoint>().is_err());
+    assert!("udp://influx".parse::<Endpoint>().is_err());
+    assert!("https://influx:8086/write".parse::<Endpoint>().is_err());
+}
+
+/// Sends samples to an [Endpoint]. Never fails, trouble on the
+/// network gets logged and the samples dropped (or buffered, with
+/// TCP).
+pub(crate) enum Sender {
+    /// See [Endpoint::Udp]
+    Udp {
+        /// host:port to send to
+        addr: String,
+        /// Our socket and the address it resolved to, once we have
+        /// them
+        socket: Option<(UdpSocket, SocketAddr)>,
+    },
+    /// See [Endpoint::Tcp]
+    Tcp(TcpOutput),
+    /// See [Endpoint::Http]
+    Http {
+        /// host:port to connect to
+        authority: String,
+        /// Path and query of the request
+        path: String,
+        /// What we send, text/plain unless told otherwise
+        content_type: &'static str,
+        /// Did the last request fail? We only complain once.
+        failing: bool,
+    },
+}
+
+impl std::fmt::Debug for Sender {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Sender::Udp { addr, .. } => write!(f, "Sender(udp://{addr})"),
+            Sender::Tcp(tcp) => write!(f, "Sender(tcp://{})", tcp.addr),
+            Sender::Http {
+                authority, path, ..
+            } => write!(f, "Sender(http://{authority}{path})"),
+        }
+    }
+}
+
+impl Sender {
+    /// Tell an HTTP endpoint the samples are `content_type`
+    pub(crate) fn content_type(mut self, content_type: &'static str) -> Self {
+        if let Sender::Http {
+            content_type: ours, ..
+        } = &mut self
+        {
+            *ours = content_type;
+        }
+        self
+    }
+
+    /// Send one sample
+    pub(crate) fn send(&mut self, sample: Vec<u8>) {
+        match self {
+            Sender::Udp { addr, socket } => {
+                if socket.is_none() {
+                    match udp_socket(addr) {
+                        Ok(bound) => *socket = Some(bound),
+                        Err(e) => warn!("Could not resolve {addr}: {e}"),
+                    }
+                }
+                if let Some((socket, target)) = socket {
+                    if let Err(e) = socket.send_to(&sample, *target) {
+                        warn!("Could not send to {addr}: {e}");
+                    }
+                }
+            }
+            Sender::Tcp(tcp) => tcp.send(sample),
+            Sender::Http {
+                authority,
+                path,
+                content_type,
+                failing,
+            } => match http_post(authority, path, content_type, &sample) {
+                Ok(()) if *failing => {
+                    info!("Sending to http://{authority}{path} works again");
+                    *failing = false;
+                }
+                Ok(()) => {}
+                Err(e) if !*failing => {
+                    warn!("Dropping samples, could not send to http://{authority}{path}: {e}");
+                    *failing = true;
+                }
+                Err(_) => {}
+            },
+        }
+    }
+}
+
+/// Name of this host, for outputs that get samples of many hosts
+pub(crate) fn hostname() -> Option<String> {
+    let host = fs::read_to_string("/proc/sys/kernel/hostname").ok()?;
+    Some(host.trim().to_string()).filter(|host| !host.is_empty())
+}
+
+/// A socket to send to `addr` from, and the address it resolved to
+fn udp_socket(addr: &str) -> io::Result<(UdpSocket, SocketAddr)> {
+    let target = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found"))?;
+    let local = match target {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    Ok((UdpSocket::bind(local)?, target))
+}
+
+/// POST `body` of `content_type` to `path` on `authority`, and check
+/// we got a 2xx answer
+fn http_post(authority: &str, path: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    let addr = authority
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut request = format!(
+        "POST {path} HTTP/1.1\r\n\
+         Host: {authority}\r\n\
+         Content-Type: {content_type}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(body);
+    stream.write_all(&request)?;
+    let mut status = String::new();
+    BufReader::new(&stream).read_line(&mut status)?;
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("got {:?}", status.trim_end()))),
+    }
+}
+
+#[test]
+fn test_sender() {
+    use std::{io::Read, net::TcpListener, thread};
+
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let mut udp = Endpoint::Udp(server.local_addr().unwrap().to_string()).sender(1);
+    udp.send(b"cpu1sec user=1i\n".to_vec());
+    let mut received = [0; 64];
+    let len = server.recv(&mut received).unwrap();
+    assert_eq!(b"cpu1sec user=1i\n", &received[..len]);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut http = Endpoint::Http {
+        authority: listener.local_addr().unwrap().to_string(),
+        path: String::from("/write?db=cpu1sec"),
+    }
+    .sender(1);
+    let server = thread::spawn(move || {
+        let mut requests = vec![];
+        for answer in ["204 No Content", "500 Internal Server Error"] {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut request = [0; 512];
+            let len = conn.read(&mut request).unwrap();
+            requests.push(String::from_utf8_lossy(&request[..len]).to_string());
+            write!(conn, "HTTP/1.1 {answer}\r\n\r\n").unwrap();
+        }
+        requests
+    });
+    http.send(b"cpu1sec user=1i\n".to_vec());
+    assert!(matches!(http, Sender::Http { failing: false, .. }));
+    http.send(b"cpu1sec user=2i\n".to_vec());
+    assert!(matches!(http, Sender::Http { failing: true, .. }));
+    let requests = server.join().unwrap();
+    assert!(requests[0].starts_with("POST /write?db=cpu1sec HTTP/1.1\r\n"));
+    assert!(requests[0].contains("Content-Type: text/plain\r\n"));
+    assert!(requests[0].ends_with("\r\n\r\ncpu1sec user=1i\n"));
+}
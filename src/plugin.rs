@@ -0,0 +1,2709 @@
+//! The munin plugin itself
+// SPDX-License-Identifier:  GPL-3.0-only
+
+#[cfg(feature = "cgroup")]
+use crate::cgroups::{Cgroups, CGROUP_ROOT};
+#[cfg(feature = "freq")]
+use crate::freq::Frequencies;
+#[cfg(feature = "irq")]
+use crate::irq::Interrupts;
+#[cfg(feature = "load")]
+use crate::loadavg;
+#[cfg(feature = "psi")]
+use crate::psi::Psi;
+#[cfg(feature = "softirq")]
+use crate::softirq::SoftirqCollector;
+#[cfg(feature = "temp")]
+use crate::temp::Temperatures;
+#[cfg(feature = "top")]
+use crate::top::TopProcesses;
+use crate::{
+    cgroup::{CgroupCpuTime, CGROUP_CPU_STAT},
+    clock::EpochClock,
+    cpufreq,
+    ctxt::{Activity, Counter},
+    fanout::{self, FanOut},
+    hypervisor, numa,
+    output::LineEndingWriter,
+    procs,
+    sink::{self, OutputSink, Sample},
+    stat::{self, cpu_stat_to_value, Unknown},
+    stock,
+    summary::{self, Summary},
+    watchdog::Watchdog,
+    AggregateFn, Collector, Compat, CpuId, CpuSet, CpuStat, Field, GroupBy, Output, Resolution,
+    Rollup, Settings, Source,
+};
+use anyhow::{Context, Result};
+use daemonize::Daemonize;
+use log::{error, info, warn};
+use munin_plugin::{Config, MuninPlugin};
+use procfs::KernelStats;
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    process,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Read all of /proc/stat (or whatever `reader` is). A single read
+/// may return only part of it, so we keep reading until EOF, or the
+/// parse would see a truncated file.
+fn read_stat<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// The numbers of the per-core lines in the /proc/stat `content`,
+/// in order. With CPUs offline they have gaps, e.g. cpu0, cpu2, cpu5.
+fn core_ids(content: &str) -> Vec<u32> {
+    content
+        .lines()
+        .filter_map(|line| {
+            line.split_whitespace()
+                .next()?
+                .strip_prefix("cpu")?
+                .parse()
+                .ok()
+        })
+        .collect()
+}
+
+/// Lines [KernelStats] insists on, though we do not need them, with
+/// what we fill in if /proc/stat lacks them, as in some minimal
+/// containers. A btime of 0 never changes, then reboots are only
+/// noticed by the counters going backwards.
+const FILLERS: [(&str, &str); 3] = [
+    ("ctxt ", "ctxt 0\n"),
+    ("btime ", "btime 0\n"),
+    ("processes ", "processes 0\n"),
+];
+
+/// Parse the /proc/stat `content`, making do with what there is.
+/// Missing lines we do not need are filled in, see [FILLERS]. If
+/// only per-core lines are broken, we still want the total, so we
+/// try again without them. Returns the numbers of the cores along
+/// (see [core_ids]), and why we dropped the per-core lines, if we
+/// did.
+fn parse_lenient(content: &str) -> Result<(KernelStats, Vec<u32>, Option<anyhow::Error>)> {
+    let mut content = Cow::Borrowed(content);
+    for (key, filler) in FILLERS {
+        if !content.lines().any(|line| line.starts_with(key)) {
+            let content = content.to_mut();
+            if !content.is_empty() && !content.ends_with('\n') {
+                content.push('\n');
+            }
+            content.push_str(filler);
+        }
+    }
+    match KernelStats::from_reader(content.as_bytes()) {
+        Ok(ks) => Ok((ks, core_ids(&content), None)),
+        Err(e) => {
+            let total_only: String = content
+                .lines()
+                .filter(|l| l.starts_with("cpu ") || !l.starts_with("cpu"))
+                .flat_map(|l| [l, "\n"])
+                .collect();
+            let ks = KernelStats::from_reader(total_only.as_bytes())?;
+            Ok((ks, vec![], Some(e.into())))
+        }
+    }
+}
+
+#[test]
+fn test_core_ids() {
+    let content = "cpu  30 0 30 300 0 0 0 0 0 0\n\
+                   cpu0 10 0 10 100 0 0 0 0 0 0\n\
+                   cpu2 10 0 10 100 0 0 0 0 0 0\n\
+                   cpu5 10 0 10 100 0 0 0 0 0 0\n\
+                   ctxt 1\nbtime 1000\nprocesses 1\n";
+    assert_eq!(vec![0, 2, 5], core_ids(content));
+}
+
+#[test]
+fn test_read_stat_chunks() {
+    /// Hands out its data in chunks of at most `chunk` bytes
+    struct Chunked<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+    impl Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.chunk.min(buf.len()).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    let stat = "cpu  20 0 20 200 0 0 0 0 0 0\n\
+                cpu0 10 0 10 100 0 0 0 0 0 0\n\
+                cpu1 10 0 10 100 0 0 0 0 0 0\n\
+                ctxt 1\nbtime 1000\nprocesses 1\n";
+    let content = read_stat(Chunked {
+        data: stat.as_bytes(),
+        chunk: stat.len() / 2 + 1,
+    })
+    .unwrap();
+    assert_eq!(stat, content);
+    let ks = KernelStats::from_reader(content.as_bytes()).unwrap();
+    assert_eq!(2, ks.cpu_time.len());
+    assert_eq!(Some(0), ks.cpu_time[1].guest_nice);
+}
+
+/// The collectors of the counters in /proc/stat, with their counter
+const COUNTERS: [(Collector, Counter); 3] = [
+    (Collector::Ctxt, Counter::Ctxt),
+    (Collector::Intr, Counter::Intr),
+    (Collector::Forks, Counter::Forks),
+];
+
+/// How often we try to read /proc/stat when starting up
+const START_TRIES: u32 = 5;
+
+/// Call `read` of `path` up to `tries` times, until it succeeds,
+/// with a pause starting at `pause` and doubling after every
+/// failure. A transient error reading /proc should not stop us from
+/// starting.
+fn retry<T>(
+    path: &Path,
+    tries: u32,
+    mut pause: Duration,
+    mut read: impl FnMut() -> io::Result<T>,
+) -> Result<T> {
+    for _ in 1..tries {
+        match read() {
+            Ok(value) => return Ok(value),
+            Err(e) => {
+                warn!(
+                    "Could not read {}: {e}, trying again in {pause:?}",
+                    path.display()
+                );
+                thread::sleep(pause);
+                pause *= 2;
+            }
+        }
+    }
+    read().with_context(|| format!("Could not read {}, giving up", path.display()))
+}
+
+#[test]
+fn test_retry() {
+    let mut calls = 0;
+    let read = || {
+        calls += 1;
+        match calls {
+            1 | 2 => Err(io::Error::other("transient")),
+            _ => Ok(calls),
+        }
+    };
+    let path = Path::new("/proc/stat");
+    assert_eq!(3, retry(path, 5, Duration::from_millis(1), read).unwrap());
+
+    let mut calls = 0;
+    let read = || -> io::Result<()> {
+        calls += 1;
+        Err(io::Error::other("gone"))
+    };
+    assert!(retry(path, 3, Duration::from_millis(1), read).is_err());
+    assert_eq!(3, calls);
+}
+
+#[test]
+fn test_try_new_proc_root() {
+    let root = std::env::temp_dir().join(format!("cpu1sec-proc-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(
+        root.join("stat"),
+        "cpu  10 0 10 100 0 0 0 0 0 0\n\
+         cpu0 10 0 10 100 0 0 0 0 0 0\n\
+         cpu7 0 0 0 0 0 0 0 0 0 0\n\
+         ctxt 1\nbtime 1000\nprocesses 1\n",
+    )
+    .unwrap();
+    // A virtual machine, guest fields are found in there
+    std::fs::write(root.join("cpuinfo"), "flags\t\t: fpu vme hypervisor\n").unwrap();
+    let cpu = CpuPlugin::try_new(Settings {
+        proc_root: root.clone(),
+        ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(1000, cpu.btime);
+    assert_eq!(vec![0, 7], cpu.cores);
+    assert_eq!(Some(true), cpu.settings.guest_fields);
+    // Unless they are switched off
+    let cpu = CpuPlugin::try_new(Settings {
+        proc_root: root.clone(),
+        guest_fields: Some(false),
+        ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(Some(false), cpu.settings.guest_fields);
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+/// How long munin keeps our data, at which resolution. This is the
+/// graph_data_size of our graphs.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Retention {
+    /// An hour at full resolution, a month in total. Smallest RRDs.
+    Short,
+    /// A day at full resolution, a year in total
+    #[default]
+    Default,
+    /// A week at full resolution, two years in total. Biggest RRDs.
+    Long,
+    /// Any other graph_data_size, e.g. `custom 2d, 10s for 1w`
+    Custom(String),
+}
+
+impl FromStr for Retention {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "short" => Ok(Retention::Short),
+            "default" => Ok(Retention::Default),
+            "long" => Ok(Retention::Long),
+            _ if s.starts_with("custom ") => Ok(Retention::Custom(s.to_string())),
+            _ => Err(anyhow::anyhow!("Unknown retention {s}")),
+        }
+    }
+}
+
+impl Retention {
+    /// The graph_data_size for munin
+    pub(crate) fn graph_data_size(&self) -> &str {
+        match self {
+            Retention::Short => "custom 1h, 10s for 1d, 1m for 1w, 5m for 1t",
+            Retention::Default => {
+                "custom 1d, 1s for 1d, 5s for 2d, 10s for 7d, 1m for 1t, 5m for 1y"
+            }
+            Retention::Long => "custom 1w, 5s for 1t, 1m for 1y, 5m for 2y",
+            Retention::Custom(plan) => plan,
+        }
+    }
+}
+
+/// What [CpuPlugin::on_sample] takes
+type SampleFn = dyn FnMut(&[CpuStat]);
+
+/// A function handed every sample, see [CpuPlugin::on_sample]
+struct Callback(Box<SampleFn>);
+
+impl std::fmt::Debug for Callback {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Callback")
+    }
+}
+
+#[derive(Debug)]
+/// The struct for our plugin, so we can easily store some values over
+/// the lifetime of our plugin.
+pub struct CpuPlugin {
+    /// Our settings
+    settings: Settings,
+
+    /// How long each collector took in the last acquire, for the
+    /// self_metrics graph
+    durations: BTreeMap<Collector, Duration>,
+
+    /// Boot time of the kernel (btime from /proc/stat), seconds
+    /// since the epoch. If this changes, the machine rebooted.
+    btime: u64,
+
+    /// Store old CpuStat data to diff against. New data is matched
+    /// up with it by [CpuStat::cpu], not by position, as CPUs come
+    /// and go, see [CpuPlugin::config_stale].
+    old: Vec<CpuStat>,
+
+    /// The cpufreq policies, with their cores. Only read with
+    /// [Settings::cpudetail].
+    policies: BTreeMap<u32, CpuSet>,
+
+    /// The NUMA nodes, with their cores. Only read with
+    /// [Settings::cpudetail] grouped by [GroupBy::Node].
+    nodes: BTreeMap<u32, CpuSet>,
+
+    /// Number of CPUs in the last /proc/stat we read, the online
+    /// ones. With SMT toggled that can be different from the cores
+    /// /proc/cpuinfo knows about.
+    online: usize,
+
+    /// Number of CPUs online when we started, the ones the config
+    /// munin has from us is about
+    configured: usize,
+
+    /// The numbers of the CPUs in the last /proc/stat we read, see
+    /// [core_ids]. Empty if we only got [KernelStats], then they are
+    /// numbered by position.
+    cores: Vec<u32>,
+
+    /// The last /proc/stat we read, [Collector::Ctxt],
+    /// [Collector::Intr], [Collector::Forks] and [Collector::Procs]
+    /// take their numbers from it too
+    stat: String,
+
+    /// The ctxt, intr and processes counters of the last /proc/stat
+    /// that counted, see [CpuPlugin::count_activity]
+    activity: Option<Activity>,
+
+    /// The counters of the /proc/stat that counted before
+    /// [CpuPlugin::activity], the rates are against these
+    previous: Option<Activity>,
+
+    /// Did the last /proc/stat we read count? Not if it was the
+    /// second one in the same second.
+    fresh: bool,
+
+    /// Did we already complain about per-core lines in /proc/stat we
+    /// could not parse?
+    core_errors_logged: bool,
+
+    /// Called with every sample, see [CpuPlugin::on_sample]
+    callback: Option<Callback>,
+
+    /// The graphs of the last sample, None if it had no values, for
+    /// outputs that want them instead of munin's text
+    latest: Option<Vec<CpuStat>>,
+
+    /// Delivers every sample to the sinks besides munin, see
+    /// [FanOut]
+    fanout: Option<FanOut>,
+
+    /// What happened during this run, logged when we get stopped
+    summary: Summary,
+
+    /// Pressure stall information, for [Collector::Psi]
+    #[cfg(feature = "psi")]
+    psi: Psi,
+
+    /// Temperatures of the sensors, for [Collector::Temp]
+    #[cfg(feature = "temp")]
+    temperatures: Temperatures,
+
+    /// Frequencies of the cores, for [Collector::Freq]
+    #[cfg(feature = "freq")]
+    frequencies: Frequencies,
+
+    /// How busy every core was in the last sample, in percent, for
+    /// the warnings of [Collector::Freq]
+    #[cfg(feature = "freq")]
+    busy: BTreeMap<u32, f64>,
+
+    /// Interrupts per IRQ, for [Collector::Irq]
+    #[cfg(feature = "irq")]
+    interrupts: Interrupts,
+
+    /// Softirqs by kind, for [Collector::Softirq]
+    #[cfg(feature = "softirq")]
+    softirqs: SoftirqCollector,
+
+    /// CPU usage and throttling of cgroups, for [Collector::Cgroup]
+    #[cfg(feature = "cgroup")]
+    cgroups: Cgroups,
+
+    /// CPU usage of the busiest processes, for [Collector::Top]
+    #[cfg(feature = "top")]
+    top: TopProcesses,
+}
+
+impl Default for CpuPlugin {
+    /// Set defaults, with settings from the environment. Panics if
+    /// /proc/stat can not be read, see [CpuPlugin::try_new].
+    fn default() -> Self {
+        Self::new(Settings::from_env())
+    }
+}
+
+impl CpuPlugin {
+    /// Create the plugin with the given settings. Panics if
+    /// /proc/stat can not be read, see [CpuPlugin::try_new].
+    pub fn new(settings: Settings) -> Self {
+        Self::try_new(settings).expect("Could not read kernelstats")
+    }
+
+    /// Create the plugin with the given settings. Reading /proc/stat
+    /// is retried a few times, with a growing pause in between,
+    /// before we give up with an error. An unset
+    /// [Settings::guest_fields] is decided here, by looking for
+    /// virtualization in [Settings::proc_root].
+    pub fn try_new(mut settings: Settings) -> Result<Self> {
+        if settings.guest_fields.is_none() {
+            settings.guest_fields = Some(hypervisor::detect(Path::new("/"), &settings.proc_root));
+        }
+        let path = settings.proc_stat();
+        let read = || File::open(&path).and_then(read_stat);
+        let pause = Duration::from_millis(100);
+        // Pre-fill the "old" data, so we always have something to
+        // diff against in acquire
+        let content = retry(&path, START_TRIES, pause, read)?;
+        let epoch = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut plugin = Self::from_stat(settings, &content, epoch)
+            .with_context(|| format!("Parsing {}", path.display()))?;
+        if plugin.settings.prime {
+            thread::sleep(plugin.settings.prime_interval);
+            let ks = plugin.parse_stat(&retry(&path, START_TRIES, pause, read)?)?;
+            plugin.prime(ks, epoch);
+        }
+        Ok(plugin)
+    }
+
+    /// Make `ks`, read shortly after the data we started with, our
+    /// baseline. Returns the difference between the two, a short but
+    /// real interval.
+    fn prime(&mut self, ks: KernelStats, epoch: u64) -> Option<Vec<CpuStat>> {
+        let diff = self.sample(ks, epoch)?;
+        if let Some(total) = diff.last() {
+            info!(
+                "Primed with a second read, {} ticks passed in {:?}",
+                total.ticks(),
+                self.settings.prime_interval
+            );
+        }
+        Some(diff)
+    }
+
+    /// Create the plugin with the given settings, starting from `ks`
+    /// taken at `epoch` instead of the current /proc/stat. For tests
+    /// and recorded data. [KernelStats] does not say which CPUs it
+    /// has, so they are numbered by position.
+    pub fn with_stats(settings: Settings, ks: KernelStats, epoch: u64) -> Self {
+        Self::with_cores(settings, ks, vec![], epoch)
+    }
+
+    /// Create the plugin with the given settings, starting from the
+    /// /proc/stat `content` taken at `epoch`
+    pub(crate) fn from_stat(settings: Settings, content: &str, epoch: u64) -> Result<Self> {
+        let (ks, cores, core_error) = parse_lenient(content)?;
+        let mut plugin = Self::with_cores(settings, ks, cores, epoch);
+        if let Some(e) = core_error {
+            plugin.log_core_error(e);
+        }
+        plugin.activity = Some(Activity::parse(content, epoch));
+        Ok(plugin)
+    }
+
+    /// [CpuPlugin::with_stats], with the numbers of the CPUs in `ks`
+    fn with_cores(mut settings: Settings, ks: KernelStats, cores: Vec<u32>, epoch: u64) -> Self {
+        if settings.resolution == Resolution::Nanoseconds && CgroupCpuTime::read().is_none() {
+            info!("No {CGROUP_CPU_STAT}, total in ns will only have tick precision");
+        }
+        if settings.resolution == Resolution::Milliseconds {
+            info!(
+                "Kernel counts {} ticks per second, converting to ms",
+                stat::ticks_per_second()
+            );
+        }
+        info!("Kernel booted at {}", ks.btime);
+        if settings.cpudetail && ks.cpu_time.len() > settings.max_core_graphs {
+            info!(
+                "{} cores, more than max_core_graphs {}, summing up the rest as \"others\"",
+                ks.cpu_time.len(),
+                settings.max_core_graphs
+            );
+        }
+        let policies = if settings.cpudetail {
+            cpufreq::policies(Path::new("/"))
+        } else {
+            BTreeMap::new()
+        };
+        if settings.cpudetail && settings.group_by == GroupBy::Policy && policies.is_empty() {
+            warn!("No cpufreq policies found, showing single cores");
+        }
+        let nodes = if settings.cpudetail && settings.group_by == GroupBy::Node {
+            numa::nodes(Path::new("/"))
+        } else {
+            BTreeMap::new()
+        };
+        if settings.cpudetail && settings.group_by == GroupBy::Node && nodes.is_empty() {
+            warn!("No NUMA nodes found, showing single cores");
+        }
+        if settings.guest_fields() && ks.total.guest_nice.is_none() {
+            info!("Kernel does not count guest and guest_nice, not emitting them");
+            settings.guest_fields = Some(false);
+        }
+        let btime = ks.btime;
+        let online = ks.cpu_time.len().max(1);
+        let old = Self::to_stats(&settings, ks, &cores, epoch);
+        #[cfg(feature = "psi")]
+        let psi = Psi::new(&settings);
+        // These read their files to know what to graph, only if
+        // needed
+        #[cfg(feature = "temp")]
+        let temperatures = if settings.collectors.contains(&Collector::Temp) {
+            Temperatures::new(Path::new("/"))
+        } else {
+            Temperatures::default()
+        };
+        #[cfg(feature = "freq")]
+        let frequencies = if settings.collectors.contains(&Collector::Freq) {
+            Frequencies::new(Path::new("/"), &settings)
+        } else {
+            Frequencies::default()
+        };
+        #[cfg(feature = "irq")]
+        let interrupts = if settings.collectors.contains(&Collector::Irq) {
+            Interrupts::new(&settings)
+        } else {
+            Interrupts::default()
+        };
+        #[cfg(feature = "softirq")]
+        let softirqs = if settings.collectors.contains(&Collector::Softirq) {
+            SoftirqCollector::new(&settings)
+        } else {
+            SoftirqCollector::default()
+        };
+        #[cfg(feature = "cgroup")]
+        let cgroups = if settings.collectors.contains(&Collector::Cgroup) {
+            Cgroups::new(Path::new(CGROUP_ROOT), &settings)
+        } else {
+            Cgroups::default()
+        };
+        #[cfg(feature = "top")]
+        let top = if settings.collectors.contains(&Collector::Top) {
+            TopProcesses::new(&settings)
+        } else {
+            TopProcesses::default()
+        };
+        Self {
+            settings,
+            durations: BTreeMap::new(),
+            btime,
+            old,
+            policies,
+            nodes,
+            online,
+            configured: online,
+            cores,
+            stat: String::new(),
+            activity: None,
+            previous: None,
+            fresh: false,
+            core_errors_logged: false,
+            callback: None,
+            latest: None,
+            fanout: None,
+            summary: Summary::default(),
+            #[cfg(feature = "psi")]
+            psi,
+            #[cfg(feature = "temp")]
+            temperatures,
+            #[cfg(feature = "freq")]
+            frequencies,
+            #[cfg(feature = "freq")]
+            busy: BTreeMap::new(),
+            #[cfg(feature = "irq")]
+            interrupts,
+            #[cfg(feature = "softirq")]
+            softirqs,
+            #[cfg(feature = "cgroup")]
+            cgroups,
+            #[cfg(feature = "top")]
+            top,
+        }
+    }
+
+    /// Do we need the usage of every core, even if we do not graph
+    /// them?
+    fn per_core(settings: &Settings) -> bool {
+        // For its warnings about busy cores at a low frequency
+        #[cfg(feature = "freq")]
+        if settings.collectors.contains(&Collector::Freq) {
+            return true;
+        }
+        settings.cpudetail || settings.aggregate.is_some()
+    }
+
+    /// Turn the KernelStats into the CpuStats we are interested in.
+    /// Per-core ones first (if we need them, see [CpuPlugin::per_core],
+    /// and only the [Settings::cores] asked for), numbered as in
+    /// `cores` if we know, total last, read from our
+    /// [Settings::source].
+    fn to_stats(settings: &Settings, ks: KernelStats, cores: &[u32], epoch: u64) -> Vec<CpuStat> {
+        let multigraph = settings.multigraph();
+        let compat = settings.compat;
+        let rollup = settings.rollup;
+        let guest_fields = settings.guest_fields();
+        let iowait_busy = settings.iowait_busy;
+        let mut stats: Vec<CpuStat> = if Self::per_core(settings) {
+            ks.cpu_time
+                .into_iter()
+                .enumerate()
+                .map(|(pos, stat)| (cores.get(pos).copied().unwrap_or(pos as u32), stat))
+                .filter(|(cpu, _)| settings.selected(*cpu))
+                .map(|(cpu, stat)| {
+                    settings.resolution.core(CpuStat {
+                        epoch,
+                        rollup,
+                        guest_fields,
+                        iowait_busy,
+                        ..cpu_stat_to_value(CpuId::Core(cpu), stat, multigraph, compat)
+                    })
+                })
+                .collect()
+        } else {
+            // If we do not want details, an empty vector is enough.
+            // "Total" values get pushed to it next.
+            vec![]
+        };
+        let cgroup = match settings.source {
+            Source::Cgroup => CgroupCpuTime::read(),
+            Source::Proc => None,
+        };
+        let total = match cgroup {
+            Some(usec) => {
+                let tps = stat::ticks_per_second();
+                CpuStat {
+                    user: usec.user_usec * tps / 1_000_000,
+                    system: usec.system_usec * tps / 1_000_000,
+                    ..Default::default()
+                }
+            }
+            // Also where we end up if the cgroup's cpu.stat went away
+            None => CpuStat {
+                user: ks.total.user,
+                nice: ks.total.nice,
+                system: ks.total.system,
+                idle: ks.total.idle,
+                iowait: ks.total.iowait.unwrap_or(0),
+                irq: ks.total.irq.unwrap_or(0),
+                softirq: ks.total.softirq.unwrap_or(0),
+                steal: ks.total.steal.unwrap_or(0),
+                guest: ks.total.guest.unwrap_or(0),
+                guest_nice: ks.total.guest_nice.unwrap_or(0),
+                ..Default::default()
+            },
+        };
+        stats.push(settings.resolution.total(CpuStat {
+            multigraph,
+            compat,
+            rollup,
+            guest_fields,
+            iowait_busy,
+            epoch,
+            ..total
+        }));
+        stats
+    }
+
+    /// The groups we show instead of single cores, the cpufreq
+    /// policies or NUMA nodes, with the CpuId of their graph. None for
+    /// single cores, also if there are no groups to show.
+    fn groups(&self) -> Option<Vec<(CpuId, &CpuSet)>> {
+        let (groups, id): (_, fn(u32) -> CpuId) = match self.settings.group_by {
+            GroupBy::Core => return None,
+            GroupBy::Policy => (&self.policies, CpuId::Policy),
+            GroupBy::Node => (&self.nodes, CpuId::Node),
+        };
+        (!groups.is_empty()).then(|| groups.iter().map(|(num, set)| (id(*num), set)).collect())
+    }
+
+    /// Turn what [CpuPlugin::to_stats] gives (or the difference of
+    /// two of those) into the graphs we write out: Per-core (or
+    /// per-group) ones first (if we want details), then the sum of
+    /// all graphs above [Settings::max_core_graphs] (if any), the sum
+    /// of the cores in [Settings::aggregate] (if wanted), total
+    /// last.
+    fn graphs(&self, mut stats: Vec<CpuStat>) -> Vec<CpuStat> {
+        let Some(total) = stats.pop() else {
+            return stats;
+        };
+        // The graphs are built with the number of cores summed up in
+        // them, for aggregate_fn
+        let add =
+            |(sum, cores): (CpuStat, u64), (stat, more): (CpuStat, u64)| (sum + stat, cores + more);
+        let members = |set: &CpuSet| -> Vec<(CpuStat, u64)> {
+            stats
+                .iter()
+                .filter(|stat| matches!(stat.cpu, CpuId::Core(cpu) if set.contains(cpu)))
+                .map(|stat| (*stat, 1))
+                .collect()
+        };
+        let aggregate = self.settings.aggregate.as_ref().map(|set| {
+            let sum = CpuStat {
+                cpu: CpuId::Aggregate,
+                epoch: total.epoch,
+                multigraph: total.multigraph,
+                compat: total.compat,
+                rollup: total.rollup,
+                guest_fields: total.guest_fields,
+                iowait_busy: total.iowait_busy,
+                ..Default::default()
+            };
+            members(set).into_iter().fold((sum, 0), add)
+        });
+        let mut graphs: Vec<(CpuStat, u64)> = if !self.settings.cpudetail {
+            vec![]
+        } else if let Some(groups) = self.groups() {
+            groups
+                .into_iter()
+                .filter_map(|(cpu, set)| {
+                    let (sum, cores) = members(set).into_iter().reduce(add)?;
+                    Some((CpuStat { cpu, ..sum }, cores))
+                })
+                .collect()
+        } else {
+            stats.iter().map(|stat| (*stat, 1)).collect()
+        };
+        if graphs.len() > self.settings.max_core_graphs {
+            let others = graphs
+                .split_off(self.settings.max_core_graphs)
+                .into_iter()
+                .reduce(add)
+                .map(|(sum, cores)| {
+                    let sum = CpuStat {
+                        cpu: CpuId::Others,
+                        ..sum
+                    };
+                    (sum, cores)
+                });
+            graphs.extend(others);
+        }
+        graphs.extend(aggregate);
+        let mut graphs: Vec<CpuStat> = graphs
+            .into_iter()
+            .map(|(sum, cores)| self.settings.aggregate_fn.apply(sum, cores))
+            .collect();
+        graphs.push(total);
+        graphs
+    }
+
+    /// Write out the config for the steal graph
+    fn config_steal<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+        writeln!(handle, "multigraph cpu1sec_steal")?;
+        writeln!(
+            handle,
+            "graph_title CPU time stolen by the hypervisor (1sec)"
+        )?;
+        writeln!(handle, "graph_category {}", self.settings.graph_category)?;
+        writeln!(handle, "update_rate {}", self.settings.update_rate())?;
+        writeln!(
+            handle,
+            "graph_args --base 1000 -r --lower-limit 0 --upper-limit 100"
+        )?;
+        writeln!(handle, "graph_vlabel %")?;
+        writeln!(handle, "graph_scale no")?;
+        writeln!(
+            handle,
+            "graph_info Percentage of time a virtual CPU had runnable tasks, but was not running."
+        )?;
+        let mut cpus = vec![String::from("total")];
+        cpus.extend(
+            self.core_graphs()?
+                .into_iter()
+                .map(|(cpu, _)| cpu.to_string()),
+        );
+        for cpu in cpus {
+            writeln!(handle, "{cpu}.label {cpu}")?;
+            writeln!(handle, "{cpu}.min 0")?;
+            writeln!(handle, "{cpu}.max 100")?;
+            writeln!(handle, "{cpu}.type GAUGE")?;
+        }
+        Ok(())
+    }
+
+    /// Write out the config for the self_metrics graph
+    fn config_self<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+        writeln!(handle, "multigraph cpu1sec_self")?;
+        writeln!(handle, "graph_title cpu1sec collector read durations")?;
+        writeln!(handle, "graph_category munin")?;
+        writeln!(handle, "update_rate {}", self.settings.update_rate())?;
+        writeln!(handle, "graph_args --base 1000 --lower-limit 0")?;
+        writeln!(handle, "graph_vlabel microseconds")?;
+        writeln!(
+            handle,
+            "graph_info How long each collector took to read its data."
+        )?;
+        for collector in &self.settings.collectors {
+            let name = collector.name();
+            writeln!(handle, "{name}.label {name}")?;
+            writeln!(handle, "{name}.min 0")?;
+            writeln!(handle, "{name}.type GAUGE")?;
+        }
+        Ok(())
+    }
+
+    /// The numbers of the CPUs online, as the kernel has them, that
+    /// are in [Settings::cores]
+    fn cores_online(&self) -> Vec<u32> {
+        let online = if self.cores.is_empty() {
+            (0..self.online as u32).collect()
+        } else {
+            self.cores.clone()
+        };
+        online
+            .into_iter()
+            .filter(|cpu| self.settings.selected(*cpu))
+            .collect()
+    }
+
+    /// The per-core graphs we emit, with the number of cores each
+    /// one covers. Empty unless we want details.
+    fn core_graphs(&self) -> Result<Vec<(CpuId, usize)>> {
+        let mut graphs: Vec<(CpuId, usize)> = vec![];
+        if self.settings.cpudetail {
+            if let Some(groups) = self.groups() {
+                graphs.extend(groups.into_iter().filter_map(|(id, set)| {
+                    let cores = set.0.iter().filter(|cpu| self.settings.selected(**cpu));
+                    match cores.count() {
+                        0 => None,
+                        cores => Some((id, cores)),
+                    }
+                }));
+            } else {
+                graphs.extend(
+                    self.cores_online()
+                        .into_iter()
+                        .map(|cpu| (CpuId::Core(cpu), 1)),
+                );
+            }
+            if graphs.len() > self.settings.max_core_graphs {
+                let others = graphs
+                    .split_off(self.settings.max_core_graphs)
+                    .iter()
+                    .map(|(_, cores)| cores)
+                    .sum();
+                graphs.push((CpuId::Others, others));
+            }
+        }
+        if let Some(set) = &self.settings.aggregate {
+            let cores = set.0.iter().filter(|cpu| self.settings.selected(**cpu));
+            graphs.push((CpuId::Aggregate, cores.count()));
+        }
+        Ok(graphs)
+    }
+
+    /// The cpufreq policy `cpu` belongs to, if any
+    fn policy_of(&self, cpu: u32) -> Option<u32> {
+        self.policies
+            .iter()
+            .find(|(_, set)| set.contains(cpu))
+            .map(|(policy, _)| *policy)
+    }
+
+    /// Write out the detailed config per core/for totals, little
+    /// helper for the config function. `cores` is the number of cores
+    /// the graph covers.
+    fn write_details<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        cpu: CpuId,
+        cores: usize,
+    ) -> Result<()> {
+        // An average of the group is just one core
+        let cores = match cpu {
+            CpuId::Others | CpuId::Aggregate | CpuId::Policy(_) | CpuId::Node(_)
+                if self.settings.aggregate_fn == AggregateFn::Avg =>
+            {
+                1
+            }
+            _ => cores,
+        };
+        let template = |text: &str| text.replace("{cpu}", &cpu.to_string());
+        writeln!(
+            handle,
+            "graph_title {}",
+            template(&self.settings.graph_title)
+        )?;
+        writeln!(handle, "graph_category {}", self.settings.graph_category)?;
+        writeln!(handle, "update_rate {}", self.settings.update_rate())?;
+        writeln!(
+            handle,
+            "graph_data_size {}",
+            self.settings.retention.graph_data_size()
+        )?;
+        let order = match self.settings.rollup {
+            Rollup::Fine => "system user nice idle iowait irq softirq",
+            Rollup::Coarse => "kernel userspace wait idle",
+        };
+        writeln!(handle, "graph_order {order}")?;
+        // The values are per interval, and so is their limit. Only
+        // ticks of a one second interval read as percent.
+        let ms = self.settings.interval.as_millis() as usize;
+        let (uplimit, vlabel) = match self.settings.resolution {
+            Resolution::Nanoseconds if cpu == CpuId::Total => (cores * ms * 1_000_000, "ns"),
+            Resolution::Milliseconds => (cores * ms, "ms"),
+            _ if ms == 1000 => (cores * 100, "%"),
+            _ => (cores * ms / 10, "ticks"),
+        };
+        writeln!(
+            handle,
+            "graph_args --base 1000 -r --lower-limit 0 --upper-limit {}",
+            uplimit
+        )?;
+        writeln!(handle, "graph_vlabel {vlabel}")?;
+        writeln!(handle, "graph_scale no")?;
+        let info = match cpu {
+            CpuId::Core(core) => self.policy_of(core).map(|policy| {
+                format!(" {cpu} scales its frequency with the cores of cpufreq policy{policy}.")
+            }),
+            CpuId::Policy(policy) => Some(format!(
+                " Sum of the cores of cpufreq policy{policy}, which scale their frequency together."
+            )),
+            CpuId::Node(node) => Some(format!(
+                " Sum of the cores of NUMA node{node}, which share its memory."
+            )),
+            _ => None,
+        };
+        writeln!(
+            handle,
+            "graph_info {}{}",
+            template(&self.settings.graph_info),
+            info.unwrap_or_default()
+        )?;
+
+        let p = self.settings.compat.prefix(&cpu.to_string());
+
+        match self.settings.rollup {
+            Rollup::Fine => self.write_fields(handle, &p)?,
+            Rollup::Coarse => self.write_coarse_fields(handle, &p)?,
+        }
+        if self.settings.clamp_max {
+            for field in self.settings.fields() {
+                writeln!(handle, "{p}{}.max {uplimit}", field.name())?;
+            }
+        }
+        for field in self.settings.fields() {
+            if let Some(warning) = self.settings.warning.get(&field) {
+                writeln!(handle, "{p}{}.warning {warning}", field.name())?;
+            }
+            if let Some(critical) = self.settings.critical.get(&field) {
+                writeln!(handle, "{p}{}.critical {critical}", field.name())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write out the config of all fields, `p` being their prefix
+    fn write_fields<W: Write>(&self, handle: &mut BufWriter<W>, p: &str) -> Result<()> {
+        writeln!(handle, "{p}system.label system")?;
+        writeln!(handle, "{p}system.draw AREA")?;
+        writeln!(handle, "{p}system.min 0")?;
+        writeln!(handle, "{p}system.type GAUGE")?;
+        writeln!(
+            handle,
+            "{p}system.info CPU time spent by the kernel in system activities"
+        )?;
+        writeln!(handle, "{p}user.label user")?;
+        writeln!(handle, "{p}user.draw STACK")?;
+        writeln!(handle, "{p}user.min 0")?;
+        writeln!(handle, "{p}user.type GAUGE")?;
+        writeln!(
+            handle,
+            "{p}user.info CPU time spent by normal programs and daemons"
+        )?;
+        writeln!(handle, "{p}nice.label nice")?;
+        writeln!(handle, "{p}nice.draw STACK")?;
+        writeln!(handle, "{p}nice.min 0")?;
+        writeln!(handle, "{p}nice.type GAUGE")?;
+        writeln!(handle, "{p}nice.info CPU time spent by nice(1)d programs")?;
+        writeln!(handle, "{p}idle.label idle")?;
+        writeln!(handle, "{p}idle.draw STACK")?;
+        writeln!(handle, "{p}idle.min 0")?;
+        writeln!(handle, "{p}idle.type GAUGE")?;
+        writeln!(handle, "{p}idle.info Idle CPU time")?;
+        writeln!(handle, "{p}iowait.label iowait")?;
+        writeln!(handle, "{p}iowait.draw STACK")?;
+        writeln!(handle, "{p}iowait.min 0")?;
+        writeln!(handle, "{p}iowait.type GAUGE")?;
+        writeln!(handle, "{p}iowait.info CPU time spent waiting for I/O operations to finish when there is nothing else to do.")?;
+        writeln!(handle, "{p}irq.label irq")?;
+        writeln!(handle, "{p}irq.draw STACK")?;
+        writeln!(handle, "{p}irq.min 0")?;
+        writeln!(handle, "{p}irq.type GAUGE")?;
+        writeln!(handle, "{p}irq.info CPU time spent handling interrupts")?;
+        writeln!(handle, "{p}softirq.label softirq")?;
+        writeln!(handle, "{p}softirq.draw STACK")?;
+        writeln!(handle, "{p}softirq.min 0")?;
+        writeln!(handle, "{p}softirq.type GAUGE")?;
+        writeln!(
+            handle,
+            "{p}softirq.info CPU time spent handling \"batched\" interrupts"
+        )?;
+        writeln!(handle, "{p}steal.label steal")?;
+        writeln!(handle, "{p}steal.draw STACK")?;
+        writeln!(handle, "{p}steal.min 0")?;
+        writeln!(handle, "{p}steal.type GAUGE")?;
+        writeln!(handle, "{p}steal.info The time that a virtual CPU had runnable tasks, but the virtual CPU itself was not running")?;
+        if !self.settings.guest_fields() {
+            return Ok(());
+        }
+        writeln!(handle, "{p}guest.label guest")?;
+        writeln!(handle, "{p}guest.draw STACK")?;
+        writeln!(handle, "{p}guest.min 0")?;
+        writeln!(handle, "{p}guest.type GAUGE")?;
+        writeln!(handle, "{p}guest.info The time spent running a virtual CPU for guest operating systems under the control of the Linux kernel.")?;
+        writeln!(handle, "{p}guest_nice.label guest_nice")?;
+        writeln!(handle, "{p}guest_nice.draw STACK")?;
+        writeln!(handle, "{p}guest_nice.min 0")?;
+        writeln!(handle, "{p}guest_nice.type GAUGE")?;
+        writeln!(handle, "{p}guest_nice.info The time spent running a nice(1)d virtual CPU for guest operating systems under the control of the Linux kernel.")?;
+        Ok(())
+    }
+
+    /// Write out the config of the bands of [Rollup::Coarse], `p`
+    /// being their prefix
+    fn write_coarse_fields<W: Write>(&self, handle: &mut BufWriter<W>, p: &str) -> Result<()> {
+        for (field, draw, info) in [
+            (
+                Field::Kernel,
+                "AREA",
+                "CPU time spent by the kernel, in system activities and handling interrupts",
+            ),
+            (
+                Field::Userspace,
+                "STACK",
+                "CPU time spent by programs and daemons, nice(1)d or not",
+            ),
+            (
+                Field::Wait,
+                "STACK",
+                "CPU time spent waiting for I/O or for the hypervisor (steal)",
+            ),
+            (Field::Idle, "STACK", "Idle CPU time"),
+        ] {
+            let field = field.name();
+            writeln!(handle, "{p}{field}.label {field}")?;
+            writeln!(handle, "{p}{field}.draw {draw}")?;
+            writeln!(handle, "{p}{field}.min 0")?;
+            writeln!(handle, "{p}{field}.type GAUGE")?;
+            writeln!(handle, "{p}{field}.info {info}")?;
+        }
+        Ok(())
+    }
+
+    /// Read the CPU usage and write out the difference to the last
+    /// time we looked
+    fn acquire_cpu<W: Write>(&mut self, handle: &mut BufWriter<W>, epoch: u64) -> Result<()> {
+        let content = read_stat(File::open(self.settings.proc_stat())?)?;
+        let ks = self.parse_stat(&content)?;
+        self.stat = content;
+        self.count_activity(epoch);
+        self.write_cpu(handle, ks, epoch)
+    }
+
+    /// Like [CpuPlugin::write_cpu], for the /proc/stat `content`,
+    /// plus the other collectors that take their numbers from it
+    pub(crate) fn write_stat<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        content: &str,
+        epoch: u64,
+    ) -> Result<()> {
+        let ks = self.parse_stat(content)?;
+        self.stat = String::from(content);
+        self.count_activity(epoch);
+        self.write_cpu(handle, ks, epoch)?;
+        for (collector, counter) in COUNTERS {
+            if self.settings.collectors.contains(&collector) {
+                self.write_counter(handle, counter)?;
+            }
+        }
+        if self.settings.collectors.contains(&Collector::Procs) {
+            procs::write(handle, &self.stat, epoch)?;
+        }
+        Ok(())
+    }
+
+    /// Take the counters of the /proc/stat we just read, taken at
+    /// `epoch`, if one of [COUNTERS] is enabled. With more than one
+    /// sample per second, only the first one in a second counts,
+    /// munin would only keep the last anyway.
+    fn count_activity(&mut self, epoch: u64) {
+        if !COUNTERS
+            .iter()
+            .any(|(collector, _)| self.settings.collectors.contains(collector))
+        {
+            return;
+        }
+        self.fresh = self.activity.is_none_or(|last| last.epoch != epoch);
+        if self.fresh {
+            self.previous = self.activity.replace(Activity::parse(&self.stat, epoch));
+        }
+    }
+
+    /// Write out `counter` per second between the last two
+    /// /proc/stat that counted, nothing if the last one did not, see
+    /// [CpuPlugin::count_activity]
+    fn write_counter<W: Write>(&self, handle: &mut BufWriter<W>, counter: Counter) -> Result<()> {
+        match &self.activity {
+            Some(new) if self.fresh => {
+                counter.write(handle, new, self.previous.as_ref(), self.settings.max_gap())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Parse the content of /proc/stat, see [parse_lenient]. If
+    /// per-core lines are broken, the per-core graphs miss this
+    /// second.
+    fn parse_stat(&mut self, content: &str) -> Result<KernelStats> {
+        let (ks, cores, core_error) = parse_lenient(content)?;
+        self.cores = cores;
+        if let Some(e) = core_error {
+            self.log_core_error(e);
+        }
+        Ok(ks)
+    }
+
+    /// Complain about per-core lines we could not parse, once
+    fn log_core_error(&mut self, e: anyhow::Error) {
+        if !self.core_errors_logged {
+            warn!(
+                "Could not parse per-core data from {}: {e}, only writing the total",
+                self.settings.proc_stat().display()
+            );
+            self.core_errors_logged = true;
+        }
+    }
+
+    /// The difference between the given KernelStats, taken at
+    /// `epoch`, and the last ones we saw, as the graphs we write out.
+    /// None if the counters got reset in between, by a reboot or
+    /// otherwise.
+    fn sample(&mut self, ks: KernelStats, epoch: u64) -> Option<Vec<CpuStat>> {
+        self.latest = None;
+        #[cfg(feature = "freq")]
+        self.busy.clear();
+        // Without per-core lines (see parse_stat) we can not say
+        if !ks.cpu_time.is_empty() && ks.cpu_time.len() != self.online {
+            self.online = ks.cpu_time.len();
+            self.summary.hotplugs += 1;
+            if self.config_stale() {
+                warn!(
+                    "CPUs were hotplugged, {} online instead of {}, munin needs to run config again",
+                    self.online, self.configured
+                );
+            } else {
+                info!("Back to {} CPUs online, config is fine again", self.online);
+            }
+        }
+        if ks.btime != self.btime {
+            // The machine rebooted underneath us, the counters
+            // started from scratch. No way to know what happened in
+            // between, so we say exactly that and start over.
+            warn!(
+                "Boot time changed from {} to {}, reboot? Resetting",
+                self.btime, ks.btime
+            );
+            self.btime = ks.btime;
+            self.old = Self::to_stats(&self.settings, ks, &self.cores, epoch);
+            self.summary.resets += 1;
+            return None;
+        }
+        let new = Self::to_stats(&self.settings, ks, &self.cores, epoch);
+        let last = self.old.last().map_or(epoch, |old| old.epoch);
+        let gap = Duration::from_secs(epoch.saturating_sub(last));
+        if gap > self.settings.max_gap() {
+            // Suspended, or we stalled. The difference covers all of
+            // the gap and we can not tell when the CPUs did the work,
+            // so we leave the gap unknown instead of showing a spike.
+            warn!("No sample for {gap:?}, suspended? Writing U for the gap");
+            self.old = new;
+            self.summary.gaps += 1;
+            return None;
+        }
+        let backwards = new.iter().find(|new| {
+            self.old
+                .iter()
+                .any(|old| old.cpu == new.cpu && new.checked_diff(old).is_none())
+        });
+        if let Some(stat) = backwards {
+            // Same boot, still the counters got reset underneath us
+            // (or the kernel has a quirk). As after a reboot, all
+            // we can do is start over from here.
+            warn!("Counters of {} went backwards, resetting", stat.cpu);
+            self.old = new;
+            self.summary.resets += 1;
+            return None;
+        }
+        // Calculate the difference. Cores we have no old data for
+        // (they were missing last time) get skipped.
+        let diff: Vec<CpuStat> = new
+            .iter()
+            .filter_map(|new| {
+                self.old
+                    .iter()
+                    .find(|old| old.cpu == new.cpu)
+                    .map(|old| match new.cpu {
+                        CpuId::Total => self.settings.resolution.total_diff(new.diff(old)),
+                        _ => new.diff(old),
+                    })
+            })
+            .collect();
+        self.old = new;
+        #[cfg(feature = "freq")]
+        {
+            self.busy = diff
+                .iter()
+                .filter_map(|stat| match stat.cpu {
+                    CpuId::Core(cpu) => Some((cpu, stat.busy())),
+                    _ => None,
+                })
+                .collect();
+        }
+        let graphs = self.graphs(diff);
+        self.summary.samples += 1;
+        if let Some(Callback(callback)) = self.callback.as_mut() {
+            callback(&graphs);
+        }
+        if let Some(fanout) = self.fanout.as_mut() {
+            fanout.send(&graphs);
+        }
+        self.latest = Some(graphs.clone());
+        Some(graphs)
+    }
+
+    /// Did CPUs go on- or offline since we started? Then the config
+    /// munin has from us lists the wrong graphs and upper limits,
+    /// and munin needs to run config again. That happens in its own
+    /// process, which sees the new CPUs.
+    pub fn config_stale(&self) -> bool {
+        self.online != self.configured
+    }
+
+    /// Have `callback` called with every sample we take, the same
+    /// graphs we write out (total last), so applications embedding
+    /// us can react to the live data. Replaces an earlier callback.
+    pub fn on_sample<F: FnMut(&[CpuStat]) + 'static>(&mut self, callback: F) {
+        self.callback = Some(Callback(Box::new(callback)));
+    }
+
+    /// Write out the difference between the given KernelStats, taken
+    /// at `epoch`, and the last ones we saw
+    pub fn write_cpu<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        ks: KernelStats,
+        epoch: u64,
+    ) -> Result<()> {
+        if self.settings.compat == Compat::MuninCpu {
+            stock::write(handle, &self.settings, &ks.total, epoch)?;
+        }
+        let Some(diff) = self.sample(ks, epoch) else {
+            let graphs = self.graphs(self.old.clone());
+            for cpustat in &graphs {
+                write!(handle, "{}", Unknown(cpustat))?;
+            }
+            return self.write_steal(handle, &graphs, true);
+        };
+
+        for cpustat in &diff {
+            // Linebreak is added within the display of cpustat, so we
+            // do not need to do this Also, this one line here will
+            // translate to something around a dozen actual lines
+            // written out.
+            write!(handle, "{cpustat}")?;
+        }
+        self.write_steal(handle, &diff, false)
+    }
+
+    /// Write out the values of the steal graph, if we have one, for
+    /// the graphs in `stats`, or all of them as unknown (U)
+    fn write_steal<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        stats: &[CpuStat],
+        unknown: bool,
+    ) -> Result<()> {
+        if !self.settings.steal_graph {
+            return Ok(());
+        }
+        writeln!(handle, "multigraph cpu1sec_steal")?;
+        // Total first, as in the config
+        for cpustat in stats.iter().rev() {
+            if unknown {
+                writeln!(handle, "{}.value {}:U", cpustat.cpu, cpustat.epoch)?;
+            } else {
+                writeln!(
+                    handle,
+                    "{}.value {}:{:.2}",
+                    cpustat.cpu,
+                    cpustat.epoch,
+                    cpustat.percent_rounded(cpustat.steal, self.settings.rounding, 2)
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// One complete sample, as munin gets it
+    fn render(&mut self, config: &Config, epoch: u64) -> Result<Vec<u8>> {
+        let mut handle = BufWriter::with_capacity(
+            config.fetch_size,
+            LineEndingWriter::new(Vec::new(), self.settings.line_ending),
+        );
+        self.acquire(&mut handle, config, epoch)?;
+        Ok(handle.into_inner()?.into_inner())
+    }
+
+    /// Write one sample to `sink`. If writing it fails, the sample
+    /// is dropped, and we go on with the next one.
+    fn write_sample(
+        &mut self,
+        sink: &mut dyn OutputSink,
+        config: &Config,
+        epoch: u64,
+    ) -> Result<()> {
+        let munin = self.render(config, epoch)?;
+        let sample = Sample {
+            epoch,
+            munin: &munin,
+            graphs: self.latest.as_deref(),
+        };
+        if let Err(e) = sink.write_sample(&sample) {
+            warn!("Dropped the sample of {epoch}, could not write it: {e}");
+        }
+        Ok(())
+    }
+
+    /// Implements `cpu1sec --once`: Wait one [Settings::interval]
+    /// after the sample we started with, write out the difference
+    /// and be done. No daemon, for scripts and classic munin setups.
+    pub fn once<W: Write>(&mut self, handle: &mut BufWriter<W>, config: &Config) -> Result<()> {
+        thread::sleep(self.settings.interval);
+        let epoch = EpochClock::new(self.settings.clock)?.now().as_secs();
+        self.acquire(handle, config, epoch)
+    }
+
+    /// Write out the config for the CPU usage graph(s)
+    fn config_cpu<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+        if self.settings.compat == Compat::MuninCpu {
+            stock::config(handle, &self.settings, self.online)?;
+        }
+        if self.settings.multigraph() {
+            writeln!(handle, "multigraph cpu1sec")?;
+        }
+        self.write_details(handle, CpuId::Total, self.online)?;
+        for (cpu, cores) in self.core_graphs()? {
+            writeln!(handle, "multigraph cpu1sec.{cpu}")?;
+            self.write_details(handle, cpu, cores)?;
+        }
+        if self.settings.steal_graph {
+            self.config_steal(handle)?;
+        }
+        Ok(())
+    }
+}
+
+impl MuninPlugin for CpuPlugin {
+    fn config<W: Write>(&self, handle: &mut BufWriter<W>) -> Result<()> {
+        // collectors is ordered by the declaration order of
+        // Collector, so the graphs always come out in the same order.
+        for collector in &self.settings.collectors {
+            match collector {
+                Collector::Cpu => self.config_cpu(handle)?,
+                Collector::Ctxt => Counter::Ctxt.config(handle, &self.settings)?,
+                Collector::Intr => Counter::Intr.config(handle, &self.settings)?,
+                Collector::Forks => Counter::Forks.config(handle, &self.settings)?,
+                Collector::Procs => procs::config(handle, &self.settings)?,
+                #[cfg(feature = "load")]
+                Collector::Load => loadavg::config(handle, &self.settings)?,
+                #[cfg(feature = "temp")]
+                Collector::Temp => self.temperatures.config(handle, &self.settings)?,
+                #[cfg(feature = "freq")]
+                Collector::Freq => self.frequencies.config(handle, &self.settings)?,
+                #[cfg(feature = "psi")]
+                Collector::Psi => self.psi.config(handle, &self.settings)?,
+                #[cfg(feature = "irq")]
+                Collector::Irq => self.interrupts.config(handle, &self.settings)?,
+                #[cfg(feature = "softirq")]
+                Collector::Softirq => self.softirqs.config(handle, &self.settings)?,
+                #[cfg(feature = "cgroup")]
+                Collector::Cgroup => self.cgroups.config(handle, &self.settings)?,
+                #[cfg(feature = "top")]
+                Collector::Top => self.top.config(handle, &self.settings)?,
+            }
+        }
+        if self.settings.self_metrics {
+            self.config_self(handle)?;
+        }
+        Ok(())
+    }
+
+    fn daemon(&mut self, config: &Config) -> Result<()> {
+        if self.settings.output == Output::Stdout {
+            // Daemonizing would send stdout to /dev/null
+            info!("Writing to stdout, staying in the foreground");
+        } else if self.settings.foreground {
+            info!("Staying in the foreground as asked");
+        } else {
+            // Need to run as daemon/forked in background, so prepare
+            let daemonize = Daemonize::new()
+                .pid_file(&config.pidfile)
+                .chown_pid_file(true)
+                .working_directory("/tmp");
+            daemonize.start()?;
+        }
+
+        self.fanout = FanOut::spawn(fanout::targets(&self.settings));
+
+        let abort = self.settings.watchdog_abort;
+        let watchdog = Watchdog::spawn(self.settings.watchdog_timeout, move || {
+            if abort {
+                error!("Exiting, hoping to get restarted");
+                process::exit(1);
+            }
+        });
+
+        let mut sink = sink::open(&self.settings, config.fetchpath.clone())?;
+        let mut handle =
+            BufWriter::new(LineEndingWriter::new(Vec::new(), self.settings.line_ending));
+        self.config(&mut handle)?;
+        if let Err(e) = sink.write_config(&handle.into_inner()?.into_inner()) {
+            warn!("Could not write the config: {e}");
+        }
+        let interval = self.settings.interval;
+        let clock = EpochClock::new(self.settings.clock)?;
+        summary::catch_stop();
+        let mut last: Option<Instant> = None;
+        while !summary::stopped() {
+            let started = Instant::now();
+            if let Some(last) = last {
+                self.summary
+                    .late(started.duration_since(last).saturating_sub(interval));
+            }
+            last = Some(started);
+            let epoch = clock.now().as_secs();
+            watchdog.guard(|| self.write_sample(sink.as_mut(), config, epoch))?;
+            self.summary.acquired(started.elapsed());
+            self.settings.sleep_mode.sleep(clock.now(), interval);
+        }
+        // info is compiled out of release builds, and this is what an
+        // operator wants to see after a run
+        warn!("Stopping, run summary: {}", self.summary);
+        if let Err(e) = sink.flush() {
+            warn!("Could not write out the last samples: {e}");
+        }
+        if let Some(fanout) = self.fanout.take() {
+            fanout.close();
+        }
+        Ok(())
+    }
+
+    fn acquire<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        _config: &Config,
+        epoch: u64,
+    ) -> Result<()> {
+        for collector in self.settings.collectors.clone() {
+            let start = Instant::now();
+            match collector {
+                Collector::Cpu => self.acquire_cpu(handle, epoch)?,
+                Collector::Ctxt => self.write_counter(handle, Counter::Ctxt)?,
+                Collector::Intr => self.write_counter(handle, Counter::Intr)?,
+                Collector::Forks => self.write_counter(handle, Counter::Forks)?,
+                Collector::Procs => procs::write(handle, &self.stat, epoch)?,
+                #[cfg(feature = "load")]
+                Collector::Load => loadavg::write(handle, &self.settings.proc_root, epoch)?,
+                #[cfg(feature = "temp")]
+                Collector::Temp => self.temperatures.write(handle, epoch)?,
+                #[cfg(feature = "freq")]
+                Collector::Freq => self.frequencies.write(handle, epoch, &self.busy)?,
+                #[cfg(feature = "psi")]
+                Collector::Psi => self.psi.write(handle, &self.settings.proc_root, epoch)?,
+                #[cfg(feature = "irq")]
+                Collector::Irq => self.interrupts.write(
+                    handle,
+                    &self.settings.proc_root,
+                    epoch,
+                    self.settings.max_gap(),
+                )?,
+                #[cfg(feature = "softirq")]
+                Collector::Softirq => self.softirqs.write(
+                    handle,
+                    &self.settings.proc_root,
+                    epoch,
+                    self.settings.max_gap(),
+                )?,
+                #[cfg(feature = "cgroup")]
+                Collector::Cgroup => self.cgroups.write(handle, epoch, self.settings.max_gap())?,
+                #[cfg(feature = "top")]
+                Collector::Top => self.top.write(
+                    handle,
+                    &self.settings.proc_root,
+                    epoch,
+                    self.settings.max_gap(),
+                )?,
+            }
+            self.durations.insert(collector, start.elapsed());
+        }
+        if self.settings.self_metrics {
+            writeln!(handle, "multigraph cpu1sec_self")?;
+            for (collector, duration) in &self.durations {
+                writeln!(
+                    handle,
+                    "{}.value {}:{}",
+                    collector.name(),
+                    epoch,
+                    duration.as_micros()
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Build KernelStats from the given cpu lines of a /proc/stat
+#[cfg(test)]
+fn kernel_stats(cpus: &str, btime: u64) -> KernelStats {
+    let stat = format!("{cpus}\nctxt 1\nbtime {btime}\nprocesses 1\n");
+    KernelStats::from_reader(stat.as_bytes()).unwrap()
+}
+
+/// A proc root in the temp dir with nothing but a stat file with the
+/// given cpu lines, for the tests that read /proc/stat themselves.
+/// Up to them to remove it.
+#[cfg(test)]
+fn fixture_root(name: &str, cpus: &str) -> std::path::PathBuf {
+    let root = std::env::temp_dir().join(format!("cpu1sec-{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(
+        root.join("stat"),
+        format!("{cpus}\nctxt 1\nbtime 1000\nprocesses 1\n"),
+    )
+    .unwrap();
+    root
+}
+
+#[test]
+fn test_btime_reset() {
+    let mut cpu = CpuPlugin::with_stats(
+        Settings::default(),
+        kernel_stats("cpu  10 0 10 100 0 0 0 0 0 0", 1000),
+        1,
+    );
+
+    let mut handle = BufWriter::new(Vec::new());
+    let ks = kernel_stats("cpu  20 0 20 200 0 0 0 0 0 0", 1000);
+    cpu.write_cpu(&mut handle, ks, 2).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.contains("total_user.value 2:10\n"));
+
+    // Rebooted, so counters are lower and btime differs
+    let mut handle = BufWriter::new(Vec::new());
+    let ks = kernel_stats("cpu  5 0 5 50 0 0 0 0 0 0", 2000);
+    cpu.write_cpu(&mut handle, ks, 3).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert_eq!(10, values.lines().count());
+    assert!(values.lines().all(|l| l.ends_with(".value 3:U")));
+    assert_eq!(2000, cpu.btime);
+    assert_eq!(5, cpu.old[0].user);
+}
+
+#[test]
+fn test_counters_backwards() {
+    let mut cpu = CpuPlugin::with_stats(
+        Settings::default(),
+        kernel_stats("cpu  10 0 10 100 5 0 0 0 0 0", 1000),
+        1,
+    );
+
+    // iowait went backwards, same boot
+    let mut handle = BufWriter::new(Vec::new());
+    let ks = kernel_stats("cpu  20 0 20 200 4 0 0 0 0 0", 1000);
+    cpu.write_cpu(&mut handle, ks, 2).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert_eq!(10, values.lines().count());
+    assert!(values.lines().all(|l| l.ends_with(".value 2:U")));
+    assert_eq!(1, cpu.summary.resets);
+    assert_eq!(4, cpu.old[0].iowait);
+
+    // And we go on from there
+    let mut handle = BufWriter::new(Vec::new());
+    let ks = kernel_stats("cpu  30 0 20 300 4 0 0 0 0 0", 1000);
+    cpu.write_cpu(&mut handle, ks, 3).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.contains("total_user.value 3:10\n"));
+    assert!(values.contains("total_iowait.value 3:0\n"));
+}
+
+#[test]
+fn test_gap() {
+    let mut cpu = CpuPlugin::with_stats(
+        Settings::default(),
+        kernel_stats("cpu  10 0 10 100 5 0 0 0 0 0", 1000),
+        1,
+    );
+
+    // Five seconds are still fine
+    let mut handle = BufWriter::new(Vec::new());
+    let ks = kernel_stats("cpu  20 0 20 200 5 0 0 0 0 0", 1000);
+    cpu.write_cpu(&mut handle, ks, 6).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.contains("total_user.value 6:10\n"));
+
+    // An hour of suspend is not
+    let mut handle = BufWriter::new(Vec::new());
+    let ks = kernel_stats("cpu  5020 0 20 300 5 0 0 0 0 0", 1000);
+    cpu.write_cpu(&mut handle, ks, 3606).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.lines().all(|l| l.ends_with(".value 3606:U")));
+    assert_eq!(1, cpu.summary.gaps);
+    assert_eq!(0, cpu.summary.resets);
+
+    // Back to normal with the next one
+    let mut handle = BufWriter::new(Vec::new());
+    let ks = kernel_stats("cpu  5030 0 20 300 5 0 0 0 0 0", 1000);
+    cpu.write_cpu(&mut handle, ks, 3607).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.contains("total_user.value 3607:10\n"));
+}
+
+#[test]
+fn test_compat_munin_cpu() {
+    let stock = [
+        "system", "user", "nice", "idle", "iowait", "irq", "softirq", "steal", "guest",
+    ];
+    let stat = CpuStat {
+        compat: Compat::MuninCpu,
+        ..Default::default()
+    };
+    let values = stat.to_string();
+    assert!(!values.contains("total_"));
+    for field in stock {
+        assert!(values
+            .lines()
+            .any(|l| l.starts_with(&format!("{field}.value "))));
+    }
+
+    let mut cpu = CpuPlugin::with_stats(
+        Settings {
+            compat: Compat::MuninCpu,
+            source: Source::Proc,
+            guest_fields: Some(true),
+            ..Default::default()
+        },
+        kernel_stats("cpu  10 0 10 100 0 0 0 0 0 0", 1000),
+        1,
+    );
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.config(&mut handle).unwrap();
+    let config = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(!config.contains("total_"));
+    for field in stock {
+        assert!(config.contains(&format!("\n{field}.label {field}\n")));
+    }
+    // The stock plugin's graph, with its type, so munin goes on with
+    // its RRDs
+    let graph = config
+        .split("multigraph ")
+        .find(|graph| graph.starts_with("cpu\n"))
+        .unwrap();
+    assert!(!graph.contains("update_rate"));
+    for field in stock {
+        assert!(graph.contains(&format!("\n{field}.label {field}\n")));
+        assert!(graph.contains(&format!("\n{field}.type DERIVE\n")));
+    }
+    assert!(config.contains("multigraph cpu1sec\n"));
+
+    // The counters, not their differences
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.write_cpu(
+        &mut handle,
+        kernel_stats("cpu  15 0 12 190 1 0 0 0 0 0", 1000),
+        2,
+    )
+    .unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.starts_with(
+        "multigraph cpu\nsystem.value 2:12\nuser.value 2:15\nnice.value 2:0\nidle.value 2:190\niowait.value 2:1\n"
+    ));
+    assert!(values.contains("multigraph cpu1sec\nuser.value 2:5\n"));
+}
+
+#[test]
+fn test_max_core_graphs() {
+    let settings = Settings {
+        cpudetail: true,
+        max_core_graphs: 2,
+        ..Default::default()
+    };
+    let ks = kernel_stats(
+        "cpu  100 0 50 400 0 0 0 0 0 0\n\
+         cpu0 10 0 5 40 0 0 0 0 0 0\n\
+         cpu1 20 0 10 80 0 0 0 0 0 0\n\
+         cpu2 30 0 15 120 0 0 0 0 0 0\n\
+         cpu3 40 0 20 160 0 0 0 0 0 0",
+        1000,
+    );
+    let cpu = CpuPlugin::with_stats(settings, ks, 1);
+    let stats = cpu.graphs(cpu.old.clone());
+    let names: Vec<String> = stats.iter().map(|stat| stat.cpu.to_string()).collect();
+    assert_eq!(vec!["cpu0", "cpu1", "others", "total"], names);
+    let others = stats[2];
+    assert_eq!((70, 35, 280), (others.user, others.system, others.idle));
+    assert!(others.to_string().contains("multigraph cpu1sec.others\n"));
+    assert!(others.to_string().contains("others_user.value 1:70\n"));
+}
+
+#[test]
+fn test_sample_epoch() {
+    // Everything derived from the sample, down to the folded graphs,
+    // carries the epoch it was taken at
+    let settings = Settings {
+        cpudetail: true,
+        max_core_graphs: 1,
+        aggregate: Some("0-1".parse().unwrap()),
+        steal_graph: true,
+        ..Default::default()
+    };
+    let ks = |user: u64| {
+        kernel_stats(
+            &format!(
+                "cpu  {user} 0 10 100 0 0 0 0 0 0\n\
+                 cpu0 {user} 0 5 50 0 0 0 0 0 0\n\
+                 cpu1 {user} 0 5 50 0 0 0 0 0 0\n\
+                 cpu2 {user} 0 5 50 0 0 0 0 0 0"
+            ),
+            1000,
+        )
+    };
+    let mut cpu = CpuPlugin::with_stats(settings, ks(10), 1);
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.write_cpu(&mut handle, ks(20), 42).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    let values: Vec<&str> = values.lines().filter(|l| l.contains(".value ")).collect();
+    assert!(values.iter().any(|l| l.starts_with("others_")));
+    assert!(values.iter().any(|l| l.starts_with("aggregate_")));
+    assert!(values
+        .iter()
+        .all(|l| l.split(' ').nth(1).unwrap().starts_with("42:")));
+}
+
+#[test]
+fn test_steal_values() {
+    let settings = Settings {
+        cpudetail: true,
+        steal_graph: true,
+        ..Default::default()
+    };
+    let ks = |[total, cpu0, cpu1]: [&str; 3], btime| {
+        kernel_stats(&format!("cpu  {total}\ncpu0 {cpu0}\ncpu1 {cpu1}"), btime)
+    };
+    let start = [
+        "20 0 20 200 0 0 0 0 0 0",
+        "10 0 10 100 0 0 0 0 0 0",
+        "10 0 10 100 0 0 0 0 0 0",
+    ];
+    let mut cpu = CpuPlugin::with_stats(settings, ks(start, 1000), 1);
+    let mut handle = BufWriter::new(Vec::new());
+    // cpu0 had 10 of its 100 ticks stolen, cpu1 none of its 100
+    let stolen = [
+        "210 0 20 200 0 0 0 10 0 0",
+        "100 0 10 100 0 0 0 10 0 0",
+        "110 0 10 100 0 0 0 0 0 0",
+    ];
+    cpu.write_cpu(&mut handle, ks(stolen, 1000), 2).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(
+        values.ends_with(
+            "multigraph cpu1sec_steal\n\
+             total.value 2:5.00\n\
+             cpu1.value 2:0.00\n\
+             cpu0.value 2:10.00\n"
+        ),
+        "{values}"
+    );
+
+    // Rebooted, nothing to say about this second, in no graph
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.write_cpu(&mut handle, ks(start, 2000), 3).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.ends_with(
+        "multigraph cpu1sec_steal\n\
+         total.value 3:U\n\
+         cpu1.value 3:U\n\
+         cpu0.value 3:U\n"
+    ));
+    assert!(values
+        .lines()
+        .filter(|l| !l.starts_with("multigraph "))
+        .all(|l| l.ends_with(":U")));
+}
+
+#[test]
+fn test_aggregate_fn() {
+    let ks = |cpu0: u64, cpu1: u64| {
+        kernel_stats(
+            &format!(
+                "cpu  {} 0 0 1000 0 0 0 0 0 0\n\
+                 cpu0 {cpu0} 0 0 500 0 0 0 0 0 0\n\
+                 cpu1 {cpu1} 0 0 500 0 0 0 0 0 0",
+                cpu0 + cpu1
+            ),
+            1000,
+        )
+    };
+    let sample = |aggregate_fn| {
+        let settings = Settings {
+            aggregate: Some("0-1".parse().unwrap()),
+            aggregate_fn,
+            ..Default::default()
+        };
+        let mut cpu = CpuPlugin::with_stats(settings, ks(100, 100), 1);
+        let mut handle = BufWriter::new(Vec::new());
+        cpu.write_cpu(&mut handle, ks(160, 121), 2).unwrap();
+        let mut config = BufWriter::new(Vec::new());
+        cpu.config(&mut config).unwrap();
+        let config = String::from_utf8(config.into_inner().unwrap()).unwrap();
+        let (_, config) = config.split_once("multigraph cpu1sec.aggregate\n").unwrap();
+        (
+            String::from_utf8(handle.into_inner().unwrap()).unwrap(),
+            config.to_string(),
+        )
+    };
+
+    let (values, config) = sample(AggregateFn::Sum);
+    assert!(values.contains("aggregate_user.value 2:81\n"));
+    assert!(config.contains("--upper-limit 200\n"));
+    let (values, config) = sample(AggregateFn::Avg);
+    // Rounded down, like ticks are
+    assert!(values.contains("aggregate_user.value 2:40\n"));
+    assert!(config.contains("--upper-limit 100\n"));
+    // The total stays the whole machine
+    assert!(values.contains("total_user.value 2:81\n"));
+}
+
+#[test]
+fn test_group_by_policy() {
+    let ks = || {
+        kernel_stats(
+            "cpu  100 0 50 400 0 0 0 0 0 0\n\
+             cpu0 10 0 5 40 0 0 0 0 0 0\n\
+             cpu1 20 0 10 80 0 0 0 0 0 0\n\
+             cpu2 30 0 15 120 0 0 0 0 0 0\n\
+             cpu3 40 0 20 160 0 0 0 0 0 0",
+            1000,
+        )
+    };
+    let policies = BTreeMap::from([(0, "0-1".parse().unwrap()), (2, "2-3".parse().unwrap())]);
+    let config = |cpu: &CpuPlugin| {
+        let mut handle = BufWriter::new(Vec::new());
+        cpu.config(&mut handle).unwrap();
+        String::from_utf8(handle.into_inner().unwrap()).unwrap()
+    };
+
+    let settings = Settings {
+        cpudetail: true,
+        ..Default::default()
+    };
+    let mut cpu = CpuPlugin::with_stats(settings.clone(), ks(), 1);
+    cpu.policies = policies.clone();
+    assert!(config(&cpu).contains(
+        "\ngraph_info This graph shows how CPU time is spent. \
+         cpu3 scales its frequency with the cores of cpufreq policy2.\n"
+    ));
+
+    let settings = Settings {
+        group_by: GroupBy::Policy,
+        ..settings
+    };
+    let mut cpu = CpuPlugin::with_stats(settings, ks(), 1);
+    cpu.policies = policies;
+    let stats = cpu.graphs(cpu.old.clone());
+    let names: Vec<String> = stats.iter().map(|stat| stat.cpu.to_string()).collect();
+    assert_eq!(vec!["policy0", "policy2", "total"], names);
+    assert_eq!(
+        (30, 15, 120),
+        (stats[0].user, stats[0].system, stats[0].idle)
+    );
+    assert_eq!(
+        (70, 35, 280),
+        (stats[1].user, stats[1].system, stats[1].idle)
+    );
+    let config = config(&cpu);
+    assert!(config.contains("\nmultigraph cpu1sec.policy2\n"));
+    assert!(!config.contains("cpu1sec.cpu"));
+}
+
+#[test]
+fn test_group_by_node() {
+    let ks = kernel_stats(
+        "cpu  100 0 50 400 0 0 0 0 0 0\n\
+         cpu0 10 0 5 40 0 0 0 0 0 0\n\
+         cpu1 20 0 10 80 0 0 0 0 0 0\n\
+         cpu2 30 0 15 120 0 0 0 0 0 0\n\
+         cpu3 40 0 20 160 0 0 0 0 0 0",
+        1000,
+    );
+    let settings = Settings {
+        cpudetail: true,
+        group_by: GroupBy::Node,
+        aggregate_fn: AggregateFn::Avg,
+        ..Default::default()
+    };
+    let mut cpu = CpuPlugin::with_stats(settings, ks, 1);
+    // Interleaved, as some firmware numbers them
+    cpu.nodes = BTreeMap::from([(0, "0,2".parse().unwrap()), (1, "1,3".parse().unwrap())]);
+    let stats = cpu.graphs(cpu.old.clone());
+    let names: Vec<String> = stats.iter().map(|stat| stat.cpu.to_string()).collect();
+    assert_eq!(vec!["node0", "node1", "total"], names);
+    assert_eq!(
+        (20, 10, 80),
+        (stats[0].user, stats[0].system, stats[0].idle)
+    );
+    assert_eq!(
+        (30, 15, 120),
+        (stats[1].user, stats[1].system, stats[1].idle)
+    );
+
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.config(&mut handle).unwrap();
+    let config = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(config.contains("\nmultigraph cpu1sec.node1\n"));
+    assert!(config.contains(" Sum of the cores of NUMA node1, which share its memory.\n"));
+    // Averaged, so one core
+    assert!(config.contains("--upper-limit 100\n"));
+    assert!(!config.contains("cpu1sec.cpu"));
+}
+
+#[test]
+fn test_aggregate() {
+    let settings = Settings {
+        aggregate: Some("2-3".parse().unwrap()),
+        ..Default::default()
+    };
+    let cores = |cpu2: u64, cpu3: Option<u64>| {
+        let mut stat = format!(
+            "cpu  1000 0 0 1000 0 0 0 0 0 0\n\
+             cpu0 100 0 0 100 0 0 0 0 0 0\n\
+             cpu1 100 0 0 100 0 0 0 0 0 0\n\
+             cpu2 {cpu2} 0 0 100 0 0 0 0 0 0"
+        );
+        if let Some(cpu3) = cpu3 {
+            stat.push_str(&format!("\ncpu3 {cpu3} 0 0 100 0 0 0 0 0 0"));
+        }
+        kernel_stats(&stat, 1000)
+    };
+    let mut cpu = CpuPlugin::with_stats(settings, cores(100, Some(100)), 1);
+
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.write_cpu(&mut handle, cores(130, Some(150)), 2)
+        .unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.starts_with("multigraph cpu1sec.aggregate\n"));
+    assert!(values.contains("aggregate_user.value 2:80\n"));
+    assert!(values.contains("\nmultigraph cpu1sec\n"));
+    assert!(!values.contains("cpu0_"));
+
+    // cpu3 went offline, only cpu2 counts
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.write_cpu(&mut handle, cores(140, None), 3).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.contains("aggregate_user.value 3:10\n"));
+}
+
+#[test]
+fn test_broken_core_line() {
+    let settings = Settings {
+        cpudetail: true,
+        ..Default::default()
+    };
+    let good = "cpu  20 0 20 200 0 0 0 0 0 0\n\
+                cpu0 10 0 10 100 0 0 0 0 0 0\n\
+                cpu1 10 0 10 100 0 0 0 0 0 0\n\
+                ctxt 1\nbtime 1000\nprocesses 1\n";
+    let mut cpu = CpuPlugin::with_stats(
+        settings,
+        KernelStats::from_reader(good.as_bytes()).unwrap(),
+        1,
+    );
+
+    let broken = "cpu  40 0 40 400 0 0 0 0 0 0\n\
+                  cpu0 20 0 20 200 0 0 0 0 0 0\n\
+                  cpu1 20 0 garbage\n\
+                  ctxt 1\nbtime 1000\nprocesses 1\n";
+    let ks = cpu.parse_stat(broken).unwrap();
+    assert!(ks.cpu_time.is_empty());
+    assert!(cpu.core_errors_logged);
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.write_cpu(&mut handle, ks, 2).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.starts_with("multigraph cpu1sec\n"));
+    assert!(values.contains("total_user.value 2:20\n"));
+    assert!(!values.contains("cpu0_"));
+
+    // A broken total is still an error
+    assert!(cpu.parse_stat("cpu  garbage\nbtime 1000\n").is_err());
+}
+
+#[test]
+fn test_minimal_container() {
+    // No ctxt, btime or processes, no guest columns
+    let stat = |user| {
+        format!(
+            "cpu  {} 0 20 200 0 0 0 0\n\
+             cpu0 {user} 0 10 100 0 0 0 0\n\
+             cpu1 10 0 10 100 0 0 0 0",
+            user + 10
+        )
+    };
+    let settings = Settings {
+        cpudetail: true,
+        guest_fields: Some(true),
+        ..Default::default()
+    };
+    let mut cpu = CpuPlugin::from_stat(settings, &stat(10), 1).unwrap();
+    assert_eq!(Some(false), cpu.settings.guest_fields);
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.config(&mut handle).unwrap();
+    let config = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(config.contains("cpu1_user.label"));
+    assert!(!config.contains("guest"));
+
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.write_stat(&mut handle, &stat(15), 2).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.contains("total_user.value 2:5\n"));
+    assert!(values.contains("cpu0_user.value 2:5\n"));
+    assert!(!values.contains("guest"));
+}
+
+#[test]
+fn test_stat_graphs() {
+    let stat = |ctxt| {
+        format!(
+            "cpu  10 0 10 100 0 0 0 0 0 0\n\
+             intr {} 1 2\n\
+             ctxt {ctxt}\n\
+             btime 1000\nprocesses 1\n",
+            ctxt * 2
+        )
+    };
+    let settings = Settings {
+        collectors: [
+            Collector::Cpu,
+            Collector::Ctxt,
+            Collector::Intr,
+            Collector::Forks,
+            Collector::Procs,
+        ]
+        .into_iter()
+        .collect(),
+        ..Default::default()
+    };
+    let mut cpu = CpuPlugin::from_stat(settings, &stat(100), 1).unwrap();
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.config(&mut handle).unwrap();
+    let config = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(config.starts_with("multigraph cpu1sec\n"));
+    assert!(config.contains("multigraph ctxt1sec\n"));
+    assert!(config.contains("multigraph intr1sec\n"));
+    assert!(config.contains("multigraph forks1sec\n"));
+    assert!(config.contains("multigraph procs1sec\n"));
+
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.write_stat(&mut handle, &stat(400), 3).unwrap();
+    // Twice in the same second, the first one counts
+    cpu.write_stat(&mut handle, &stat(500), 3).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.contains("multigraph ctxt1sec\nctxt.value 3:150\n"));
+    assert!(values.contains("multigraph intr1sec\nintr.value 3:300\n"));
+    assert!(values.contains("multigraph forks1sec\nforks.value 3:0\n"));
+    assert_eq!(1, values.matches("multigraph ctxt1sec\n").count());
+    // No procs_running in there
+    assert!(values.contains("multigraph procs1sec\nrunning.value 3:U\n"));
+}
+
+#[test]
+fn test_only_ctxt() {
+    let stat = |ctxt| {
+        format!(
+            "cpu  10 0 10 100 0 0 0 0 0 0\n\
+             intr {ctxt} 1 2\n\
+             ctxt {ctxt}\n\
+             btime 1000\nprocesses {ctxt}\n"
+        )
+    };
+    let (settings, errors) =
+        Settings::from_vars(|name| (name == "ctxt").then(|| String::from("1")));
+    assert!(errors.is_empty());
+    assert_eq!(
+        vec![Collector::Cpu, Collector::Ctxt],
+        settings.collectors.iter().copied().collect::<Vec<_>>()
+    );
+    let mut cpu = CpuPlugin::from_stat(settings, &stat(100), 1).unwrap();
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.config(&mut handle).unwrap();
+    let config = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(config.contains("multigraph ctxt1sec\n"));
+    assert!(!config.contains("intr"));
+    assert!(!config.contains("forks"));
+
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.write_stat(&mut handle, &stat(400), 4).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.contains("multigraph ctxt1sec\nctxt.value 4:100\n"));
+    assert!(!values.contains("intr"));
+    assert!(!values.contains("forks"));
+}
+
+#[test]
+fn test_retention() {
+    for (retention, expected) in [
+        ("short", "custom 1h, 10s for 1d, 1m for 1w, 5m for 1t"),
+        (
+            "default",
+            "custom 1d, 1s for 1d, 5s for 2d, 10s for 7d, 1m for 1t, 5m for 1y",
+        ),
+        ("long", "custom 1w, 5s for 1t, 1m for 1y, 5m for 2y"),
+        ("custom 2d, 10s for 1w", "custom 2d, 10s for 1w"),
+    ] {
+        let cpu = CpuPlugin::with_stats(
+            Settings {
+                retention: retention.parse().unwrap(),
+                source: Source::Proc,
+                guest_fields: Some(true),
+                ..Default::default()
+            },
+            kernel_stats("cpu  10 0 10 100 0 0 0 0 0 0", 1000),
+            1,
+        );
+        let mut handle = BufWriter::new(Vec::new());
+        cpu.config(&mut handle).unwrap();
+        let config = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+        assert!(config.contains(&format!("\ngraph_data_size {expected}\n")));
+    }
+    assert!("forever".parse::<Retention>().is_err());
+}
+
+#[test]
+fn test_prime() {
+    let settings = Settings {
+        prime: true,
+        ..Default::default()
+    };
+    let mut cpu = CpuPlugin::with_stats(
+        settings,
+        kernel_stats("cpu  100000 0 50000 900000 0 0 0 0 0 0", 1000),
+        1,
+    );
+    let diff = cpu
+        .prime(
+            kernel_stats("cpu  100003 0 50001 900006 0 0 0 0 0 0", 1000),
+            1,
+        )
+        .unwrap();
+    assert_eq!(1, diff.len());
+    assert_eq!((3, 1, 6), (diff[0].user, diff[0].system, diff[0].idle));
+    assert_eq!(10, diff[0].ticks());
+    // The second read is the baseline now
+    assert_eq!(100003, cpu.old[0].user);
+}
+
+#[test]
+fn test_clamp_max() {
+    let config = |clamp_max| {
+        let cpu = CpuPlugin::with_stats(
+            Settings {
+                cpudetail: true,
+                clamp_max,
+                source: Source::Proc,
+                guest_fields: Some(true),
+                ..Default::default()
+            },
+            kernel_stats(
+                "cpu  20 0 20 200 0 0 0 0 0 0\n\
+                 cpu0 10 0 10 100 0 0 0 0 0 0\n\
+                 cpu1 10 0 10 100 0 0 0 0 0 0",
+                1000,
+            ),
+            1,
+        );
+        let mut handle = BufWriter::new(Vec::new());
+        cpu.config(&mut handle).unwrap();
+        String::from_utf8(handle.into_inner().unwrap()).unwrap()
+    };
+    let clamped = config(true);
+    assert!(clamped.contains("\ntotal_user.max 200\n"));
+    assert!(clamped.contains("\ncpu0_guest_nice.max 100\n"));
+    assert!(!config(false).contains(".max "));
+}
+
+#[test]
+fn test_summary() {
+    let two = "cpu  20 0 20 200 0 0 0 0 0 0\n\
+               cpu0 10 0 10 100 0 0 0 0 0 0\n\
+               cpu1 10 0 10 100 0 0 0 0 0 0";
+    let one = "cpu  30 0 30 300 0 0 0 0 0 0\n\
+               cpu0 20 0 20 200 0 0 0 0 0 0";
+    let mut cpu = CpuPlugin::with_stats(Settings::default(), kernel_stats(two, 1000), 1);
+    let mut handle = BufWriter::new(Vec::new());
+    for (epoch, (stat, btime)) in (2..).zip([(two, 1000), (one, 1000), (two, 2000), (two, 2000)]) {
+        cpu.write_cpu(&mut handle, kernel_stats(stat, btime), epoch)
+            .unwrap();
+    }
+    cpu.summary.acquired(Duration::from_millis(3));
+    cpu.summary.acquired(Duration::from_millis(2));
+    cpu.summary.late(Duration::from_millis(40));
+    assert_eq!(
+        Summary {
+            samples: 3,
+            resets: 1,
+            gaps: 0,
+            hotplugs: 2,
+            peak_acquire: Duration::from_millis(3),
+            max_lateness: Duration::from_millis(40),
+        },
+        cpu.summary
+    );
+    assert_eq!(
+        "3 samples, 1 counter resets, 0 gaps, 2 hotplug changes, peak acquire 3ms, max lateness 40ms",
+        cpu.summary.to_string()
+    );
+}
+
+#[test]
+fn test_config_stale() {
+    let two = "cpu  20 0 20 200 0 0 0 0 0 0\n\
+               cpu0 10 0 10 100 0 0 0 0 0 0\n\
+               cpu1 10 0 10 100 0 0 0 0 0 0";
+    let one = "cpu  30 0 30 300 0 0 0 0 0 0\n\
+               cpu0 20 0 20 200 0 0 0 0 0 0";
+    let mut cpu = CpuPlugin::with_stats(Settings::default(), kernel_stats(two, 1000), 1);
+    assert!(!cpu.config_stale());
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.write_cpu(&mut handle, kernel_stats(one, 1000), 2)
+        .unwrap();
+    assert!(cpu.config_stale());
+    cpu.write_cpu(&mut handle, kernel_stats(two, 1000), 3)
+        .unwrap();
+    assert!(!cpu.config_stale());
+}
+
+#[test]
+fn test_hotplug() {
+    let two = "cpu  20 0 20 200 0 0 0 0 0 0\n\
+               cpu0 10 0 10 100 0 0 0 0 0 0\n\
+               cpu1 10 0 10 100 0 0 0 0 0 0";
+    let one = "cpu  30 0 30 300 0 0 0 0 0 0\n\
+               cpu0 20 0 20 200 0 0 0 0 0 0";
+    let back = "cpu  50 0 50 500 0 0 0 0 0 0\n\
+                cpu0 30 0 30 300 0 0 0 0 0 0\n\
+                cpu1 20 0 20 200 0 0 0 0 0 0";
+    let settings = Settings {
+        cpudetail: true,
+        ..Default::default()
+    };
+    let mut cpu = CpuPlugin::with_stats(settings, kernel_stats(two, 1000), 1);
+    let values = |cpu: &mut CpuPlugin, stat, epoch| {
+        let mut handle = BufWriter::new(Vec::new());
+        cpu.write_cpu(&mut handle, kernel_stats(stat, 1000), epoch)
+            .unwrap();
+        String::from_utf8(handle.into_inner().unwrap()).unwrap()
+    };
+
+    // cpu1 went away, cpu0 is still diffed against cpu0
+    let out = values(&mut cpu, one, 2);
+    assert!(out.contains("cpu0_user.value 2:10\n"));
+    assert!(!out.contains("cpu1_"));
+    assert_eq!(1, cpu.old.iter().filter(|s| s.cpu != CpuId::Total).count());
+
+    // cpu1 is back, but there is nothing to diff it against yet
+    let out = values(&mut cpu, back, 3);
+    assert!(out.contains("cpu0_user.value 3:10\n"));
+    assert!(!out.contains("cpu1_"));
+    let out = values(&mut cpu, back, 4);
+    assert!(out.contains("cpu1_user.value 4:0\n"));
+}
+
+#[test]
+fn test_non_contiguous_cores() {
+    let stat = |user| {
+        format!(
+            "cpu  {} 0 30 300 0 0 0 0 0 0\n\
+             cpu0 {user} 0 10 100 0 0 0 0 0 0\n\
+             cpu2 10 0 10 100 0 0 0 0 0 0\n\
+             cpu5 10 0 10 100 0 0 0 0 0 0\n\
+             ctxt 1\nbtime 1000\nprocesses 1\n",
+            user + 20
+        )
+    };
+    let settings = Settings {
+        cpudetail: true,
+        aggregate: Some("2,5".parse().unwrap()),
+        ..Default::default()
+    };
+    let mut cpu = CpuPlugin::from_stat(settings, &stat(10), 1).unwrap();
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.config(&mut handle).unwrap();
+    let config = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    for core in ["cpu0", "cpu2", "cpu5"] {
+        assert!(config.contains(&format!("multigraph cpu1sec.{core}\n")));
+        assert!(config.contains(&format!("{core}_user.label")));
+    }
+    assert!(!config.contains("cpu1_"));
+
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.write_stat(&mut handle, &stat(15), 2).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.contains("multigraph cpu1sec.cpu5\ncpu5_user.value 2:0\n"));
+    assert!(values.contains("cpu0_user.value 2:5\n"));
+    assert!(values.contains("multigraph cpu1sec.aggregate\naggregate_user.value 2:0\n"));
+}
+
+#[test]
+fn test_once() {
+    let root = fixture_root("once", "cpu  10 0 10 100 0 0 0 0 0 0");
+    let mut cpu = CpuPlugin::try_new(Settings {
+        interval: crate::MIN_INTERVAL,
+        source: Source::Proc,
+        proc_root: root.clone(),
+        ..Default::default()
+    })
+    .unwrap();
+    let started = Instant::now();
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.once(&mut handle, &Config::new(String::from("cpu1sec")))
+        .unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+    assert!(started.elapsed() >= crate::MIN_INTERVAL);
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.contains("total_user.value "));
+    // The fixture stays as it is, no time passed in there
+    assert!(values.lines().all(|line| line.ends_with(":0")));
+    assert_eq!(1, cpu.summary.samples);
+}
+
+#[test]
+fn test_selected_cores() {
+    let stat = |user| {
+        format!(
+            "cpu  {} 0 30 300 0 0 0 0 0 0\n\
+             cpu0 10 0 10 100 0 0 0 0 0 0\n\
+             cpu1 {user} 0 10 100 0 0 0 0 0 0\n\
+             cpu2 10 0 10 100 0 0 0 0 0 0\n\
+             cpu3 10 0 10 100 0 0 0 0 0 0\n\
+             ctxt 1\nbtime 1000\nprocesses 1\n",
+            user + 30
+        )
+    };
+    let settings = Settings {
+        cpudetail: true,
+        cores: Some("1,3".parse().unwrap()),
+        aggregate: Some("0-1".parse().unwrap()),
+        ..Default::default()
+    };
+    let mut cpu = CpuPlugin::from_stat(settings, &stat(10), 1).unwrap();
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.config(&mut handle).unwrap();
+    let config = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(config.contains("multigraph cpu1sec.cpu1\n"));
+    assert!(config.contains("multigraph cpu1sec.cpu3\n"));
+    assert!(!config.contains("cpu1sec.cpu0\n"));
+    assert!(!config.contains("cpu2_"));
+
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.write_stat(&mut handle, &stat(15), 2).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.contains("cpu1_user.value 2:5\n"));
+    assert!(!values.contains("cpu0_"));
+    // Only cpu1 of the aggregate is selected, the total has them all
+    assert!(values.contains("aggregate_user.value 2:5\n"));
+    assert!(values.contains("total_user.value 2:5\n"));
+}
+
+#[test]
+fn test_graph_titles() {
+    let cpu = CpuPlugin::with_stats(
+        Settings {
+            cpudetail: true,
+            steal_graph: true,
+            graph_title: String::from("{cpu} every second"),
+            graph_category: String::from("1sec"),
+            graph_info: String::from("Where {cpu} spends its time."),
+            ..Default::default()
+        },
+        kernel_stats(
+            "cpu  10 0 10 100 0 0 0 0 0 0\ncpu0 10 0 10 100 0 0 0 0 0 0",
+            1000,
+        ),
+        1,
+    );
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.config(&mut handle).unwrap();
+    let config = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(config.contains("graph_title cpu0 every second\n"));
+    assert!(config.contains("graph_title total every second\n"));
+    assert!(config.contains("graph_info Where cpu0 spends its time.\n"));
+    assert!(!config.contains("graph_category system"));
+    assert_eq!(3, config.matches("graph_category 1sec\n").count());
+}
+
+#[test]
+fn test_thresholds_config() {
+    let cpu = CpuPlugin::with_stats(
+        Settings {
+            cpudetail: true,
+            warning: BTreeMap::from([(Field::Iowait, String::from("30"))]),
+            critical: BTreeMap::from([(Field::Steal, String::from("10"))]),
+            ..Default::default()
+        },
+        kernel_stats(
+            "cpu  10 0 10 100 0 0 0 0 0 0\ncpu0 10 0 10 100 0 0 0 0 0 0",
+            1000,
+        ),
+        1,
+    );
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.config(&mut handle).unwrap();
+    let config = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(config.contains("cpu0_iowait.warning 30\n"));
+    assert!(config.contains("total_iowait.warning 30\n"));
+    assert!(config.contains("cpu0_steal.critical 10\n"));
+    assert_eq!(2, config.matches(".warning ").count());
+    assert!(!config.contains("user.warning"));
+}
+
+#[cfg(feature = "freq")]
+#[test]
+fn test_busy_for_freq() {
+    // No per-core graphs, still the usage of every core
+    let stat = |user, idle| {
+        format!(
+            "cpu  {user} 0 0 {} 0 0 0 0 0 0\n\
+             cpu0 {user} 0 0 {idle} 0 0 0 0 0 0\n\
+             cpu1 0 0 0 {idle} 0 0 0 0 0 0",
+            idle * 2
+        )
+    };
+    let mut cpu = CpuPlugin::with_stats(
+        Settings {
+            collectors: [Collector::Cpu, Collector::Freq].into_iter().collect(),
+            ..Default::default()
+        },
+        kernel_stats(&stat(0, 100), 1000),
+        1,
+    );
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.write_cpu(&mut handle, kernel_stats(&stat(90, 110), 1000), 2)
+        .unwrap();
+    assert_eq!(BTreeMap::from([(0, 90.0), (1, 0.0)]), cpu.busy);
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(!values.contains("cpu1sec.cpu0"));
+}
+
+#[test]
+fn test_upper_limit_online() {
+    // Whatever /proc/cpuinfo says, three CPUs are online
+    let cpu = CpuPlugin::with_stats(
+        Settings {
+            cpudetail: true,
+            ..Default::default()
+        },
+        kernel_stats(
+            "cpu  30 0 30 300 0 0 0 0 0 0\n\
+             cpu0 10 0 10 100 0 0 0 0 0 0\n\
+             cpu2 10 0 10 100 0 0 0 0 0 0\n\
+             cpu3 10 0 10 100 0 0 0 0 0 0",
+            1000,
+        ),
+        1,
+    );
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.config(&mut handle).unwrap();
+    let config = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    let total = config.split("multigraph cpu1sec.").next().unwrap();
+    assert!(total.contains("--upper-limit 300\n"));
+    assert_eq!(3, config.matches("--upper-limit 100\n").count());
+}
+
+#[test]
+fn test_upper_limit_interval() {
+    let config = |interval, resolution| {
+        let cpu = CpuPlugin::with_stats(
+            Settings {
+                interval,
+                resolution,
+                ..Default::default()
+            },
+            kernel_stats(
+                "cpu  0 0 0 0 0 0 0 0 0 0\ncpu0 0 0 0 0 0 0 0 0 0 0\ncpu1 0 0 0 0 0 0 0 0 0 0",
+                1000,
+            ),
+            1,
+        );
+        let mut handle = BufWriter::new(Vec::new());
+        cpu.config(&mut handle).unwrap();
+        String::from_utf8(handle.into_inner().unwrap()).unwrap()
+    };
+    let second = config(Duration::from_secs(1), Resolution::Ticks);
+    assert!(second.contains("--upper-limit 200\ngraph_vlabel %\n"));
+    // 500 ticks per core and interval, no percent
+    let five = config(Duration::from_secs(5), Resolution::Ticks);
+    assert!(five.contains("--upper-limit 1000\ngraph_vlabel ticks\n"));
+    let five = config(Duration::from_secs(5), Resolution::Nanoseconds);
+    assert!(five.contains("--upper-limit 10000000000\ngraph_vlabel ns\n"));
+    let half = config(Duration::from_millis(500), Resolution::Milliseconds);
+    assert!(half.contains("--upper-limit 1000\ngraph_vlabel ms\n"));
+}
+
+#[test]
+fn test_resolution_ms() {
+    let settings = Settings {
+        cpudetail: true,
+        resolution: Resolution::Milliseconds,
+        ..Default::default()
+    };
+    let mut cpu = CpuPlugin::with_stats(
+        settings,
+        kernel_stats("cpu  0 0 0 0 0 0 0 0 0 0\ncpu0 0 0 0 0 0 0 0 0 0 0", 1000),
+        1,
+    );
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.config(&mut handle).unwrap();
+    let config = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert_eq!(2, config.matches("graph_vlabel ms\n").count());
+    assert_eq!(2, config.matches("--upper-limit 1000\n").count());
+
+    // One second worth of ticks, whatever USER_HZ is
+    let tps = stat::ticks_per_second();
+    let mut handle = BufWriter::new(Vec::new());
+    let ks = kernel_stats(
+        &format!("cpu  {tps} 0 0 0 0 0 0 0 0 0\ncpu0 {tps} 0 0 0 0 0 0 0 0 0"),
+        1000,
+    );
+    cpu.write_cpu(&mut handle, ks, 2).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.contains("cpu0_user.value 2:1000\n"));
+    assert!(values.contains("total_user.value 2:1000\n"));
+}
+
+#[test]
+fn test_rollup_config() {
+    let cpu = CpuPlugin::with_stats(
+        Settings {
+            rollup: Rollup::Coarse,
+            ..Default::default()
+        },
+        kernel_stats("cpu  10 0 10 100 0 0 0 0 0 0", 1000),
+        1,
+    );
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.config(&mut handle).unwrap();
+    let config = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    for field in ["userspace", "kernel", "wait", "idle"] {
+        assert!(config.contains(&format!("\ntotal_{field}.label {field}\n")));
+    }
+    assert!(!config.contains("total_user."));
+}
+
+#[test]
+fn test_on_sample() {
+    use std::{cell::RefCell, rc::Rc};
+
+    let mut cpu = CpuPlugin::with_stats(
+        Settings::default(),
+        kernel_stats("cpu  10 0 10 100 0 0 0 0 0 0", 1000),
+        1,
+    );
+    let seen = Rc::new(RefCell::new(vec![]));
+    let samples = Rc::clone(&seen);
+    cpu.on_sample(move |stats| samples.borrow_mut().extend_from_slice(stats));
+
+    let mut handle = BufWriter::new(Vec::new());
+    for (epoch, user) in [(2, 15), (3, 35)] {
+        let stat = format!("cpu  {user} 0 10 100 0 0 0 0 0 0");
+        cpu.write_cpu(&mut handle, kernel_stats(&stat, 1000), epoch)
+            .unwrap();
+    }
+    let seen = seen.borrow();
+    assert_eq!(2, seen.len());
+    assert_eq!((2, 5), (seen[0].epoch, seen[0].user));
+    assert_eq!((3, 20), (seen[1].epoch, seen[1].user));
+    assert_eq!(CpuId::Total, seen[1].cpu);
+}
+
+#[test]
+fn test_stdout() {
+    use crate::sink::MuninText;
+
+    let root = fixture_root("stdout", "cpu  10 0 10 100 0 0 0 0 0 0");
+    let (settings, errors) = Settings::from_vars(|name| match name {
+        "stdout" => Some(String::from("1")),
+        "source" => Some(String::from("proc")),
+        "proc_root" => Some(root.display().to_string()),
+        _ => None,
+    });
+    assert!(errors.is_empty());
+    assert_eq!(Output::Stdout, settings.output);
+
+    let mut cpu = CpuPlugin::new(settings);
+    let config = Config::new(String::from("cpu1sec"));
+    let mut stdout = MuninText { out: vec![] };
+    stdout.write_config(b"graph_title CPU\n").unwrap();
+    for epoch in [2, 3] {
+        cpu.write_sample(&mut stdout, &config, epoch).unwrap();
+        // Each block is out as soon as it is written
+        let out = String::from_utf8(stdout.out.clone()).unwrap();
+        assert!(out.contains(&format!("total_user.value {epoch}:")));
+    }
+    std::fs::remove_dir_all(&root).unwrap();
+    assert!(cpu.latest.is_some());
+    let out = String::from_utf8(stdout.out).unwrap();
+    assert!(out.starts_with("graph_title CPU\n"));
+    assert_eq!(2, out.matches("total_user.value ").count());
+}
+
+#[test]
+fn test_self_metrics() {
+    // Only a /proc/stat, the other collectors write U
+    let root = fixture_root("self", "cpu  20 0 20 200 0 0 0 0 0 0");
+    let mut cpu = CpuPlugin::with_stats(
+        Settings {
+            collectors: Collector::ALL.iter().copied().collect(),
+            self_metrics: true,
+            proc_root: root.clone(),
+            source: Source::Proc,
+            guest_fields: Some(true),
+            ..Default::default()
+        },
+        kernel_stats("cpu  10 0 10 100 0 0 0 0 0 0", 1000),
+        41,
+    );
+    let mut handle = BufWriter::new(Vec::new());
+    cpu.acquire(&mut handle, &Config::new(String::from("cpu1sec")), 42)
+        .unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.starts_with("multigraph cpu1sec\n"));
+    let (_, own) = values.split_once("multigraph cpu1sec_self\n").unwrap();
+    assert_eq!(Collector::ALL.len(), own.lines().count());
+    for collector in Collector::ALL {
+        assert!(own.contains(&format!("{}.value 42:", collector.name())));
+    }
+}
+
+#[test]
+fn test_config_stable_order() {
+    let cpu = CpuPlugin::with_stats(
+        Settings {
+            cpudetail: true,
+            collectors: Collector::ALL.iter().copied().collect(),
+            source: Source::Proc,
+            guest_fields: Some(true),
+            ..Default::default()
+        },
+        kernel_stats(
+            "cpu  20 0 20 200 0 0 0 0 0 0\n\
+             cpu0 10 0 10 100 0 0 0 0 0 0\n\
+             cpu1 10 0 10 100 0 0 0 0 0 0",
+            1000,
+        ),
+        1,
+    );
+    let mut first = BufWriter::new(Vec::new());
+    cpu.config(&mut first).unwrap();
+    let mut second = BufWriter::new(Vec::new());
+    cpu.config(&mut second).unwrap();
+    assert_eq!(first.into_inner().unwrap(), second.into_inner().unwrap());
+}
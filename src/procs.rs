@@ -0,0 +1,86 @@
+//! Runnable and blocked tasks, from the procs_running and
+//! procs_blocked lines of the /proc/stat we read for the CPU usage
+//! anyway, see [crate::Collector::Procs]
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::Settings;
+use anyhow::Result;
+use std::io::{BufWriter, Write};
+
+/// Name of our graph
+const GRAPH: &str = "procs1sec";
+
+/// Our fields, with the /proc/stat line they come from
+const FIELDS: [(&str, &str); 2] = [("running", "procs_running"), ("blocked", "procs_blocked")];
+
+/// Write out the config of the `procs1sec` graph
+pub(crate) fn config<W: Write>(handle: &mut BufWriter<W>, settings: &Settings) -> Result<()> {
+    writeln!(handle, "multigraph {GRAPH}")?;
+    writeln!(handle, "graph_title Runnable and blocked tasks (1sec)")?;
+    writeln!(handle, "graph_category {}", settings.graph_category)?;
+    writeln!(handle, "update_rate {}", settings.update_rate())?;
+    writeln!(
+        handle,
+        "graph_data_size {}",
+        settings.retention.graph_data_size()
+    )?;
+    writeln!(handle, "graph_args --base 1000 --lower-limit 0")?;
+    writeln!(handle, "graph_vlabel tasks")?;
+    writeln!(handle, "graph_scale no")?;
+    writeln!(
+        handle,
+        "graph_info Tasks (processes and threads) at the time of the sample. A pile of runnable ones means the CPUs can not keep up, blocked ones are in uninterruptible sleep (D state), mostly waiting for I/O."
+    )?;
+    for (field, info) in [
+        ("running", "Tasks running or waiting for a CPU"),
+        ("blocked", "Tasks blocked waiting for I/O to finish"),
+    ] {
+        writeln!(handle, "{field}.label {field}")?;
+        writeln!(handle, "{field}.min 0")?;
+        writeln!(handle, "{field}.type GAUGE")?;
+        writeln!(handle, "{field}.info {info}")?;
+    }
+    Ok(())
+}
+
+/// Write out the values of the `procs1sec` graph from the /proc/stat
+/// `content` read at `epoch`. Unknown for lines it lacks, as in some
+/// minimal containers.
+pub(crate) fn write<W: Write>(handle: &mut BufWriter<W>, content: &str, epoch: u64) -> Result<()> {
+    writeln!(handle, "multigraph {GRAPH}")?;
+    for (field, key) in FIELDS {
+        let value = content.lines().find_map(|line| {
+            let mut words = line.split_whitespace();
+            if words.next()? != key {
+                return None;
+            }
+            words.next()?.parse::<u64>().ok()
+        });
+        match value {
+            Some(value) => writeln!(handle, "{field}.value {epoch}:{value}")?,
+            None => writeln!(handle, "{field}.value {epoch}:U")?,
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_write() {
+    let values = |content: &str| {
+        let mut handle = BufWriter::new(Vec::new());
+        write(&mut handle, content, 9).unwrap();
+        String::from_utf8(handle.into_inner().unwrap()).unwrap()
+    };
+    assert_eq!(
+        "multigraph procs1sec\nrunning.value 9:17\nblocked.value 9:3\n",
+        values(
+            "cpu  10 0 10 100 0 0 0 0 0 0\n\
+             ctxt 1\nbtime 1000\nprocesses 1\n\
+             procs_running 17\nprocs_blocked 3\n"
+        )
+    );
+    assert_eq!(
+        "multigraph procs1sec\nrunning.value 9:U\nblocked.value 9:U\n",
+        values("cpu  10 0 10 100 0 0 0 0\n")
+    );
+}
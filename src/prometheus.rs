@@ -0,0 +1,227 @@
+//! Prometheus exporter, serving the samples of the daemon on
+//! `/metrics`, next to whatever goes to munin
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{fanout::Target, CpuId, CpuStat, Field, Settings};
+use anyhow::Result;
+use log::{info, warn};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
+
+/// What we know of one field of one graph
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+struct Metric {
+    /// Sum of all samples since we started, the counter
+    total: u64,
+    /// The last sample, the gauge
+    last: u64,
+}
+
+/// Everything we serve
+#[derive(Debug, Default)]
+struct Metrics {
+    /// Values by graph and field
+    values: BTreeMap<(CpuId, Field), Metric>,
+    /// Epoch of the last sample
+    epoch: u64,
+}
+
+impl Metrics {
+    /// Add the sample `graphs`, as written out to munin
+    fn update(&mut self, graphs: &[CpuStat]) {
+        for stat in graphs {
+            self.epoch = stat.epoch;
+            let fields = stat.rollup.fields().iter();
+            for field in fields.filter(|f| stat.guest_fields || !f.is_guest()) {
+                let value = field.value(stat);
+                let metric = self.values.entry((stat.cpu, *field)).or_default();
+                metric.total += value;
+                metric.last = value;
+            }
+        }
+    }
+
+    /// The metrics in Prometheus' text format
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, value: fn(&Metric) -> u64| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for ((cpu, field), metric) in &self.values {
+                let labels = format!("cpu=\"{cpu}\",{}", field.prometheus());
+                let _ = writeln!(out, "{name}{{{labels}}} {}", value(metric));
+            }
+        };
+        family(
+            "cpu1sec_time_total",
+            "counter",
+            "CPU time spent since the daemon started, in the unit of the munin graphs",
+            |metric| metric.total,
+        );
+        family(
+            "cpu1sec_time",
+            "gauge",
+            "CPU time spent in the last interval, in the unit of the munin graphs",
+            |metric| metric.last,
+        );
+        let _ = writeln!(
+            out,
+            "# HELP cpu1sec_last_sample_seconds Epoch of the last sample"
+        );
+        let _ = writeln!(out, "# TYPE cpu1sec_last_sample_seconds gauge");
+        let _ = writeln!(out, "cpu1sec_last_sample_seconds {}", self.epoch);
+        out
+    }
+}
+
+#[test]
+fn test_metrics() {
+    let stat = |cpu, user| CpuStat {
+        cpu,
+        epoch: 2,
+        user,
+        guest_fields: false,
+        ..Default::default()
+    };
+    let mut metrics = Metrics::default();
+    metrics.update(&[stat(CpuId::Core(0), 30), stat(CpuId::Total, 40)]);
+    metrics.update(&[stat(CpuId::Core(0), 5), stat(CpuId::Total, 10)]);
+    let out = metrics.render();
+    assert!(out.contains("# TYPE cpu1sec_time_total counter\n"));
+    assert!(out.contains("cpu1sec_time_total{cpu=\"cpu0\",mode=\"user\"} 35\n"));
+    assert!(out.contains("cpu1sec_time_total{cpu=\"total\",mode=\"user\"} 50\n"));
+    assert!(out.contains("cpu1sec_time{cpu=\"total\",mode=\"user\"} 10\n"));
+    assert!(out.contains("cpu1sec_last_sample_seconds 2\n"));
+    assert!(!out.contains("guest"));
+}
+
+/// Serves the metrics over HTTP, from a thread of its own
+#[derive(Debug)]
+pub(crate) struct Exporter {
+    /// Where we listen
+    pub(crate) addr: SocketAddr,
+    /// Shared with the thread serving them
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl Exporter {
+    /// Listen on `addr` (host:port) and serve the metrics from there
+    /// on. Has to happen after daemonizing, threads do not survive
+    /// the fork.
+    pub(crate) fn spawn(addr: &str) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
+        let shared = Arc::clone(&metrics);
+        thread::Builder::new()
+            .name(String::from("prometheus"))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if let Err(e) = stream.and_then(|stream| serve(stream, &shared)) {
+                        warn!("Could not serve a Prometheus scrape: {e}");
+                    }
+                }
+            })?;
+        Ok(Self { addr, metrics })
+    }
+
+    /// Add the sample `graphs`
+    pub(crate) fn update(&self, graphs: &[CpuStat]) {
+        self.metrics
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .update(graphs);
+    }
+}
+
+impl Target for Exporter {
+    fn deliver(&mut self, graphs: &[CpuStat]) {
+        self.update(graphs);
+    }
+}
+
+/// The [Target] for [Settings::prometheus_addr], if set and we can
+/// listen there. munin still gets its data if not.
+pub(crate) fn target(settings: &Settings) -> Option<Box<dyn Target>> {
+    let addr = settings.prometheus_addr.as_ref()?;
+    match Exporter::spawn(addr) {
+        Ok(exporter) => {
+            info!(
+                "Serving Prometheus metrics on http://{}/metrics",
+                exporter.addr
+            );
+            Some(Box::new(exporter))
+        }
+        Err(e) => {
+            warn!("Not serving Prometheus metrics on {addr}: {e}");
+            None
+        }
+    }
+}
+
+/// Answer one HTTP request
+fn serve(stream: TcpStream, metrics: &Mutex<Metrics>) -> io::Result<()> {
+    // A scraper that hangs must not block the next one forever
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // We have no use for the headers, but read them, closing with
+    // unread data makes the kernel reset the connection
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let (status, body) = match request.split_whitespace().nth(1) {
+        Some("/metrics") => (
+            "200 OK",
+            metrics
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .render(),
+        ),
+        _ => ("404 Not Found", String::from("Try /metrics\n")),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+#[test]
+fn test_exporter() {
+    use std::io::Read;
+
+    let exporter = Exporter::spawn("127.0.0.1:0").unwrap();
+    exporter.update(&[CpuStat {
+        epoch: 7,
+        idle: 100,
+        ..Default::default()
+    }]);
+    let get = |path: &str| {
+        let mut stream = TcpStream::connect(exporter.addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let response = get("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("cpu1sec_time{cpu=\"total\",mode=\"idle\"} 100\n"));
+    assert!(response.ends_with("cpu1sec_last_sample_seconds 7\n"));
+    assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
@@ -0,0 +1,288 @@
+//! Pressure stall information from /proc/pressure, the `psi1sec_*`
+//! graphs of [crate::Collector::Psi]
+//!
+//! One graph per resource, cpu always, io and memory with
+//! [crate::Settings::psi_io] and [crate::Settings::psi_memory]. The
+//! kernel's own avg10 lags by design, so next to it we show how much
+//! of the last interval tasks were stalled, from the difference of
+//! the total stall time in microseconds.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::Settings;
+use anyhow::{anyhow, Result};
+use log::info;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{BufWriter, Write},
+    path::Path,
+    time::Instant,
+};
+
+/// The resources the kernel reports pressure for
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum Resource {
+    /// Tasks waiting for a CPU
+    Cpu,
+    /// Tasks waiting for I/O
+    Io,
+    /// Tasks waiting for memory, reclaim and swap in
+    Memory,
+}
+
+impl Resource {
+    /// Name of the resource, as the file in /proc/pressure
+    fn name(&self) -> &'static str {
+        match self {
+            Resource::Cpu => "cpu",
+            Resource::Io => "io",
+            Resource::Memory => "memory",
+        }
+    }
+}
+
+/// One line of a pressure file
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+struct Line {
+    /// Share of the last 10 seconds stalled, in percent
+    avg10: f64,
+    /// Time stalled since boot, in microseconds
+    total: u64,
+}
+
+/// The content of a pressure file
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+struct Pressure {
+    /// At least some tasks stalled
+    some: Line,
+    /// All non-idle tasks stalled at once. Kernels before 5.13 have
+    /// none for cpu.
+    full: Option<Line>,
+}
+
+impl Pressure {
+    /// Parse the content of a pressure file, e.g.
+    /// `some avg10=1.63 avg60=5.45 avg300=4.99 total=248662452`
+    /// followed by the same for `full`
+    fn parse(content: &str) -> Result<Self> {
+        let mut some = None;
+        let mut full = None;
+        for line in content.lines() {
+            let mut words = line.split_whitespace();
+            let kind = words.next();
+            let mut parsed = Line::default();
+            for (key, value) in words.filter_map(|word| word.split_once('=')) {
+                match key {
+                    "avg10" => parsed.avg10 = value.parse()?,
+                    "total" => parsed.total = value.parse()?,
+                    _ => (),
+                }
+            }
+            match kind {
+                Some("some") => some = Some(parsed),
+                Some("full") => full = Some(parsed),
+                _ => (),
+            }
+        }
+        Ok(Self {
+            some: some.ok_or_else(|| anyhow!("No some line in {content:?}"))?,
+            full,
+        })
+    }
+}
+
+#[test]
+fn test_parse() {
+    let pressure = Pressure::parse(
+        "some avg10=1.63 avg60=5.45 avg300=4.99 total=248662452\n\
+         full avg10=0.14 avg60=0.00 avg300=0.00 total=17\n",
+    )
+    .unwrap();
+    assert_eq!(
+        Line {
+            avg10: 1.63,
+            total: 248662452
+        },
+        pressure.some
+    );
+    assert_eq!(Some(17), pressure.full.map(|full| full.total));
+    let old = Pressure::parse("some avg10=0.00 avg60=0.00 avg300=0.00 total=5\n").unwrap();
+    assert_eq!(None, old.full);
+    assert!(Pressure::parse("").is_err());
+    assert!(Pressure::parse("some avg10=x total=1\n").is_err());
+}
+
+/// Reads the pressure of the resources we look at, and keeps what
+/// it read last to take the difference
+#[derive(Debug)]
+pub(crate) struct Psi {
+    /// The resources, cpu first
+    resources: Vec<Resource>,
+    /// What we read last, and when
+    last: BTreeMap<Resource, (Pressure, Instant)>,
+}
+
+impl Psi {
+    /// Look at the resources asked for in `settings`
+    pub(crate) fn new(settings: &Settings) -> Self {
+        let mut resources = vec![Resource::Cpu];
+        if settings.psi_io {
+            resources.push(Resource::Io);
+        }
+        if settings.psi_memory {
+            resources.push(Resource::Memory);
+        }
+        Self {
+            resources,
+            last: BTreeMap::new(),
+        }
+    }
+
+    /// Write out the config of our graphs
+    pub(crate) fn config<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        settings: &Settings,
+    ) -> Result<()> {
+        for resource in &self.resources {
+            let name = resource.name();
+            writeln!(handle, "multigraph psi1sec_{name}")?;
+            writeln!(handle, "graph_title Pressure stall {name} (1sec)")?;
+            writeln!(handle, "graph_category {}", settings.graph_category)?;
+            writeln!(handle, "update_rate {}", settings.update_rate())?;
+            writeln!(
+                handle,
+                "graph_data_size {}",
+                settings.retention.graph_data_size()
+            )?;
+            writeln!(
+                handle,
+                "graph_args --base 1000 -r --lower-limit 0 --upper-limit 100"
+            )?;
+            writeln!(handle, "graph_vlabel %")?;
+            writeln!(handle, "graph_scale no")?;
+            writeln!(
+                handle,
+                "graph_info Share of time tasks were stalled waiting for {name}, from /proc/pressure/{name}. some is time at least one task stalled, full time all non-idle tasks stalled at once."
+            )?;
+            for (field, info) in [
+                ("some", "Share of the last interval some tasks stalled"),
+                ("full", "Share of the last interval all tasks stalled"),
+                ("some_avg10", "The kernel's average of some over 10 seconds"),
+                ("full_avg10", "The kernel's average of full over 10 seconds"),
+            ] {
+                writeln!(handle, "{field}.label {field}")?;
+                writeln!(handle, "{field}.min 0")?;
+                writeln!(handle, "{field}.max 100")?;
+                writeln!(handle, "{field}.type GAUGE")?;
+                writeln!(handle, "{field}.info {info}")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the pressure files of `proc_root` and write out the
+    /// values of our graphs for `epoch`. What can not be read, or
+    /// has nothing to diff against yet, is unknown.
+    pub(crate) fn write<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        proc_root: &Path,
+        epoch: u64,
+    ) -> Result<()> {
+        for resource in &self.resources {
+            let name = resource.name();
+            let path = proc_root.join("pressure").join(name);
+            let now = Instant::now();
+            let pressure = fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|content| Pressure::parse(&content));
+            let pressure = match pressure {
+                Ok(pressure) => Some(pressure),
+                Err(e) => {
+                    info!("Could not read {}: {e}", path.display());
+                    None
+                }
+            };
+            let last = match pressure {
+                Some(pressure) => self.last.insert(*resource, (pressure, now)),
+                None => self.last.remove(resource),
+            };
+            // Percent of the time since the last read, the totals
+            // are in microseconds
+            let stalled = |new: Option<Line>, old: Option<Line>| -> Option<f64> {
+                let (_, since) = last?;
+                let stalled = new?.total.checked_sub(old?.total)?;
+                let elapsed = now.duration_since(since).as_micros();
+                (elapsed > 0).then(|| (stalled as f64 * 100.0 / elapsed as f64).min(100.0))
+            };
+            let old = last.map(|(old, _)| old);
+            let values = [
+                (
+                    "some",
+                    stalled(pressure.map(|p| p.some), old.map(|p| p.some)),
+                ),
+                (
+                    "full",
+                    stalled(pressure.and_then(|p| p.full), old.and_then(|p| p.full)),
+                ),
+                ("some_avg10", pressure.map(|p| p.some.avg10)),
+                (
+                    "full_avg10",
+                    pressure.and_then(|p| p.full).map(|full| full.avg10),
+                ),
+            ];
+            writeln!(handle, "multigraph psi1sec_{name}")?;
+            for (field, value) in values {
+                match value {
+                    Some(value) => writeln!(handle, "{field}.value {epoch}:{value:.2}")?,
+                    None => writeln!(handle, "{field}.value {epoch}:U")?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_write() {
+    let root = std::env::temp_dir().join(format!("cpu1sec-psi-{}", std::process::id()));
+    fs::create_dir_all(root.join("pressure")).unwrap();
+    let mut psi = Psi::new(&Settings {
+        psi_io: true,
+        ..Default::default()
+    });
+    let mut values = |epoch| {
+        let mut handle = BufWriter::new(Vec::new());
+        psi.write(&mut handle, &root, epoch).unwrap();
+        String::from_utf8(handle.into_inner().unwrap()).unwrap()
+    };
+    let cpu = root.join("pressure").join("cpu");
+    fs::write(&cpu, "some avg10=1.50 avg60=0.00 avg300=0.00 total=1000\n").unwrap();
+    let first = values(1);
+    assert!(first.starts_with(
+        "multigraph psi1sec_cpu\n\
+         some.value 1:U\n\
+         full.value 1:U\n\
+         some_avg10.value 1:1.50\n\
+         full_avg10.value 1:U\n"
+    ));
+    // No io pressure file, all unknown
+    assert!(first.ends_with("multigraph psi1sec_io\nsome.value 1:U\nfull.value 1:U\nsome_avg10.value 1:U\nfull_avg10.value 1:U\n"));
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    // Stalled all the time since, and then some
+    fs::write(
+        &cpu,
+        "some avg10=1.50 avg60=0.00 avg300=0.00 total=1000000\n",
+    )
+    .unwrap();
+    assert!(values(2).contains("some.value 2:100.00\nfull.value 2:U\n"));
+
+    let mut config = BufWriter::new(Vec::new());
+    psi.config(&mut config, &Settings::default()).unwrap();
+    let config = String::from_utf8(config.into_inner().unwrap()).unwrap();
+    assert!(config.starts_with("multigraph psi1sec_cpu\n"));
+    assert!(config.contains("multigraph psi1sec_io\n"));
+    assert!(!config.contains("memory"));
+    fs::remove_dir_all(&root).unwrap();
+}
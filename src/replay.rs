@@ -0,0 +1,169 @@
+//! Replaying recorded /proc/stat snapshots, to reproduce the exact
+//! CPU behavior of some machine in tests and demos
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{CpuPlugin, Settings};
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use std::{
+    fs,
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+/// A recorded sequence of /proc/stat snapshots. They are read from a
+/// directory holding one copy of /proc/stat per second, each named
+/// after the epoch it was taken at, e.g. `1650000000.stat`, as
+/// `while sleep 1; do cat /proc/stat > $(date +%s).stat; done`
+/// records them. Snapshots may be gzip compressed, as
+/// `1650000000.stat.gz`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ReplaySource {
+    /// The snapshots with their epoch, oldest first
+    snapshots: Vec<(u64, PathBuf)>,
+}
+
+impl ReplaySource {
+    /// Load the snapshots in `dir`. Other files are ignored.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut snapshots = vec![];
+        for entry in fs::read_dir(dir).with_context(|| format!("Reading {}", dir.display()))? {
+            let path = entry?.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let name = name.strip_suffix(".gz").unwrap_or(name);
+            if let Some(stem) = name.strip_suffix(".stat") {
+                match stem.parse().ok() {
+                    Some(epoch) => snapshots.push((epoch, path)),
+                    None => bail!("{} is not named after an epoch", path.display()),
+                }
+            }
+        }
+        snapshots.sort();
+        Ok(Self { snapshots })
+    }
+
+    /// Number of snapshots
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Are there no snapshots at all?
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Run a plugin with `settings` over the snapshots, writing what
+    /// it writes to `handle`. It starts from the first snapshot, so
+    /// values are written for all but that. With `interval` we wait
+    /// that long before every snapshot, like the daemon does between
+    /// its reads, without we go as fast as we can.
+    pub fn replay<W: Write>(
+        &self,
+        settings: Settings,
+        handle: &mut BufWriter<W>,
+        interval: Option<Duration>,
+    ) -> Result<()> {
+        let mut snapshots = self.snapshots.iter();
+        let Some((epoch, first)) = snapshots.next() else {
+            bail!("No snapshots to replay");
+        };
+        let mut cpu = CpuPlugin::from_stat(settings, &read(first)?, *epoch)
+            .with_context(|| format!("Parsing {}", first.display()))?;
+        for (epoch, path) in snapshots {
+            if let Some(interval) = interval {
+                thread::sleep(interval);
+            }
+            cpu.write_stat(handle, &read(path)?, *epoch)
+                .with_context(|| format!("Parsing {}", path.display()))?;
+            handle.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// The first bytes of every gzip file
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Read one snapshot, decompressing it if it is gzipped
+fn read(path: &Path) -> Result<String> {
+    let mut content = fs::read(path)?;
+    if content.starts_with(&GZIP_MAGIC) {
+        let mut plain = vec![];
+        GzDecoder::new(content.as_slice())
+            .read_to_end(&mut plain)
+            .with_context(|| format!("Decompressing {}", path.display()))?;
+        content = plain;
+    }
+    String::from_utf8(content).with_context(|| format!("Reading {}", path.display()))
+}
+
+#[test]
+fn test_replay() {
+    let dir = std::env::temp_dir().join(format!("cpu1sec-replay-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let rest = "intr 0\nctxt 0\nbtime 1650000000\nprocesses 1\n";
+    fs::write(
+        dir.join("1650000001.stat"),
+        format!("cpu  11461 2 2462 150458 251 1 5 315 0 0\n{rest}"),
+    )
+    .unwrap();
+    fs::write(
+        dir.join("1650000000.stat"),
+        format!("cpu  11401 0 2442 150340 247 0 3 314 0 0\n{rest}"),
+    )
+    .unwrap();
+    fs::write(dir.join("README"), "Recorded on a test box").unwrap();
+
+    let source = ReplaySource::load(&dir).unwrap();
+    assert_eq!(2, source.len());
+    let settings = Settings {
+        source: crate::Source::Proc,
+        ..Default::default()
+    };
+    let mut handle = BufWriter::new(Vec::new());
+    source.replay(settings, &mut handle, None).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.starts_with(
+        "total_user.value 1650000001:60\n\
+         total_nice.value 1650000001:2\n\
+         total_system.value 1650000001:20\n\
+         total_idle.value 1650000001:118\n"
+    ));
+
+    fs::write(dir.join("yesterday.stat"), "").unwrap();
+    assert!(ReplaySource::load(&dir).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_replay_gzip() {
+    use flate2::{write::GzEncoder, Compression};
+
+    let dir = std::env::temp_dir().join(format!("cpu1sec-replay-gz-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let rest = "intr 0\nctxt 0\nbtime 1650000000\nprocesses 1\n";
+    fs::write(
+        dir.join("1650000000.stat"),
+        format!("cpu  11401 0 2442 150340 247 0 3 314 0 0\n{rest}"),
+    )
+    .unwrap();
+    let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+    write!(gz, "cpu  11461 2 2462 150458 251 1 5 315 0 0\n{rest}").unwrap();
+    fs::write(dir.join("1650000001.stat.gz"), gz.finish().unwrap()).unwrap();
+
+    let source = ReplaySource::load(&dir).unwrap();
+    assert_eq!(2, source.len());
+    let settings = Settings {
+        source: crate::Source::Proc,
+        ..Default::default()
+    };
+    let mut handle = BufWriter::new(Vec::new());
+    source.replay(settings, &mut handle, None).unwrap();
+    let values = String::from_utf8(handle.into_inner().unwrap()).unwrap();
+    assert!(values.starts_with("total_user.value 1650000001:60\n"));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,1312 @@
+//! Settings of the plugin, and how we get them from munin
+//!
+//! Munin hands plugin configuration over via environment variables,
+//! so that is where everything is read from. The command line and a
+//! configuration file (see [ConfigFile]) set the same variables, see
+//! [Cli::var] for which wins. Invalid values are warned about and
+//! replaced by their default, `cpu1sec checkconfig` lists them all
+//! and fails instead.
+//!
+//! [ConfigFile]: crate::ConfigFile
+//! [Cli::var]: crate::Cli::var
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{
+    AggregateFn, Clock, Collector, Compat, Endpoint, Field, Format, GroupBy, LineEnding, Output,
+    Resolution, Retention, Rollup, Rotate, Rounding, SleepMode, Source,
+};
+use anyhow::{anyhow, Result};
+use log::warn;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fmt::Display,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Shortest [Settings::interval] we accept, anything below would
+/// keep a core busy with sampling
+pub const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Everything the user can configure
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Settings {
+    /// Should we spit out data for detailed graphs for every CPU the system has, or just a total?
+    /// The default will be determined:
+    ///  * from the environment variable cpudetail, if set to 1, detailed graphs will be shown,
+    ///  * anything else will be false, only total graph shown.
+    pub cpudetail: bool,
+
+    /// Naming scheme for our datasources, see [Compat]. Taken from
+    /// the environment variable compat, `compat=munin_cpu` takes over
+    /// the graph and RRDs of munin's stock cpu plugin.
+    pub compat: Compat,
+
+    /// Which collectors are enabled, see [Collector]
+    pub collectors: BTreeSet<Collector>,
+
+    /// Should the psi collector show io pressure too, next to cpu?
+    /// Taken from the environment variable psi_io, set to 1 to
+    /// enable.
+    pub psi_io: bool,
+
+    /// Should the psi collector show memory pressure too? Taken from
+    /// the environment variable psi_memory, set to 1 to enable.
+    pub psi_memory: bool,
+
+    /// IRQs the irq collector graphs, as /proc/interrupts names
+    /// them. Taken from the environment variable irqs, a list like
+    /// `0,24,NMI,LOC`. If unset, the [Settings::irq_top] ones.
+    pub irqs: Option<Vec<String>>,
+
+    /// How many IRQs the irq collector graphs without
+    /// [Settings::irqs], the ones with the most interrupts since
+    /// boot. Taken from the environment variable irq_top, default 10.
+    pub irq_top: usize,
+
+    /// Should the softirq collector graph every CPU on its own too,
+    /// besides the sum of all? Taken from the environment variable
+    /// softirq_percore, set to 1 to enable. Only the
+    /// [Settings::cores], at most [Settings::max_core_graphs].
+    pub softirq_percore: bool,
+
+    /// cgroups the cgroup collector graphs, as paths below
+    /// /sys/fs/cgroup. Taken from the environment variable
+    /// cgroup_paths, a list like
+    /// `system.slice,user.slice,system.slice/docker-1a2b.scope`. If
+    /// unset, the children of the root cgroup.
+    pub cgroup_paths: Option<Vec<String>>,
+
+    /// How many processes the top collector graphs. Taken from the
+    /// environment variable top_n, default 10.
+    pub top_n: usize,
+
+    /// What the top collector tells processes apart by, see [TopBy].
+    /// Taken from the environment variable top_by, `comm` or `pid`.
+    pub top_by: TopBy,
+
+    /// How to wait between samples in daemon mode, see [SleepMode].
+    /// Taken from the environment variable sleep_mode.
+    pub sleep_mode: SleepMode,
+
+    /// Where the daemon takes the epochs of its samples from, see
+    /// [Clock]. Taken from the environment variable clock,
+    /// `clock=monotonic` keeps them moving forward whatever happens
+    /// to the wall clock.
+    pub clock: Clock,
+
+    /// How long a single acquire may take in daemon mode before the
+    /// watchdog complains, see the watchdog module. Taken from the
+    /// environment variable watchdog_timeout, in seconds, default 10.
+    pub watchdog_timeout: Duration,
+
+    /// Should the daemon exit if the watchdog fires? Lets a
+    /// supervisor restart us. Taken from the environment variable
+    /// watchdog_abort, set to 1 to abort.
+    pub watchdog_abort: bool,
+
+    /// Should we emit a graph with the time each collector took to
+    /// read its data? Taken from the environment variable
+    /// self_metrics, set to 1 to enable.
+    pub self_metrics: bool,
+
+    /// Unit for the total graph, or all graphs with `ms`, see
+    /// [Resolution]. Taken from the environment variable resolution.
+    pub resolution: Resolution,
+
+    /// Should we emit a graph of the steal time as percentage of
+    /// each CPU (and the total)? Taken from the environment variable
+    /// steal_graph, set to 1 to enable.
+    pub steal_graph: bool,
+
+    /// How the percentages of the steal graph get rounded, see
+    /// [Rounding]. Taken from the environment variable rounding,
+    /// nearest (default), floor or ceil.
+    pub rounding: Rounding,
+
+    /// Maximum number of per-core graphs in detailed mode. All cores
+    /// above that get summed up into one "others" graph, so a machine
+    /// with hundreds of cores does not drown munin. Taken from the
+    /// environment variable max_core_graphs, default 64.
+    pub max_core_graphs: usize,
+
+    /// Where the daemon sends its samples, see [Output]. Taken from
+    /// the environment variable output, `stdout=1` is short for
+    /// `output=stdout`.
+    pub output: Output,
+
+    /// Should the daemon stay in the foreground instead of forking
+    /// off? Taken from the environment variable foreground, set to 1
+    /// to stay. [Output::Stdout] always does.
+    pub foreground: bool,
+
+    /// munin-async's spool directory, written to with
+    /// [Output::Spool]. Taken from the environment variable
+    /// spool_dir, default `/var/lib/munin-async`.
+    pub spool_dir: PathBuf,
+
+    /// host:port munin masters can query us on with [Output::Node].
+    /// Taken from the environment variable node_addr, default
+    /// `[::]:4949`, munin-node's port.
+    pub node_addr: String,
+
+    /// host:port to stream samples to with [Output::Tcp]. Taken from
+    /// the environment variable tcp_addr, required for output=tcp.
+    pub tcp_addr: Option<String>,
+
+    /// How many samples to keep while the TCP connection is down,
+    /// the oldest get dropped first. Taken from the environment
+    /// variable tcp_buffer, default 300.
+    pub tcp_buffer: usize,
+
+    /// host:port the daemon serves Prometheus metrics on, at
+    /// `/metrics`, besides its usual output. Taken from the
+    /// environment variable prometheus_addr, e.g. `[::]:9101`. Needs
+    /// a build with the prometheus feature.
+    pub prometheus_addr: Option<String>,
+
+    /// Where the daemon sends its samples in InfluxDB line protocol,
+    /// besides its usual output. Taken from the environment variable
+    /// influx_url, an [Endpoint] like `udp://localhost:8089` or
+    /// `http://localhost:8086/write?db=cpu1sec`.
+    pub influx_url: Option<Endpoint>,
+
+    /// Carbon daemon the daemon pushes its samples to in Graphite's
+    /// plaintext protocol, besides its usual output. Taken from the
+    /// environment variable graphite_url, an [Endpoint] like
+    /// `tcp://localhost:2003`. A TCP connection gets re-established,
+    /// backing off while Carbon is down.
+    pub graphite_url: Option<Endpoint>,
+
+    /// Start of the Graphite metric names, followed by
+    /// `.<cpu>.<field>`. Taken from the environment variable
+    /// graphite_prefix, default `cpu1sec.<host name>`.
+    pub graphite_prefix: Option<String>,
+
+    /// statsd relay the daemon pushes its samples to as gauges,
+    /// besides its usual output. Taken from the environment variable
+    /// statsd_url, an [Endpoint] like `udp://localhost:8125`.
+    pub statsd_url: Option<Endpoint>,
+
+    /// Start of the statsd metric names. Taken from the environment
+    /// variable statsd_prefix, default `cpu1sec`.
+    pub statsd_prefix: String,
+
+    /// Put the CPU into a DogStatsD tag, `cpu1sec.user:42|g|#cpu:cpu0`,
+    /// instead of the metric name. Taken from the environment variable
+    /// statsd_tags, default off.
+    pub statsd_tags: bool,
+
+    /// MQTT broker the daemon publishes its samples to, besides its
+    /// usual output. Taken from the environment variable mqtt_url, a
+    /// tcp:// [Endpoint] like `tcp://broker:1883`.
+    pub mqtt_url: Option<Endpoint>,
+
+    /// Topic the samples are published under, one subtopic per CPU.
+    /// Taken from the environment variable mqtt_topic, default
+    /// `monitoring/<host name>/cpu1sec`.
+    pub mqtt_topic: Option<String>,
+
+    /// MQTT quality of service, 0 (at most once) or 1 (at least
+    /// once). Taken from the environment variable mqtt_qos, default 0.
+    pub mqtt_qos: u8,
+
+    /// File the daemon appends its samples to as JSON lines, besides
+    /// its usual output, `-` for stdout. Taken from the environment
+    /// variable json_path.
+    pub json_path: Option<PathBuf>,
+
+    /// OTel collector the daemon pushes its samples to as OpenTelemetry
+    /// metrics, besides its usual output. Taken from the environment
+    /// variable otlp_url, an http:// [Endpoint] for OTLP/HTTP, like
+    /// `http://localhost:4318/v1/metrics`. gRPC is not supported.
+    pub otlp_url: Option<Endpoint>,
+
+    /// File the daemon appends its samples to as CSV, besides its
+    /// usual output. Taken from the environment variable csv_path.
+    pub csv_path: Option<PathBuf>,
+
+    /// Unix socket the daemon serves its samples on to local tools,
+    /// as JSON lines, besides its usual output. Taken from the
+    /// environment variable socket_path.
+    pub socket_path: Option<PathBuf>,
+
+    /// File the daemon keeps a snapshot of its latest sample in, for
+    /// local readers to map, see [crate::shm]. Best on a tmpfs, like
+    /// `/dev/shm/cpu1sec`. Taken from the environment variable
+    /// shm_path.
+    pub shm_path: Option<PathBuf>,
+
+    /// Where the daemon streams its samples to WebSocket clients, as
+    /// JSON, besides its usual output. Taken from the environment
+    /// variable websocket_addr, e.g. `[::]:8081`.
+    pub websocket_addr: Option<String>,
+
+    /// Where the daemon serves its latest samples as JSON over HTTP,
+    /// on `/api/v1/current` and `/api/v1/history`. Taken from the
+    /// environment variable api_addr, e.g. `[::]:8080`.
+    pub api_addr: Option<String>,
+
+    /// How far `/api/v1/history` goes back. Taken from the
+    /// environment variable api_history, in seconds, default 600.
+    pub api_history: Duration,
+
+    /// SQLite database the daemon keeps a history of its samples in,
+    /// for `cpu1sec query`. Taken from the environment variable
+    /// sqlite_path. Needs a build with the sqlite feature.
+    pub sqlite_path: Option<PathBuf>,
+
+    /// How long the history in [Settings::sqlite_path] goes back.
+    /// Taken from the environment variable sqlite_keep, in hours,
+    /// default 48.
+    pub sqlite_keep: Duration,
+
+    /// Directory the daemon archives its samples in as Parquet
+    /// files. Taken from the environment variable parquet_dir. Needs
+    /// a build with the parquet feature.
+    pub parquet_dir: Option<PathBuf>,
+
+    /// How long one file in [Settings::parquet_dir] goes. Taken from
+    /// the environment variable parquet_rotate, in seconds, at least
+    /// 60, default 3600.
+    pub parquet_rotate: Duration,
+
+    /// When to start a new CSV file, see [Rotate]. Taken from the
+    /// environment variable csv_rotate, default never.
+    pub csv_rotate: Rotate,
+
+    /// Separates the fields of the CSV file. Taken from the
+    /// environment variable csv_sep, default `,`.
+    pub csv_sep: char,
+
+    /// Decimal separator for the numbers of the CSV file, e.g. `,`
+    /// for spreadsheets with a European locale. Taken from the
+    /// environment variable csv_decimal, default `.`.
+    pub csv_decimal: char,
+
+    /// Where the total graph gets its values from, see [Source].
+    /// Taken from the environment variable source. If unset, we
+    /// use the cgroup when we run in a container, see
+    /// [Source::Cgroup].
+    pub source: Source,
+
+    /// Where procfs is mounted, default `/proc`. Taken from the
+    /// environment variable proc_root, e.g. `proc_root=/host/proc`
+    /// when we run in a monitoring container and want the numbers
+    /// of the host. Then the default [Settings::source] is
+    /// [Source::Proc].
+    pub proc_root: PathBuf,
+
+    /// Line terminator of everything the daemon writes out, see
+    /// [LineEnding]. Taken from the environment variable line_ending,
+    /// default lf. Munin itself wants lf, crlf is meant for
+    /// [Output::Tcp].
+    pub line_ending: LineEnding,
+
+    /// Format of the samples sent with [Output::Tcp], or written to
+    /// [Output::Stdout] for collectd, see [Format]. Taken from the
+    /// environment variable format.
+    pub format: Format,
+
+    /// Host the samples belong to with [Format::Collectd]. Taken from
+    /// the environment variable collectd_hostname, which collectd's
+    /// Exec plugin sets (as COLLECTD_HOSTNAME), default the name of
+    /// this host.
+    pub collectd_hostname: Option<String>,
+
+    /// How long munin keeps our data, see [Retention]. Taken from the
+    /// environment variable retention, short, default or long, or a
+    /// graph_data_size of your own starting with `custom `. The
+    /// environment variable graph_data_size wins, it is handed to
+    /// munin as is.
+    pub retention: Retention,
+
+    /// Title of the CPU usage graphs. Taken from the environment
+    /// variable graph_title, `{cpu}` gets replaced by the CPU (or
+    /// group of them) a graph is for.
+    pub graph_title: String,
+
+    /// munin category of our graphs. Taken from the environment
+    /// variable graph_category, default system.
+    pub graph_category: String,
+
+    /// Description of the CPU usage graphs. Taken from the
+    /// environment variable graph_info, `{cpu}` gets replaced as in
+    /// [Settings::graph_title].
+    pub graph_info: String,
+
+    /// Seconds between the values munin stores, the step of its
+    /// RRDs. Taken from the environment variable update_rate, default
+    /// [Settings::interval] in whole seconds, which is also the
+    /// least we accept, see [Settings::update_rate].
+    pub update_rate: Option<u64>,
+
+    /// Time between two samples of the daemon. Taken from the
+    /// environment variable interval, in milliseconds, default 1000,
+    /// at least [MIN_INTERVAL]. Munin keeps one value per second at
+    /// most, shorter intervals are for the other outputs.
+    pub interval: Duration,
+
+    /// Should we read /proc/stat a second time at startup, shortly
+    /// after the first read, and use that as baseline? Taken from
+    /// the environment variable prime, set to 1 to enable.
+    pub prime: bool,
+
+    /// Time between the two reads with [Settings::prime]. Taken from
+    /// the environment variable prime_interval, in milliseconds,
+    /// default 50.
+    pub prime_interval: Duration,
+
+    /// Longest time between two samples we still believe. After a
+    /// suspend or a stall, the next difference covers all of the gap
+    /// and would show up as one huge spike, so we write `U` for it
+    /// instead. Taken from the environment variable max_gap, in
+    /// milliseconds, default 5000, never less than two
+    /// [Settings::interval]s, see [Settings::max_gap].
+    pub max_gap: Duration,
+
+    /// Cores we look at, the others get left out of the detailed
+    /// graphs, the cpufreq policies and [Settings::aggregate], the
+    /// total still counts them. Taken from the environment variable
+    /// cores, a list like `0-3,8,16-23`, default all.
+    pub cores: Option<CpuSet>,
+
+    /// Cores to sum up into one extra graph, next to the total, e.g.
+    /// the ones an application is pinned to. Taken from the
+    /// environment variable aggregate, a list like `0-7,12`. Cores of
+    /// the set that are offline do not count.
+    pub aggregate: Option<CpuSet>,
+
+    /// Should every datasource get a max, the upper limit of its
+    /// graph? Values above it, e.g. from a calculation going wrong,
+    /// are then dropped instead of spiking the graph. Taken from the
+    /// environment variable clamp_max, set to 1 to enable.
+    pub clamp_max: bool,
+
+    /// munin warning thresholds of the fields, in the unit of their
+    /// graph. Taken from the environment variables `<field>_warning`,
+    /// e.g. `iowait_warning=30` or `idle_warning=10:`, as munin wants
+    /// them, `max`, `min:` or `min:max`.
+    pub warning: BTreeMap<Field, String>,
+
+    /// munin critical thresholds of the fields, like
+    /// [Settings::warning], from `<field>_critical`
+    pub critical: BTreeMap<Field, String>,
+
+    /// Which values to write out, see [Rollup]. Taken from the
+    /// environment variable rollup, fine (default) or coarse.
+    pub rollup: Rollup,
+
+    /// Do we write out guest and guest_nice? They only ever count
+    /// with a hypervisor around, below or in our kernel. Taken from
+    /// the environment variable guest_fields, set to 1 to write them
+    /// out, anything else to leave them out. If unset (None), the
+    /// plugin looks for virtualization when it starts, in
+    /// [Settings::proc_root], see [crate::CpuPlugin::try_new], and
+    /// writes them if it has nothing to look at.
+    pub guest_fields: Option<bool>,
+
+    /// How the per-core graphs of [Settings::cpudetail] are grouped,
+    /// see [GroupBy]. Taken from the environment variable group_by,
+    /// core (default), policy or node.
+    pub group_by: GroupBy,
+
+    /// How the cores of the others, aggregate, policy and node graphs
+    /// are combined, see [AggregateFn]. Taken from the environment
+    /// variable aggregate_fn, sum (default) or avg. With avg, those
+    /// graphs show one average core and get the upper limit of a
+    /// single core.
+    pub aggregate_fn: AggregateFn,
+
+    /// Does time waiting for I/O count as busy in the derived busy
+    /// percentage of the JSON, CSV, API and archive outputs? The raw
+    /// iowait values stay as they are. Taken from the environment
+    /// variable iowait_busy, default false: iowait counts as idle, the
+    /// CPU could have run something else meanwhile.
+    pub iowait_busy: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            cpudetail: false,
+            compat: Compat::default(),
+            collectors: BTreeSet::from([Collector::Cpu]),
+            psi_io: false,
+            psi_memory: false,
+            irqs: None,
+            irq_top: 10,
+            softirq_percore: false,
+            cgroup_paths: None,
+            top_n: 10,
+            top_by: TopBy::default(),
+            sleep_mode: SleepMode::default(),
+            clock: Clock::default(),
+            watchdog_timeout: Duration::from_secs(10),
+            watchdog_abort: false,
+            self_metrics: false,
+            resolution: Resolution::default(),
+            steal_graph: false,
+            rounding: Rounding::default(),
+            max_core_graphs: 64,
+            output: Output::default(),
+            foreground: false,
+            spool_dir: PathBuf::from("/var/lib/munin-async"),
+            node_addr: String::from("[::]:4949"),
+            tcp_addr: None,
+            tcp_buffer: 300,
+            prometheus_addr: None,
+            influx_url: None,
+            graphite_url: None,
+            graphite_prefix: None,
+            statsd_url: None,
+            statsd_prefix: String::from("cpu1sec"),
+            statsd_tags: false,
+            mqtt_url: None,
+            mqtt_topic: None,
+            mqtt_qos: 0,
+            json_path: None,
+            otlp_url: None,
+            csv_path: None,
+            socket_path: None,
+            shm_path: None,
+            websocket_addr: None,
+            api_addr: None,
+            api_history: Duration::from_secs(600),
+            sqlite_path: None,
+            sqlite_keep: Duration::from_secs(48 * 3600),
+            parquet_dir: None,
+            parquet_rotate: Duration::from_secs(3600),
+            csv_rotate: Rotate::Never,
+            csv_sep: ',',
+            csv_decimal: '.',
+            source: Source::default(),
+            proc_root: PathBuf::from("/proc"),
+            line_ending: LineEnding::default(),
+            format: Format::default(),
+            collectd_hostname: None,
+            retention: Retention::default(),
+            update_rate: None,
+            graph_title: String::from("CPU usage {cpu} (1sec)"),
+            graph_category: String::from("system"),
+            graph_info: String::from("This graph shows how CPU time is spent."),
+            interval: Duration::from_secs(1),
+            prime: false,
+            prime_interval: Duration::from_millis(50),
+            max_gap: Duration::from_secs(5),
+            cores: None,
+            aggregate: None,
+            clamp_max: false,
+            warning: BTreeMap::new(),
+            critical: BTreeMap::new(),
+            rollup: Rollup::default(),
+            guest_fields: None,
+            group_by: GroupBy::default(),
+            aggregate_fn: AggregateFn::default(),
+            iowait_busy: false,
+        }
+    }
+}
+
+/// Looks up configuration variables and collects the problems found
+/// in their values
+struct Vars<F> {
+    /// Lookup function, returns the value of a variable, if set
+    var: F,
+    /// Everything that was wrong
+    errors: Vec<anyhow::Error>,
+}
+
+/// Value of a flag variable, any case of 1, yes, true or on to
+/// enable and 0, no, false or off to disable
+struct Flag(bool);
+
+impl FromStr for Flag {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "1" | "yes" | "true" | "on" => Ok(Flag(true)),
+            "0" | "no" | "false" | "off" => Ok(Flag(false)),
+            _ => Err(anyhow!("expected one of 1/yes/true/on or 0/no/false/off")),
+        }
+    }
+}
+
+impl<F: Fn(&str) -> Option<String>> Vars<F> {
+    /// The value of the variable `name`. Munin users like to write
+    /// them in upper case, so `NAME` works too.
+    fn get(&self, name: &str) -> Option<String> {
+        (self.var)(name).or_else(|| (self.var)(&name.to_ascii_uppercase()))
+    }
+
+    /// A flag, see [Flag], disabled if unset or invalid
+    fn flag(&mut self, name: &str) -> bool {
+        self.parse(name, Flag(false)).0
+    }
+
+    /// A value that can be parsed, `default` if unset or invalid
+    fn parse<T>(&mut self, name: &str, default: T) -> T
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        match self.get(name) {
+            None => default,
+            Some(val) => val.parse().unwrap_or_else(|e| {
+                self.errors
+                    .push(anyhow!("Invalid value {val:?} for {name}: {e}"));
+                default
+            }),
+        }
+    }
+
+    /// An optional value that can be parsed, None if unset or invalid
+    fn parse_opt<T>(&mut self, name: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let val = self.get(name)?;
+        val.parse()
+            .map_err(|e| {
+                self.errors
+                    .push(anyhow!("Invalid value {val:?} for {name}: {e}"))
+            })
+            .ok()
+    }
+
+    /// The thresholds of `level` (warning or critical) set for any
+    /// field, see [Settings::warning]
+    fn thresholds(&mut self, level: &str) -> BTreeMap<Field, String> {
+        let mut thresholds = BTreeMap::new();
+        // Idle is in both, only look at it once
+        let fields: BTreeSet<Field> = Field::FINE.iter().chain(&Field::COARSE).copied().collect();
+        for field in fields {
+            let name = format!("{}_{level}", field.name());
+            let Some(val) = self.get(&name) else {
+                continue;
+            };
+            // Either bound may be left out, but not both
+            let bounds: Vec<&str> = val.split(':').collect();
+            let valid = bounds.len() <= 2
+                && bounds.iter().any(|b| !b.is_empty())
+                && bounds
+                    .iter()
+                    .all(|b| b.is_empty() || b.parse::<f64>().is_ok());
+            if valid {
+                thresholds.insert(field, val);
+            } else {
+                self.errors.push(anyhow!(
+                    "Invalid value {val:?} for {name}: expected max, min: or min:max"
+                ));
+            }
+        }
+        thresholds
+    }
+
+    /// The enabled collectors. The `collectors` variable wins,
+    /// otherwise we look at the individual flags.
+    fn collectors(&mut self) -> BTreeSet<Collector> {
+        match self.get("collectors") {
+            Some(list) => parse_collectors(&list, &mut self.errors),
+            None => Collector::ALL
+                .iter()
+                .copied()
+                .filter(|c| match c.env_flag() {
+                    Some(flag) => self.flag(flag),
+                    None => true,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// What the top collector tells processes apart by, and names its
+/// fields after
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum TopBy {
+    /// The command name, all processes of the same name add up. Stays
+    /// the same over restarts of a service.
+    #[default]
+    Comm,
+    /// The process ID, for telling apart the workers of a service.
+    /// Gone once the process exits.
+    Pid,
+}
+
+impl FromStr for TopBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "comm" => Ok(TopBy::Comm),
+            "pid" => Ok(TopBy::Pid),
+            _ => Err(anyhow!("Unknown top_by {s}, expected comm or pid")),
+        }
+    }
+}
+
+/// A set of CPUs, written like the kernel writes its cpu lists, e.g.
+/// `0-3,8,10-11`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct CpuSet(pub BTreeSet<u32>);
+
+impl FromStr for CpuSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut cpus = BTreeSet::new();
+        for range in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            let (first, last): (u32, u32) = (first.trim().parse()?, last.trim().parse()?);
+            if first > last {
+                return Err(anyhow!("Invalid cpu range {range}"));
+            }
+            cpus.extend(first..=last);
+        }
+        if cpus.is_empty() {
+            return Err(anyhow!("Empty cpu list"));
+        }
+        Ok(Self(cpus))
+    }
+}
+
+impl CpuSet {
+    /// Is the given CPU part of the set?
+    pub fn contains(&self, cpu: u32) -> bool {
+        self.0.contains(&cpu)
+    }
+}
+
+#[test]
+fn test_cpu_set() {
+    assert_eq!(
+        BTreeSet::from([0, 1, 2, 3, 8, 10, 11]),
+        "0-3,8, 10-11".parse::<CpuSet>().unwrap().0
+    );
+    assert!("3-1".parse::<CpuSet>().is_err());
+    assert!("one".parse::<CpuSet>().is_err());
+    assert!("".parse::<CpuSet>().is_err());
+}
+
+/// Parse a comma separated list of collector names. Unknown names
+/// end up in `errors`, [Collector::Cpu] is always part of the result.
+fn parse_collectors(list: &str, errors: &mut Vec<anyhow::Error>) -> BTreeSet<Collector> {
+    let mut collectors = BTreeSet::from([Collector::Cpu]);
+    for name in list.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        match name.parse() {
+            Ok(collector) => {
+                collectors.insert(collector);
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+    collectors
+}
+
+#[test]
+fn test_parse_collectors() {
+    // Whatever features this build has
+    let all: BTreeSet<Collector> = Collector::ALL.iter().copied().collect();
+    let names: Vec<&str> = Collector::ALL.iter().map(|c| c.name()).collect();
+    let mut errors = vec![];
+    assert_eq!(all, parse_collectors(&names.join(","), &mut errors));
+    assert!(errors.is_empty());
+    assert_eq!(
+        BTreeSet::from([Collector::Cpu]),
+        parse_collectors("cpu, unknown,", &mut errors)
+    );
+    assert_eq!(1, errors.len());
+    assert_eq!(
+        BTreeSet::from([Collector::Cpu]),
+        parse_collectors("", &mut errors)
+    );
+
+    // The flags switch on every collector, unless the list overrides
+    // them
+    let flags = |list: Option<&'static str>| {
+        move |name: &str| {
+            if name == "collectors" {
+                return list.map(String::from);
+            }
+            Collector::ALL
+                .iter()
+                .any(|c| c.env_flag() == Some(name))
+                .then(|| String::from("1"))
+        }
+    };
+    let (settings, errors) = Settings::from_vars(flags(None));
+    assert!(errors.is_empty());
+    assert_eq!(all, settings.collectors);
+    let (settings, errors) = Settings::from_vars(flags(Some("cpu")));
+    assert!(errors.is_empty());
+    assert_eq!(BTreeSet::from([Collector::Cpu]), settings.collectors);
+    let (_, errors) = Settings::from_vars(flags(Some("cpu,bogus")));
+    assert_eq!(1, errors.len());
+}
+
+impl Settings {
+    /// Do we emit more than one graph, and so need multigraph
+    /// output?
+    pub fn multigraph(&self) -> bool {
+        self.cpudetail
+            || self.self_metrics
+            || self.steal_graph
+            || self.aggregate.is_some()
+            || self.compat == Compat::MuninCpu
+            || self.collectors.len() > 1
+    }
+
+    /// Is `cpu` one of the [Settings::cores] we look at?
+    pub(crate) fn selected(&self, cpu: u32) -> bool {
+        self.cores.as_ref().is_none_or(|set| set.contains(cpu))
+    }
+
+    /// The stat file of [Settings::proc_root]
+    pub(crate) fn proc_stat(&self) -> PathBuf {
+        self.proc_root.join("stat")
+    }
+
+    /// update_rate for munin, [Settings::update_rate] if set, the
+    /// interval in whole seconds otherwise
+    pub(crate) fn update_rate(&self) -> u64 {
+        self.update_rate.unwrap_or_else(|| self.interval_secs())
+    }
+
+    /// The interval in whole seconds, at least one
+    fn interval_secs(&self) -> u64 {
+        self.interval.as_secs_f64().ceil().max(1.0) as u64
+    }
+
+    /// Gap between two samples from which on we write `U`, see
+    /// [Settings::max_gap]
+    pub(crate) fn max_gap(&self) -> Duration {
+        self.max_gap.max(self.interval * 2)
+    }
+
+    /// Do we write out guest and guest_nice? Yes unless
+    /// [Settings::guest_fields] says no, or was resolved to no
+    pub(crate) fn guest_fields(&self) -> bool {
+        self.guest_fields.unwrap_or(true)
+    }
+
+    /// Names of the values we write out for every CPU
+    pub(crate) fn fields(&self) -> Vec<Field> {
+        self.rollup
+            .fields()
+            .iter()
+            .filter(|field| self.guest_fields() || !field.is_guest())
+            .copied()
+            .collect()
+    }
+
+    /// Get our settings from the environment. Invalid values get
+    /// warned about and their default is used.
+    pub fn from_env() -> Self {
+        let (settings, errors) = Self::from_vars(|name| env::var(name).ok());
+        for e in errors {
+            warn!("{e}, using default");
+        }
+        settings
+    }
+
+    /// Get our settings using `var` to look up the values of
+    /// variables. Also returns every problem found with the values.
+    pub fn from_vars<F>(var: F) -> (Self, Vec<anyhow::Error>)
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let default = Self::default();
+        let mut vars = Vars {
+            var,
+            errors: vec![],
+        };
+        let proc_root = vars
+            .get("proc_root")
+            .map_or(default.proc_root.clone(), PathBuf::from);
+        let mut settings = Self {
+            cpudetail: vars.flag("cpudetail"),
+            compat: vars.parse("compat", default.compat),
+            collectors: vars.collectors(),
+            psi_io: vars.flag("psi_io"),
+            psi_memory: vars.flag("psi_memory"),
+            irqs: vars.get("irqs").map(|list| {
+                list.split(',')
+                    .map(str::trim)
+                    .filter(|id| !id.is_empty())
+                    .map(String::from)
+                    .collect()
+            }),
+            irq_top: vars.parse("irq_top", default.irq_top),
+            softirq_percore: vars.flag("softirq_percore"),
+            cgroup_paths: vars.get("cgroup_paths").map(|list| {
+                list.split(',')
+                    .map(|path| path.trim().trim_matches('/'))
+                    .filter(|path| !path.is_empty())
+                    .map(String::from)
+                    .collect()
+            }),
+            top_n: vars.parse("top_n", default.top_n),
+            top_by: vars.parse("top_by", default.top_by),
+            sleep_mode: vars.parse("sleep_mode", default.sleep_mode),
+            clock: vars.parse("clock", default.clock),
+            watchdog_timeout: Duration::from_secs(
+                vars.parse("watchdog_timeout", default.watchdog_timeout.as_secs()),
+            ),
+            watchdog_abort: vars.flag("watchdog_abort"),
+            self_metrics: vars.flag("self_metrics"),
+            resolution: vars.parse("resolution", default.resolution),
+            steal_graph: vars.flag("steal_graph"),
+            rounding: vars.parse("rounding", default.rounding),
+            max_core_graphs: vars.parse("max_core_graphs", default.max_core_graphs),
+            output: vars.parse("output", default.output),
+            foreground: vars.flag("foreground"),
+            spool_dir: vars
+                .get("spool_dir")
+                .map_or(default.spool_dir.clone(), PathBuf::from),
+            node_addr: vars.get("node_addr").unwrap_or(default.node_addr.clone()),
+            tcp_addr: vars.get("tcp_addr"),
+            tcp_buffer: vars.parse("tcp_buffer", default.tcp_buffer),
+            prometheus_addr: vars.get("prometheus_addr"),
+            influx_url: vars.parse_opt("influx_url"),
+            graphite_url: vars.parse_opt("graphite_url"),
+            graphite_prefix: vars.get("graphite_prefix"),
+            statsd_url: vars.parse_opt("statsd_url"),
+            statsd_prefix: vars.get("statsd_prefix").unwrap_or(default.statsd_prefix),
+            statsd_tags: vars.flag("statsd_tags"),
+            mqtt_url: vars.parse_opt("mqtt_url"),
+            mqtt_topic: vars.get("mqtt_topic"),
+            mqtt_qos: vars.parse("mqtt_qos", default.mqtt_qos),
+            json_path: vars.get("json_path").map(PathBuf::from),
+            otlp_url: vars.parse_opt("otlp_url"),
+            csv_path: vars.get("csv_path").map(PathBuf::from),
+            socket_path: vars.get("socket_path").map(PathBuf::from),
+            shm_path: vars.get("shm_path").map(PathBuf::from),
+            websocket_addr: vars.get("websocket_addr"),
+            api_addr: vars.get("api_addr"),
+            api_history: Duration::from_secs(
+                vars.parse("api_history", default.api_history.as_secs()),
+            ),
+            sqlite_path: vars.get("sqlite_path").map(PathBuf::from),
+            sqlite_keep: Duration::from_secs(
+                vars.parse("sqlite_keep", default.sqlite_keep.as_secs() / 3600) * 3600,
+            ),
+            parquet_dir: vars.get("parquet_dir").map(PathBuf::from),
+            parquet_rotate: Duration::from_secs(
+                vars.parse("parquet_rotate", default.parquet_rotate.as_secs()),
+            ),
+            csv_rotate: vars.parse("csv_rotate", Rotate::Never),
+            csv_sep: vars.parse("csv_sep", ','),
+            csv_decimal: vars.parse("csv_decimal", '.'),
+            source: match vars.get("source") {
+                Some(_) => vars.parse("source", default.source),
+                // Someone else's procfs, the cgroup we run in is of
+                // no interest
+                None if proc_root != default.proc_root => Source::Proc,
+                None => Source::detect(Path::new("/")),
+            },
+            proc_root: proc_root.clone(),
+            line_ending: vars.parse("line_ending", default.line_ending),
+            format: vars.parse("format", default.format),
+            collectd_hostname: vars.get("collectd_hostname"),
+            retention: match vars.get("graph_data_size") {
+                Some(size) => Retention::Custom(size),
+                None => vars.parse("retention", default.retention.clone()),
+            },
+            update_rate: vars.parse_opt("update_rate"),
+            graph_title: vars.get("graph_title").unwrap_or(default.graph_title),
+            graph_category: vars.get("graph_category").unwrap_or(default.graph_category),
+            graph_info: vars.get("graph_info").unwrap_or(default.graph_info),
+            interval: Duration::from_millis(
+                vars.parse("interval", default.interval.as_millis() as u64),
+            ),
+            prime: vars.flag("prime"),
+            prime_interval: Duration::from_millis(
+                vars.parse("prime_interval", default.prime_interval.as_millis() as u64),
+            ),
+            max_gap: Duration::from_millis(
+                vars.parse("max_gap", default.max_gap.as_millis() as u64),
+            ),
+            cores: vars.parse_opt("cores"),
+            aggregate: vars.parse_opt("aggregate"),
+            clamp_max: vars.flag("clamp_max"),
+            warning: vars.thresholds("warning"),
+            critical: vars.thresholds("critical"),
+            rollup: vars.parse("rollup", default.rollup),
+            guest_fields: vars.get("guest_fields").map(|_| vars.flag("guest_fields")),
+            group_by: vars.parse("group_by", default.group_by),
+            aggregate_fn: vars.parse("aggregate_fn", default.aggregate_fn),
+            iowait_busy: vars.flag("iowait_busy"),
+        };
+        if settings.interval < MIN_INTERVAL {
+            vars.errors.push(anyhow!(
+                "interval {:?} is too short, using {MIN_INTERVAL:?}",
+                settings.interval
+            ));
+            settings.interval = MIN_INTERVAL;
+        }
+        if let Some(rate) = settings.update_rate {
+            if rate < settings.interval_secs() {
+                vars.errors.push(anyhow!(
+                    "update_rate {rate} is shorter than the interval, using {}",
+                    settings.interval_secs()
+                ));
+                settings.update_rate = None;
+            }
+        }
+        if cfg!(not(feature = "prometheus")) && settings.prometheus_addr.is_some() {
+            vars.errors.push(anyhow!(
+                "prometheus_addr needs a build with the prometheus feature, ignoring it"
+            ));
+            settings.prometheus_addr = None;
+        }
+        if cfg!(not(feature = "sqlite")) && settings.sqlite_path.is_some() {
+            vars.errors.push(anyhow!(
+                "sqlite_path needs a build with the sqlite feature, ignoring it"
+            ));
+            settings.sqlite_path = None;
+        }
+        if cfg!(not(feature = "parquet")) && settings.parquet_dir.is_some() {
+            vars.errors.push(anyhow!(
+                "parquet_dir needs a build with the parquet feature, ignoring it"
+            ));
+            settings.parquet_dir = None;
+        }
+        if settings.parquet_rotate < Duration::from_secs(60) {
+            vars.errors.push(anyhow!(
+                "parquet_rotate {} is shorter than a minute",
+                settings.parquet_rotate.as_secs()
+            ));
+            settings.parquet_rotate = default.parquet_rotate;
+        }
+        if matches!(
+            settings.mqtt_url,
+            Some(Endpoint::Udp(_) | Endpoint::Http { .. })
+        ) {
+            vars.errors
+                .push(anyhow!("mqtt_url has to be tcp://, ignoring it"));
+            settings.mqtt_url = None;
+        }
+        if settings.mqtt_qos > 1 {
+            vars.errors.push(anyhow!(
+                "mqtt_qos {} is not supported, only 0 and 1",
+                settings.mqtt_qos
+            ));
+            settings.mqtt_qos = default.mqtt_qos;
+        }
+        if matches!(settings.graphite_url, Some(Endpoint::Http { .. })) {
+            vars.errors.push(anyhow!(
+                "graphite_url has to be tcp:// or udp://, ignoring it"
+            ));
+            settings.graphite_url = None;
+        }
+        if matches!(settings.statsd_url, Some(Endpoint::Http { .. })) {
+            vars.errors.push(anyhow!(
+                "statsd_url has to be udp:// or tcp://, ignoring it"
+            ));
+            settings.statsd_url = None;
+        }
+        if matches!(settings.otlp_url, Some(Endpoint::Udp(_) | Endpoint::Tcp(_))) {
+            vars.errors
+                .push(anyhow!("otlp_url has to be http://, ignoring it"));
+            settings.otlp_url = None;
+        }
+        if vars.flag("stdout") {
+            settings.output = Output::Stdout;
+        }
+        if settings.output == Output::Tcp && settings.tcp_addr.is_none() {
+            vars.errors
+                .push(anyhow!("output=tcp needs tcp_addr, using munin"));
+            settings.output = Output::Munin;
+        }
+        if matches!(settings.format, Format::Binary | Format::Json)
+            && settings.output != Output::Tcp
+        {
+            vars.errors.push(anyhow!(
+                "format={} needs output=tcp, using munin",
+                settings.format.name()
+            ));
+            settings.format = Format::Munin;
+        }
+        if settings.format == Format::Collectd && settings.output != Output::Stdout {
+            vars.errors
+                .push(anyhow!("format=collectd needs output=stdout, using munin"));
+            settings.format = Format::Munin;
+        }
+        if settings.output == Output::Stdout
+            && settings.json_path.as_deref() == Some(Path::new("-"))
+        {
+            vars.errors.push(anyhow!(
+                "json_path=- would mix into output=stdout, ignoring it"
+            ));
+            settings.json_path = None;
+        }
+        if settings.csv_sep == settings.csv_decimal {
+            vars.errors.push(anyhow!(
+                "csv_sep and csv_decimal are both {:?}, using , and .",
+                settings.csv_sep
+            ));
+            settings.csv_sep = ',';
+            settings.csv_decimal = '.';
+        }
+        (settings, vars.errors)
+    }
+}
+
+/// Implements `cpu1sec checkconfig`: Write the effective settings
+/// and all problems found to `out`. Returns false if there were any
+/// problems.
+pub fn checkconfig<W, F>(out: &mut W, var: F) -> Result<bool>
+where
+    W: Write,
+    F: Fn(&str) -> Option<String>,
+{
+    let (settings, errors) = Settings::from_vars(var);
+    writeln!(out, "{settings:#?}")?;
+    for e in &errors {
+        writeln!(out, "ERROR: {e}")?;
+    }
+    Ok(errors.is_empty())
+}
+
+#[test]
+fn test_flags() {
+    let (settings, errors) = Settings::from_vars(|name| match name {
+        "CPUDETAIL" => Some(String::from("yes")),
+        "steal_graph" => Some(String::from("On")),
+        "STEAL_GRAPH" => Some(String::from("no")),
+        "prime" => Some(String::from("TRUE")),
+        "clamp_max" => Some(String::from("false")),
+        "self_metrics" => Some(String::from("maybe")),
+        _ => None,
+    });
+    assert!(settings.cpudetail);
+    assert!(settings.steal_graph);
+    assert!(settings.prime);
+    assert!(!settings.clamp_max);
+    assert!(!settings.self_metrics);
+    assert_eq!(1, errors.len());
+    assert!(errors[0].to_string().contains("self_metrics"));
+}
+
+#[test]
+fn test_thresholds() {
+    let (settings, errors) = Settings::from_vars(|name| match name {
+        "iowait_warning" => Some(String::from("30")),
+        "iowait_critical" => Some(String::from("50")),
+        "idle_warning" => Some(String::from("10:")),
+        "steal_critical" => Some(String::from("0.5:2.5")),
+        "user_warning" => Some(String::from("lots")),
+        "nice_warning" => Some(String::from(":")),
+        "idle_critical" => Some(String::from("none")),
+        _ => None,
+    });
+    assert_eq!(
+        BTreeMap::from([
+            (Field::Idle, String::from("10:")),
+            (Field::Iowait, String::from("30"))
+        ]),
+        settings.warning
+    );
+    assert_eq!(
+        BTreeMap::from([
+            (Field::Iowait, String::from("50")),
+            (Field::Steal, String::from("0.5:2.5"))
+        ]),
+        settings.critical
+    );
+    // Each invalid one once, even idle in both sets of fields
+    assert_eq!(3, errors.len());
+}
+
+#[test]
+fn test_iowait_busy() {
+    let (settings, errors) = Settings::from_vars(|_| None);
+    assert!(errors.is_empty());
+    assert!(!settings.iowait_busy);
+    for (val, busy) in [("true", true), ("false", false)] {
+        let (settings, errors) =
+            Settings::from_vars(|name| (name == "iowait_busy").then(|| String::from(val)));
+        assert!(errors.is_empty());
+        assert_eq!(busy, settings.iowait_busy);
+    }
+}
+
+#[test]
+fn test_proc_root() {
+    let (settings, errors) = Settings::from_vars(|name| match name {
+        "proc_root" => Some(String::from("/host/proc")),
+        _ => None,
+    });
+    assert!(errors.is_empty());
+    assert_eq!(Path::new("/host/proc/stat"), settings.proc_stat());
+    assert_eq!(Source::Proc, settings.source);
+    // Left to the plugin, which looks in /host/proc
+    assert_eq!(None, settings.guest_fields);
+}
+
+#[test]
+fn test_interval() {
+    let settings = |interval: &'static str| {
+        Settings::from_vars(move |name| match name {
+            "interval" => Some(String::from(interval)),
+            "source" => Some(String::from("proc")),
+            _ => None,
+        })
+    };
+    let (fine, errors) = settings("2500");
+    assert!(errors.is_empty());
+    assert_eq!(Duration::from_millis(2500), fine.interval);
+    assert_eq!(3, fine.update_rate());
+    assert_eq!(Duration::from_secs(5), fine.max_gap());
+
+    let (clamped, errors) = settings("0");
+    assert_eq!(MIN_INTERVAL, clamped.interval);
+    assert_eq!(1, clamped.update_rate());
+    assert_eq!(Duration::from_secs(5), clamped.max_gap());
+    assert_eq!(1, errors.len());
+
+    let (invalid, errors) = settings("abc");
+    assert_eq!(Duration::from_secs(1), invalid.interval);
+    assert_eq!(1, errors.len());
+}
+
+#[test]
+fn test_update_rate() {
+    let settings = |rate: &'static str| {
+        Settings::from_vars(move |name| match name {
+            "interval" => Some(String::from("2000")),
+            "update_rate" => Some(String::from(rate)),
+            "graph_data_size" => Some(String::from("normal")),
+            _ => None,
+        })
+    };
+    let (fine, errors) = settings("10");
+    assert!(errors.is_empty());
+    assert_eq!(10, fine.update_rate());
+    assert_eq!("normal", fine.retention.graph_data_size());
+
+    let (short, errors) = settings("1");
+    assert_eq!(2, short.update_rate());
+    assert_eq!(1, errors.len());
+}
+
+#[test]
+fn test_json_path() {
+    let settings = |output: &'static str| {
+        Settings::from_vars(move |name| match name {
+            "output" => Some(String::from(output)),
+            "json_path" => Some(String::from("-")),
+            _ => None,
+        })
+    };
+    let (fine, errors) = settings("munin");
+    assert!(errors.is_empty());
+    assert_eq!(Some(Path::new("-")), fine.json_path.as_deref());
+
+    let (mixed, errors) = settings("stdout");
+    assert_eq!(None, mixed.json_path);
+    assert_eq!(1, errors.len());
+}
+
+#[test]
+fn test_collectd() {
+    let settings = |output: &'static str| {
+        Settings::from_vars(move |name| match name {
+            "output" => Some(String::from(output)),
+            "format" => Some(String::from("collectd")),
+            "COLLECTD_HOSTNAME" => Some(String::from("web01")),
+            _ => None,
+        })
+    };
+    let (fine, errors) = settings("stdout");
+    assert!(errors.is_empty());
+    assert_eq!(Format::Collectd, fine.format);
+    assert_eq!(Some(String::from("web01")), fine.collectd_hostname);
+
+    let (munin, errors) = settings("munin");
+    assert_eq!(Format::Munin, munin.format);
+    assert_eq!(1, errors.len());
+}
+
+#[test]
+fn test_format_json() {
+    let settings = |output: &'static str| {
+        Settings::from_vars(move |name| match name {
+            "output" => Some(String::from(output)),
+            "tcp_addr" => Some(String::from("collector:4711")),
+            "format" => Some(String::from("json")),
+            _ => None,
+        })
+    };
+    let (fine, errors) = settings("tcp");
+    assert!(errors.is_empty());
+    assert_eq!(Format::Json, fine.format);
+
+    let (munin, errors) = settings("munin");
+    assert_eq!(Format::Munin, munin.format);
+    assert_eq!(
+        "format=json needs output=tcp, using munin",
+        errors[0].to_string()
+    );
+}
+
+#[test]
+fn test_csv() {
+    let settings = |sep: &'static str, decimal: &'static str| {
+        Settings::from_vars(move |name| match name {
+            "csv_path" => Some(String::from("/var/lib/munin/cpu1sec.csv")),
+            "csv_rotate" => Some(String::from("100M")),
+            "csv_sep" => Some(String::from(sep)),
+            "csv_decimal" => Some(String::from(decimal)),
+            _ => None,
+        })
+    };
+    let (european, errors) = settings(";", ",");
+    assert!(errors.is_empty());
+    assert_eq!(Rotate::Size(100 << 20), european.csv_rotate);
+    assert_eq!((';', ','), (european.csv_sep, european.csv_decimal));
+
+    let (same, errors) = settings(",", ",");
+    assert_eq!((',', '.'), (same.csv_sep, same.csv_decimal));
+    assert_eq!(1, errors.len());
+}
+
+#[test]
+fn test_checkconfig() {
+    let vars = |name: &str| match name {
+        "cpudetail" => Some(String::from("1")),
+        "sleep_mode" => Some(String::from("busy")),
+        "clock" => Some(String::from("monotonic")),
+        _ => None,
+    };
+    let mut out = vec![];
+    assert!(checkconfig(&mut out, vars).unwrap());
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("cpudetail: true"));
+    assert!(out.contains("sleep_mode: Busy"));
+    assert!(out.contains("clock: Monotonic"));
+
+    let vars = |name: &str| match name {
+        "watchdog_timeout" => Some(String::from("soon")),
+        "collectors" => Some(String::from("cpu,tmep")),
+        _ => None,
+    };
+    let mut out = vec![];
+    assert!(!checkconfig(&mut out, vars).unwrap());
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(2, out.matches("ERROR: ").count());
+
+    for cores in ["5-2", "abc"] {
+        let vars = |name: &str| (name == "cores").then(|| String::from(cores));
+        let mut out = vec![];
+        assert!(!checkconfig(&mut out, vars).unwrap());
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(1, out.matches("ERROR: ").count(), "{cores}");
+        assert!(out.contains(cores));
+    }
+}
@@ -0,0 +1,275 @@
+//! Snapshot of the latest sample in a memory mapped file
+//! (`shm_path`), for local readers that want it more often, or at
+//! other times, than any output hands it out
+//!
+//! The file is best put on a tmpfs, like `/dev/shm/cpu1sec`. We only
+//! ever grow it, readers can keep it mapped across our restarts.
+//!
+//! | Offset | Type    | Content                                         |
+//! |--------|---------|-------------------------------------------------|
+//! | 0      | 8 bytes | Magic, `CPU1SEC\0`                              |
+//! | 8      | u32     | Version of this layout, [VERSION]               |
+//! | 12     | u32     | Bytes of room for the frame                     |
+//! | 16     | u64     | Sequence number, odd while we write             |
+//! | 24     |         | The sample, one frame as in [crate::binary]     |
+//!
+//! Numbers are little endian, but for the sequence number, which is
+//! in the byte order of the host. A reader takes the sequence number,
+//! copies the frame and takes the sequence number again. Was it odd,
+//! or did it change, a write got in between, and it has to try
+//! again. [read] does just that, no locks involved.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{binary, fanout::Target, CpuStat, Settings};
+use anyhow::{bail, Result};
+use log::warn;
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+    ptr,
+    sync::atomic::{fence, AtomicU64, Ordering},
+    thread,
+};
+
+/// Start of the file
+const MAGIC: &[u8; 8] = b"CPU1SEC\0";
+
+/// Version of the layout. Readers have to check it, it changes with
+/// every incompatible change.
+pub const VERSION: u32 = 1;
+
+/// Size of the header before the frame
+const HEADER: usize = 24;
+
+/// Where the sequence number is
+const SEQUENCE: usize = 16;
+
+/// How often [read] tries to get a sample between two writes
+const ATTEMPTS: usize = 100;
+
+/// A file, mapped into our memory
+#[derive(Debug)]
+struct Mapping {
+    /// Start of the mapping
+    ptr: *mut u8,
+    /// Its length
+    len: usize,
+}
+
+// The mapping belongs to whoever has the Mapping, sending it to the
+// fan-out thread is fine
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    /// Map the first `len` bytes of `file`, `writable` or read only
+    fn new(file: &File, len: usize, writable: bool) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        // SAFETY: a fresh mapping of a file we hold open, nothing
+        // else in here has it
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    /// The sequence number
+    fn sequence(&self) -> &AtomicU64 {
+        // SAFETY: mappings are page aligned and longer than HEADER,
+        // and the other side only ever touches it atomically too
+        unsafe { &*self.ptr.add(SEQUENCE).cast::<AtomicU64>() }
+    }
+
+    /// Copy `len` bytes at `offset` out. Volatile, a writer may be
+    /// at them right now, the sequence number tells.
+    fn load(&self, offset: usize, len: usize) -> Vec<u8> {
+        assert!(offset + len <= self.len);
+        // SAFETY: within the mapping, see above
+        (offset..offset + len)
+            .map(|at| unsafe { self.ptr.add(at).read_volatile() })
+            .collect()
+    }
+
+    /// Copy `bytes` in at `offset`
+    fn store(&mut self, offset: usize, bytes: &[u8]) {
+        assert!(offset + bytes.len() <= self.len);
+        for (at, byte) in bytes.iter().enumerate() {
+            // SAFETY: within the mapping, see above
+            unsafe { self.ptr.add(offset + at).write_volatile(*byte) }
+        }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: ours, and nothing refers to it anymore
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+/// Publishes every sample to the file at [crate::Settings::shm_path]
+#[derive(Debug)]
+pub(crate) struct Snapshot {
+    /// The file, to grow it
+    file: File,
+    /// The file, mapped
+    map: Mapping,
+}
+
+impl Snapshot {
+    /// Publish to `path`, creating it if need be. An existing file
+    /// is not truncated, its readers would crash on the pages they
+    /// lost.
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let empty = binary::encode(0, &[]);
+        let len = (file.metadata()?.len() as usize).max(HEADER + empty.len());
+        file.set_len(len as u64)?;
+        let mut map = Mapping::new(&file, len, true)?;
+        if map.load(0, 8) != MAGIC || map.load(8, 4) != VERSION.to_le_bytes() {
+            map.store(HEADER, &empty);
+            map.store(12, &((len - HEADER) as u32).to_le_bytes());
+            map.store(8, &VERSION.to_le_bytes());
+            map.store(0, MAGIC);
+        }
+        // Should we have died in the middle of a write
+        let sequence = map.sequence().load(Ordering::Relaxed);
+        map.sequence().store((sequence + 1) & !1, Ordering::Release);
+        Ok(Self { file, map })
+    }
+
+    /// Publish the sample `graphs`
+    pub(crate) fn publish(&mut self, graphs: &[CpuStat]) -> io::Result<()> {
+        let epoch = graphs.first().map_or(0, |stat| stat.epoch);
+        let frame = binary::encode(epoch, graphs);
+        if HEADER + frame.len() > self.map.len {
+            // More CPUs than before, hotplug
+            let len = HEADER + frame.len().next_power_of_two();
+            self.file.set_len(len as u64)?;
+            self.map = Mapping::new(&self.file, len, true)?;
+        }
+        let sequence = self.map.sequence().fetch_add(1, Ordering::Relaxed);
+        // The odd number has to be out before anything else changes
+        fence(Ordering::Release);
+        let room = (self.map.len - HEADER) as u32;
+        self.map.store(12, &room.to_le_bytes());
+        self.map.store(HEADER, &frame);
+        self.map
+            .sequence()
+            .store(sequence.wrapping_add(2), Ordering::Release);
+        Ok(())
+    }
+}
+
+impl Target for Snapshot {
+    fn deliver(&mut self, graphs: &[CpuStat]) {
+        if let Err(e) = self.publish(graphs) {
+            warn!("Could not publish sample to shared memory: {e}");
+        }
+    }
+}
+
+/// The [Target] for [Settings::shm_path], if set and we can create
+/// it
+pub(crate) fn target(settings: &Settings) -> Option<Box<dyn Target>> {
+    let path = settings.shm_path.as_ref()?;
+    match Snapshot::create(path) {
+        Ok(snapshot) => Some(Box::new(snapshot)),
+        Err(e) => {
+            warn!("Not publishing samples to {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// Read the latest sample from the snapshot at `path`
+pub fn read(path: &Path) -> Result<Vec<CpuStat>> {
+    for _ in 0..ATTEMPTS {
+        // Map anew every time, we may have grown the file
+        let file = File::open(path)?;
+        let len = file.metadata()?.len() as usize;
+        if len < HEADER {
+            bail!("{} is too short for a snapshot", path.display());
+        }
+        let map = Mapping::new(&file, len, false)?;
+        if map.load(0, 8) != MAGIC {
+            bail!("{} is no snapshot of cpu1sec", path.display());
+        }
+        let version = u32::from_le_bytes(map.load(8, 4).try_into().unwrap_or_default());
+        if version != VERSION {
+            bail!("Snapshot has version {version}, we know {VERSION}");
+        }
+        let before = map.sequence().load(Ordering::Acquire);
+        let room = u32::from_le_bytes(map.load(12, 4).try_into().unwrap_or_default()) as usize;
+        if before % 2 == 0 && HEADER + room <= len {
+            let frame = map.load(HEADER, room);
+            fence(Ordering::Acquire);
+            if map.sequence().load(Ordering::Relaxed) == before {
+                return binary::decode(&mut frame.as_slice());
+            }
+        }
+        thread::yield_now();
+    }
+    bail!("Snapshot changes faster than we can read it")
+}
+
+#[test]
+fn test_snapshot() {
+    use crate::CpuId;
+
+    let path = std::env::temp_dir().join(format!("cpu1sec-shm-{}", std::process::id()));
+    std::fs::write(&path, "something else").unwrap();
+    assert!(read(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
+
+    let mut snapshot = Snapshot::create(&path).unwrap();
+    // Nothing published yet
+    assert!(read(&path).unwrap().is_empty());
+    let stat = |cpu, epoch| CpuStat {
+        cpu,
+        epoch,
+        user: 30,
+        idle: 70,
+        ..Default::default()
+    };
+    snapshot.publish(&[stat(CpuId::Aggregate, 1)]).unwrap();
+    assert_eq!(vec![stat(CpuId::Aggregate, 1)], read(&path).unwrap());
+
+    // Grows for more CPUs
+    let many: Vec<CpuStat> = (0..64).map(|cpu| stat(CpuId::Core(cpu), 2)).collect();
+    snapshot.publish(&many).unwrap();
+    assert_eq!(many, read(&path).unwrap());
+    assert_eq!(4, snapshot.map.sequence().load(Ordering::Relaxed));
+
+    // A restart keeps the file, and its size
+    drop(snapshot);
+    let mut snapshot = Snapshot::create(&path).unwrap();
+    assert_eq!(many, read(&path).unwrap());
+    snapshot.publish(&many[..1]).unwrap();
+    assert_eq!(many[..1], read(&path).unwrap());
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,164 @@
+//! Where the daemon writes its samples to, one [OutputSink] per
+//! [Output]. The sampling loop only ever talks to the trait, a new
+//! output or format is a new implementation.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{
+    binary,
+    collectd::Collectd,
+    json,
+    node::Node,
+    output::{self, Sink, TcpOutput},
+    spool::Spool,
+    CpuStat, Format, Output, Settings,
+};
+use anyhow::Result;
+use log::info;
+use std::{fs::OpenOptions, io, path::PathBuf, time::Duration};
+
+/// How much of the samples [Output::Node] keeps until a munin master
+/// fetches them
+const NODE_BUFFER: Duration = Duration::from_secs(600);
+
+/// One sample, in the forms the outputs want it
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Sample<'a> {
+    /// When it was taken
+    pub(crate) epoch: u64,
+    /// All of it, as munin gets it
+    pub(crate) munin: &'a [u8],
+    /// The CPU graphs, None if this sample has no values for them
+    /// (first one, counter reset, gap)
+    pub(crate) graphs: Option<&'a [CpuStat]>,
+}
+
+/// Takes the samples of the daemon
+pub(crate) trait OutputSink {
+    /// Write the `config` of our graphs, in munin's format, once
+    /// before the first sample. Only for outputs without a munin
+    /// asking for it.
+    fn write_config(&mut self, _config: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Write one `sample`. If that fails, it is dropped, and the next
+    /// one gets its chance.
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()>;
+
+    /// Get out whatever is still pending, before we stop
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The sink for the [Output] of `settings`. [Output::Munin] appends to
+/// `fetchpath`.
+pub(crate) fn open(settings: &Settings, fetchpath: PathBuf) -> Result<Box<dyn OutputSink>> {
+    Ok(
+        match (settings.output, &settings.tcp_addr, settings.format) {
+            (Output::Tcp, Some(addr), format) => {
+                let mut tcp = TcpOutput::new(addr.clone(), settings.tcp_buffer);
+                if format == Format::Json {
+                    tcp.greeting = json::header();
+                }
+                Box::new(Stream { tcp, format })
+            }
+            (Output::Spool, _, _) => Box::new(Spool::new(&settings.spool_dir)),
+            (Output::Node, _, _) => {
+                // Room for the samples of two polls by a munin master,
+                // should it miss one
+                let capacity = NODE_BUFFER.as_millis() / settings.interval.as_millis().max(1);
+                let node = Node::spawn(&settings.node_addr, capacity as usize)?;
+                info!("Serving munin on {}", node.addr);
+                Box::new(node)
+            }
+            (Output::Stdout, _, Format::Collectd) => Box::new(Collectd::new(settings)),
+            (Output::Stdout, _, _) => Box::new(MuninText {
+                out: io::stdout().lock(),
+            }),
+            _ => Box::new(FetchFile { path: fetchpath }),
+        },
+    )
+}
+
+/// The file munin's fetch picks the samples up from
+#[derive(Debug)]
+struct FetchFile {
+    /// Where it is
+    path: PathBuf,
+}
+
+impl OutputSink for FetchFile {
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        // fetch renames the file away, so open it fresh every time
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        output::write_block(&mut file, sample.munin)
+    }
+}
+
+/// munin's text, config first, to something that stays open, like
+/// stdout
+#[derive(Debug)]
+pub(crate) struct MuninText<S: Sink> {
+    /// Where it goes
+    pub(crate) out: S,
+}
+
+impl<S: Sink> OutputSink for MuninText<S> {
+    fn write_config(&mut self, config: &[u8]) -> io::Result<()> {
+        output::write_block(&mut self.out, config)
+    }
+
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        output::write_block(&mut self.out, sample.munin)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// [Output::Tcp], in the [Format] asked for
+struct Stream {
+    /// The connection
+    tcp: TcpOutput,
+    /// What we send over it
+    format: Format,
+}
+
+impl OutputSink for Stream {
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        match (self.format, sample.graphs) {
+            (Format::Binary, Some(graphs)) => self.tcp.send(binary::encode(sample.epoch, graphs)),
+            (Format::Json, Some(graphs)) => self.tcp.send(json::encode(graphs)),
+            (Format::Binary | Format::Json, None) => {}
+            _ => self.tcp.send(sample.munin.to_vec()),
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_fetch_file() {
+    let path = std::env::temp_dir().join(format!("cpu1sec-sink-{}", std::process::id()));
+    let mut sink = open(&Settings::default(), path.clone()).unwrap();
+    sink.write_config(b"graph_title CPU\n").unwrap();
+    for epoch in [2, 3] {
+        let munin = format!("total_user.value {epoch}:1\n");
+        let sample = Sample {
+            epoch,
+            munin: munin.as_bytes(),
+            graphs: None,
+        };
+        sink.write_sample(&sample).unwrap();
+    }
+    sink.flush().unwrap();
+    assert_eq!(
+        "total_user.value 2:1\ntotal_user.value 3:1\n",
+        std::fs::read_to_string(&path).unwrap()
+    );
+    std::fs::remove_file(&path).unwrap();
+}
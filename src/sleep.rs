@@ -0,0 +1,87 @@
+//! Waiting between two samples in daemon mode
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use anyhow::Result;
+use std::{
+    hint,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How the daemon waits between two samples
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum SleepMode {
+    /// Sleep for the full interval after each sample. Simple, but
+    /// the time taken by a sample adds up, so it drifts.
+    Fixed,
+    /// Sleep until the next interval boundary (the next full second
+    /// with the default interval of 1s).
+    #[default]
+    Aligned,
+    /// Spin until the next interval boundary. Lowest jitter, but
+    /// burns a core, so only useful with a core dedicated to us.
+    Busy,
+}
+
+impl FromStr for SleepMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fixed" => Ok(SleepMode::Fixed),
+            "aligned" => Ok(SleepMode::Aligned),
+            "busy" => Ok(SleepMode::Busy),
+            _ => Err(anyhow::anyhow!("Unknown sleep mode {s}")),
+        }
+    }
+}
+
+impl SleepMode {
+    /// How long to wait for the next sample, with `now` being the
+    /// time since [std::time::UNIX_EPOCH]
+    fn duration(&self, now: Duration, interval: Duration) -> Duration {
+        match self {
+            SleepMode::Fixed => interval,
+            SleepMode::Aligned | SleepMode::Busy => {
+                let into = now.as_nanos() % interval.as_nanos();
+                interval - Duration::from_nanos(into as u64)
+            }
+        }
+    }
+
+    /// Wait for the next sample, with `now` being the time since
+    /// [std::time::UNIX_EPOCH], as the daemon's clock says
+    pub(crate) fn sleep(&self, now: Duration, interval: Duration) {
+        let wait = self.duration(now, interval);
+        match self {
+            SleepMode::Fixed | SleepMode::Aligned => thread::sleep(wait),
+            SleepMode::Busy => {
+                let until = Instant::now() + wait;
+                while Instant::now() < until {
+                    hint::spin_loop();
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sleep_duration() {
+    let second = Duration::from_secs(1);
+    let now = Duration::from_millis(1_650_000_000_300);
+    assert_eq!(
+        Duration::from_millis(700),
+        SleepMode::Aligned.duration(now, second)
+    );
+    assert_eq!(
+        Duration::from_millis(700),
+        SleepMode::Busy.duration(now, second)
+    );
+    assert_eq!(second, SleepMode::Fixed.duration(now, second));
+    // Right on the boundary we wait a full interval
+    assert_eq!(
+        second,
+        SleepMode::Aligned.duration(Duration::from_secs(1_650_000_000), second)
+    );
+}
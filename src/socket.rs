@@ -0,0 +1,166 @@
+//! Unix socket serving the samples of the daemon to local tools, as
+//! JSON lines (see [crate::json])
+//!
+//! A client connects and sends one line: `latest` gets the last
+//! sample and the connection closed, `stream` gets every sample from
+//! then on, until it disconnects.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{fanout::Target, json, CpuStat, Settings};
+use anyhow::Result;
+use log::warn;
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
+
+/// How long a client may take to say what it wants, or to read a
+/// sample
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the clients get
+#[derive(Debug, Default)]
+struct Shared {
+    /// The last sample
+    latest: Vec<u8>,
+    /// Clients that want every sample
+    streams: Vec<UnixStream>,
+}
+
+/// Serves the samples on a Unix socket, from a thread of its own
+#[derive(Debug)]
+pub(crate) struct SocketServer {
+    /// Where we listen
+    path: PathBuf,
+    /// Shared with the thread accepting clients
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl SocketServer {
+    /// Listen on `path`, replacing a socket left over from an
+    /// earlier run. Has to happen after daemonizing, threads do not
+    /// survive the fork.
+    pub(crate) fn spawn(path: &Path) -> Result<Self> {
+        if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let clients = Arc::clone(&shared);
+        thread::Builder::new()
+            .name(String::from("socket"))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if let Err(e) = stream.and_then(|stream| serve(stream, &clients)) {
+                        warn!("Could not serve a socket client: {e}");
+                    }
+                }
+            })?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            shared,
+        })
+    }
+
+    /// Hand the sample `graphs` to the clients. Streams that can not
+    /// take it get dropped.
+    pub(crate) fn update(&self, graphs: &[CpuStat]) {
+        let sample = json::encode(graphs);
+        let mut shared = self.shared.lock().unwrap_or_else(PoisonError::into_inner);
+        shared
+            .streams
+            .retain_mut(|stream| stream.write_all(&sample).is_ok());
+        shared.latest = sample;
+    }
+}
+
+impl Target for SocketServer {
+    fn deliver(&mut self, graphs: &[CpuStat]) {
+        self.update(graphs);
+    }
+}
+
+/// The [Target] for [Settings::socket_path], if set and we can
+/// listen there
+pub(crate) fn target(settings: &Settings) -> Option<Box<dyn Target>> {
+    let path = settings.socket_path.as_ref()?;
+    match SocketServer::spawn(path) {
+        Ok(server) => Some(Box::new(server)),
+        Err(e) => {
+            warn!("Not serving samples on {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+impl Drop for SocketServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Answer one client
+fn serve(stream: UnixStream, shared: &Mutex<Shared>) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+    let mut shared = shared.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut stream = stream;
+    match request.trim() {
+        "latest" => stream.write_all(&shared.latest),
+        "stream" => {
+            shared.streams.push(stream);
+            Ok(())
+        }
+        _ => stream.write_all(b"Say latest or stream\n"),
+    }
+}
+
+#[test]
+fn test_socket_server() {
+    use std::io::Read;
+
+    let path = std::env::temp_dir().join(format!("cpu1sec-socket-{}", std::process::id()));
+    fs::write(&path, "").unwrap();
+    // Not a socket, we leave that alone
+    assert!(SocketServer::spawn(&path).is_err());
+    fs::remove_file(&path).unwrap();
+
+    let server = SocketServer::spawn(&path).unwrap();
+    let stat = |epoch| CpuStat {
+        epoch,
+        ..Default::default()
+    };
+    server.update(&[stat(1)]);
+    let request = |what: &str| {
+        let mut client = UnixStream::connect(&path).unwrap();
+        writeln!(client, "{what}").unwrap();
+        client
+    };
+    let mut latest = String::new();
+    request("latest").read_to_string(&mut latest).unwrap();
+    assert!(latest.starts_with("{\"cpu\":\"total\",\"epoch\":1,"));
+
+    let stream = request("stream");
+    // The server thread has to take it first
+    while server.shared.lock().unwrap().streams.is_empty() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    server.update(&[stat(2)]);
+    server.update(&[stat(3)]);
+    let mut lines = BufReader::new(stream).lines();
+    assert!(lines.next().unwrap().unwrap().contains("\"epoch\":2,"));
+    assert!(lines.next().unwrap().unwrap().contains("\"epoch\":3,"));
+
+    drop(server);
+    assert!(!path.exists());
+}
@@ -0,0 +1,293 @@
+//! Softirqs per second, by kind, from /proc/softirqs, the
+//! `softirq1sec` graphs of [crate::Collector::Softirq]
+//!
+//! They explain what the softirq time of the CPU usage graphs is
+//! made of: network receive and transmit, timers, RCU and so on. The
+//! total graph sums up all CPUs, with [crate::Settings::softirq_percore]
+//! every CPU gets a graph of its own below it. The kinds and CPUs
+//! are taken from /proc/softirqs when we start, munin needs to know
+//! them up front.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::Settings;
+use anyhow::{anyhow, Result};
+use log::{info, warn};
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::Path,
+    time::Duration,
+};
+
+/// Name of our graph
+const GRAPH: &str = "softirq1sec";
+
+/// The content of /proc/softirqs
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct Softirqs {
+    /// The CPUs, by number, in the order of the columns
+    cpus: Vec<u32>,
+    /// The kinds, e.g. NET_RX, with their count per CPU since boot
+    kinds: Vec<(String, Vec<u64>)>,
+}
+
+impl Softirqs {
+    /// Parse the content of /proc/softirqs
+    fn parse(content: &str) -> Result<Self> {
+        let mut lines = content.lines();
+        let cpus = lines
+            .next()
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|cpu| {
+                cpu.strip_prefix("CPU")
+                    .and_then(|cpu| cpu.parse().ok())
+                    .ok_or_else(|| anyhow!("Unknown CPU {cpu} in softirqs"))
+            })
+            .collect::<Result<Vec<u32>>>()?;
+        if cpus.is_empty() {
+            return Err(anyhow!("No CPUs in the header of softirqs"));
+        }
+        let kinds = lines
+            .filter_map(|line| {
+                let (kind, counts) = line.split_once(':')?;
+                let counts = counts
+                    .split_whitespace()
+                    .map(str::parse)
+                    .collect::<Result<Vec<u64>, _>>()
+                    .ok()?;
+                Some((kind.trim().to_string(), counts))
+            })
+            .collect();
+        Ok(Self { cpus, kinds })
+    }
+
+    /// Read /proc/softirqs below `proc_root`
+    fn read(proc_root: &Path) -> Result<Self> {
+        Self::parse(&fs::read_to_string(proc_root.join("softirqs"))?)
+    }
+
+    /// Count of `kind` on the CPU in column `column`, all of them for
+    /// None
+    fn count(&self, kind: &str, column: Option<usize>) -> Option<u64> {
+        let (_, counts) = self.kinds.iter().find(|(name, _)| name == kind)?;
+        match column {
+            Some(column) => counts.get(column).copied(),
+            None => Some(counts.iter().sum()),
+        }
+    }
+}
+
+#[test]
+fn test_parse() {
+    let softirqs = Softirqs::parse(
+        "                    CPU0       CPU2\n\
+                  HI:          0          1\n\
+               TIMER:     207034        100\n\
+              NET_RX:      23897          3\n",
+    )
+    .unwrap();
+    assert_eq!(vec![0, 2], softirqs.cpus);
+    assert_eq!(3, softirqs.kinds.len());
+    assert_eq!(Some(207134), softirqs.count("TIMER", None));
+    assert_eq!(Some(3), softirqs.count("NET_RX", Some(1)));
+    assert_eq!(None, softirqs.count("RCU", None));
+    assert!(Softirqs::parse("").is_err());
+    assert!(Softirqs::parse("  Core0\n").is_err());
+}
+
+/// Reads /proc/softirqs, and keeps what it read last to take the
+/// difference
+#[derive(Debug, Default)]
+pub(crate) struct SoftirqCollector {
+    /// The kinds of softirqs
+    kinds: Vec<String>,
+    /// The CPUs with a graph of their own, with their column
+    cpus: Vec<(u32, usize)>,
+    /// Epoch of the last read, and what we read then
+    last: Option<(u64, Softirqs)>,
+}
+
+impl SoftirqCollector {
+    /// Take the kinds and CPUs from the softirqs file of
+    /// [Settings::proc_root]. Per-core graphs only with
+    /// [Settings::softirq_percore], for the CPUs in
+    /// [Settings::cores], at most [Settings::max_core_graphs].
+    pub(crate) fn new(settings: &Settings) -> Self {
+        let softirqs = match Softirqs::read(&settings.proc_root) {
+            Ok(softirqs) => softirqs,
+            Err(e) => {
+                warn!("Could not read softirqs: {e}, nothing to graph");
+                return Self::default();
+            }
+        };
+        let cpus = if settings.softirq_percore {
+            softirqs
+                .cpus
+                .iter()
+                .enumerate()
+                .map(|(column, cpu)| (*cpu, column))
+                .filter(|(cpu, _)| settings.selected(*cpu))
+                .take(settings.max_core_graphs)
+                .collect()
+        } else {
+            vec![]
+        };
+        Self {
+            kinds: softirqs.kinds.into_iter().map(|(kind, _)| kind).collect(),
+            cpus,
+            last: None,
+        }
+    }
+
+    /// Write out the config of our graphs
+    pub(crate) fn config<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        settings: &Settings,
+    ) -> Result<()> {
+        writeln!(handle, "multigraph {GRAPH}")?;
+        self.write_details(handle, settings, "all CPUs")?;
+        for (cpu, _) in &self.cpus {
+            writeln!(handle, "multigraph {GRAPH}.cpu{cpu}")?;
+            self.write_details(handle, settings, &format!("cpu{cpu}"))?;
+        }
+        Ok(())
+    }
+
+    /// The config of one of our graphs, for `cpus`
+    fn write_details<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        settings: &Settings,
+        cpus: &str,
+    ) -> Result<()> {
+        writeln!(handle, "graph_title Softirqs {cpus} (1sec)")?;
+        writeln!(handle, "graph_category {}", settings.graph_category)?;
+        writeln!(handle, "update_rate {}", settings.update_rate())?;
+        writeln!(
+            handle,
+            "graph_data_size {}",
+            settings.retention.graph_data_size()
+        )?;
+        writeln!(handle, "graph_args --base 1000 --lower-limit 0")?;
+        writeln!(handle, "graph_vlabel softirqs per second")?;
+        writeln!(
+            handle,
+            "graph_info Softirqs handled per second on {cpus}, by kind, from /proc/softirqs."
+        )?;
+        for (i, kind) in self.kinds.iter().enumerate() {
+            let field = kind.to_ascii_lowercase();
+            writeln!(handle, "{field}.label {kind}")?;
+            writeln!(
+                handle,
+                "{field}.draw {}",
+                if i == 0 { "AREA" } else { "STACK" }
+            )?;
+            writeln!(handle, "{field}.min 0")?;
+            writeln!(handle, "{field}.type GAUGE")?;
+        }
+        Ok(())
+    }
+
+    /// Read /proc/softirqs below `proc_root` and write out the
+    /// softirqs per second since the last read, for `epoch`. Unknown
+    /// if we can not tell, after more than `max_gap` or with counts
+    /// going backwards. With more than one sample per second only the
+    /// first one in a second counts.
+    pub(crate) fn write<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        proc_root: &Path,
+        epoch: u64,
+        max_gap: Duration,
+    ) -> Result<()> {
+        if self.last.as_ref().is_some_and(|(last, _)| *last == epoch) {
+            return Ok(());
+        }
+        let new = Softirqs::read(proc_root).unwrap_or_else(|e| {
+            info!("Could not read softirqs: {e}");
+            Softirqs::default()
+        });
+        let old = self
+            .last
+            .as_ref()
+            .filter(|(last, _)| epoch > *last && Duration::from_secs(epoch - last) <= max_gap)
+            // The columns only match if the CPUs do
+            .filter(|(_, old)| old.cpus == new.cpus)
+            .map(|(last, old)| (epoch - last, old));
+        let graphs = std::iter::once((String::from(GRAPH), None)).chain(
+            self.cpus
+                .iter()
+                .map(|(cpu, column)| (format!("{GRAPH}.cpu{cpu}"), Some(*column))),
+        );
+        for (graph, column) in graphs {
+            writeln!(handle, "multigraph {graph}")?;
+            for kind in &self.kinds {
+                let field = kind.to_ascii_lowercase();
+                let rate = old.and_then(|(seconds, old)| {
+                    let diff = new
+                        .count(kind, column)?
+                        .checked_sub(old.count(kind, column)?)?;
+                    Some(diff as f64 / seconds as f64)
+                });
+                match rate {
+                    Some(rate) => writeln!(handle, "{field}.value {epoch}:{rate:.0}")?,
+                    None => writeln!(handle, "{field}.value {epoch}:U")?,
+                }
+            }
+        }
+        self.last = Some((epoch, new));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_softirq_collector() {
+    let root = std::env::temp_dir().join(format!("cpu1sec-softirq-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let softirqs = |timer, net_rx| {
+        format!(
+            "                    CPU0       CPU1\n\
+                       TIMER:  {timer}         10\n\
+                      NET_RX:  {net_rx}         1\n"
+        )
+    };
+    fs::write(root.join("softirqs"), softirqs(100, 50)).unwrap();
+    let settings = Settings {
+        proc_root: root.clone(),
+        softirq_percore: true,
+        cores: Some("1".parse().unwrap()),
+        ..Default::default()
+    };
+    let mut collector = SoftirqCollector::new(&settings);
+    let mut config = BufWriter::new(Vec::new());
+    collector.config(&mut config, &settings).unwrap();
+    let config = String::from_utf8(config.into_inner().unwrap()).unwrap();
+    assert!(config.starts_with("multigraph softirq1sec\ngraph_title Softirqs all CPUs (1sec)\n"));
+    assert!(config.contains("timer.label TIMER\ntimer.draw AREA\n"));
+    assert!(config.contains("net_rx.label NET_RX\nnet_rx.draw STACK\n"));
+    assert!(config.contains("multigraph softirq1sec.cpu1\n"));
+    assert!(!config.contains("softirq1sec.cpu0"));
+
+    let mut values = |epoch| {
+        let mut handle = BufWriter::new(Vec::new());
+        collector
+            .write(&mut handle, &root, epoch, Duration::from_secs(5))
+            .unwrap();
+        String::from_utf8(handle.into_inner().unwrap()).unwrap()
+    };
+    assert!(values(1).starts_with("multigraph softirq1sec\ntimer.value 1:U\n"));
+    fs::write(root.join("softirqs"), softirqs(300, 50)).unwrap();
+    assert_eq!(
+        "multigraph softirq1sec\n\
+         timer.value 3:100\n\
+         net_rx.value 3:0\n\
+         multigraph softirq1sec.cpu1\n\
+         timer.value 3:0\n\
+         net_rx.value 3:0\n",
+        values(3)
+    );
+    assert_eq!("", values(3));
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,90 @@
+//! Where the total CPU usage is read from
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::cgroup::CGROUP_CPU_STAT;
+use anyhow::Result;
+use log::info;
+use std::{fs, path::Path, str::FromStr};
+
+/// Source of the values for the total graph
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Source {
+    /// /proc/stat, the numbers of the whole machine
+    #[default]
+    Proc,
+    /// The cgroup v2 `cpu.stat` of the cgroup we run in. Inside a
+    /// container that is the container itself, not the host. It only
+    /// knows user and system time, every other field stays 0, and
+    /// per-core graphs still come from /proc/stat.
+    Cgroup,
+}
+
+impl FromStr for Source {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "proc" => Ok(Source::Proc),
+            "cgroup" => Ok(Source::Cgroup),
+            _ => Err(anyhow::anyhow!("Unknown source {s}")),
+        }
+    }
+}
+
+impl Source {
+    /// The source to use if the user did not pick one: [Source::Cgroup]
+    /// if we run in a container that has a `cpu.stat`, [Source::Proc]
+    /// otherwise. `root` is the directory to look at, usually `/`.
+    pub(crate) fn detect(root: &Path) -> Self {
+        if containerized(root) && root.join(CGROUP_CPU_STAT.trim_start_matches('/')).exists() {
+            info!("Running in a container, reading the total from {CGROUP_CPU_STAT}");
+            Source::Cgroup
+        } else {
+            Source::Proc
+        }
+    }
+}
+
+/// Do we run inside a container? Looks for the marker files docker
+/// and podman leave behind, and for container runtimes in the cgroup
+/// path of pid 1.
+fn containerized(root: &Path) -> bool {
+    if root.join(".dockerenv").exists() || root.join("run/.containerenv").exists() {
+        return true;
+    }
+    fs::read_to_string(root.join("proc/1/cgroup")).is_ok_and(|cgroup| {
+        ["docker", "kubepods", "containerd", "libpod", "lxc"]
+            .iter()
+            .any(|runtime| cgroup.contains(runtime))
+    })
+}
+
+#[test]
+fn test_detect_source() {
+    let root = std::env::temp_dir().join(format!("cpu1sec-source-{}", std::process::id()));
+    fs::create_dir_all(root.join("proc/1")).unwrap();
+    fs::create_dir_all(root.join("sys/fs/cgroup")).unwrap();
+    fs::write(
+        root.join("sys/fs/cgroup/cpu.stat"),
+        "user_usec 1\nsystem_usec 1\n",
+    )
+    .unwrap();
+
+    // Plain host
+    fs::write(root.join("proc/1/cgroup"), "0::/init.scope\n").unwrap();
+    assert_eq!(Source::Proc, Source::detect(&root));
+
+    // Looks like docker
+    fs::write(
+        root.join("proc/1/cgroup"),
+        "0::/system.slice/docker-0123abcd.scope\n",
+    )
+    .unwrap();
+    assert_eq!(Source::Cgroup, Source::detect(&root));
+
+    // But without cpu.stat there is nothing to read from
+    fs::remove_file(root.join("sys/fs/cgroup/cpu.stat")).unwrap();
+    assert_eq!(Source::Proc, Source::detect(&root));
+
+    fs::remove_dir_all(&root).unwrap();
+}
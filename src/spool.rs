@@ -0,0 +1,126 @@
+//! Spool directory in the layout of munin-asyncd (`output=spool`), so
+//! munin-async serves our samples and they survive restarts of the
+//! node and missed polls
+//!
+//! Samples go into files named `munin-daemon.cpu1sec.<start>.<length>`,
+//! one per [PERIOD], every sample introduced by a `timestamp <epoch>`
+//! line, the way munin-asyncd writes them.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{
+    output,
+    sink::{OutputSink, Sample},
+};
+use log::{info, warn};
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Seconds of samples in one spool file
+const PERIOD: u64 = 86400;
+
+/// How long we keep spool files, in seconds. munin-async only sends
+/// what munin asks for, anything older than this is long in munin's
+/// RRDs, or lost anyway.
+const KEEP: u64 = 7 * PERIOD;
+
+/// Name the spool files of our plugin start with
+const PREFIX: &str = "munin-daemon.cpu1sec.";
+
+/// Writes samples to a munin-async spool directory, see
+/// [crate::Settings::spool_dir]
+#[derive(Debug)]
+pub(crate) struct Spool {
+    /// The spool directory
+    dir: PathBuf,
+}
+
+impl Spool {
+    /// Write to the spool directory `dir`
+    pub(crate) fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// The file the sample of `epoch` goes into
+    fn path(&self, epoch: u64) -> PathBuf {
+        let start = epoch - epoch % PERIOD;
+        self.dir.join(format!("{PREFIX}{start}.{PERIOD}"))
+    }
+
+    /// Remove our spool files that are older than [KEEP] at `epoch`
+    fn expire(&self, epoch: u64) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let start = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(PREFIX))
+                .and_then(|rest| rest.split_once('.'))
+                .and_then(|(start, _)| start.parse::<u64>().ok());
+            match start {
+                Some(start) if start + PERIOD + KEEP <= epoch => {
+                    match fs::remove_file(entry.path()) {
+                        Ok(()) => info!("Removed old spool file {}", entry.path().display()),
+                        Err(e) => warn!("Could not remove {}: {e}", entry.path().display()),
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+impl OutputSink for Spool {
+    fn write_sample(&mut self, sample: &Sample) -> io::Result<()> {
+        let path = self.path(sample.epoch);
+        if !path.exists() {
+            self.expire(sample.epoch);
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut block = format!("timestamp {}\n", sample.epoch).into_bytes();
+        block.extend_from_slice(sample.munin);
+        output::write_block(&mut file, &block)
+    }
+}
+
+#[test]
+fn test_spool() {
+    let dir = std::env::temp_dir().join(format!("cpu1sec-spool-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let mut spool = Spool::new(&dir);
+    let old = dir.join(format!("{PREFIX}0.{PERIOD}"));
+    let other = dir.join(format!("munin-daemon.load.0.{PERIOD}"));
+    fs::write(&old, "timestamp 1\n").unwrap();
+    fs::write(&other, "timestamp 1\n").unwrap();
+
+    let epoch = 100 * PERIOD + 5;
+    for (at, munin) in [
+        (epoch, b"total_user.value 8640005:1\n"),
+        (epoch + 1, b"total_user.value 8640006:2\n"),
+    ] {
+        let sample = Sample {
+            epoch: at,
+            munin,
+            graphs: None,
+        };
+        spool.write_sample(&sample).unwrap();
+    }
+    let content =
+        fs::read_to_string(dir.join(format!("{PREFIX}{}.{PERIOD}", 100 * PERIOD))).unwrap();
+    assert_eq!(
+        format!(
+            "timestamp {epoch}\ntotal_user.value 8640005:1\ntimestamp {}\ntotal_user.value 8640006:2\n",
+            epoch + 1
+        ),
+        content
+    );
+    assert!(!old.exists());
+    assert!(other.exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
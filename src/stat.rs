@@ -0,0 +1,964 @@
+//! CPU usage values and how they get written out to munin
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{cgroup::CgroupCpuTime, Field};
+use anyhow::Result;
+use procfs::CpuTime;
+use serde::{Serialize, Serializer};
+use std::{
+    ops::{Add, Div, Sub},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Naming scheme for the datasources we emit
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Compat {
+    /// Our own naming, every field is prefixed with the CPU it
+    /// belongs to, e.g. `total_user` or `cpu3_user`
+    #[default]
+    Native,
+    /// Take over from munin's stock `cpu` plugin. Next to our own
+    /// graphs we write the stock plugin's graph, `multigraph cpu`,
+    /// with its field names (`user`, `system`, ...), the DERIVE type
+    /// and the counters from /proc/stat, so munin goes on writing
+    /// into the RRDs the stock plugin left behind (`cpu-user-d.rrd`
+    /// and friends) and their history stays. The 1-second data is in
+    /// our cpu1sec graphs as always, their total fields named like
+    /// the stock ones too, for dashboards, alerts and scripts that
+    /// look for those names. Per-core fields keep their `cpuN_`
+    /// prefix, the stock plugin has nothing to match those against.
+    ///
+    /// Disable the stock plugin, two plugins writing to the same
+    /// graph do not mix.
+    MuninCpu,
+}
+
+impl FromStr for Compat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "native" => Ok(Compat::Native),
+            "munin_cpu" => Ok(Compat::MuninCpu),
+            _ => Err(anyhow::anyhow!("Unknown compat mode {s}")),
+        }
+    }
+}
+
+impl Compat {
+    /// Prefix for all datasource names of the given cpu ("total",
+    /// "cpu0", ...), including the separating underscore
+    pub(crate) fn prefix(&self, cpu: &str) -> String {
+        match self {
+            Compat::MuninCpu if cpu.eq("total") => String::new(),
+            _ => format!("{cpu}_"),
+        }
+    }
+}
+
+/// Which values we write out
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Rollup {
+    /// All ten values the kernel has
+    #[default]
+    Fine,
+    /// Four coarse bands: userspace (user + nice), kernel (system +
+    /// irq + softirq), wait (iowait + steal) and idle
+    Coarse,
+}
+
+impl FromStr for Rollup {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fine" => Ok(Rollup::Fine),
+            "coarse" => Ok(Rollup::Coarse),
+            _ => Err(anyhow::anyhow!("Unknown rollup {s}")),
+        }
+    }
+}
+
+impl Rollup {
+    /// The values we write out
+    pub(crate) fn fields(&self) -> &'static [Field] {
+        match self {
+            Rollup::Fine => &Field::FINE,
+            Rollup::Coarse => &Field::COARSE,
+        }
+    }
+}
+
+/// How the cores of a group (others, aggregate, cpufreq policy, NUMA
+/// node) are
+/// combined into its graph
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum AggregateFn {
+    /// Sum up their ticks, the graph shows all of their time
+    #[default]
+    Sum,
+    /// Average them, the graph shows one average core of the group
+    Avg,
+}
+
+impl FromStr for AggregateFn {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sum" => Ok(AggregateFn::Sum),
+            "avg" => Ok(AggregateFn::Avg),
+            _ => Err(anyhow::anyhow!("Unknown aggregate function {s}")),
+        }
+    }
+}
+
+impl AggregateFn {
+    /// Turn `sum`, the sum of `cores` cores, into the graph of their
+    /// group
+    pub(crate) fn apply(&self, sum: CpuStat, cores: u64) -> CpuStat {
+        match self {
+            AggregateFn::Avg if cores > 1 => sum / cores,
+            _ => sum,
+        }
+    }
+}
+
+/// How ticks get rounded when turned into the percentages we write
+/// out, see [CpuStat::percent_rounded]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Rounding {
+    /// To the nearest value, halves away from zero
+    #[default]
+    Nearest,
+    /// Down, never shows more than there was
+    Floor,
+    /// Up, a single tick never shows as 0
+    Ceil,
+}
+
+impl FromStr for Rounding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "nearest" => Ok(Rounding::Nearest),
+            "floor" => Ok(Rounding::Floor),
+            "ceil" => Ok(Rounding::Ceil),
+            _ => Err(anyhow::anyhow!("Unknown rounding {s}")),
+        }
+    }
+}
+
+/// Which CPU, or sum of CPUs, a [CpuStat] is about
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum CpuId {
+    /// One core, numbered as the kernel does
+    Core(u32),
+    /// Sum of all cores above [crate::Settings::max_core_graphs]
+    Others,
+    /// Sum of the cores in [crate::Settings::aggregate]
+    Aggregate,
+    /// Sum of the cores of a cpufreq policy, see [crate::GroupBy]
+    Policy(u32),
+    /// Sum of the cores of a NUMA node, see [crate::GroupBy]
+    Node(u32),
+    /// The whole machine
+    #[default]
+    Total,
+}
+
+/// The name used for graphs and datasources, "cpuN", "others",
+/// "aggregate", "policyN", "nodeN" or "total"
+impl std::fmt::Display for CpuId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CpuId::Core(cpu) => write!(f, "cpu{cpu}"),
+            CpuId::Others => write!(f, "others"),
+            CpuId::Aggregate => write!(f, "aggregate"),
+            CpuId::Policy(policy) => write!(f, "policy{policy}"),
+            CpuId::Node(node) => write!(f, "node{node}"),
+            CpuId::Total => write!(f, "total"),
+        }
+    }
+}
+
+#[test]
+fn test_cpu_id() {
+    assert_eq!("cpu0", CpuId::Core(0).to_string());
+    // No longer the total
+    assert_eq!("cpu4294967295", CpuId::Core(u32::MAX).to_string());
+    assert_eq!("others", CpuId::Others.to_string());
+    assert_eq!("aggregate", CpuId::Aggregate.to_string());
+    assert_eq!("policy4", CpuId::Policy(4).to_string());
+    assert_eq!("node1", CpuId::Node(1).to_string());
+    assert_eq!("total", CpuId::Total.to_string());
+}
+
+/// Structured outputs get the name, as in the munin output
+impl Serialize for CpuId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Stores CPU values (ticks), so we can easily put them in a vector,
+/// "substract" them to know difference, ...
+///
+/// Serializes to the CPU, epoch and the values, for structured
+/// outputs. How the values get written out to munin is left out.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct CpuStat {
+    /// CPU the data is for
+    pub cpu: CpuId,
+    /// Epoch the data belongs to
+    pub epoch: u64,
+    /// Ticks spent in user mode
+    pub user: u64,
+    /// Ticks spent in user mode with low priority (nice)
+    pub nice: u64,
+    /// Ticks spent in system mode
+    pub system: u64,
+    /// Ticks spent in the idle state
+    pub idle: u64,
+    /// Ticks waiting for I/O to complete (unreliable)
+    pub iowait: u64,
+    /// Ticks servicing interrupts
+    pub irq: u64,
+    /// Ticks servicing softirqs
+    pub softirq: u64,
+    /// Ticks of stolen time.
+    ///
+    /// Stolen time is the time spent in other operating systems when
+    /// running in a virtualized environment
+    pub steal: u64,
+    /// Ticks spent running a virtual CPU for guest operating systems
+    /// under control of the linux kernel
+    pub guest: u64,
+    /// Ticks spent running a niced guest
+    pub guest_nice: u64,
+    /// Do we emit multigraph output and need to say which graph the
+    /// values belong to? See [crate::Settings::multigraph]
+    #[serde(skip)]
+    pub multigraph: bool,
+    /// Naming scheme for the datasources, see [crate::Settings::compat]
+    #[serde(skip)]
+    pub compat: Compat,
+    /// Which values we write out, see [crate::Settings::rollup]
+    #[serde(skip)]
+    pub rollup: Rollup,
+    /// Do we write out guest and guest_nice? See
+    /// [crate::Settings::guest_fields]
+    #[serde(skip)]
+    pub guest_fields: bool,
+    /// Does iowait count as busy? See [crate::Settings::iowait_busy]
+    #[serde(skip)]
+    pub iowait_busy: bool,
+}
+
+/// Simple way of writing out the associated data
+impl std::fmt::Display for CpuStat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.write_values(f, false)
+    }
+}
+
+/// Display a CpuStat with all of its values being unknown (U) to
+/// munin, for when we can't say anything sensible about a sample.
+pub(crate) struct Unknown<'a>(pub(crate) &'a CpuStat);
+
+impl std::fmt::Display for Unknown<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.write_values(f, true)
+    }
+}
+
+impl CpuStat {
+    /// The CPU time spent between `previous` and this snapshot.
+    ///
+    /// Every value of the result is the absolute difference between
+    /// the two values, the same as `self - previous` does. cpu, epoch
+    /// and the output settings (multigraph, compat, ...) are taken from
+    /// `self`, so the delta is labeled with the CPU and time of the
+    /// newer snapshot. Nothing checks that both snapshots are about
+    /// the same CPU, that is up to the caller.
+    ///
+    /// ```
+    /// use munin_cpu1sec::{CpuId, CpuStat};
+    ///
+    /// let previous = CpuStat {
+    ///     cpu: CpuId::Core(0),
+    ///     epoch: 1_650_000_000,
+    ///     user: 1000,
+    ///     system: 300,
+    ///     idle: 5000,
+    ///     ..Default::default()
+    /// };
+    /// let current = CpuStat {
+    ///     cpu: CpuId::Core(0),
+    ///     epoch: 1_650_000_001,
+    ///     user: 1042,
+    ///     system: 308,
+    ///     idle: 5050,
+    ///     ..Default::default()
+    /// };
+    /// let delta = current.diff(&previous);
+    /// assert_eq!(CpuId::Core(0), delta.cpu);
+    /// assert_eq!(1_650_000_001, delta.epoch);
+    /// assert_eq!((42, 8, 50), (delta.user, delta.system, delta.idle));
+    /// assert_eq!(0, delta.nice);
+    /// ```
+    pub fn diff(&self, previous: &CpuStat) -> CpuStat {
+        CpuStat {
+            epoch: self.epoch,
+            ..*self - *previous
+        }
+    }
+
+    /// Like [CpuStat::diff], but None if any value went backwards
+    /// since `previous`. The kernel counters only ever grow, so then
+    /// they got reset or the kernel has a quirk, and the sample is
+    /// invalid, there is no sensible difference to take.
+    pub fn checked_diff(&self, previous: &CpuStat) -> Option<CpuStat> {
+        Some(CpuStat {
+            user: self.user.checked_sub(previous.user)?,
+            nice: self.nice.checked_sub(previous.nice)?,
+            system: self.system.checked_sub(previous.system)?,
+            idle: self.idle.checked_sub(previous.idle)?,
+            iowait: self.iowait.checked_sub(previous.iowait)?,
+            irq: self.irq.checked_sub(previous.irq)?,
+            softirq: self.softirq.checked_sub(previous.softirq)?,
+            steal: self.steal.checked_sub(previous.steal)?,
+            guest: self.guest.checked_sub(previous.guest)?,
+            guest_nice: self.guest_nice.checked_sub(previous.guest_nice)?,
+            ..*self
+        })
+    }
+
+    /// All the values the kernel has, see [Field::FINE]
+    pub(crate) fn fields(&self) -> [(Field, u64); 10] {
+        Field::FINE.map(|field| (field, field.value(self)))
+    }
+
+    /// Write out the values in munin format, or all of them as
+    /// unknown (U)
+    fn write_values(&self, f: &mut std::fmt::Formatter, unknown: bool) -> std::fmt::Result {
+        if self.multigraph {
+            if self.cpu == CpuId::Total {
+                writeln!(f, "multigraph cpu1sec")?;
+            } else {
+                writeln!(f, "multigraph cpu1sec.{}", self.cpu)?;
+            }
+        }
+
+        for field in self.rollup.fields() {
+            if !self.guest_fields && field.is_guest() {
+                continue;
+            }
+            let name = field.munin(self.compat, self.cpu);
+            if unknown {
+                writeln!(f, "{name}.value {}:U", self.epoch)?;
+            } else {
+                writeln!(f, "{name}.value {}:{}", self.epoch, field.value(self))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// All ticks that passed, that is, the wall-clock time of this
+    /// CPU. guest and guest_nice are already part of user and nice,
+    /// so they are not counted again.
+    pub(crate) fn ticks(&self) -> u64 {
+        self.user
+            + self.nice
+            + self.system
+            + self.idle
+            + self.iowait
+            + self.irq
+            + self.softirq
+            + self.steal
+    }
+
+    /// Turn a value of this CpuStat into a percentage of all ticks
+    pub(crate) fn percent(&self, value: u64) -> f64 {
+        match self.ticks() {
+            0 => 0.0,
+            ticks => value as f64 * 100.0 / ticks as f64,
+        }
+    }
+
+    /// [CpuStat::percent], rounded to `decimals` places as
+    /// `rounding` says. Done in integers, so a half is exactly a
+    /// half.
+    pub(crate) fn percent_rounded(&self, value: u64, rounding: Rounding, decimals: u32) -> f64 {
+        let scale = 10u64.pow(decimals);
+        let ticks = self.ticks();
+        if ticks == 0 {
+            return 0.0;
+        }
+        let scaled = value * 100 * scale;
+        let rounded = match rounding {
+            Rounding::Nearest => (2 * scaled + ticks) / (2 * ticks),
+            Rounding::Floor => scaled / ticks,
+            Rounding::Ceil => scaled.div_ceil(ticks),
+        };
+        rounded as f64 / scale as f64
+    }
+
+    /// Percentage of the time the CPU was busy, that is, not idle.
+    /// Waiting for I/O counts as idle too, unless
+    /// [crate::Settings::iowait_busy].
+    pub fn busy(&self) -> f64 {
+        match self.iowait_busy {
+            true => self.percent(self.ticks() - self.idle),
+            false => self.percent(self.ticks() - self.idle - self.iowait),
+        }
+    }
+}
+
+#[test]
+fn test_percent_rounded() {
+    // One tick in eight, 12.5%
+    let stat = CpuStat {
+        steal: 1,
+        idle: 7,
+        ..Default::default()
+    };
+    for (rounding, whole, hundredths) in [
+        (Rounding::Nearest, 13.0, 12.5),
+        (Rounding::Floor, 12.0, 12.5),
+        (Rounding::Ceil, 13.0, 12.5),
+    ] {
+        assert_eq!(whole, stat.percent_rounded(stat.steal, rounding, 0));
+        assert_eq!(hundredths, stat.percent_rounded(stat.steal, rounding, 2));
+    }
+    // One in 800, 0.125%
+    let stat = CpuStat {
+        steal: 1,
+        idle: 799,
+        ..Default::default()
+    };
+    for (rounding, expected) in [
+        (Rounding::Nearest, 0.13),
+        (Rounding::Floor, 0.12),
+        (Rounding::Ceil, 0.13),
+    ] {
+        assert_eq!(expected, stat.percent_rounded(stat.steal, rounding, 2));
+    }
+    assert_eq!(
+        0.0,
+        CpuStat::default().percent_rounded(0, Rounding::Ceil, 2)
+    );
+    assert_eq!(Rounding::Floor, "floor".parse().unwrap());
+    assert!("up".parse::<Rounding>().is_err());
+}
+
+#[test]
+fn test_steal_percent() {
+    let stat = CpuStat {
+        user: 40,
+        system: 10,
+        idle: 25,
+        steal: 25,
+        // Part of user, so not counted
+        guest: 20,
+        ..Default::default()
+    };
+    assert_eq!(25.0, stat.percent(stat.steal));
+    assert_eq!(75.0, stat.busy());
+    assert_eq!(0.0, CpuStat::default().percent(0));
+    assert_eq!(0.0, CpuStat::default().busy());
+
+    let waiting = CpuStat {
+        epoch: 1,
+        iowait: 25,
+        idle: 25,
+        user: 50,
+        ..Default::default()
+    };
+    assert_eq!(50.0, waiting.busy());
+    let waiting = CpuStat {
+        iowait_busy: true,
+        ..waiting
+    };
+    assert_eq!(75.0, waiting.busy());
+    // The raw value stays
+    assert!(waiting.to_string().contains("total_iowait.value 1:25\n"));
+}
+
+/// Defaults, mainly setting the epoch to the second of "creation" of
+/// this dataset
+impl Default for CpuStat {
+    fn default() -> Self {
+        CpuStat {
+            // By default we assume we do graphs for "total"
+            cpu: CpuId::Total,
+            multigraph: false,
+            compat: Compat::Native,
+            rollup: Rollup::Fine,
+            guest_fields: true,
+            iowait_busy: false,
+            // Data is for *right* *now*
+            epoch: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Couldn't get epoch")
+                .as_secs(),
+            user: 0,
+            nice: 0,
+            system: 0,
+            idle: 0,
+            iowait: 0,
+            irq: 0,
+            softirq: 0,
+            steal: 0,
+            guest: 0,
+            guest_nice: 0,
+        }
+    }
+}
+
+/// For diffing, we want to be able to "substract" CpuStats.
+///
+/// What we actually do is calculate the absolute difference between
+/// the two numbers, but for a munin plugin that is what is of
+/// interest to us.
+impl Sub for CpuStat {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Self {
+            // No sense substracting CPU number
+            cpu: self.cpu,
+            // We always take the newer epoch
+            epoch: self.epoch.max(other.epoch),
+            user: self.user.abs_diff(other.user),
+            nice: self.nice.abs_diff(other.nice),
+            system: self.system.abs_diff(other.system),
+            idle: self.idle.abs_diff(other.idle),
+            iowait: self.iowait.abs_diff(other.iowait),
+            irq: self.irq.abs_diff(other.irq),
+            softirq: self.softirq.abs_diff(other.softirq),
+            steal: self.steal.abs_diff(other.steal),
+            guest: self.guest.abs_diff(other.guest),
+            guest_nice: self.guest_nice.abs_diff(other.guest_nice),
+            // Boolean value do not substract
+            multigraph: self.multigraph,
+            compat: self.compat,
+            rollup: self.rollup,
+            guest_fields: self.guest_fields,
+            iowait_busy: self.iowait_busy,
+        }
+    }
+}
+
+/// Summing up CpuStats, e.g. to fold several cores into one.
+///
+/// cpu, epoch and the output settings are taken from the left side.
+impl Add for CpuStat {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Self {
+            user: self.user + other.user,
+            nice: self.nice + other.nice,
+            system: self.system + other.system,
+            idle: self.idle + other.idle,
+            iowait: self.iowait + other.iowait,
+            irq: self.irq + other.irq,
+            softirq: self.softirq + other.softirq,
+            steal: self.steal + other.steal,
+            guest: self.guest + other.guest,
+            guest_nice: self.guest_nice + other.guest_nice,
+            ..self
+        }
+    }
+}
+
+#[test]
+fn test_guest_fields() {
+    let stat = CpuStat {
+        epoch: 1,
+        guest: 20,
+        guest_fields: false,
+        ..Default::default()
+    };
+    assert!(!stat.to_string().contains("guest"));
+    let stat = CpuStat {
+        guest_fields: true,
+        ..stat
+    };
+    assert!(stat.to_string().contains("total_guest.value 1:20\n"));
+    assert!(stat.to_string().contains("total_guest_nice.value 1:0\n"));
+}
+
+/// Dividing all values of a CpuStat, e.g. to average several cores.
+/// Like ticks, the results are whole numbers, rounded down.
+///
+/// cpu, epoch and the output settings are kept.
+impl Div<u64> for CpuStat {
+    type Output = Self;
+    fn div(self, divisor: u64) -> Self {
+        Self {
+            user: self.user / divisor,
+            nice: self.nice / divisor,
+            system: self.system / divisor,
+            idle: self.idle / divisor,
+            iowait: self.iowait / divisor,
+            irq: self.irq / divisor,
+            softirq: self.softirq / divisor,
+            steal: self.steal / divisor,
+            guest: self.guest / divisor,
+            guest_nice: self.guest_nice / divisor,
+            ..self
+        }
+    }
+}
+
+#[test]
+fn test_rollup_coarse() {
+    let stat = CpuStat {
+        epoch: 1,
+        user: 40,
+        nice: 2,
+        system: 10,
+        irq: 3,
+        softirq: 4,
+        iowait: 5,
+        steal: 6,
+        idle: 30,
+        guest: 20,
+        rollup: Rollup::Coarse,
+        ..Default::default()
+    };
+    assert_eq!(
+        "total_userspace.value 1:42\n\
+         total_kernel.value 1:17\n\
+         total_wait.value 1:11\n\
+         total_idle.value 1:30\n",
+        stat.to_string()
+    );
+    // Nothing got lost on the way
+    let coarse: u64 = Field::COARSE.iter().map(|field| field.value(&stat)).sum();
+    assert_eq!(stat.ticks(), coarse);
+}
+
+#[test]
+fn test_sub() {
+    let one = CpuStat {
+        cpu: CpuId::Core(2),
+        epoch: 0,
+        user: 42,
+        nice: 42,
+        system: 42,
+        idle: 42,
+        iowait: 42,
+        irq: 21,
+        softirq: 21,
+        steal: 21,
+        guest: 21,
+        guest_nice: 21,
+        multigraph: false,
+        compat: Compat::Native,
+        rollup: Rollup::Fine,
+        guest_fields: true,
+        iowait_busy: false,
+    };
+
+    let two = CpuStat {
+        cpu: CpuId::Core(1),
+        epoch: 1,
+        user: 21,
+        nice: 21,
+        system: 21,
+        idle: 21,
+        iowait: 21,
+        irq: 42,
+        softirq: 42,
+        steal: 42,
+        guest: 42,
+        guest_nice: 42,
+        multigraph: true,
+        compat: Compat::Native,
+        rollup: Rollup::Fine,
+        guest_fields: true,
+        iowait_busy: false,
+    };
+    let diff = one - two;
+    assert_eq!(
+        CpuStat {
+            cpu: CpuId::Core(2),
+            epoch: 1,
+            user: 21,
+            nice: 21,
+            system: 21,
+            idle: 21,
+            iowait: 21,
+            irq: 21,
+            softirq: 21,
+            steal: 21,
+            guest: 21,
+            guest_nice: 21,
+            multigraph: false,
+            compat: Compat::Native,
+            rollup: Rollup::Fine,
+            guest_fields: true,
+            iowait_busy: false,
+        },
+        diff
+    );
+}
+
+#[test]
+fn test_checked_diff() {
+    let old = CpuStat {
+        epoch: 1,
+        user: 100,
+        iowait: 10,
+        ..Default::default()
+    };
+    let new = CpuStat {
+        epoch: 2,
+        user: 142,
+        iowait: 12,
+        ..Default::default()
+    };
+    let diff = new.checked_diff(&old).unwrap();
+    assert_eq!((2, 42, 2), (diff.epoch, diff.user, diff.iowait));
+
+    // iowait is known to go backwards on some kernels
+    let new = CpuStat { iowait: 9, ..new };
+    assert_eq!(None, new.checked_diff(&old));
+    assert_eq!(1, new.diff(&old).iowait);
+}
+
+/// Take CpuTime and shove it into CpuStat
+pub(crate) fn cpu_stat_to_value(
+    cpu: CpuId,
+    stat: CpuTime,
+    multigraph: bool,
+    compat: Compat,
+) -> CpuStat {
+    CpuStat {
+        cpu,
+        multigraph,
+        compat,
+        user: stat.user,
+        nice: stat.nice,
+        system: stat.system,
+        idle: stat.idle,
+        iowait: stat.iowait.unwrap_or(0),
+        irq: stat.irq.unwrap_or(0),
+        softirq: stat.softirq.unwrap_or(0),
+        steal: stat.steal.unwrap_or(0),
+        guest: stat.guest.unwrap_or(0),
+        guest_nice: stat.guest_nice.unwrap_or(0),
+        ..Default::default()
+    }
+}
+
+/// Unit of the values in the total graph, or all graphs with
+/// [Resolution::Milliseconds]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Resolution {
+    /// Ticks (jiffies), straight from /proc/stat
+    #[default]
+    Ticks,
+    /// Nanoseconds. user and system come from the microsecond
+    /// counters in the root cgroup's cpu.stat, if available, the rest
+    /// is converted from ticks. See [Resolution::total_diff] for how
+    /// the two get along.
+    Nanoseconds,
+    /// Milliseconds of CPU time per interval, converted from ticks
+    /// with the kernel's USER_HZ. Unlike the others this is for all
+    /// graphs, not only the total, so they all read the same no
+    /// matter how fast the kernel ticks.
+    Milliseconds,
+}
+
+impl FromStr for Resolution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ticks" => Ok(Resolution::Ticks),
+            "ns" => Ok(Resolution::Nanoseconds),
+            "ms" => Ok(Resolution::Milliseconds),
+            _ => Err(anyhow::anyhow!("Unknown resolution {s}")),
+        }
+    }
+}
+
+impl Resolution {
+    /// Bring the (total) CpuStat into our resolution
+    pub(crate) fn total(&self, stat: CpuStat) -> CpuStat {
+        match self {
+            Resolution::Ticks => stat,
+            Resolution::Nanoseconds => stat_to_ns(stat, ticks_per_second(), CgroupCpuTime::read()),
+            Resolution::Milliseconds => stat_to_ms(stat, ticks_per_second()),
+        }
+    }
+
+    /// Finish the difference `diff` of two totals from
+    /// [Resolution::total]. In ns, user and system there still count
+    /// nice, irq and softirq, as cpu.stat does, so they only ever
+    /// grow. Those come in whole ticks though, while cpu.stat grows
+    /// smoothly, so they are taken out of the difference, not the
+    /// counters, and a tick more than cpu.stat saw is clamped to 0.
+    pub(crate) fn total_diff(&self, diff: CpuStat) -> CpuStat {
+        match self {
+            Resolution::Nanoseconds => CpuStat {
+                user: diff.user.saturating_sub(diff.nice),
+                system: diff.system.saturating_sub(diff.irq + diff.softirq),
+                ..diff
+            },
+            Resolution::Ticks | Resolution::Milliseconds => diff,
+        }
+    }
+
+    /// Bring the CpuStat of a core into our resolution
+    pub(crate) fn core(&self, stat: CpuStat) -> CpuStat {
+        match self {
+            Resolution::Ticks | Resolution::Nanoseconds => stat,
+            Resolution::Milliseconds => stat_to_ms(stat, ticks_per_second()),
+        }
+    }
+}
+
+/// The kernel's USER_HZ, the ticks per second of /proc/stat, from
+/// sysconf(_SC_CLK_TCK). Usually 100, but not everywhere.
+pub(crate) fn ticks_per_second() -> u64 {
+    procfs::ticks_per_second().unwrap_or(100) as u64
+}
+
+/// Convert a CpuStat from ticks into milliseconds, `tps` being the
+/// ticks per second
+fn stat_to_ms(stat: CpuStat, tps: u64) -> CpuStat {
+    // Since-boot counters on big boxes get large, so no u64 for the
+    // intermediate value
+    let ms = |ticks: u64| (u128::from(ticks) * 1000 / u128::from(tps)) as u64;
+    CpuStat {
+        user: ms(stat.user),
+        nice: ms(stat.nice),
+        system: ms(stat.system),
+        idle: ms(stat.idle),
+        iowait: ms(stat.iowait),
+        irq: ms(stat.irq),
+        softirq: ms(stat.softirq),
+        steal: ms(stat.steal),
+        guest: ms(stat.guest),
+        guest_nice: ms(stat.guest_nice),
+        ..stat
+    }
+}
+
+#[test]
+fn test_stat_to_ms() {
+    let stat = CpuStat {
+        user: 250,
+        system: 25,
+        idle: 1,
+        ..Default::default()
+    };
+    let conv = stat_to_ms(stat, 250);
+    assert_eq!((1000, 100, 4), (conv.user, conv.system, conv.idle));
+    let conv = stat_to_ms(stat, 100);
+    assert_eq!((2500, 250, 10), (conv.user, conv.system, conv.idle));
+    assert_eq!(Ok(Resolution::Milliseconds), "ms".parse().map_err(|_| ()));
+}
+
+/// Convert a CpuStat from ticks into nanoseconds, `tps` being the
+/// ticks per second. user includes nice and system includes irq and
+/// softirq, as in cpu.stat. With `usec` given, user and system are
+/// taken from there, as it measures them. [Resolution::total_diff]
+/// takes the separate fields out again.
+fn stat_to_ns(stat: CpuStat, tps: u64, usec: Option<CgroupCpuTime>) -> CpuStat {
+    // Since-boot counters on big boxes get large, so no u64 for the
+    // intermediate value
+    let ns = |ticks: u64| (u128::from(ticks) * 1_000_000_000 / u128::from(tps)) as u64;
+    let mut conv = CpuStat {
+        user: ns(stat.user),
+        nice: ns(stat.nice),
+        system: ns(stat.system),
+        idle: ns(stat.idle),
+        iowait: ns(stat.iowait),
+        irq: ns(stat.irq),
+        softirq: ns(stat.softirq),
+        steal: ns(stat.steal),
+        guest: ns(stat.guest),
+        guest_nice: ns(stat.guest_nice),
+        ..stat
+    };
+    match usec {
+        Some(usec) => {
+            conv.user = usec.user_usec * 1000;
+            conv.system = usec.system_usec * 1000;
+        }
+        None => {
+            conv.user += conv.nice;
+            conv.system += conv.irq + conv.softirq;
+        }
+    }
+    conv
+}
+
+#[test]
+fn test_stat_to_ns() {
+    let usec = CgroupCpuTime::parse(
+        "usage_usec 3002345\nuser_usec 2001234\nsystem_usec 1001111\nnr_periods 0\n",
+    )
+    .unwrap();
+    let stat = CpuStat {
+        user: 200,
+        nice: 10,
+        system: 90,
+        irq: 5,
+        softirq: 5,
+        idle: 1000,
+        ..Default::default()
+    };
+    let conv = stat_to_ns(stat, 100, Some(usec));
+    // Sub-tick precision from cpu.stat, as it measures them
+    assert_eq!(2_001_234_000, conv.user);
+    assert_eq!(1_001_111_000, conv.system);
+    // The rest converted from ticks
+    assert_eq!(100_000_000, conv.nice);
+    assert_eq!(10_000_000_000, conv.idle);
+
+    // Without cpu.stat we only have ticks, counted the same way
+    let conv = stat_to_ns(stat, 100, None);
+    assert_eq!(2_100_000_000, conv.user);
+    assert_eq!(1_000_000_000, conv.system);
+    let diff = Resolution::Nanoseconds.total_diff(conv.diff(&CpuStat::default()));
+    assert_eq!((2_000_000_000, 900_000_000), (diff.user, diff.system));
+}
+
+#[test]
+fn test_total_diff() {
+    let usec = |user| {
+        CgroupCpuTime::parse(&format!(
+            "user_usec {user}
+system_usec 1000000
+"
+        ))
+        .unwrap()
+    };
+    let stat = |nice| CpuStat {
+        user: 200,
+        nice,
+        system: 100,
+        idle: 1000,
+        ..Default::default()
+    };
+    let old = stat_to_ns(stat(10), 100, Some(usec(2_001_234)));
+    // nice crosses a tick boundary, 10ms more, while cpu.stat only
+    // saw 5ms of user time
+    let new = stat_to_ns(stat(11), 100, Some(usec(2_006_234)));
+    let diff = new.checked_diff(&old).expect("no reset");
+    let diff = Resolution::Nanoseconds.total_diff(diff);
+    assert_eq!((0, 10_000_000, 0), (diff.user, diff.nice, diff.system));
+    // A quiet nice leaves all of it to user
+    let newer = stat_to_ns(stat(11), 100, Some(usec(2_016_234)));
+    let diff = Resolution::Nanoseconds.total_diff(newer.checked_diff(&new).unwrap());
+    assert_eq!((10_000_000, 0), (diff.user, diff.nice));
+    // Other resolutions are left alone
+    assert_eq!(stat(1), Resolution::Ticks.total_diff(stat(1)));
+}
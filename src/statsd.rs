@@ -0,0 +1,132 @@
+//! statsd gauges, for pushing the samples to a statsd (or
+//! DogStatsD) relay next to munin
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{fanout::Target, output::Sender, CpuStat, Endpoint, Settings};
+use std::fmt::Write;
+
+/// Largest datagram we send. Relays commonly read no more than that,
+/// and it stays clear of fragmentation on an ethernet MTU.
+const PACKET_SIZE: usize = 1432;
+
+/// The sample `graphs` as gauges, one per line, with the same fields
+/// munin gets: `<prefix>.<cpu>.<field>:<value>|g`, or, with `tags`,
+/// DogStatsD's `<prefix>.<field>:<value>|g|#cpu:<cpu>`
+pub(crate) fn encode(graphs: &[CpuStat], prefix: &str, tags: bool) -> Vec<String> {
+    let mut lines = vec![];
+    for stat in graphs {
+        let fields = stat.rollup.fields().iter();
+        for field in fields.filter(|field| stat.guest_fields || !field.is_guest()) {
+            let (name, value) = (field.name(), field.value(stat));
+            let mut line = String::new();
+            let _ = if tags {
+                write!(line, "{prefix}.{name}:{value}|g|#cpu:{}", stat.cpu)
+            } else {
+                write!(line, "{prefix}.{}.{name}:{value}|g", stat.cpu)
+            };
+            lines.push(line);
+        }
+    }
+    lines
+}
+
+/// Pack `lines` into packets of up to [PACKET_SIZE] bytes, newline
+/// separated. A line never gets split.
+fn packets(lines: &[String]) -> Vec<Vec<u8>> {
+    let mut packets: Vec<Vec<u8>> = vec![];
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= PACKET_SIZE => {
+                packet.push(b'\n');
+                packet.extend_from_slice(line.as_bytes());
+            }
+            _ => packets.push(line.as_bytes().to_vec()),
+        }
+    }
+    packets
+}
+
+#[test]
+fn test_encode() {
+    use crate::{CpuId, Rollup};
+
+    let graphs = [
+        CpuStat {
+            cpu: CpuId::Core(0),
+            user: 123,
+            guest_fields: false,
+            ..Default::default()
+        },
+        CpuStat {
+            idle: 7,
+            rollup: Rollup::Coarse,
+            ..Default::default()
+        },
+    ];
+    let plain = encode(&graphs, "cpu1sec", false);
+    assert_eq!(12, plain.len());
+    assert_eq!("cpu1sec.cpu0.user:123|g", plain[0]);
+    assert_eq!("cpu1sec.total.idle:7|g", plain[11]);
+    let tagged = encode(&graphs, "cpu1sec", true);
+    assert_eq!("cpu1sec.user:123|g|#cpu:cpu0", tagged[0]);
+    assert_eq!("cpu1sec.idle:7|g|#cpu:total", tagged[11]);
+
+    assert_eq!(vec![plain.join("\n").into_bytes()], packets(&plain));
+    let many = vec![String::from("x").repeat(1000); 3];
+    let packed = packets(&many);
+    assert_eq!(3, packed.len());
+    assert!(packed.iter().all(|packet| packet.len() == 1000));
+}
+
+/// Pushes samples to statsd, see [crate::Settings::statsd_url]
+#[derive(Debug)]
+pub(crate) struct Statsd {
+    /// Where they go
+    sender: Sender,
+    /// Start of our metric names
+    prefix: String,
+    /// Put the CPU into a DogStatsD tag instead of the name?
+    tags: bool,
+}
+
+impl Statsd {
+    /// Push to `endpoint`, naming our metrics `<prefix>.<cpu>.<field>`,
+    /// or `<prefix>.<field>` with DogStatsD `tags`
+    pub(crate) fn new(endpoint: &Endpoint, prefix: String, tags: bool, buffer: usize) -> Self {
+        Self {
+            sender: endpoint.sender(buffer),
+            prefix,
+            tags,
+        }
+    }
+
+    /// Push the sample `graphs`
+    pub(crate) fn send(&mut self, graphs: &[CpuStat]) {
+        let lines = encode(graphs, &self.prefix, self.tags);
+        for mut packet in packets(&lines) {
+            // statsd over TCP needs the lines terminated, over UDP
+            // the datagram ends them
+            if matches!(self.sender, Sender::Tcp(_)) {
+                packet.push(b'\n');
+            }
+            self.sender.send(packet);
+        }
+    }
+}
+
+impl Target for Statsd {
+    fn deliver(&mut self, graphs: &[CpuStat]) {
+        self.send(graphs);
+    }
+}
+
+/// The [Target] for [Settings::statsd_url], if set
+pub(crate) fn target(settings: &Settings) -> Option<Box<dyn Target>> {
+    let endpoint = settings.statsd_url.as_ref()?;
+    Some(Box::new(Statsd::new(
+        endpoint,
+        settings.statsd_prefix.clone(),
+        settings.statsd_tags,
+        settings.tcp_buffer,
+    )))
+}
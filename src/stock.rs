@@ -0,0 +1,131 @@
+//! The graph of munin's stock cpu plugin, under its name, for
+//! [crate::Compat::MuninCpu]. Same graph name, field names and DERIVE
+//! type, fed with the counters from /proc/stat as the stock plugin
+//! does, so munin goes on writing into the RRDs the stock plugin left
+//! behind (`cpu-user-d.rrd` and friends) and their history stays.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{stat, Settings};
+use anyhow::Result;
+use procfs::CpuTime;
+use std::io::{BufWriter, Write};
+
+/// Name of the graph, the name of the stock plugin
+const GRAPH: &str = "cpu";
+
+/// A field of the stock plugin: name, info and its counter
+type StockField = (&'static str, &'static str, fn(&CpuTime) -> Option<u64>);
+
+/// The fields of the stock plugin, in its order. guest only with
+/// [Settings::guest_fields].
+const FIELDS: [StockField; 9] = [
+    (
+        "system",
+        "CPU time spent by the kernel in system activities",
+        |total| Some(total.system),
+    ),
+    (
+        "user",
+        "CPU time spent by normal programs and daemons",
+        |total| Some(total.user),
+    ),
+    (
+        "nice",
+        "CPU time spent by nice(1)d programs",
+        |total| Some(total.nice),
+    ),
+    ("idle", "Idle CPU time", |total| Some(total.idle)),
+    (
+        "iowait",
+        "CPU time spent waiting for I/O operations to finish when there is nothing else to do.",
+        |total| total.iowait,
+    ),
+    (
+        "irq",
+        "CPU time spent handling interrupts",
+        |total| total.irq,
+    ),
+    (
+        "softirq",
+        "CPU time spent handling \"batched\" interrupts",
+        |total| total.softirq,
+    ),
+    (
+        "steal",
+        "The time that a virtual CPU had runnable tasks, but the virtual CPU itself was not running",
+        |total| total.steal,
+    ),
+    (
+        "guest",
+        "The time spent running a virtual CPU for guest operating systems under the control of the Linux kernel.",
+        |total| total.guest,
+    ),
+];
+
+/// The fields we write, see [FIELDS]
+fn fields(settings: &Settings) -> impl Iterator<Item = StockField> + '_ {
+    FIELDS
+        .into_iter()
+        .filter(|(field, _, _)| settings.guest_fields() || *field != "guest")
+}
+
+/// Write out the config of the `cpu` graph, as the stock plugin does
+/// for `cores` CPUs. No update_rate or graph_data_size, the RRDs are
+/// the ones the stock plugin had.
+pub(crate) fn config<W: Write>(
+    handle: &mut BufWriter<W>,
+    settings: &Settings,
+    cores: usize,
+) -> Result<()> {
+    writeln!(handle, "multigraph {GRAPH}")?;
+    writeln!(handle, "graph_title CPU usage")?;
+    writeln!(
+        handle,
+        "graph_order system user nice idle iowait irq softirq"
+    )?;
+    writeln!(
+        handle,
+        "graph_args --base 1000 -r --lower-limit 0 --upper-limit {}",
+        cores * 100
+    )?;
+    writeln!(handle, "graph_vlabel %")?;
+    writeln!(handle, "graph_scale no")?;
+    writeln!(handle, "graph_info This graph shows how CPU time is spent.")?;
+    writeln!(handle, "graph_category system")?;
+    writeln!(handle, "graph_period second")?;
+    // DERIVE of ticks is percent only with 100 of them per second
+    let tps = stat::ticks_per_second();
+    for (pos, (field, info, _)) in fields(settings).enumerate() {
+        writeln!(handle, "{field}.label {field}")?;
+        writeln!(
+            handle,
+            "{field}.draw {}",
+            if pos == 0 { "AREA" } else { "STACK" }
+        )?;
+        writeln!(handle, "{field}.min 0")?;
+        writeln!(handle, "{field}.type DERIVE")?;
+        writeln!(handle, "{field}.info {info}")?;
+        if tps != 100 {
+            writeln!(handle, "{field}.cdef {field},100,*,{tps},/")?;
+        }
+    }
+    Ok(())
+}
+
+/// Write out the values of the `cpu` graph, the counters of `total`
+/// from /proc/stat read at `epoch`. munin takes the differences.
+pub(crate) fn write<W: Write>(
+    handle: &mut BufWriter<W>,
+    settings: &Settings,
+    total: &CpuTime,
+    epoch: u64,
+) -> Result<()> {
+    writeln!(handle, "multigraph {GRAPH}")?;
+    for (field, _, counter) in fields(settings) {
+        match counter(total) {
+            Some(value) => writeln!(handle, "{field}.value {epoch}:{value}")?,
+            None => writeln!(handle, "{field}.value {epoch}:U")?,
+        }
+    }
+    Ok(())
+}
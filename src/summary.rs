@@ -0,0 +1,76 @@
+//! What the daemon went through during its run, logged when it gets
+//! stopped
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+/// Set once we got SIGTERM or SIGINT
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Signal handler, only notes that we should stop
+extern "C" fn on_signal(_signal: libc::c_int) {
+    STOP.store(true, Ordering::Relaxed);
+}
+
+/// Catch SIGTERM and SIGINT, so the daemon can stop on its own, see
+/// [stopped]
+pub(crate) fn catch_stop() {
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: The handler only stores to an atomic, which is
+    // async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGTERM, handler);
+        libc::signal(libc::SIGINT, handler);
+    }
+}
+
+/// Did we get asked to stop?
+pub(crate) fn stopped() -> bool {
+    STOP.load(Ordering::Relaxed)
+}
+
+/// Counters of a run
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) struct Summary {
+    /// Samples taken
+    pub(crate) samples: u64,
+    /// Counter resets, the machine rebooted underneath us
+    pub(crate) resets: u64,
+    /// Gaps between samples, longer than [Settings::max_gap]
+    ///
+    /// [Settings::max_gap]: crate::Settings::max_gap
+    pub(crate) gaps: u64,
+    /// Times the number of online CPUs changed
+    pub(crate) hotplugs: u64,
+    /// Longest acquire
+    pub(crate) peak_acquire: Duration,
+    /// Most a sample came later than the interval asked for
+    pub(crate) max_lateness: Duration,
+}
+
+impl Summary {
+    /// Note an acquire that took `took`
+    pub(crate) fn acquired(&mut self, took: Duration) {
+        self.peak_acquire = self.peak_acquire.max(took);
+    }
+
+    /// Note a sample that came `late`
+    pub(crate) fn late(&mut self, late: Duration) {
+        self.max_lateness = self.max_lateness.max(late);
+    }
+}
+
+/// The one line we log
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} samples, {} counter resets, {} gaps, {} hotplug changes, peak acquire {:?}, max lateness {:?}",
+            self.samples, self.resets, self.gaps, self.hotplugs, self.peak_acquire, self.max_lateness
+        )
+    }
+}
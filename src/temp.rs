@@ -0,0 +1,287 @@
+//! Temperatures from the hwmon sensors, or the thermal zones without
+//! those, the `temp1sec` graph of [crate::Collector::Temp]
+//!
+//! Next to the usage graphs it shows when cores got throttled
+//! because they ran hot. The sensors are looked up when we start:
+//! every `temp*_input` of `/sys/class/hwmon`, named after their chip
+//! and label, with their max and crit as munin warning and critical.
+//! Machines without hwmon, like many ARM boards, often still have
+//! `/sys/class/thermal`, then each thermal zone is a sensor.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::Settings;
+use anyhow::Result;
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Name of our graph
+const GRAPH: &str = "temp1sec";
+
+/// Where the hwmon chips are, relative to `/`
+const HWMON: &str = "sys/class/hwmon";
+
+/// Where the thermal zones are, relative to `/`
+const THERMAL: &str = "sys/class/thermal";
+
+/// A temperature in millidegrees Celsius from `path`, in degrees
+fn read_celsius(path: &Path) -> Option<f64> {
+    let millis: i64 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    Some(millis as f64 / 1000.0)
+}
+
+/// The first line of `path`, if there is one
+fn read_name(path: &Path) -> Option<String> {
+    let content = fs::read_to_string(path).ok()?;
+    Some(content.lines().next()?.trim().to_string()).filter(|name| !name.is_empty())
+}
+
+/// `name` as munin field name: lower case, anything but letters and
+/// digits an underscore, not starting with a digit
+fn field_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("_{name}")
+    } else {
+        name
+    }
+}
+
+/// One temperature sensor
+#[derive(Debug, Clone, PartialEq)]
+struct Sensor {
+    /// munin field name, unique in our graph
+    field: String,
+    /// What munin shows
+    label: String,
+    /// Where the temperature is read from
+    path: PathBuf,
+    /// Temperature the chip considers high
+    warning: Option<f64>,
+    /// Temperature the chip considers critical
+    critical: Option<f64>,
+}
+
+/// The entries of `dir` whose name starts with `prefix`, sorted by
+/// the number after it
+fn numbered(dir: &Path, prefix: &str) -> Vec<PathBuf> {
+    let mut entries: Vec<(u32, PathBuf)> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let number = entry
+                .file_name()
+                .to_str()?
+                .strip_prefix(prefix)?
+                .parse()
+                .ok()?;
+            Some((number, entry.path()))
+        })
+        .collect();
+    entries.sort();
+    entries.into_iter().map(|(_, path)| path).collect()
+}
+
+/// The temperature sensors of the hwmon chips below `root`
+fn hwmon_sensors(root: &Path) -> Vec<Sensor> {
+    let mut sensors = vec![];
+    for chip in numbered(&root.join(HWMON), "hwmon") {
+        let name = read_name(&chip.join("name")).unwrap_or_else(|| String::from("hwmon"));
+        let mut inputs: Vec<(u32, PathBuf)> = fs::read_dir(&chip)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                let file = entry.file_name();
+                let number = file
+                    .to_str()?
+                    .strip_prefix("temp")?
+                    .strip_suffix("_input")?
+                    .parse()
+                    .ok()?;
+                Some((number, entry.path()))
+            })
+            .collect();
+        inputs.sort();
+        for (number, path) in inputs {
+            let sensor = format!("temp{number}");
+            let label = read_name(&chip.join(format!("{sensor}_label")));
+            sensors.push(Sensor {
+                field: field_name(&format!("{name}_{}", label.as_deref().unwrap_or(&sensor))),
+                label: format!("{name} {}", label.as_deref().unwrap_or(&sensor)),
+                path,
+                warning: read_celsius(&chip.join(format!("{sensor}_max"))),
+                critical: read_celsius(&chip.join(format!("{sensor}_crit"))),
+            });
+        }
+    }
+    sensors
+}
+
+/// The thermal zones below `root`, as sensors
+fn thermal_sensors(root: &Path) -> Vec<Sensor> {
+    numbered(&root.join(THERMAL), "thermal_zone")
+        .into_iter()
+        .filter_map(|zone| {
+            let kind = read_name(&zone.join("type"))?;
+            let zone_name = zone.file_name()?.to_str()?.to_string();
+            Some(Sensor {
+                field: field_name(&format!("{kind}_{zone_name}")),
+                label: kind,
+                path: zone.join("temp"),
+                warning: None,
+                critical: None,
+            })
+        })
+        .collect()
+}
+
+/// Reads the temperatures
+#[derive(Debug, Default)]
+pub(crate) struct Temperatures {
+    /// The sensors we read
+    sensors: Vec<Sensor>,
+}
+
+impl Temperatures {
+    /// Look up the sensors below `root`, usually `/`, see the module
+    /// documentation
+    pub(crate) fn new(root: &Path) -> Self {
+        let mut sensors = hwmon_sensors(root);
+        if sensors.is_empty() {
+            sensors = thermal_sensors(root);
+        }
+        // Two chips of the same name, like two NVMe drives
+        let mut seen = std::collections::BTreeMap::new();
+        for sensor in &mut sensors {
+            let count = seen.entry(sensor.field.clone()).or_insert(0);
+            *count += 1;
+            if *count > 1 {
+                sensor.field = format!("{}_{count}", sensor.field);
+            }
+        }
+        Self { sensors }
+    }
+
+    /// Write out the config of the `temp1sec` graph
+    pub(crate) fn config<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        settings: &Settings,
+    ) -> Result<()> {
+        writeln!(handle, "multigraph {GRAPH}")?;
+        writeln!(handle, "graph_title Temperatures (1sec)")?;
+        writeln!(handle, "graph_category {}", settings.graph_category)?;
+        writeln!(handle, "update_rate {}", settings.update_rate())?;
+        writeln!(
+            handle,
+            "graph_data_size {}",
+            settings.retention.graph_data_size()
+        )?;
+        writeln!(handle, "graph_args --base 1000")?;
+        writeln!(handle, "graph_vlabel degrees Celsius")?;
+        writeln!(handle, "graph_scale no")?;
+        writeln!(
+            handle,
+            "graph_info Temperatures of the hwmon sensors, or of the thermal zones without those."
+        )?;
+        for sensor in &self.sensors {
+            let field = &sensor.field;
+            writeln!(handle, "{field}.label {}", sensor.label)?;
+            writeln!(handle, "{field}.type GAUGE")?;
+            if let Some(warning) = sensor.warning {
+                writeln!(handle, "{field}.warning {warning}")?;
+            }
+            if let Some(critical) = sensor.critical {
+                writeln!(handle, "{field}.critical {critical}")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the temperatures and write out the values of the
+    /// `temp1sec` graph for `epoch`. Unknown for sensors that can not
+    /// be read.
+    pub(crate) fn write<W: Write>(&self, handle: &mut BufWriter<W>, epoch: u64) -> Result<()> {
+        writeln!(handle, "multigraph {GRAPH}")?;
+        for sensor in &self.sensors {
+            let field = &sensor.field;
+            match read_celsius(&sensor.path) {
+                Some(celsius) => writeln!(handle, "{field}.value {epoch}:{celsius:.1}")?,
+                None => writeln!(handle, "{field}.value {epoch}:U")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_temperatures() {
+    let root = std::env::temp_dir().join(format!("cpu1sec-temp-{}", std::process::id()));
+    let write = |path: &str, content: &str| {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    };
+    write("sys/class/thermal/thermal_zone0/type", "x86_pkg_temp\n");
+    write("sys/class/thermal/thermal_zone0/temp", "51000\n");
+    let zones = Temperatures::new(&root);
+    assert_eq!(1, zones.sensors.len());
+    assert_eq!("x86_pkg_temp_thermal_zone0", zones.sensors[0].field);
+
+    write("sys/class/hwmon/hwmon1/name", "coretemp\n");
+    write("sys/class/hwmon/hwmon1/temp1_input", "47500\n");
+    write("sys/class/hwmon/hwmon1/temp1_label", "Package id 0\n");
+    write("sys/class/hwmon/hwmon1/temp1_max", "80000\n");
+    write("sys/class/hwmon/hwmon1/temp1_crit", "100000\n");
+    write("sys/class/hwmon/hwmon1/temp2_input", "45000\n");
+    write("sys/class/hwmon/hwmon0/name", "nvme\n");
+    write("sys/class/hwmon/hwmon0/temp1_input", "38850\n");
+    write("sys/class/hwmon/hwmon2/name", "nvme\n");
+    write("sys/class/hwmon/hwmon2/temp1_input", "not a number\n");
+    let temps = Temperatures::new(&root);
+    let fields: Vec<&str> = temps.sensors.iter().map(|s| s.field.as_str()).collect();
+    assert_eq!(
+        vec![
+            "nvme_temp1",
+            "coretemp_package_id_0",
+            "coretemp_temp2",
+            "nvme_temp1_2"
+        ],
+        fields
+    );
+
+    let mut config = BufWriter::new(Vec::new());
+    temps.config(&mut config, &Settings::default()).unwrap();
+    let config = String::from_utf8(config.into_inner().unwrap()).unwrap();
+    assert!(config.contains(
+        "coretemp_package_id_0.label coretemp Package id 0\n\
+         coretemp_package_id_0.type GAUGE\n\
+         coretemp_package_id_0.warning 80\n\
+         coretemp_package_id_0.critical 100\n"
+    ));
+
+    let mut values = BufWriter::new(Vec::new());
+    temps.write(&mut values, 8).unwrap();
+    assert_eq!(
+        "multigraph temp1sec\n\
+         nvme_temp1.value 8:38.9\n\
+         coretemp_package_id_0.value 8:47.5\n\
+         coretemp_temp2.value 8:45.0\n\
+         nvme_temp1_2.value 8:U\n",
+        String::from_utf8(values.into_inner().unwrap()).unwrap()
+    );
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,437 @@
+//! CPU usage of the busiest processes, from /proc/<pid>/stat, the
+//! `top1sec` graph of [crate::Collector::Top]
+//!
+//! Answers "what caused that spike" next to the usage graphs. Munin
+//! needs to know the fields of a graph up front, so the processes are
+//! picked when we start: the [crate::Settings::top_n] ones that used
+//! the most CPU time since they started, told apart by
+//! [crate::Settings::top_by]. Everything else adds up in `other`, so
+//! spikes of processes we did not pick still show. 100% is one CPU
+//! busy all the time, like `top` shows it.
+//!
+//! Reading the stat file of every process each second is not cheap
+//! on boxes with many of them, hence its own feature and flag.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{stat, Settings, TopBy};
+use anyhow::{anyhow, Result};
+use log::warn;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{BufWriter, Write},
+    path::Path,
+    time::Duration,
+};
+
+/// Name of our graph
+const GRAPH: &str = "top1sec";
+
+/// What we need of a /proc/<pid>/stat
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Process {
+    /// The process ID
+    pid: u32,
+    /// The command name, as the kernel has it
+    comm: String,
+    /// User and system time since it started, in ticks
+    ticks: u64,
+}
+
+impl Process {
+    /// Parse the content of /proc/<pid>/stat
+    fn parse(content: &str) -> Result<Self> {
+        // comm may contain anything, even spaces and parentheses, so
+        // it ends at the last one
+        let (pid, rest) = content
+            .split_once(" (")
+            .ok_or_else(|| anyhow!("No comm in process stat"))?;
+        let (comm, rest) = rest
+            .rsplit_once(')')
+            .ok_or_else(|| anyhow!("No end of comm in process stat"))?;
+        // After comm, utime and stime are the 12th and 13th field
+        let mut fields = rest.split_whitespace().skip(11);
+        let mut ticks = || -> Result<u64> {
+            Ok(fields
+                .next()
+                .ok_or_else(|| anyhow!("Process stat too short"))?
+                .parse()?)
+        };
+        Ok(Self {
+            pid: pid.trim().parse()?,
+            comm: comm.to_string(),
+            ticks: ticks()? + ticks()?,
+        })
+    }
+
+    /// All processes below `proc_root`. Those that exit while we
+    /// look are skipped.
+    fn scan(proc_root: &Path) -> Vec<Self> {
+        fs::read_dir(proc_root)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| {
+                let entry = entry.ok()?;
+                entry.file_name().to_str()?.parse::<u32>().ok()?;
+                let content = fs::read_to_string(entry.path().join("stat")).ok()?;
+                Self::parse(&content).ok()
+            })
+            .collect()
+    }
+
+    /// What tells us apart from other processes with `by`
+    fn key(&self, by: TopBy) -> String {
+        match by {
+            TopBy::Comm => self.comm.clone(),
+            TopBy::Pid => self.pid.to_string(),
+        }
+    }
+}
+
+#[test]
+fn test_parse() {
+    let process = Process::parse(
+        "4242 (tmux: server) S 1 4242 4242 0 -1 4194560 5290 0 0 0 1200 345 0 0 20 0 1 0 2931 10592256 1015 18446744073709551615\n",
+    )
+    .unwrap();
+    assert_eq!(
+        Process {
+            pid: 4242,
+            comm: String::from("tmux: server"),
+            ticks: 1545
+        },
+        process
+    );
+    assert_eq!(
+        "a) (b",
+        Process::parse("7 (a) (b) R 1 1 1 0 -1 0 0 0 0 0 1 2\n")
+            .unwrap()
+            .comm
+    );
+    assert!(Process::parse("7 (short) R 1 1\n").is_err());
+    assert!(Process::parse("").is_err());
+}
+
+/// munin field name of the process `key`, which may neither start
+/// with a digit nor have anything but letters, digits and underscores
+fn field(key: &str, by: TopBy) -> String {
+    let key: String = key
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    match by {
+        TopBy::Comm => format!("comm_{key}"),
+        TopBy::Pid => format!("pid_{key}"),
+    }
+}
+
+/// The ticks of every process, by pid, with their comm to notice
+/// reused pids
+type Ticks = BTreeMap<u32, (String, u64)>;
+
+/// The processes we graph, and the CPU time of all when we looked
+/// last
+#[derive(Debug, Default)]
+pub(crate) struct TopProcesses {
+    /// What processes are told apart by
+    by: TopBy,
+    /// The processes, by key, with their field and label
+    selected: Vec<(String, String, String)>,
+    /// Ticks per second of the CPU times
+    tps: u64,
+    /// Epoch of the last read, and the ticks of every process then
+    last: Option<(u64, Ticks)>,
+}
+
+impl TopProcesses {
+    /// Pick the processes of [Settings::proc_root] to graph, see the
+    /// module documentation
+    pub(crate) fn new(settings: &Settings) -> Self {
+        let by = settings.top_by;
+        let processes = Process::scan(&settings.proc_root);
+        if processes.is_empty() {
+            warn!(
+                "No processes in {}, nothing to graph",
+                settings.proc_root.display()
+            );
+        }
+        let mut totals: BTreeMap<String, (u64, String)> = BTreeMap::new();
+        for process in processes {
+            let total = totals
+                .entry(process.key(by))
+                .or_insert((0, process.comm.clone()));
+            total.0 += process.ticks;
+        }
+        let mut totals: Vec<(String, (u64, String))> = totals.into_iter().collect();
+        // Stable order for processes with the same time
+        totals.sort_by(|(a, (a_ticks, _)), (b, (b_ticks, _))| {
+            b_ticks.cmp(a_ticks).then_with(|| a.cmp(b))
+        });
+        totals.truncate(settings.top_n);
+        // Different keys can end up as the same field, like Foo and
+        // foo, or kworker/0:1 and kworker_0_1. Later ones get a
+        // number.
+        let mut fields = BTreeSet::new();
+        let selected = totals
+            .into_iter()
+            .map(|(key, (_, comm))| {
+                let label = match by {
+                    TopBy::Comm => comm,
+                    TopBy::Pid => format!("{key} {comm}"),
+                };
+                let base = field(&key, by);
+                let mut field = base.clone();
+                let mut count = 1;
+                while !fields.insert(field.clone()) {
+                    count += 1;
+                    field = format!("{base}_{count}");
+                }
+                (key, field, label)
+            })
+            .collect();
+        Self {
+            by,
+            selected,
+            tps: stat::ticks_per_second(),
+            last: None,
+        }
+    }
+
+    /// Write out the config of the `top1sec` graph
+    pub(crate) fn config<W: Write>(
+        &self,
+        handle: &mut BufWriter<W>,
+        settings: &Settings,
+    ) -> Result<()> {
+        writeln!(handle, "multigraph {GRAPH}")?;
+        writeln!(handle, "graph_title Busiest processes (1sec)")?;
+        writeln!(handle, "graph_category {}", settings.graph_category)?;
+        writeln!(handle, "update_rate {}", settings.update_rate())?;
+        writeln!(
+            handle,
+            "graph_data_size {}",
+            settings.retention.graph_data_size()
+        )?;
+        writeln!(handle, "graph_args --base 1000 --lower-limit 0")?;
+        writeln!(handle, "graph_vlabel %")?;
+        writeln!(handle, "graph_scale no")?;
+        writeln!(
+            handle,
+            "graph_info CPU time of the processes that used the most when the plugin started, from /proc/<pid>/stat. 100% is one CPU."
+        )?;
+        for (i, (_, field, label)) in self.selected.iter().enumerate() {
+            writeln!(handle, "{field}.label {label}")?;
+            writeln!(
+                handle,
+                "{field}.draw {}",
+                if i == 0 { "AREA" } else { "STACK" }
+            )?;
+            writeln!(handle, "{field}.min 0")?;
+            writeln!(handle, "{field}.type GAUGE")?;
+        }
+        writeln!(handle, "other.label other")?;
+        writeln!(
+            handle,
+            "other.draw {}",
+            if self.selected.is_empty() {
+                "AREA"
+            } else {
+                "STACK"
+            }
+        )?;
+        writeln!(handle, "other.min 0")?;
+        writeln!(handle, "other.type GAUGE")?;
+        writeln!(handle, "other.info All other processes")?;
+        Ok(())
+    }
+
+    /// Read the stat of every process below `proc_root` and write out
+    /// the CPU usage of ours since the last read, for `epoch`.
+    /// Unknown if we can not tell, after more than `max_gap`, and for
+    /// a pid that is gone. Processes that exited since the last read
+    /// miss their last bit of CPU time. With more than one sample per
+    /// second only the first one in a second counts.
+    pub(crate) fn write<W: Write>(
+        &mut self,
+        handle: &mut BufWriter<W>,
+        proc_root: &Path,
+        epoch: u64,
+        max_gap: Duration,
+    ) -> Result<()> {
+        if self.last.as_ref().is_some_and(|(last, _)| *last == epoch) {
+            return Ok(());
+        }
+        let processes = Process::scan(proc_root);
+        let old = self
+            .last
+            .as_ref()
+            .filter(|(last, _)| epoch > *last && Duration::from_secs(epoch - last) <= max_gap)
+            .map(|(last, old)| (epoch - last, old));
+        let mut ticks: BTreeMap<String, u64> = BTreeMap::new();
+        let mut other = 0;
+        if let Some((_, old)) = old {
+            for process in &processes {
+                // Started since, or a reused pid: all of it is new
+                let since = match old.get(&process.pid) {
+                    Some((comm, ticks)) if *comm == process.comm => {
+                        process.ticks.saturating_sub(*ticks)
+                    }
+                    _ => process.ticks,
+                };
+                let key = process.key(self.by);
+                if self
+                    .selected
+                    .iter()
+                    .any(|(selected, _, _)| *selected == key)
+                {
+                    *ticks.entry(key).or_default() += since;
+                } else {
+                    other += since;
+                }
+            }
+        }
+        // Ticks to percent of a CPU
+        let percent = |ticks: u64| {
+            let (seconds, _) = old?;
+            Some(ticks as f64 * 100.0 / (self.tps * seconds) as f64)
+        };
+        writeln!(handle, "multigraph {GRAPH}")?;
+        for (key, field, _) in &self.selected {
+            // A comm without processes used nothing, a pid is gone
+            let value = match (ticks.get(key), self.by) {
+                (Some(ticks), _) => percent(*ticks),
+                (None, TopBy::Comm) => percent(0),
+                (None, TopBy::Pid) => None,
+            };
+            match value {
+                Some(value) => writeln!(handle, "{field}.value {epoch}:{value:.1}")?,
+                None => writeln!(handle, "{field}.value {epoch}:U")?,
+            }
+        }
+        match percent(other) {
+            Some(value) => writeln!(handle, "other.value {epoch}:{value:.1}")?,
+            None => writeln!(handle, "other.value {epoch}:U")?,
+        }
+        self.last = Some((
+            epoch,
+            processes
+                .into_iter()
+                .map(|process| (process.pid, (process.comm, process.ticks)))
+                .collect(),
+        ));
+        Ok(())
+    }
+}
+
+#[test]
+fn test_top_processes() {
+    let root = std::env::temp_dir().join(format!("cpu1sec-top-{}", std::process::id()));
+    let write = |pid: u32, comm: &str, utime: u64| {
+        let dir = root.join(pid.to_string());
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("stat"),
+            format!("{pid} ({comm}) S 1 1 1 0 -1 0 0 0 0 0 {utime} 0 0 0 20 0 1 0 1\n"),
+        )
+        .unwrap();
+    };
+    write(1, "systemd", 500);
+    write(100, "postgres", 3000);
+    write(101, "postgres", 2000);
+    write(200, "kworker/0:1", 100);
+    fs::create_dir_all(root.join("self")).unwrap();
+
+    let settings = Settings {
+        proc_root: root.clone(),
+        top_n: 2,
+        ..Default::default()
+    };
+    let mut top = TopProcesses::new(&settings);
+    top.tps = 100;
+    let mut config = BufWriter::new(Vec::new());
+    top.config(&mut config, &settings).unwrap();
+    let config = String::from_utf8(config.into_inner().unwrap()).unwrap();
+    assert!(config.contains("comm_postgres.label postgres\ncomm_postgres.draw AREA\n"));
+    assert!(config.contains("comm_systemd.label systemd\ncomm_systemd.draw STACK\n"));
+    assert!(config.contains("other.label other\nother.draw STACK\n"));
+    assert!(!config.contains("kworker"));
+
+    let values = |top: &mut TopProcesses, epoch| {
+        let mut handle = BufWriter::new(Vec::new());
+        top.write(&mut handle, &root, epoch, Duration::from_secs(5))
+            .unwrap();
+        String::from_utf8(handle.into_inner().unwrap()).unwrap()
+    };
+    assert_eq!(
+        "multigraph top1sec\n\
+         comm_postgres.value 1:U\n\
+         comm_systemd.value 1:U\n\
+         other.value 1:U\n",
+        values(&mut top, 1)
+    );
+    write(100, "postgres", 3150);
+    write(101, "postgres", 2050);
+    // A new one, and a reused pid
+    write(300, "make", 20);
+    fs::remove_dir_all(root.join("1")).unwrap();
+    write(200, "cc1", 10);
+    assert_eq!(
+        "multigraph top1sec\n\
+         comm_postgres.value 3:100.0\n\
+         comm_systemd.value 3:0.0\n\
+         other.value 3:15.0\n",
+        values(&mut top, 3)
+    );
+    assert_eq!("", values(&mut top, 3));
+
+    let settings = Settings {
+        top_by: TopBy::Pid,
+        ..settings
+    };
+    let mut top = TopProcesses::new(&settings);
+    top.tps = 100;
+    assert_eq!(
+        vec!["pid_100", "pid_101"],
+        top.selected
+            .iter()
+            .map(|(_, field, _)| field.as_str())
+            .collect::<Vec<_>>()
+    );
+    assert_eq!("100 postgres", top.selected[0].2);
+    values(&mut top, 1);
+    fs::remove_dir_all(root.join("101")).unwrap();
+    write(100, "postgres", 3250);
+    assert!(values(&mut top, 2).contains("pid_100.value 2:100.0\npid_101.value 2:U\n"));
+
+    // Names munin would see as the same field
+    write(400, "kworker/0:1", 100);
+    write(401, "kworker_0_1", 90);
+    write(402, "Make", 15);
+    let settings = Settings {
+        top_by: TopBy::Comm,
+        top_n: 10,
+        ..settings
+    };
+    let top = TopProcesses::new(&settings);
+    assert_eq!(
+        vec![
+            "comm_postgres",
+            "comm_kworker_0_1",
+            "comm_kworker_0_1_2",
+            "comm_make",
+            "comm_make_2",
+            "comm_cc1"
+        ],
+        top.selected
+            .iter()
+            .map(|(_, field, _)| field.as_str())
+            .collect::<Vec<_>>()
+    );
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,73 @@
+//! Watchdog for the data collection in daemon mode
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use log::error;
+use std::{
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+
+/// Keeps an eye on a piece of work (our acquire), so a read that
+/// hangs forever (stuck mount, buggy driver) does not silently stall
+/// the whole daemon.
+pub(crate) struct Watchdog {
+    /// Tells the watchdog thread when work starts (true) and when it
+    /// is done (false)
+    tx: Sender<bool>,
+}
+
+impl Watchdog {
+    /// Start the watchdog thread. If guarded work does not finish
+    /// within `timeout`, an error is logged and `on_timeout` is run.
+    pub(crate) fn spawn<F>(timeout: Duration, on_timeout: F) -> Self
+    where
+        F: Fn() + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            // Wait for work to start, then for it to finish
+            while let Ok(true) = rx.recv() {
+                match rx.recv_timeout(timeout) {
+                    Ok(_) => continue,
+                    Err(RecvTimeoutError::Timeout) => {
+                        error!("Data collection did not finish within {timeout:?}");
+                        on_timeout();
+                        // Wait for the work to finish before watching
+                        // the next one, no point in reporting the same
+                        // hang again.
+                        if rx.recv().is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// Run `work` under the eyes of the watchdog
+    pub(crate) fn guard<T>(&self, work: impl FnOnce() -> T) -> T {
+        // If the watchdog thread is gone, we can't do anything about
+        // it, the work itself still matters more.
+        let _ = self.tx.send(true);
+        let result = work();
+        let _ = self.tx.send(false);
+        result
+    }
+}
+
+#[test]
+fn test_watchdog() {
+    let (tx, rx) = mpsc::channel();
+    let watchdog = Watchdog::spawn(Duration::from_millis(50), move || {
+        tx.send(()).unwrap();
+    });
+    // Quick work does not trigger it
+    watchdog.guard(|| ());
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    // A source that blocks does
+    watchdog.guard(|| thread::sleep(Duration::from_millis(200)));
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_ok());
+}
@@ -0,0 +1,296 @@
+//! WebSocket server streaming the samples of the daemon to browsers
+//! (`websocket_addr`), for live dashboards without polling
+//!
+//! Every sample goes out as one text message, a JSON array with an
+//! object per graph, as in [crate::json]. Only as much of RFC 6455 as
+//! that takes: the handshake and unmasked text frames from us.
+//! Whatever clients send is ignored, a client that goes away is
+//! dropped with the next sample it can not take.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use crate::{fanout::Target, json, CpuStat, Settings};
+use anyhow::Result;
+use log::{info, warn};
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
+
+/// How long a client may take for its handshake, or to take a sample
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Appended to the key of the client for the accept header
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Streams the samples to WebSocket clients, accepting them on a
+/// thread of its own
+#[derive(Debug)]
+pub(crate) struct WebSocketServer {
+    /// Where we listen
+    pub(crate) addr: SocketAddr,
+    /// The clients, shared with the thread accepting them
+    clients: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl WebSocketServer {
+    /// Listen on `addr` (host:port). Has to happen after daemonizing,
+    /// threads do not survive the fork.
+    pub(crate) fn spawn(addr: &str) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let clients = Arc::new(Mutex::new(vec![]));
+        let shared = Arc::clone(&clients);
+        thread::Builder::new()
+            .name(String::from("websocket"))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream.and_then(handshake) {
+                        Ok(Some(client)) => shared
+                            .lock()
+                            .unwrap_or_else(PoisonError::into_inner)
+                            .push(client),
+                        Ok(None) => (),
+                        Err(e) => warn!("Could not accept a WebSocket client: {e}"),
+                    }
+                }
+            })?;
+        Ok(Self { addr, clients })
+    }
+
+    /// Send the sample `graphs` to all clients
+    pub(crate) fn update(&self, graphs: &[CpuStat]) {
+        let frame = frame(&json::encode_array(graphs));
+        self.clients
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain_mut(|client| client.write_all(&frame).is_ok());
+    }
+}
+
+impl Target for WebSocketServer {
+    fn deliver(&mut self, graphs: &[CpuStat]) {
+        self.update(graphs);
+    }
+}
+
+/// The [Target] for [Settings::websocket_addr], if set and we can
+/// listen there
+pub(crate) fn target(settings: &Settings) -> Option<Box<dyn Target>> {
+    let addr = settings.websocket_addr.as_ref()?;
+    match WebSocketServer::spawn(addr) {
+        Ok(server) => {
+            info!("Streaming samples on ws://{}/", server.addr);
+            Some(Box::new(server))
+        }
+        Err(e) => {
+            warn!("Not streaming samples on {addr}: {e}");
+            None
+        }
+    }
+}
+
+/// Do the opening handshake with a new client. None if it did not
+/// ask for a WebSocket, it got told so.
+fn handshake(stream: TcpStream) -> io::Result<Option<TcpStream>> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut key = None;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.trim().to_string());
+            }
+        }
+        line.clear();
+    }
+    let mut out = &stream;
+    let Some(key) = key else {
+        let body = "Connect with a WebSocket\n";
+        write!(
+            out,
+            "HTTP/1.1 426 Upgrade Required\r\n\
+             Upgrade: websocket\r\n\
+             Content-Type: text/plain\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len()
+        )?;
+        return Ok(None);
+    };
+    write!(
+        out,
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept(&key)
+    )?;
+    Ok(Some(stream))
+}
+
+/// The Sec-WebSocket-Accept answer to the Sec-WebSocket-Key `key`
+fn accept(key: &str) -> String {
+    base64(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/// `payload` as a single, unmasked, text frame
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x81];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// SHA-1 of `data`, all the handshake needs it for
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [
+        0x6745_2301,
+        0xefcd_ab89,
+        0x98ba_dcfe,
+        0x1032_5476,
+        0xc3d2_e1f0,
+    ];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend((data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(x);
+        }
+    }
+    let mut digest = [0; 20];
+    for (out, h) in digest.chunks_exact_mut(4).zip(h) {
+        out.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+/// Standard base64 of `data`, with padding
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | u32::from(*byte) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[test]
+fn test_handshake_helpers() {
+    // The example of RFC 6455
+    assert_eq!(
+        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=",
+        accept("dGhlIHNhbXBsZSBub25jZQ==")
+    );
+    assert_eq!("qZk+NkcGgWq6PiVxeFDCbJzQ2J0=", base64(&sha1(b"abc")));
+    assert_eq!("Zm9vYg==", base64(b"foob"));
+    assert_eq!(vec![0x81, 2, b'{', b'}'], frame(b"{}"));
+    let long = frame(&[b' '; 300]);
+    assert_eq!(&[0x81, 126, 1, 44], &long[..4]);
+    assert_eq!(304, long.len());
+}
+
+#[test]
+fn test_websocket_server() {
+    use std::io::Read;
+
+    let server = WebSocketServer::spawn("127.0.0.1:0").unwrap();
+    let mut plain = TcpStream::connect(server.addr).unwrap();
+    write!(plain, "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    plain.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+
+    let client = TcpStream::connect(server.addr).unwrap();
+    write!(
+        &client,
+        "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+         Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+         Sec-WebSocket-Version: 13\r\n\r\n"
+    )
+    .unwrap();
+    let mut reader = BufReader::new(&client);
+    let mut headers = vec![];
+    let mut line = String::new();
+    while reader.read_line(&mut line).unwrap() > 2 {
+        headers.push(line.trim_end().to_string());
+        line.clear();
+    }
+    assert_eq!("HTTP/1.1 101 Switching Protocols", headers[0]);
+    assert!(headers.contains(&String::from(
+        "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    )));
+    // The server thread has to take it first
+    while server.clients.lock().unwrap().is_empty() {
+        thread::sleep(Duration::from_millis(1));
+    }
+    server.update(&[CpuStat {
+        epoch: 5,
+        ..Default::default()
+    }]);
+    let mut head = [0; 2];
+    reader.read_exact(&mut head).unwrap();
+    assert_eq!(0x81, head[0]);
+    // Longer than 125 bytes, a 16 bit length follows
+    assert_eq!(126, head[1]);
+    let mut len = [0; 2];
+    reader.read_exact(&mut len).unwrap();
+    let mut payload = vec![0; usize::from(u16::from_be_bytes(len))];
+    reader.read_exact(&mut payload).unwrap();
+    let payload = String::from_utf8(payload).unwrap();
+    assert!(payload.starts_with("[{\"cpu\":\"total\",\"epoch\":5,"));
+}
@@ -0,0 +1,74 @@
+//! Runs the plugin over recorded /proc/stat snapshots and compares
+//! its output to what we expect, to lock in the output format.
+//!
+//! Every `tests/fixtures/<name>.input` starts with the plugin
+//! settings, as `name=value` lines like munin would pass them in the
+//! environment, followed by /proc/stat snapshots, separated by empty
+//! lines. Lines starting with `#` are comments. The plugin starts
+//! from the first snapshot, taken at epoch 1650000000, every
+//! following one is read one second after the one before.
+//! `<name>.expected` holds all the values written for them.
+//!
+//! To lock in a behavior, add a new pair.
+// SPDX-License-Identifier:  GPL-3.0-only
+
+use munin_cpu1sec::{CpuPlugin, Settings};
+use procfs::KernelStats;
+use std::{collections::HashMap, fs, io::BufWriter};
+
+/// Epoch of the first snapshot
+const EPOCH: u64 = 1_650_000_000;
+
+/// Feed the snapshots in `input` to the plugin, return what it wrote
+fn run(input: &str) -> String {
+    let mut vars = HashMap::new();
+    let mut snapshots = vec![String::new()];
+    for line in input.lines().filter(|l| !l.starts_with('#')) {
+        match line.split_once('=') {
+            Some((name, value)) => {
+                vars.insert(name.to_string(), value.to_string());
+            }
+            None if line.is_empty() => snapshots.push(String::new()),
+            None => {
+                let snapshot = snapshots.last_mut().unwrap();
+                snapshot.push_str(line);
+                snapshot.push('\n');
+            }
+        }
+    }
+    let mut snapshots = snapshots
+        .iter()
+        .filter(|s| !s.is_empty())
+        .map(|s| KernelStats::from_reader(s.as_bytes()).unwrap());
+
+    // Do not look at the machine we run on
+    vars.entry(String::from("source"))
+        .or_insert_with(|| String::from("proc"));
+    vars.entry(String::from("guest_fields"))
+        .or_insert_with(|| String::from("1"));
+    let (settings, errors) = Settings::from_vars(|name| vars.get(name).cloned());
+    assert!(errors.is_empty(), "Invalid settings: {errors:?}");
+
+    let mut cpu = CpuPlugin::with_stats(settings, snapshots.next().unwrap(), EPOCH);
+    let mut handle = BufWriter::new(Vec::new());
+    for (epoch, ks) in (EPOCH + 1..).zip(snapshots) {
+        cpu.write_cpu(&mut handle, ks, epoch).unwrap();
+    }
+    String::from_utf8(handle.into_inner().unwrap()).unwrap()
+}
+
+#[test]
+fn test_fixtures() {
+    let pattern = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/*.input");
+    let mut count = 0;
+    for input in glob::glob(pattern).unwrap() {
+        let input = input.unwrap();
+        let expected = input.with_extension("expected");
+        let expected = fs::read_to_string(&expected)
+            .unwrap_or_else(|e| panic!("Can not read {}: {e}", expected.display()));
+        let output = run(&fs::read_to_string(&input).unwrap());
+        assert_eq!(expected, output, "Output differs for {}", input.display());
+        count += 1;
+    }
+    assert!(count > 0, "No fixtures found");
+}